@@ -1,5 +1,4 @@
 // Copyright 2021 Oxide Computer Company
-use std::net::SocketAddrV4;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
@@ -36,8 +35,8 @@ arg_enum! {
 #[derive(Debug, StructOpt)]
 #[structopt(about = "crucible upstairs test client")]
 pub struct Opt {
-    #[structopt(short, long, default_value = "127.0.0.1:9000")]
-    target: Vec<SocketAddrV4>,
+    #[structopt(flatten)]
+    crucible: CrucibleOpts,
 
     #[structopt(
         short,
@@ -65,9 +64,6 @@ pub struct Opt {
     #[structopt(short, long)]
     quit: bool,
 
-    #[structopt(short, long)]
-    key: Option<String>,
-
     /*
      * For tests that support it, load the expected write count from
      * the provided file.
@@ -87,7 +83,7 @@ pub fn opts() -> Result<Opt> {
     let opt: Opt = Opt::from_args();
     println!("raw options: {:?}", opt);
 
-    if opt.target.is_empty() {
+    if opt.crucible.target.is_empty() {
         bail!("must specify at least one --target");
     }
 
@@ -205,11 +201,9 @@ fn main() -> Result<()> {
         bail!("Verify requires verify_in file");
     }
 
-    let crucible_opts = CrucibleOpts {
-        target: opt.target,
-        lossy: opt.lossy,
-        key: opt.key,
-    };
+    let mut crucible_opts = opt.crucible;
+    crucible_opts.lossy = opt.lossy;
+    crucible_opts.quorum()?;
 
     /*
      * Crucible needs a runtime as it will create several async tasks to
@@ -235,6 +229,9 @@ fn main() -> Result<()> {
     println!("Crucible runtime is spawned");
 
     guest.activate()?;
+    if guest.query_degraded()? {
+        println!("Upstairs is active, but not every downstairs is");
+    }
 
     std::thread::sleep(std::time::Duration::from_secs(2));
 
@@ -9,7 +9,9 @@ use anyhow::{anyhow, bail, Context, Result};
 use serde::{Deserialize, Serialize};
 use tempfile::NamedTempFile;
 
+mod reconcile;
 mod region;
+pub use reconcile::{reconcile_extent, ExtentFix, ExtentVersion};
 pub use region::{
     Block, RegionDefinition, RegionOptions, MAX_BLOCK_SIZE, MIN_BLOCK_SIZE,
 };
@@ -54,6 +56,27 @@ pub enum CrucibleError {
 
     #[error("Saw a UUID that wasn't ours!")]
     UuidMismatch,
+
+    #[error("Attempt to modify a read-only region!")]
+    ModifyingReadOnlyRegion,
+
+    #[error("Extent repair refused: {0}")]
+    RepairRefused(String),
+
+    #[error("Upstairs is busy, too much write data is queued")]
+    UpstairsBusy,
+}
+
+impl CrucibleError {
+    /*
+     * Return true if the same IO could work if we tried it again, as it
+     * can once a downstairs that was out of space or resources has some
+     * back.  The other errors come from the request itself or the state
+     * of the region, and will not go away.
+     */
+    pub fn is_transient(&self) -> bool {
+        matches!(self, CrucibleError::IoError(_))
+    }
 }
 
 impl From<std::io::Error> for CrucibleError {
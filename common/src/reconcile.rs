@@ -0,0 +1,122 @@
+// Copyright 2021 Oxide Computer Company
+use serde::{Deserialize, Serialize};
+
+/*
+ * What each copy of an extent tells us about how recent it is.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ExtentVersion {
+    pub gen: u64,
+    pub flush: u64,
+    pub dirty: bool,
+}
+
+/*
+ * How to make the copies of one extent agree.  `source` is the index of
+ * the copy to keep, and every copy in `repair` should be replaced with it.
+ */
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ExtentFix {
+    pub source: usize,
+    pub repair: Vec<usize>,
+}
+
+/*
+ * Decide which copy of an extent wins, given the version of each copy.
+ *
+ * The copy with the highest generation wins, and after that the highest
+ * flush number.  If more than one copy is tied, one that is not dirty is
+ * picked over one that is, as a dirty copy has writes that were never
+ * flushed and so may never have been acked.  Past that, the first copy
+ * wins.
+ *
+ * Copies that have the same version as the winner are left alone, unless
+ * the winner is dirty.  Two dirty copies can have different data under the
+ * same version, so then every other copy is replaced.
+ *
+ * Returns None if the copies already agree.
+ */
+pub fn reconcile_extent(versions: &[ExtentVersion]) -> Option<ExtentFix> {
+    let (source, best) = versions
+        .iter()
+        .enumerate()
+        .rev()
+        .max_by_key(|(_, v)| (v.gen, v.flush, !v.dirty))?;
+
+    let repair = versions
+        .iter()
+        .enumerate()
+        .filter(|(i, v)| *i != source && (best.dirty || *v != best))
+        .map(|(i, _)| i)
+        .collect::<Vec<_>>();
+
+    if repair.is_empty() {
+        None
+    } else {
+        Some(ExtentFix { source, repair })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn v(gen: u64, flush: u64, dirty: bool) -> ExtentVersion {
+        ExtentVersion { gen, flush, dirty }
+    }
+
+    #[test]
+    fn reconcile_all_match() {
+        let same = v(1, 4, false);
+        assert_eq!(reconcile_extent(&[same, same, same]), None);
+        assert_eq!(reconcile_extent(&[]), None);
+    }
+
+    #[test]
+    fn reconcile_highest_wins() {
+        assert_eq!(
+            reconcile_extent(&[v(1, 4, false), v(1, 5, false), v(1, 4, false)]),
+            Some(ExtentFix {
+                source: 1,
+                repair: vec![0, 2]
+            })
+        );
+
+        /*
+         * Generation is looked at before the flush number.
+         */
+        assert_eq!(
+            reconcile_extent(&[v(1, 9, false), v(2, 3, false), v(2, 3, false)]),
+            Some(ExtentFix {
+                source: 1,
+                repair: vec![0]
+            })
+        );
+    }
+
+    #[test]
+    fn reconcile_dirty() {
+        /*
+         * A clean copy beats a dirty one with the same version.
+         */
+        assert_eq!(
+            reconcile_extent(&[v(1, 4, true), v(1, 4, false), v(1, 4, false)]),
+            Some(ExtentFix {
+                source: 1,
+                repair: vec![0]
+            })
+        );
+
+        /*
+         * If the winner is dirty, everyone else gets its copy, even if
+         * they look the same.
+         */
+        assert_eq!(
+            reconcile_extent(&[v(1, 4, true), v(1, 4, true), v(1, 3, false)]),
+            Some(ExtentFix {
+                source: 0,
+                repair: vec![1, 2]
+            })
+        );
+    }
+}
@@ -0,0 +1,665 @@
+// Copyright 2021 Oxide Computer Company
+use super::*;
+use crate::fault::FaultConfig;
+use crate::region::ExtentMeta;
+use crate::repair::{fetch_extents, push_extents};
+
+use serde::{Deserialize, Serialize};
+use std::os::unix::fs::FileTypeExt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, BufWriter};
+use tokio::net::{UnixListener, UnixStream};
+
+/*
+ * The admin socket lets an operator see what a downstairs is doing, kick
+ * off an upstairs if needed, add or remove regions, change how much IO
+ * each region may do, and inject faults for testing.
+ *
+ * Each request is a single line of JSON, and each gets back a single line
+ * of JSON in response.  For example, this will return the overall status:
+ *
+ *     echo '"Status"' | nc -U /path/to/admin.sock
+ *
+ * and this will disconnect an upstairs:
+ *
+ *     echo '{"Disconnect":"<upstairs uuid>"}' | nc -U /path/to/admin.sock
+ *
+ * Requests that are about one region take its UUID, which may be null if
+ * we are only serving one region.  To copy extent 3 of a region from
+ * another downstairs:
+ *
+ *     echo '{"FetchExtents":{"region":null,"peer":"10.0.0.2:3801",
+ *         "peer_region":null,"extents":[3]}}' | nc -U /path/to/admin.sock
+ */
+#[derive(Debug, Deserialize)]
+pub enum AdminRequest {
+    /*
+     * Region definitions, connected upstairs, job counts, and IO counters.
+     */
+    Status,
+    /*
+     * Every job on the work queue of a region.
+     */
+    Jobs(Option<Uuid>),
+    /*
+     * The generation, flush number, and dirty bit for every extent of a
+     * region.
+     */
+    Extents(Option<Uuid>),
+    /*
+     * Drop every connection from the upstairs with this UUID.
+     */
+    Disconnect(Uuid),
+    /*
+     * Change the IOPS and bandwidth limits of a region.
+     */
+    SetQos(Option<Uuid>, QosLimits),
+    /*
+     * Change the faults we inject into the IO of a region.
+     */
+    SetFaults(Option<Uuid>, FaultConfig),
+    /*
+     * Start serving the region in this directory.
+     */
+    AddRegion(PathBuf),
+    /*
+     * Stop serving a region.  It must not have any connections.
+     */
+    RemoveRegion(Uuid),
+    /*
+     * Replace extents of a region with copies from a peer downstairs.
+     * No upstairs may be active on the region while we do this.
+     */
+    FetchExtents {
+        region: Option<Uuid>,
+        peer: SocketAddr,
+        peer_region: Option<Uuid>,
+        extents: Vec<u32>,
+    },
+    /*
+     * Copy extents of a region over the ones on a peer downstairs.  No
+     * upstairs may be active on the peer region.
+     */
+    PushExtents {
+        region: Option<Uuid>,
+        peer: SocketAddr,
+        peer_region: Option<Uuid>,
+        extents: Vec<u32>,
+    },
+}
+
+#[derive(Debug, Serialize)]
+pub enum AdminResponse {
+    Status(HostStatus),
+    Jobs(Vec<JobStatus>),
+    Extents(Vec<ExtentStatus>),
+    /*
+     * How many connections were told to disconnect.
+     */
+    Disconnected(usize),
+    Qos(QosLimits),
+    Faults(FaultConfig),
+    RegionAdded(Uuid),
+    RegionRemoved(Uuid),
+    /*
+     * How many extents were copied.
+     */
+    ExtentsRepaired(usize),
+    Error(String),
+}
+
+#[derive(Debug, Serialize)]
+pub struct HostStatus {
+    regions: Vec<DownstairsStatus>,
+    connections: Vec<ConnectionStatus>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DownstairsStatus {
+    region: RegionDefinition,
+    read_only: bool,
+    active_upstairs: Option<Uuid>,
+    new_jobs: usize,
+    dep_wait_jobs: usize,
+    in_progress_jobs: usize,
+    responses_waiting: usize,
+    last_flush: u64,
+    counters: IoCountersSnapshot,
+    qos: QosLimits,
+    faults: FaultConfig,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ConnectionStatus {
+    id: u64,
+    address: String,
+    upstairs_uuid: Option<Uuid>,
+    region: Option<Uuid>,
+    active: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct JobStatus {
+    ds_id: u64,
+    upstairs_uuid: Uuid,
+    op: String,
+    eid: Option<u64>,
+    state: WorkState,
+    dependencies: Vec<u64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ExtentStatus {
+    extent: u32,
+    #[serde(flatten)]
+    meta: ExtentMeta,
+}
+
+/*
+ * Counters for the IO this downstairs has done since it started.
+ */
+#[derive(Debug, Default)]
+pub struct IoCounters {
+    reads: AtomicU64,
+    read_bytes: AtomicU64,
+    writes: AtomicU64,
+    write_bytes: AtomicU64,
+    flushes: AtomicU64,
+    errors: AtomicU64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct IoCountersSnapshot {
+    reads: u64,
+    read_bytes: u64,
+    writes: u64,
+    write_bytes: u64,
+    flushes: u64,
+    errors: u64,
+}
+
+impl IoCounters {
+    pub fn read(&self, bytes: usize, result: &Result<(), CrucibleError>) {
+        self.reads.fetch_add(1, Ordering::Relaxed);
+        self.read_bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+        self.error(result);
+    }
+
+    pub fn write(&self, bytes: usize, result: &Result<(), CrucibleError>) {
+        self.writes.fetch_add(1, Ordering::Relaxed);
+        self.write_bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+        self.error(result);
+    }
+
+    pub fn flush(&self, result: &Result<(), CrucibleError>) {
+        self.flushes.fetch_add(1, Ordering::Relaxed);
+        self.error(result);
+    }
+
+    fn error(&self, result: &Result<(), CrucibleError>) {
+        if result.is_err() {
+            self.errors.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn snapshot(&self) -> IoCountersSnapshot {
+        IoCountersSnapshot {
+            reads: self.reads.load(Ordering::Relaxed),
+            read_bytes: self.read_bytes.load(Ordering::Relaxed),
+            writes: self.writes.load(Ordering::Relaxed),
+            write_bytes: self.write_bytes.load(Ordering::Relaxed),
+            flushes: self.flushes.load(Ordering::Relaxed),
+            errors: self.errors.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl Downstairs {
+    async fn status(&self) -> DownstairsStatus {
+        let work = self.work.lock().await;
+
+        let mut new_jobs = 0;
+        let mut dep_wait_jobs = 0;
+        let mut in_progress_jobs = 0;
+        for job in work.active.values() {
+            match job.state {
+                WorkState::New => new_jobs += 1,
+                WorkState::DepWait => dep_wait_jobs += 1,
+                WorkState::InProgress => in_progress_jobs += 1,
+                _ => {}
+            }
+        }
+
+        DownstairsStatus {
+            region: self.region.def(),
+            read_only: self.region.read_only(),
+            active_upstairs: self.active_upstairs(),
+            new_jobs,
+            dep_wait_jobs,
+            in_progress_jobs,
+            responses_waiting: work.responses.len(),
+            last_flush: work.last_flush,
+            counters: self.counters.snapshot(),
+            qos: self.qos.limits(),
+            faults: self.faults.lock().await.config().clone(),
+        }
+    }
+
+    async fn job_status(&self) -> Vec<JobStatus> {
+        let work = self.work.lock().await;
+
+        let mut jobs = work
+            .active
+            .values()
+            .map(|job| {
+                let (op, eid, dependencies) = match &job.work {
+                    IOop::Read {
+                        dependencies,
+                        eid,
+                        offset: _,
+                        num_blocks: _,
+                    } => ("Read", Some(*eid), dependencies),
+                    IOop::Write {
+                        dependencies,
+                        eid,
+                        offset: _,
+                        data: _,
+                    } => ("Write", Some(*eid), dependencies),
+                    IOop::Flush {
+                        dependencies,
+                        flush_number: _,
+                    } => ("Flush", None, dependencies),
+                };
+                JobStatus {
+                    ds_id: job.ds_id,
+                    upstairs_uuid: job.upstairs_uuid,
+                    op: op.to_string(),
+                    eid,
+                    state: job.state.clone(),
+                    dependencies: dependencies.to_vec(),
+                }
+            })
+            .collect::<Vec<_>>();
+        jobs.sort_unstable_by_key(|j| j.ds_id);
+
+        jobs
+    }
+}
+
+/*
+ * The metadata of an extent that is not open comes from its database,
+ * which can take a while to open.  We only hold the region for one extent
+ * at a time, so its IO can go on between them.
+ */
+async fn extent_status(
+    d: &Arc<Mutex<Downstairs>>,
+) -> Result<Vec<ExtentStatus>> {
+    let count = d.lock().await.region.def().extent_count();
+    let mut extents = Vec::with_capacity(count as usize);
+    for eid in 0..count {
+        let meta = d.lock().await.region.extent_meta(eid)?;
+        extents.push(ExtentStatus { extent: eid, meta });
+    }
+    Ok(extents)
+}
+
+impl Host {
+    async fn status(&self) -> HostStatus {
+        let mut regions = Vec::with_capacity(self.regions.len());
+        let mut active = HashMap::new();
+        for (uuid, d) in self.regions.iter() {
+            let ds = d.lock().await;
+            regions.push(ds.status().await);
+            active.insert(*uuid, ds.active_upstairs());
+        }
+        regions.sort_unstable_by_key(|r| r.region.uuid());
+
+        let mut connections = self
+            .connections
+            .iter()
+            .map(|(id, c)| ConnectionStatus {
+                id: *id,
+                address: c.address.to_string(),
+                upstairs_uuid: c.upstairs_uuid,
+                region: c.region,
+                active: match (c.region, c.upstairs_uuid) {
+                    (Some(r), Some(u)) => active.get(&r) == Some(&Some(u)),
+                    _ => false,
+                },
+            })
+            .collect::<Vec<_>>();
+        connections.sort_unstable_by_key(|c| c.id);
+
+        HostStatus {
+            regions,
+            connections,
+        }
+    }
+
+    fn region(&self, uuid: Option<Uuid>) -> Result<Arc<Mutex<Downstairs>>> {
+        match self.find_region(uuid) {
+            Some((_, d)) => Ok(d),
+            None => {
+                bail!("No region {:?}, we have {}", uuid, self.regions.len())
+            }
+        }
+    }
+
+    /*
+     * Tell every connection from this upstairs to hang up.  Returns how
+     * many connections we found.
+     */
+    fn disconnect(&self, upstairs_uuid: Uuid) -> usize {
+        let mut count = 0;
+        for c in self.connections.values() {
+            if c.upstairs_uuid == Some(upstairs_uuid) {
+                println!(
+                    "Admin request to disconnect {:?} at {}",
+                    upstairs_uuid, c.address
+                );
+                /*
+                 * If the channel is full, a disconnect is already on the
+                 * way.
+                 */
+                let _ = c.disconnect_tx.try_send(Disconnect::Admin);
+                count += 1;
+            }
+        }
+        count
+    }
+}
+
+async fn admin_request(
+    h: &Arc<Mutex<Host>>,
+    req: AdminRequest,
+) -> Result<AdminResponse> {
+    let mut host = h.lock().await;
+    Ok(match req {
+        AdminRequest::Status => AdminResponse::Status(host.status().await),
+        AdminRequest::Jobs(uuid) => {
+            let d = host.region(uuid)?;
+            let ds = d.lock().await;
+            AdminResponse::Jobs(ds.job_status().await)
+        }
+        AdminRequest::Extents(uuid) => {
+            let d = host.region(uuid)?;
+            drop(host);
+            AdminResponse::Extents(extent_status(&d).await?)
+        }
+        AdminRequest::Disconnect(uuid) => {
+            AdminResponse::Disconnected(host.disconnect(uuid))
+        }
+        AdminRequest::SetQos(uuid, limits) => {
+            let d = host.region(uuid)?;
+            let mut ds = d.lock().await;
+            ds.qos.set_limits(limits);
+            println!(
+                "QoS for {:?} is now {:?}",
+                ds.region.def().uuid(),
+                limits
+            );
+            AdminResponse::Qos(limits)
+        }
+        AdminRequest::SetFaults(uuid, faults) => {
+            faults.validate()?;
+            let d = host.region(uuid)?;
+            let ds = d.lock().await;
+            println!(
+                "Faults for {:?} are now {:?}",
+                ds.region.def().uuid(),
+                faults
+            );
+            ds.faults.lock().await.set_config(faults.clone());
+            AdminResponse::Faults(faults)
+        }
+        AdminRequest::AddRegion(dir) => {
+            AdminResponse::RegionAdded(host.add_region(&dir)?)
+        }
+        AdminRequest::RemoveRegion(uuid) => {
+            host.remove_region(uuid)?;
+            AdminResponse::RegionRemoved(uuid)
+        }
+        AdminRequest::FetchExtents {
+            region,
+            peer,
+            peer_region,
+            extents,
+        } => {
+            /*
+             * Don't hold the host while we talk to the peer, it could be
+             * this downstairs.
+             */
+            let d = host.region(region)?;
+            drop(host);
+            fetch_extents(&d, peer, peer_region, &extents).await?;
+            AdminResponse::ExtentsRepaired(extents.len())
+        }
+        AdminRequest::PushExtents {
+            region,
+            peer,
+            peer_region,
+            extents,
+        } => {
+            let d = host.region(region)?;
+            drop(host);
+            push_extents(&d, peer, peer_region, &extents).await?;
+            AdminResponse::ExtentsRepaired(extents.len())
+        }
+    })
+}
+
+async fn admin_connection(
+    h: &Arc<Mutex<Host>>,
+    mut us: UnixStream,
+) -> Result<()> {
+    let (usr, usw) = us.split();
+    let mut busw = BufWriter::new(usw);
+    let mut lines = BufReader::new(usr).lines();
+
+    while let Some(line) = lines.next_line().await? {
+        let response = match serde_json::from_str::<AdminRequest>(&line) {
+            Ok(req) => match admin_request(h, req).await {
+                Ok(response) => response,
+                Err(e) => AdminResponse::Error(format!("{:?}", e)),
+            },
+            Err(e) => AdminResponse::Error(format!("bad request: {}", e)),
+        };
+
+        let mut buf = serde_json::to_string(&response)?;
+        buf += "\n";
+        busw.write_all(buf.as_bytes()).await?;
+        busw.flush().await?;
+    }
+
+    Ok(())
+}
+
+/*
+ * Listen on the admin socket and answer requests from anyone who
+ * connects.  A socket left behind by an earlier downstairs is removed.
+ */
+pub async fn admin_listen(path: PathBuf, h: Arc<Mutex<Host>>) -> Result<()> {
+    if let Ok(md) = std::fs::symlink_metadata(&path) {
+        if !md.file_type().is_socket() {
+            bail!("Admin socket path {:?} exists and is not a socket", path);
+        }
+        std::fs::remove_file(&path)?;
+    }
+
+    let listener = UnixListener::bind(&path)?;
+    println!("admin socket listening on {:?}", path);
+
+    loop {
+        let (us, _) = listener.accept().await?;
+        let h = h.clone();
+        tokio::spawn(async move {
+            if let Err(e) = admin_connection(&h, us).await {
+                println!("ADMIN: connection error: {:?}", e);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::region::DEFAULT_MAX_OPEN_EXTENTS;
+    use crucible_common::RegionOptions;
+    use tempfile::{tempdir, TempDir};
+
+    /*
+     * A host serving one new region of three extents.
+     */
+    fn test_host() -> Result<(TempDir, Arc<Mutex<Host>>, Uuid)> {
+        let dir = tempdir()?;
+        let mut options: RegionOptions = Default::default();
+        options.set_block_size(512);
+        options.set_extent_size(Block::new(10, 9));
+        options.set_uuid(Uuid::new_v4());
+        let mut region = Region::create(&dir, options)?;
+        region.extend(3)?;
+        drop(region);
+
+        let mut host = Host::new(
+            false,
+            FaultConfig::default(),
+            DEFAULT_MAX_OPEN_EXTENTS,
+            false,
+            QosLimits::default(),
+        );
+        let uuid = host.add_region(&dir)?;
+        Ok((dir, Arc::new(Mutex::new(host)), uuid))
+    }
+
+    #[tokio::test]
+    async fn admin_status() -> Result<()> {
+        let (dir, h, uuid) = test_host()?;
+        let (tx, _rx) = channel(1);
+        let address = "127.0.0.1:4000".parse()?;
+        h.lock().await.add_connection(address, tx);
+
+        /*
+         * Ask over the socket, as an operator would.
+         */
+        let path = dir.path().join("admin.sock");
+        tokio::spawn(admin_listen(path.clone(), h.clone()));
+        let mut us = loop {
+            match UnixStream::connect(&path).await {
+                Ok(us) => break us,
+                Err(_) => tokio::time::sleep(Duration::from_millis(10)).await,
+            }
+        };
+        us.write_all(b"\"Status\"\n").await?;
+        let mut line = String::new();
+        BufReader::new(&mut us).read_line(&mut line).await?;
+
+        let status: serde_json::Value = serde_json::from_str(&line)?;
+        let regions = &status["Status"]["regions"];
+        assert_eq!(regions.as_array().unwrap().len(), 1);
+        assert_eq!(regions[0]["region"]["uuid"], uuid.to_string());
+        assert_eq!(regions[0]["new_jobs"], 0);
+        let connections = &status["Status"]["connections"];
+        assert_eq!(connections[0]["address"], "127.0.0.1:4000");
+        assert_eq!(connections[0]["active"], false);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn admin_jobs() -> Result<()> {
+        let (_dir, h, uuid) = test_host()?;
+        let upstairs = Uuid::new_v4();
+        {
+            let host = h.lock().await;
+            let mut ds = host.regions[&uuid].lock().await;
+            let (tx, _rx) = channel(1);
+            ds.promote_to_active(upstairs, Arc::new(tx)).await;
+            ds.add_work(
+                upstairs,
+                1001,
+                IOop::Flush {
+                    dependencies: vec![1000],
+                    flush_number: 1,
+                },
+            )
+            .await?;
+            ds.add_work(
+                upstairs,
+                1000,
+                IOop::Read {
+                    dependencies: vec![],
+                    eid: 2,
+                    offset: Block::new_512(0),
+                    num_blocks: 1,
+                },
+            )
+            .await?;
+        }
+
+        match admin_request(&h, AdminRequest::Jobs(None)).await? {
+            AdminResponse::Jobs(jobs) => {
+                assert_eq!(jobs.len(), 2);
+                assert_eq!(jobs[0].ds_id, 1000);
+                assert_eq!(jobs[0].op, "Read");
+                assert_eq!(jobs[0].eid, Some(2));
+                assert_eq!(jobs[1].ds_id, 1001);
+                assert_eq!(jobs[1].op, "Flush");
+                assert_eq!(jobs[1].dependencies, vec![1000]);
+                assert_eq!(jobs[1].upstairs_uuid, upstairs);
+            }
+            r => panic!("unexpected response {:?}", r),
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn admin_extents() -> Result<()> {
+        let (_dir, h, uuid) = test_host()?;
+        {
+            let host = h.lock().await;
+            let ds = host.regions[&uuid].lock().await;
+            ds.region.region_write(1, Block::new_512(0), &[1; 512])?;
+        }
+
+        match admin_request(&h, AdminRequest::Extents(Some(uuid))).await? {
+            AdminResponse::Extents(extents) => {
+                assert_eq!(extents.len(), 3);
+                let dirty = extents
+                    .iter()
+                    .map(|e| (e.extent, e.meta.dirty))
+                    .collect::<Vec<_>>();
+                assert_eq!(dirty, vec![(0, false), (1, true), (2, false)]);
+            }
+            r => panic!("unexpected response {:?}", r),
+        }
+
+        assert!(
+            admin_request(&h, AdminRequest::Extents(Some(Uuid::new_v4())))
+                .await
+                .is_err()
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn admin_disconnect() -> Result<()> {
+        let (_dir, h, uuid) = test_host()?;
+        let upstairs = Uuid::new_v4();
+        let (tx, mut rx) = channel(1);
+        let (other_tx, mut other_rx) = channel(1);
+        {
+            let mut host = h.lock().await;
+            let id = host.add_connection("127.0.0.1:4000".parse()?, tx);
+            host.connection_upstairs(id, upstairs, uuid);
+            let id = host.add_connection("127.0.0.1:4001".parse()?, other_tx);
+            host.connection_upstairs(id, Uuid::new_v4(), uuid);
+        }
+
+        match admin_request(&h, AdminRequest::Disconnect(upstairs)).await? {
+            AdminResponse::Disconnected(count) => assert_eq!(count, 1),
+            r => panic!("unexpected response {:?}", r),
+        }
+        assert_eq!(rx.recv().await, Some(Disconnect::Admin));
+        assert!(other_rx.try_recv().is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,387 @@
+// Copyright 2021 Oxide Computer Company
+use super::*;
+use crate::region::ExtentMeta;
+use serde::Deserialize;
+
+/*
+ * An incremental export of a region holds every extent that has been
+ * flushed since a given flush number, along with its metadata.
+ *
+ * The stream starts with a Header record, then has an Extent record for
+ * each extent, each one followed by the raw data of the extent, and ends
+ * with an End record so a truncated stream can be told apart from one
+ * with fewer extents in it.  Records are encoded with bincode.
+ */
+const DELTA_MAGIC: [u8; 8] = *b"CRUCDLTA";
+const DELTA_VERSION: u32 = 1;
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+enum DeltaRecord {
+    Header {
+        magic: [u8; 8],
+        version: u32,
+        uuid: Uuid,
+        block_size: u64,
+        extent_size: u64,
+        extent_count: u32,
+        since_flush: u64,
+    },
+    Extent {
+        eid: u32,
+        gen_number: u64,
+        flush_number: u64,
+        dirty: bool,
+        len: u64,
+    },
+    End {
+        extents: u32,
+    },
+}
+
+/*
+ * Write every extent with a flush number greater than since_flush, or that
+ * has writes not yet flushed, to out.  Returns how many were written.
+ */
+pub fn export_delta<W: Write>(
+    region: &Region,
+    since_flush: u64,
+    out: &mut W,
+) -> Result<u32> {
+    let def = region.def();
+    bincode::serialize_into(
+        &mut *out,
+        &DeltaRecord::Header {
+            magic: DELTA_MAGIC,
+            version: DELTA_VERSION,
+            uuid: def.uuid(),
+            block_size: def.block_size(),
+            extent_size: def.extent_size().value,
+            extent_count: def.extent_count(),
+            since_flush,
+        },
+    )?;
+
+    let mut extents = 0;
+    for eid in 0..def.extent_count() {
+        let meta = region.extent_meta(eid)?;
+        if meta.flush_number <= since_flush && !meta.dirty {
+            continue;
+        }
+
+        let (meta, data) = region.extent_copy(eid)?;
+        bincode::serialize_into(
+            &mut *out,
+            &DeltaRecord::Extent {
+                eid,
+                gen_number: meta.gen_number,
+                flush_number: meta.flush_number,
+                dirty: meta.dirty,
+                len: data.len() as u64,
+            },
+        )?;
+        out.write_all(&data)?;
+        extents += 1;
+    }
+
+    bincode::serialize_into(&mut *out, &DeltaRecord::End { extents })?;
+    out.flush()?;
+
+    Ok(extents)
+}
+
+/*
+ * Apply a delta written by export_delta to a region.  The delta must come
+ * from a region with our UUID.  Each extent in it replaces ours whole, and
+ * the region is extended if the delta has extents past our end.  Returns
+ * how many extents were replaced.
+ *
+ * Every extent is staged before any is put in place, so a delta that is
+ * cut short or damaged leaves our extents as they were.
+ */
+pub fn import_delta<R: Read>(
+    region: &mut Region,
+    input: &mut R,
+) -> Result<u32> {
+    let def = region.def();
+    let extent_count = match bincode::deserialize_from(&mut *input)? {
+        DeltaRecord::Header {
+            magic,
+            version,
+            uuid,
+            block_size,
+            extent_size,
+            extent_count,
+            since_flush,
+        } => {
+            if magic != DELTA_MAGIC {
+                bail!("Input is not a region delta");
+            }
+            if version != DELTA_VERSION {
+                bail!("Unsupported delta version {}", version);
+            }
+            if uuid != def.uuid() {
+                bail!(
+                    "Delta is from region {}, not this region {}",
+                    uuid,
+                    def.uuid()
+                );
+            }
+            if block_size != def.block_size()
+                || extent_size != def.extent_size().value
+            {
+                bail!(
+                    "Delta has block size {} extent size {}, region has {} {}",
+                    block_size,
+                    extent_size,
+                    def.block_size(),
+                    def.extent_size().value
+                );
+            }
+            println!(
+                "Applying delta from {} since flush {}",
+                uuid, since_flush
+            );
+            extent_count
+        }
+        r => bail!("Delta starts with {:?}, not a header", r),
+    };
+
+    if extent_count > def.extent_count() {
+        println!("Extending region to {} extents", extent_count);
+        region.extend(extent_count)?;
+    }
+
+    let mut staged = Vec::new();
+    let result = stage_delta(region, input, extent_count, &mut staged);
+    if let Err(e) = result {
+        for (eid, _) in staged {
+            region.discard_extent(eid)?;
+        }
+        return Err(e);
+    }
+
+    for (eid, meta) in staged.iter() {
+        region.commit_extent(*eid, meta)?;
+    }
+
+    Ok(staged.len() as u32)
+}
+
+/*
+ * Read each extent in a delta into its staging file, up to and including
+ * the End record.  What was staged is left in `staged`, even on error, so
+ * the caller can throw it out.
+ */
+fn stage_delta<R: Read>(
+    region: &Region,
+    input: &mut R,
+    extent_count: u32,
+    staged: &mut Vec<(u32, ExtentMeta)>,
+) -> Result<()> {
+    let def = region.def();
+    let extent_bytes = def.block_size() * def.extent_size().value;
+    let mut data = vec![0; extent_bytes as usize];
+    loop {
+        match bincode::deserialize_from(&mut *input)? {
+            DeltaRecord::Extent {
+                eid,
+                gen_number,
+                flush_number,
+                dirty,
+                len,
+            } => {
+                if eid >= extent_count {
+                    bail!(
+                        "Delta has extent {} past its last extent {}",
+                        eid,
+                        extent_count
+                    );
+                }
+                if let Some((last, _)) = staged.last() {
+                    if eid <= *last {
+                        bail!("Delta has extent {} after {}", eid, last);
+                    }
+                }
+                if len != extent_bytes {
+                    bail!(
+                        "Delta extent {} is {} bytes, expected {}",
+                        eid,
+                        len,
+                        extent_bytes
+                    );
+                }
+
+                input.read_exact(&mut data)?;
+                let meta = ExtentMeta {
+                    gen_number,
+                    flush_number,
+                    dirty,
+                    ..Default::default()
+                };
+                staged.push((eid, meta));
+                region.stage_extent(eid, 0, &data)?;
+            }
+            DeltaRecord::End { extents } => {
+                if extents as usize != staged.len() {
+                    bail!(
+                        "Delta should have {} extents, found {}",
+                        extents,
+                        staged.len()
+                    );
+                }
+                return Ok(());
+            }
+            r => bail!("Unexpected {:?} in delta", r),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crucible_common::RegionOptions;
+    use tempfile::tempdir;
+
+    fn region_options(uuid: Uuid) -> RegionOptions {
+        let mut options: RegionOptions = Default::default();
+        options.set_block_size(512);
+        options.set_extent_size(Block::new(10, 9));
+        options.set_uuid(uuid);
+        options
+    }
+
+    #[test]
+    fn delta_round_trip() -> Result<()> {
+        let uuid = Uuid::new_v4();
+        let dir = tempdir()?;
+        let mut r1 = Region::create(&dir, region_options(uuid))?;
+        r1.extend(4)?;
+        let dir2 = tempdir()?;
+        let mut r2 = Region::create(&dir2, region_options(uuid))?;
+        r2.extend(2)?;
+
+        /*
+         * Extent 1 was flushed before the delta point, extent 3 after.
+         */
+        r1.region_write(1, Block::new_512(0), &[1; 512])?;
+        r1.region_flush(5)?;
+        r1.region_write(3, Block::new_512(9), &[3; 512])?;
+        r1.region_flush(8)?;
+
+        let mut delta = Vec::new();
+        assert_eq!(export_delta(&r1, 5, &mut delta)?, 1);
+        assert_eq!(import_delta(&mut r2, &mut &delta[..])?, 1);
+
+        assert_eq!(r2.def().extent_count(), 4);
+        assert_eq!(r2.flush_numbers()?, vec![0, 0, 0, 8]);
+
+        let mut buffer = BytesMut::with_capacity(512);
+        buffer.resize(512, 0);
+        r2.region_read(3, Block::new_512(9), &mut buffer)?;
+        assert_eq!(&buffer[..], &[3; 512][..]);
+        r2.region_read(1, Block::new_512(0), &mut buffer)?;
+        assert_eq!(&buffer[..], &[0; 512][..]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn delta_truncated() -> Result<()> {
+        let uuid = Uuid::new_v4();
+        let dir = tempdir()?;
+        let mut r1 = Region::create(&dir, region_options(uuid))?;
+        r1.extend(2)?;
+        r1.region_write(0, Block::new_512(0), &[1; 512])?;
+        r1.region_flush(1)?;
+
+        let mut delta = Vec::new();
+        export_delta(&r1, 0, &mut delta)?;
+        delta.truncate(delta.len() - 1);
+
+        let dir2 = tempdir()?;
+        let mut r2 = Region::create(&dir2, region_options(uuid))?;
+        r2.extend(2)?;
+        r2.region_write(0, Block::new_512(0), &[2; 512])?;
+        r2.region_flush(4)?;
+        assert!(import_delta(&mut r2, &mut &delta[..]).is_err());
+
+        /*
+         * The whole extent was there, only the End record was cut short,
+         * and still none of it may be put in place.
+         */
+        assert_eq!(r2.flush_numbers()?, vec![4, 0]);
+        let mut buffer = BytesMut::with_capacity(512);
+        buffer.resize(512, 0);
+        r2.region_read(0, Block::new_512(0), &mut buffer)?;
+        assert_eq!(&buffer[..], &[2; 512][..]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn delta_bad_extent_length() -> Result<()> {
+        let uuid = Uuid::new_v4();
+        let dir = tempdir()?;
+        let mut r1 = Region::create(&dir, region_options(uuid))?;
+        r1.extend(2)?;
+
+        /*
+         * The length comes from the delta, so it must be checked before
+         * we allocate for it.
+         */
+        let mut delta = Vec::new();
+        bincode::serialize_into(
+            &mut delta,
+            &DeltaRecord::Header {
+                magic: DELTA_MAGIC,
+                version: DELTA_VERSION,
+                uuid,
+                block_size: 512,
+                extent_size: 10,
+                extent_count: 2,
+                since_flush: 0,
+            },
+        )?;
+        bincode::serialize_into(
+            &mut delta,
+            &DeltaRecord::Extent {
+                eid: 1,
+                gen_number: 0,
+                flush_number: 1,
+                dirty: false,
+                len: u64::MAX,
+            },
+        )?;
+
+        let err = import_delta(&mut r1, &mut &delta[..]).unwrap_err();
+        assert!(err.to_string().contains("expected 5120"));
+        assert_eq!(r1.flush_numbers()?, vec![0, 0]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn delta_other_region() -> Result<()> {
+        let dir = tempdir()?;
+        let mut r1 = Region::create(&dir, region_options(Uuid::new_v4()))?;
+        r1.extend(2)?;
+        r1.region_write(0, Block::new_512(0), &[1; 512])?;
+        r1.region_flush(1)?;
+
+        let mut delta = Vec::new();
+        export_delta(&r1, 0, &mut delta)?;
+
+        /*
+         * A region with the same shape, but not a copy of this one, is
+         * refused, and left alone.
+         */
+        let dir2 = tempdir()?;
+        let mut r2 = Region::create(&dir2, region_options(Uuid::new_v4()))?;
+        r2.extend(2)?;
+        let err = import_delta(&mut r2, &mut &delta[..]).unwrap_err();
+        assert!(err.to_string().contains("not this region"));
+        assert_eq!(r2.flush_numbers()?, vec![0, 0]);
+
+        Ok(())
+    }
+}
@@ -31,7 +31,7 @@ pub fn dump_region(
     let mut block_size = 0;
 
     for (index, dir) in region_dir.iter().enumerate() {
-        let region = Region::open(&dir, Default::default(), false)?;
+        let region = Region::open(&dir, Default::default(), false, true)?;
 
         block_size = region.def().block_size() as usize;
         blocks_per_extent = region.def().extent_size().value;
@@ -43,9 +43,7 @@ pub fn dump_region(
          * struct, which is another hashmap where the index is the region
          * directory index and the value is the ExtentMeta for that region.
          */
-        for e in &region.extents {
-            let en = e.number();
-
+        for en in 0..total_extents {
             /*
              * If we are looking at one extent in detail, we skip all the
              * others.
@@ -55,18 +53,12 @@ pub fn dump_region(
                     continue;
                 }
             }
-            let inner = e.inner();
 
             /*
-             * Create the ExtentMeta struct for this directory's extent
-             * number
+             * Read the ExtentMeta for this directory's extent number.
+             * This does not require the extent itself to be opened.
              */
-            let extent_info = ExtentMeta {
-                ext_version: 0,
-                gen_number: inner.gen_number().unwrap(),
-                flush_number: inner.flush_number().unwrap(),
-                dirty: inner.dirty().unwrap(),
-            };
+            let extent_info = region.extent_meta(en)?;
 
             /*
              * If we have an entry already, then add this at our directory
@@ -220,7 +212,7 @@ fn show_extent(
          * in the Vec based on index.
          */
         for (index, dir) in region_dir.iter().enumerate() {
-            let region = Region::open(&dir, Default::default(), false)?;
+            let region = Region::open(&dir, Default::default(), false, true)?;
 
             region.region_read(
                 cmp_extent as u64,
@@ -0,0 +1,274 @@
+// Copyright 2021 Oxide Computer Company
+use anyhow::{bail, Result};
+use crucible_common::CrucibleError;
+use rand::prelude::*;
+use rand::rngs::StdRng;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/*
+ * What faults to inject into the IO for a region.  Everything is off by
+ * default.  Rates are the chance, from 0.0 to 1.0, that each IO is hit.
+ *
+ * All the random choices come from generators seeded with `seed`, so the
+ * same config and the same IO from the upstairs gives the same faults.
+ * Each kind of fault has its own generator, as they are drawn from
+ * different tasks, and the order those run in is not fixed.
+ *
+ * This can be read from a JSON file at startup, for example:
+ *
+ *     { "seed": 7, "write_error_rate": 0.1, "failed_extents": [3] }
+ */
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FaultConfig {
+    pub seed: u64,
+
+    /*
+     * Return an error instead of doing the IO.
+     */
+    pub read_error_rate: f64,
+    pub write_error_rate: f64,
+    pub flush_error_rate: f64,
+
+    /*
+     * Wait this long before doing an IO.  Work for the region is done one
+     * job at a time, so everything queued behind this IO waits too.  The
+     * region is not locked while we wait, unlike a stall, so acks for
+     * finished work still go out and new IO is still taken in.
+     */
+    pub delay_rate: f64,
+    pub delay_ms: u64,
+
+    /*
+     * Stop all IO for the region for this long, as if the disk under it
+     * stopped answering.
+     */
+    pub stall_rate: f64,
+    pub stall_ms: u64,
+
+    /*
+     * Hang up on an upstairs once it has sent us this many messages after
+     * negotiation.  This happens again each time it reconnects.
+     */
+    pub disconnect_after: Option<u64>,
+
+    /*
+     * Every read or write to these extents returns an error.
+     */
+    pub failed_extents: Vec<u64>,
+}
+
+impl FaultConfig {
+    pub fn validate(&self) -> Result<()> {
+        for (name, rate) in [
+            ("read_error_rate", self.read_error_rate),
+            ("write_error_rate", self.write_error_rate),
+            ("flush_error_rate", self.flush_error_rate),
+            ("delay_rate", self.delay_rate),
+            ("stall_rate", self.stall_rate),
+        ] {
+            if !(0.0..=1.0).contains(&rate) {
+                bail!("{} must be between 0 and 1, not {}", name, rate);
+            }
+        }
+        if self.disconnect_after == Some(0) {
+            bail!("disconnect_after must be at least 1");
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum FaultOp {
+    Read,
+    Write,
+    Flush,
+}
+
+#[derive(Debug)]
+pub struct Faults {
+    config: FaultConfig,
+    error_rng: StdRng,
+    delay_rng: StdRng,
+    stall_rng: StdRng,
+}
+
+impl Faults {
+    pub fn new(config: FaultConfig) -> Self {
+        let mut seeds = StdRng::seed_from_u64(config.seed);
+        let error_rng = StdRng::seed_from_u64(seeds.gen());
+        let delay_rng = StdRng::seed_from_u64(seeds.gen());
+        let stall_rng = StdRng::seed_from_u64(seeds.gen());
+        Faults {
+            config,
+            error_rng,
+            delay_rng,
+            stall_rng,
+        }
+    }
+
+    pub fn config(&self) -> &FaultConfig {
+        &self.config
+    }
+
+    /*
+     * Replace the config.  The generators start over from the new seed.
+     */
+    pub fn set_config(&mut self, config: FaultConfig) {
+        *self = Faults::new(config);
+    }
+
+    fn hit(rng: &mut StdRng, rate: f64) -> bool {
+        rate > 0.0 && rng.gen_bool(rate)
+    }
+
+    /*
+     * If this IO should fail, the error to return for it.
+     */
+    pub fn io_error(
+        &mut self,
+        op: FaultOp,
+        eid: Option<u64>,
+    ) -> Option<CrucibleError> {
+        if let Some(eid) = eid {
+            if self.config.failed_extents.contains(&eid) {
+                return Some(CrucibleError::IoError(format!(
+                    "injected fault on extent {}",
+                    eid
+                )));
+            }
+        }
+
+        let rate = match op {
+            FaultOp::Read => self.config.read_error_rate,
+            FaultOp::Write => self.config.write_error_rate,
+            FaultOp::Flush => self.config.flush_error_rate,
+        };
+        if Self::hit(&mut self.error_rng, rate) {
+            println!("Injecting {:?} error", op);
+            Some(CrucibleError::IoError("injected fault".to_string()))
+        } else {
+            None
+        }
+    }
+
+    pub fn delay(&mut self) -> Option<Duration> {
+        if Self::hit(&mut self.delay_rng, self.config.delay_rate) {
+            Some(Duration::from_millis(self.config.delay_ms))
+        } else {
+            None
+        }
+    }
+
+    pub fn stall(&mut self) -> Option<Duration> {
+        if Self::hit(&mut self.stall_rng, self.config.stall_rate) {
+            Some(Duration::from_millis(self.config.stall_ms))
+        } else {
+            None
+        }
+    }
+
+    pub fn disconnect_after(&self) -> Option<u64> {
+        self.config.disconnect_after
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn errors(faults: &mut Faults, op: FaultOp, count: usize) -> Vec<bool> {
+        (0..count)
+            .map(|_| faults.io_error(op, None).is_some())
+            .collect()
+    }
+
+    #[test]
+    fn faults_off_by_default() {
+        let mut faults = Faults::new(FaultConfig::default());
+        assert!(errors(&mut faults, FaultOp::Read, 1000).iter().all(|e| !e));
+        assert_eq!(faults.delay(), None);
+        assert_eq!(faults.stall(), None);
+        assert_eq!(faults.disconnect_after(), None);
+    }
+
+    #[test]
+    fn faults_same_seed_same_faults() {
+        let config = FaultConfig {
+            seed: 1234,
+            write_error_rate: 0.5,
+            ..Default::default()
+        };
+
+        let mut a = Faults::new(config.clone());
+        let mut b = Faults::new(config);
+        let first = errors(&mut a, FaultOp::Write, 100);
+        assert_eq!(first, errors(&mut b, FaultOp::Write, 100));
+        assert!(first.iter().any(|e| *e));
+        assert!(first.iter().any(|e| !e));
+
+        /*
+         * Setting the config again starts over.
+         */
+        a.set_config(a.config().clone());
+        assert_eq!(first, errors(&mut a, FaultOp::Write, 100));
+
+        /*
+         * Only writes were asked for.
+         */
+        assert!(errors(&mut a, FaultOp::Read, 100).iter().all(|e| !e));
+    }
+
+    #[test]
+    fn faults_kinds_do_not_share_a_generator() {
+        let config = FaultConfig {
+            seed: 99,
+            write_error_rate: 0.5,
+            delay_rate: 0.5,
+            delay_ms: 1,
+            ..Default::default()
+        };
+
+        /*
+         * Drawing delays in between does not change which IOs fail.
+         */
+        let mut a = Faults::new(config.clone());
+        let mut b = Faults::new(config);
+        let mut with_delays = Vec::new();
+        for _ in 0..100 {
+            a.delay();
+            with_delays.push(a.io_error(FaultOp::Write, None).is_some());
+        }
+        assert_eq!(with_delays, errors(&mut b, FaultOp::Write, 100));
+    }
+
+    #[test]
+    fn faults_failed_extent() {
+        let mut faults = Faults::new(FaultConfig {
+            failed_extents: vec![3],
+            ..Default::default()
+        });
+        assert!(faults.io_error(FaultOp::Read, Some(3)).is_some());
+        assert!(faults.io_error(FaultOp::Write, Some(3)).is_some());
+        assert!(faults.io_error(FaultOp::Read, Some(2)).is_none());
+        assert!(faults.io_error(FaultOp::Flush, None).is_none());
+    }
+
+    #[test]
+    fn faults_validate() {
+        assert!(FaultConfig::default().validate().is_ok());
+        assert!(FaultConfig {
+            read_error_rate: 1.5,
+            ..Default::default()
+        }
+        .validate()
+        .is_err());
+        assert!(FaultConfig {
+            disconnect_after: Some(0),
+            ..Default::default()
+        }
+        .validate()
+        .is_err());
+    }
+}
@@ -3,8 +3,8 @@ use futures::lock::{Mutex, MutexGuard};
 use std::collections::HashMap;
 use std::fmt;
 use std::fs::File;
-use std::io::{Read, Write};
-use std::net::{Ipv4Addr, SocketAddrV4};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::time::Duration;
@@ -17,9 +17,12 @@ use anyhow::{bail, Result};
 use bytes::BytesMut;
 use futures::{SinkExt, StreamExt};
 use rand::prelude::*;
+use serde::Serialize;
 use structopt::StructOpt;
+use tokio::io::AsyncWrite;
 use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
 use tokio::net::{TcpListener, TcpStream};
+use tokio::signal::unix::{signal, SignalKind};
 use tokio::sync::mpsc;
 use tokio::sync::mpsc::{channel, Receiver, Sender};
 use tokio::time::{sleep_until, Instant};
@@ -28,10 +31,24 @@ use tracing_subscriber::layer::SubscriberExt;
 use tracing_subscriber::util::SubscriberInitExt;
 use uuid::Uuid;
 
+mod admin;
+mod delta;
 mod dump;
+mod fault;
+mod qos;
 mod region;
+mod relayout;
+mod repair;
+mod verify;
+use admin::{admin_listen, IoCounters};
+use delta::{export_delta, import_delta};
 use dump::dump_region;
+use fault::{FaultConfig, FaultOp, Faults};
+use qos::{Qos, QosLimits};
 use region::Region;
+use relayout::relayout_region;
+use repair::{proc_repair, repair_regions};
+use verify::verify_regions;
 
 #[derive(Debug, StructOpt)]
 #[structopt(about = "disk-side storage component")]
@@ -50,9 +67,47 @@ enum Args {
         #[structopt(long, default_value = "15")]
         extent_count: u64,
 
+        /*
+         * Import this file into the new region, or stdin if this is "-".
+         */
         #[structopt(short, long, parse(from_os_str), name = "FILE")]
         import_path: Option<PathBuf>,
 
+        /*
+         * Encrypt what we import with this base64 key, the same one the
+         * upstairs is given with --key.
+         */
+        #[structopt(short, long, name = "KEY", requires = "FILE")]
+        key: Option<String>,
+
+        #[structopt(short, long, name = "UUID", parse(try_from_str))]
+        uuid: Uuid,
+    },
+    /*
+     * Make a new region that is a copy of an existing one, under a new
+     * UUID.  Extent files are cloned with reflinks where the filesystem
+     * supports it, and copied otherwise.
+     */
+    Clone {
+        /*
+         * Directory of the region to copy.
+         */
+        #[structopt(short, long, parse(from_os_str), name = "DIRECTORY")]
+        data: PathBuf,
+
+        /*
+         * Directory for the new region.
+         */
+        #[structopt(short, long, parse(from_os_str), name = "OUT_DIRECTORY")]
+        output: PathBuf,
+
+        /*
+         * Start every extent of the new region over at generation and
+         * flush number zero, instead of keeping ours.
+         */
+        #[structopt(long)]
+        reset_meta: bool,
+
         #[structopt(short, long, name = "UUID", parse(try_from_str))]
         uuid: Uuid,
     },
@@ -85,18 +140,77 @@ enum Args {
         #[structopt(short, long, parse(from_os_str), name = "DIRECTORY")]
         data: PathBuf,
 
+        /*
+         * Export to this file, or stdout if this is "-".  Runs of zeros
+         * are left as holes in the file.
+         */
         #[structopt(short, long, parse(from_os_str), name = "OUT_FILE")]
         export_path: PathBuf,
 
+        /*
+         * Decrypt what we export with this base64 key, the same one the
+         * upstairs is given with --key.
+         */
+        #[structopt(short, long, name = "KEY", conflicts_with = "FLUSH")]
+        key: Option<String>,
+
+        /*
+         * Only export the extents flushed after this flush number, as a
+         * delta that can be applied to another region with import-delta.
+         */
+        #[structopt(
+            long,
+            name = "FLUSH",
+            conflicts_with_all = &["COUNT", "SKIP"]
+        )]
+        since_flush: Option<u64>,
+
         #[structopt(short, long, default_value = "0", name = "SKIP")]
         skip: u64,
     },
+    /*
+     * Apply a delta made by export --since-flush to an existing region.
+     */
+    ImportDelta {
+        #[structopt(short, long, parse(from_os_str), name = "DIRECTORY")]
+        data: PathBuf,
+
+        /*
+         * Import this file, or stdin if this is "-".
+         */
+        #[structopt(short, long, parse(from_os_str), name = "FILE")]
+        import_path: PathBuf,
+    },
     Run {
         #[structopt(short, long, default_value = "0.0.0.0")]
         address: Ipv4Addr,
 
+        /*
+         * Limit each region to this many bytes read or written per
+         * second.  This can be changed later through the admin socket.
+         */
+        #[structopt(long, name = "BYTES")]
+        bandwidth_limit: Option<u64>,
+
+        /*
+         * Listen for admin requests on a unix socket at this path.
+         */
+        #[structopt(long, parse(from_os_str), name = "SOCKET")]
+        admin_socket: Option<PathBuf>,
+
+        /*
+         * A region to serve.  This can be given more than once.
+         */
         #[structopt(short, long, parse(from_os_str), name = "DIRECTORY")]
-        data: PathBuf,
+        data: Vec<PathBuf>,
+
+        /*
+         * Read a fault injection config for every region from this JSON
+         * file.  It can be changed later through the admin socket.
+         */
+        #[structopt(long, parse(from_os_str), name = "FAULTS")]
+        faults: Option<PathBuf>,
+
         /*
          * Test option, makes the search for new work sleep and sometimes
          * skip doing work.  XXX Note that the flow control between upstairs
@@ -106,15 +220,127 @@ enum Args {
         #[structopt(long)]
         lossy: bool,
 
+        /*
+         * Limit each region to this many reads and writes per second.
+         * Flushes are not limited.
+         */
+        #[structopt(long, name = "IOPS")]
+        iops_limit: Option<u64>,
+
+        /*
+         * The most extents we will keep open at once.  Extents are opened
+         * as IO needs them, and the least recently used are closed when
+         * we go over this limit.
+         */
+        #[structopt(long, default_value = "1024")]
+        max_open_extents: usize,
+
         #[structopt(short, long, default_value = "9000")]
         port: u16,
 
+        /*
+         * Serve the region read-only.  Writes and flushes are refused,
+         * and more than one upstairs may use the region at the same time.
+         */
+        #[structopt(long)]
+        read_only: bool,
+
+        /*
+         * Serve every region found in the directories under this one.
+         */
+        #[structopt(long, parse(from_os_str), name = "REGIONS")]
+        region_dir: Option<PathBuf>,
+
+        /*
+         * Test option, fails one in four reads, writes, and flushes.  This
+         * overrides the error rates from --faults.
+         */
         #[structopt(long)]
         return_errors: bool,
 
         #[structopt(short, long)]
         trace_endpoint: Option<String>,
     },
+    /*
+     * Rewrite a region into a new directory with a different block size
+     * or extent size.  The new region is checked against the old one
+     * before we are done.
+     */
+    Relayout {
+        /*
+         * Directory of the region to rewrite.
+         */
+        #[structopt(short, long, parse(from_os_str), name = "DIRECTORY")]
+        data: PathBuf,
+
+        /*
+         * Directory for the new region.
+         */
+        #[structopt(short, long, parse(from_os_str), name = "OUT_DIRECTORY")]
+        output: PathBuf,
+
+        /*
+         * The new block size, if it should change.
+         */
+        #[structopt(long)]
+        block_size: Option<u64>,
+
+        /*
+         * The new number of blocks per extent, if it should change.
+         */
+        #[structopt(long)]
+        extent_size: Option<u64>,
+
+        /*
+         * The base64 key the upstairs is given with --key, which is
+         * needed to lay out an encrypted region again.  Either this or
+         * --unencrypted must be given, as an encrypted region laid out
+         * again without its key can't be read any more.
+         */
+        #[structopt(
+            short,
+            long,
+            name = "KEY",
+            required_unless = "UNENCRYPTED"
+        )]
+        key: Option<String>,
+
+        /*
+         * The region is not encrypted.
+         */
+        #[structopt(long, name = "UNENCRYPTED", conflicts_with = "KEY")]
+        unencrypted: bool,
+    },
+    /*
+     * Compare every extent of two or three replicas of a region, and
+     * print a JSON report of where they differ.  Exits with an error if
+     * they do not all match.
+     */
+    Verify {
+        /*
+         * Directories containing a region.
+         */
+        #[structopt(short, long, parse(from_os_str), name = "DIRECTORY")]
+        data: Vec<PathBuf>,
+    },
+    /*
+     * Make two or three replicas of a region agree, by copying the most
+     * recent copy of each extent over the others.  Prints a JSON report
+     * of the extents repaired.  The downstairs must not be running.
+     */
+    Repair {
+        /*
+         * Directories containing a region.
+         */
+        #[structopt(short, long, parse(from_os_str), name = "DIRECTORY")]
+        data: Vec<PathBuf>,
+
+        /*
+         * Only report what would be repaired, without changing anything.
+         */
+        #[structopt(long)]
+        dry_run: bool,
+    },
 }
 
 fn deadline_secs(secs: u64) -> Instant {
@@ -123,18 +349,134 @@ fn deadline_secs(secs: u64) -> Instant {
         .unwrap()
 }
 
+/*
+ * Export and import move data in chunks this large, rather than a block at
+ * a time.  The chunk size must be a whole number of the largest block size
+ * we are able to support.
+ */
+const CHUNK_SIZE: usize = 32 * 1024 * 1024;
+
+/*
+ * An export or import path of "-" means stdout or stdin.
+ */
+fn is_stdio<P: AsRef<Path>>(path: P) -> bool {
+    path.as_ref() == Path::new("-")
+}
+
+/*
+ * Split a buffer into runs of whole blocks that are either all zero, or
+ * not.  Each run is returned as whether it is zero, and its byte range.
+ */
+fn zero_runs(
+    buf: &[u8],
+    block_size: usize,
+) -> Vec<(bool, std::ops::Range<usize>)> {
+    let mut runs: Vec<(bool, std::ops::Range<usize>)> = Vec::new();
+    for (i, block) in buf.chunks(block_size).enumerate() {
+        let zero = block.iter().all(|b| *b == 0);
+        let start = i * block_size;
+        match runs.last_mut() {
+            Some((z, range)) if *z == zero => range.end = start + block.len(),
+            _ => runs.push((zero, start..start + block.len())),
+        }
+    }
+    runs
+}
+
+/*
+ * Decrypt the blocks read from an extent, starting at first_block in it.
+ * The tweak for each block is its offset in the extent, as the upstairs
+ * uses.  Blocks that were never written are all zeros, and are left that
+ * way rather than decrypted into noise.
+ */
+fn decrypt_written(
+    context: &EncryptionContext,
+    data: &mut [u8],
+    first_block: u64,
+    block_size: usize,
+) {
+    for (zero, range) in zero_runs(data, block_size) {
+        if !zero {
+            let sector = first_block + (range.start / block_size) as u64;
+            context.decrypt_in_place(&mut data[range], sector as u128);
+        }
+    }
+}
+
+/*
+ * The encryption context the upstairs would use on this region, given
+ * the same base64 key.
+ */
+fn encryption_context(
+    key: Option<String>,
+    region: &Region,
+) -> Result<Option<EncryptionContext>> {
+    key.map(|key| {
+        let block_size = region.def().block_size() as usize;
+        Ok(EncryptionContext::new(decode_key(&key)?, block_size))
+    })
+    .transpose()
+}
+
+/*
+ * Where an export is going.  A file is left with holes where the region
+ * is all zeros, stdout has to be given every byte.
+ */
+enum ExportOut<'a> {
+    File(File),
+    Stream(&'a mut dyn Write),
+}
+
+impl ExportOut<'_> {
+    fn write_runs(&mut self, data: &[u8], block_size: usize) -> Result<()> {
+        for (zero, range) in zero_runs(data, block_size) {
+            match self {
+                ExportOut::File(f) if zero => {
+                    f.seek(SeekFrom::Current(range.len() as i64))?;
+                }
+                ExportOut::File(f) => f.write_all(&data[range])?,
+                ExportOut::Stream(s) => s.write_all(&data[range])?,
+            }
+        }
+        Ok(())
+    }
+
+    fn finish(self) -> Result<()> {
+        match self {
+            ExportOut::File(mut f) => {
+                /*
+                 * If we ended on a hole, the file is not yet as long as
+                 * what we exported.
+                 */
+                let end = f.stream_position()?;
+                f.set_len(end)?;
+                f.sync_all()?;
+            }
+            ExportOut::Stream(s) => s.flush()?,
+        }
+        Ok(())
+    }
+}
+
 /*
  * Export the contents or partial contents of a Downstairs Region to
- * the file indicated.
+ * the file indicated, or to stdout.
  *
  * We will start from the provided start_block.
  * We will stop after "count" blocks are written to the export_path.
+ *
+ * Our own output goes to stderr, so it does not end up mixed in with the
+ * data when exporting to stdout.
+ *
+ * With an encryption context, the data is decrypted as the upstairs would
+ * do it, except for blocks that were never written.
  */
 fn downstairs_export<P: AsRef<Path> + std::fmt::Debug>(
     region: &mut Region,
     export_path: P,
     start_block: u64,
-    mut count: u64,
+    count: u64,
+    context: Option<&EncryptionContext>,
 ) -> Result<()> {
     /*
      * Export an existing downstairs region to a file
@@ -144,121 +486,140 @@ fn downstairs_export<P: AsRef<Path> + std::fmt::Debug>(
     assert!(block_size > 0);
     assert!(space_per_extent > 0);
     assert!(extent_count > 0);
-    assert!(space_per_extent > 0);
     let file_size = space_per_extent * extent_count as u64;
 
-    if count == 0 {
-        count = extent_size.value * extent_count as u64;
-    }
+    let total_blocks = extent_size.value * extent_count as u64;
+    let count = if count == 0 { total_blocks } else { count }
+        .min(total_blocks.saturating_sub(start_block));
 
-    println!(
+    eprintln!(
         "Export total_size: {}  Extent size:{}  Total Extents:{}",
         file_size, space_per_extent, extent_count
     );
-    println!(
+    eprintln!(
         "Exporting from start_block: {}  count:{}",
         start_block, count
     );
 
-    let mut data = BytesMut::with_capacity(block_size as usize);
-    data.resize(block_size as usize, 0);
-
-    let mut out_file = File::create(export_path)?;
-    let mut blocks_copied = 0;
-
-    'eid_loop: for eid in 0..extent_count {
-        let extent_offset = space_per_extent * eid as u64;
-        for block_offset in 0..extent_size.value {
-            if (extent_offset + block_offset) >= start_block {
-                blocks_copied += 1;
-                region
-                    .region_read(
-                        eid as u64,
-                        Block::new_with_ddef(block_offset, &region.def()),
-                        &mut data,
-                    )
-                    .unwrap();
-                out_file.write_all(&data).unwrap();
-                data.resize(block_size as usize, 0);
-
-                if blocks_copied >= count {
-                    break 'eid_loop;
-                }
-            }
-        }
-    }
+    let mut stdout = std::io::stdout();
+    let out = if is_stdio(&export_path) {
+        ExportOut::Stream(&mut stdout)
+    } else {
+        ExportOut::File(File::create(&export_path)?)
+    };
+    export_to(region, out, start_block, count, context)?;
 
-    println!("Read and wrote out {} blocks", blocks_copied);
+    eprintln!("Read and wrote out {} blocks", count);
 
     Ok(())
 }
 
+fn export_to(
+    region: &mut Region,
+    mut out: ExportOut,
+    start_block: u64,
+    count: u64,
+    context: Option<&EncryptionContext>,
+) -> Result<()> {
+    let (block_size, _, _) = region.region_def();
+    let rm = region.def();
+    let bs = block_size as usize;
+    let chunk_blocks = (CHUNK_SIZE / bs) as u64;
+    let mut data = BytesMut::with_capacity(CHUNK_SIZE);
+
+    let mut offset = Block::new_with_ddef(start_block, &rm);
+    let end = start_block + count;
+    while offset.value < end {
+        let nblocks =
+            Block::new_with_ddef(chunk_blocks.min(end - offset.value), &rm);
+        for (eid, eoff, len) in extent_from_offset(rm, offset, nblocks)? {
+            data.resize(len.bytes(), 0);
+            region.region_read(eid, eoff, &mut data)?;
+            if let Some(context) = context {
+                decrypt_written(context, &mut data, eoff.value, bs);
+            }
+            out.write_runs(&data, bs)?;
+        }
+        offset.advance(nblocks);
+    }
+    out.finish()
+}
+
 /*
- * Import the contents of a file into a new Region.
+ * Import the contents of a file, or of stdin, into a new Region.
  * The total size of the region will be rounded up to the next largest
  * extent multiple.
+ *
+ * The region starts out all zeros, so blocks of zeros in the input are
+ * not written.  That does not hold if we are encrypting, as the upstairs
+ * would decrypt those zeros into noise.
  */
 fn downstairs_import<P: AsRef<Path> + std::fmt::Debug>(
     region: &mut Region,
     import_path: P,
+    context: Option<&EncryptionContext>,
 ) -> Result<()> {
-    /*
-     * Open the file to import and determine how many extents we will need
-     * based on the length.
-     */
-    let mut f = File::open(&import_path)?;
-    let file_size = f.metadata()?.len();
     let (_, extent_size, _) = region.region_def();
     let space_per_extent = extent_size.byte_value();
 
-    let mut extents_needed = file_size / space_per_extent;
-    if file_size % space_per_extent != 0 {
-        extents_needed += 1;
-    }
-    println!(
-        "Import file_size: {}  Extent size: {}  Needed extents: {}",
-        file_size, space_per_extent, extents_needed
-    );
-
-    if extents_needed > region.def().extent_count().into() {
+    let mut f: Box<dyn Read> = if is_stdio(&import_path) {
+        println!("Importing from stdin to region");
+        Box::new(std::io::stdin())
+    } else {
         /*
-         * The file to import would require more extents than we have.
-         * Extend the region to fit the file.
+         * Open the file to import and determine how many extents we will
+         * need based on the length.
          */
-        println!("Extending region to fit image");
-        region.extend(extents_needed as u32)?;
-    } else {
-        println!("Region already large enough for image");
-    }
+        let f = File::open(&import_path)?;
+        let file_size = f.metadata()?.len();
 
-    println!("Importing {:?} to region", import_path);
-    let rm = region.def();
+        let mut extents_needed = file_size / space_per_extent;
+        if file_size % space_per_extent != 0 {
+            extents_needed += 1;
+        }
+        println!(
+            "Import file_size: {}  Extent size: {}  Needed extents: {}",
+            file_size, space_per_extent, extents_needed
+        );
+
+        if extents_needed > region.def().extent_count().into() {
+            /*
+             * The file to import would require more extents than we have.
+             * Extend the region to fit the file.
+             */
+            println!("Extending region to fit image");
+            region.extend(extents_needed as u32)?;
+        } else {
+            println!("Region already large enough for image");
+        }
+
+        println!("Importing {:?} to region", import_path);
+        Box::new(f)
+    };
+    import_from(region, &mut f, context)
+}
+
+fn import_from(
+    region: &mut Region,
+    f: &mut dyn Read,
+    context: Option<&EncryptionContext>,
+) -> Result<()> {
+    let (_, extent_size, _) = region.region_def();
+    let space_per_extent = extent_size.byte_value();
 
-    /*
-     * We want to read and write large chunks of data, rather than individual
-     * blocks, to improve import performance.  The chunk buffer must be a
-     * whole number of the largest block size we are able to support.
-     */
-    const CHUNK_SIZE: usize = 32 * 1024 * 1024;
     assert_eq!(CHUNK_SIZE % MAX_BLOCK_SIZE, 0);
     let mut buffer = vec![0; CHUNK_SIZE];
+    let mut rm = region.def();
+    let bs = rm.block_size() as usize;
 
-    let mut offset = Block::new_with_ddef(0, &region.def());
+    let mut offset = Block::new_with_ddef(0, &rm);
     loop {
-        buffer.resize(CHUNK_SIZE, 0);
-
         /*
          * Read data into the buffer until it is full, or we hit EOF.
          */
         let mut total = 0;
-        loop {
-            assert!(total <= CHUNK_SIZE);
-            if total == CHUNK_SIZE {
-                break;
-            }
-
-            let n = f.read(&mut buffer[total..(CHUNK_SIZE - total)])?;
-
+        while total < CHUNK_SIZE {
+            let n = f.read(&mut buffer[total..])?;
             if n == 0 {
                 /*
                  * We have hit EOF.  Extend the read buffer with zeroes until
@@ -270,7 +631,6 @@ fn downstairs_import<P: AsRef<Path> + std::fmt::Debug>(
                 }
                 break;
             }
-
             total += n;
         }
 
@@ -281,20 +641,49 @@ fn downstairs_import<P: AsRef<Path> + std::fmt::Debug>(
             break;
         }
 
+        /*
+         * When reading from stdin we don't know how big the image is
+         * until we get to the end of it, so grow the region as we go.
+         */
+        let needed = (offset.byte_value() + total as u64 + space_per_extent
+            - 1)
+            / space_per_extent;
+        if needed > rm.extent_count().into() {
+            region.extend(needed as u32)?;
+            rm = region.def();
+        }
+
         /*
          * Use the same function upsairs uses to decide where to put the
          * data based on the LBA offset.
          */
-        let nblocks = Block::from_bytes(total, &rm);
-        let mut pos = Block::from_bytes(0, &rm);
-        for (eid, offset, len) in extent_from_offset(rm, offset, nblocks)? {
-            let data = &buffer[pos.bytes()..(pos.bytes() + len.bytes())];
-            region.region_write(eid, offset, data)?;
-            pos.advance(len);
-        }
-        assert_eq!(nblocks, pos);
-        assert_eq!(total, pos.bytes());
-        offset.advance(nblocks);
+        for (zero, range) in zero_runs(&buffer[..total], bs) {
+            if zero && context.is_none() {
+                continue;
+            }
+            let mut pos = range.start;
+            let start = Block::new_with_ddef(
+                offset.value + (range.start / bs) as u64,
+                &rm,
+            );
+            let nblocks = Block::from_bytes(range.len(), &rm);
+            for (eid, eoff, len) in extent_from_offset(rm, start, nblocks)? {
+                if let Some(context) = context {
+                    context.encrypt_in_place(
+                        &mut buffer[pos..pos + len.bytes()],
+                        eoff.value as u128,
+                    );
+                }
+                region.region_write(
+                    eid,
+                    eoff,
+                    &buffer[pos..pos + len.bytes()],
+                )?;
+                pos += len.bytes();
+            }
+            assert_eq!(pos, range.end);
+        }
+        offset.advance(Block::from_bytes(total, &rm));
     }
 
     /*
@@ -304,7 +693,7 @@ fn downstairs_import<P: AsRef<Path> + std::fmt::Debug>(
      */
     println!(
         "Populated {} extents by copying {} bytes ({} blocks)",
-        extents_needed,
+        rm.extent_count(),
         offset.byte_value(),
         offset.value,
     );
@@ -532,6 +921,74 @@ async fn proc_frame(
     Ok(())
 }
 
+/*
+ * A new IO request has been received for a read-only region.
+ *
+ * The region never changes, so reads have no dependencies that need to
+ * be waited on, and we can answer them right away without putting them
+ * on the work queue.  This also means any number of upstairs can share
+ * the region, as nothing they do can interfere with each other.  Writes
+ * and flushes are refused.
+ */
+async fn proc_frame_read_only(
+    upstairs_uuid: Uuid,
+    ad: &mut Arc<Mutex<Downstairs>>,
+    m: &Message,
+    fw: &mut Arc<Mutex<FramedWrite<OwnedWriteHalf, CrucibleEncoder>>>,
+) -> Result<()> {
+    let response = match m {
+        Message::Ruok => Message::Imok,
+        Message::Write(uuid, ds_id, _, _, _, _) => {
+            if upstairs_uuid != *uuid {
+                Message::UuidMismatch(upstairs_uuid)
+            } else {
+                Message::WriteAck(
+                    *uuid,
+                    *ds_id,
+                    Err(CrucibleError::ModifyingReadOnlyRegion),
+                )
+            }
+        }
+        Message::Flush(uuid, ds_id, _, _) => {
+            if upstairs_uuid != *uuid {
+                Message::UuidMismatch(upstairs_uuid)
+            } else {
+                Message::FlushAck(
+                    *uuid,
+                    *ds_id,
+                    Err(CrucibleError::ModifyingReadOnlyRegion),
+                )
+            }
+        }
+        Message::ReadRequest(uuid, ds_id, _, eid, offset, num_blocks) => {
+            if upstairs_uuid != *uuid {
+                Message::UuidMismatch(upstairs_uuid)
+            } else {
+                let ds = ad.lock().await;
+                let (bs, _, _) = ds.region.region_def();
+                let sz = *num_blocks as usize * bs as usize;
+                let mut data = BytesMut::with_capacity(sz);
+                data.resize(sz, 1);
+
+                let fault =
+                    ds.faults.lock().await.io_error(FaultOp::Read, Some(*eid));
+                let result = match fault {
+                    Some(e) => Err(e),
+                    None => ds.region.region_read(*eid, *offset, &mut data),
+                };
+                ds.counters.read(sz, &result);
+                Message::ReadResponse(*uuid, *ds_id, data.freeze(), result)
+            }
+        }
+        x => bail!("unexpected frame {:?}", x),
+    };
+
+    let mut fw = fw.lock().await;
+    fw.send(response).await?;
+
+    Ok(())
+}
+
 async fn do_work_task(
     ads: &mut Arc<Mutex<Downstairs>>,
     mut job_channel_rx: Receiver<u64>,
@@ -542,6 +999,15 @@ async fn do_work_task(
             job_id = job_channel_rx.recv() => {
                 match job_id {
                     Some(job_id) => {
+                        let delay = {
+                            let ds = ads.lock().await;
+                            let delay = ds.faults.lock().await.delay();
+                            delay
+                        };
+                        if let Some(delay) = delay {
+                            tokio::time::sleep(delay).await;
+                        }
+
                         let ds = ads.lock().await;
 
                         if ds.lossy && random() && random() {
@@ -550,6 +1016,16 @@ async fn do_work_task(
                             tokio::time::sleep(Duration::from_secs(1)).await;
                         }
 
+                        /*
+                         * A stall holds the region lock, so nothing else
+                         * can happen on this region until it is over.
+                         */
+                        let stall = ds.faults.lock().await.stall();
+                        if let Some(stall) = stall {
+                            println!("Injecting a {:?} stall", stall);
+                            tokio::time::sleep(stall).await;
+                        }
+
                         ds.do_work(job_id, &ack_ready_tx).await?;
                     }
                     None => {
@@ -562,15 +1038,37 @@ async fn do_work_task(
     }
 }
 
-async fn ack_sender(
+/*
+ * The most acks we will hold back before flushing them to the upstairs.
+ */
+const MAX_ACK_BATCH: usize = 64;
+
+async fn ack_sender<W: AsyncWrite + Unpin>(
     ads: &Arc<Mutex<Downstairs>>,
-    fw: &mut Arc<Mutex<FramedWrite<OwnedWriteHalf, CrucibleEncoder>>>,
+    fw: &mut Arc<Mutex<FramedWrite<W, CrucibleEncoder>>>,
     job_channel_tx: &Arc<Mutex<Sender<u64>>>,
     mut ack_ready_rx: Receiver<u64>,
 ) -> Result<()> {
     while let Some(job_id) = ack_ready_rx.recv().await {
         let mut ds = ads.lock().await;
-        ds.complete_work(job_id, fw, job_channel_tx).await?;
+        let mut fw = fw.lock().await;
+        ds.complete_work(job_id, &mut fw, job_channel_tx).await?;
+
+        /*
+         * Anything else that finished while we were busy goes out in the
+         * same write to the socket, instead of one write for each ack.
+         */
+        let mut batch = 1;
+        while batch < MAX_ACK_BATCH {
+            match ack_ready_rx.try_recv() {
+                Ok(job_id) => {
+                    ds.complete_work(job_id, &mut fw, job_channel_tx).await?;
+                    batch += 1;
+                }
+                Err(_) => break,
+            }
+        }
+        SinkExt::<Message>::flush(&mut *fw).await?;
     }
 
     Ok(())
@@ -582,7 +1080,12 @@ async fn ack_sender(
  * the next function if everything was successful and we can start
  * taking IOs from the upstairs.
  */
-async fn proc(ads: &mut Arc<Mutex<Downstairs>>, sock: TcpStream) -> Result<()> {
+async fn proc(
+    host: &Arc<Mutex<Host>>,
+    conn_id: u64,
+    sock: TcpStream,
+    mut disconnect_rx: Receiver<Disconnect>,
+) -> Result<()> {
     let (read, write) = sock.into_split();
     let mut fr = FramedRead::new(read, CrucibleDecoder::new());
     let fw =
@@ -591,6 +1094,12 @@ async fn proc(ads: &mut Arc<Mutex<Downstairs>>, sock: TcpStream) -> Result<()> {
     let mut negotiated = 0;
     let mut upstairs_uuid = None;
 
+    /*
+     * The region this upstairs asked for, which we find when it sends
+     * HereIAm.
+     */
+    let mut ads: Option<Arc<Mutex<Downstairs>>> = None;
+
     let (_another_upstairs_active_tx, mut another_upstairs_active_rx) =
         channel(1);
     let another_upstairs_active_tx = Arc::new(_another_upstairs_active_tx);
@@ -614,6 +1123,29 @@ async fn proc(ads: &mut Arc<Mutex<Downstairs>>, sock: TcpStream) -> Result<()> {
             _ = sleep_until(deadline_secs(50)) => {
                 bail!("did not negotiate a protocol");
             }
+            /*
+             * An operator has asked us to drop this upstairs, or we are
+             * shutting down.  We have not taken any IO yet, so there is
+             * nothing to finish before we go.
+             */
+            why = disconnect_rx.recv() => {
+                println!("Disconnecting upstairs {:?}: {:?}",
+                    upstairs_uuid, why);
+                if let (Some(upstairs_uuid), Some(ads)) =
+                    (upstairs_uuid, &ads)
+                {
+                    let mut ds = ads.lock().await;
+                    if ds.is_active(upstairs_uuid) {
+                        ds.clear_active();
+                    }
+                }
+
+                if why == Some(Disconnect::Shutdown) {
+                    let mut fw = fw.lock().await;
+                    fw.send(Message::ShuttingDown).await?;
+                }
+                return Ok(());
+            }
             /*
              * This Upstairs' thread will receive this signal when another
              * Upstairs promotes itself to active. The only way this path is
@@ -631,7 +1163,7 @@ async fn proc(ads: &mut Arc<Mutex<Downstairs>>, sock: TcpStream) -> Result<()> {
                     shutting down connection for {:?}", upstairs_uuid);
 
                 let active_upstairs = {
-                    let ds = ads.lock().await;
+                    let ds = ads.as_ref().unwrap().lock().await;
                     ds.active_upstairs().unwrap()
                 };
                 let mut fw = fw.lock().await;
@@ -645,9 +1177,10 @@ async fn proc(ads: &mut Arc<Mutex<Downstairs>>, sock: TcpStream) -> Result<()> {
                  */
                 match new_read.transpose()? {
                     None => {
-                        let mut ds = ads.lock().await;
-
-                        if let Some(upstairs_uuid) = upstairs_uuid {
+                        if let (Some(upstairs_uuid), Some(ads)) =
+                            (upstairs_uuid, &ads)
+                        {
+                            let mut ds = ads.lock().await;
                             println!(
                                 "upstairs {:?} disconnected, {} jobs left",
                                 upstairs_uuid, ds.jobs().await,
@@ -659,10 +1192,7 @@ async fn proc(ads: &mut Arc<Mutex<Downstairs>>, sock: TcpStream) -> Result<()> {
                                 ds.clear_active();
                             }
                         } else {
-                            println!(
-                                "upstairs disconnected, {} jobs left",
-                                ds.jobs().await,
-                            );
+                            println!("upstairs disconnected");
                         }
 
                         return Ok(());
@@ -671,7 +1201,7 @@ async fn proc(ads: &mut Arc<Mutex<Downstairs>>, sock: TcpStream) -> Result<()> {
                         let mut fw = fw.lock().await;
                         fw.send(Message::Imok).await?;
                     }
-                    Some(Message::HereIAm(version, uuid)) => {
+                    Some(Message::HereIAm(version, uuid, region)) => {
                         if negotiated != 0 {
                             bail!("Received connect out of order {}",
                                 negotiated);
@@ -679,12 +1209,38 @@ async fn proc(ads: &mut Arc<Mutex<Downstairs>>, sock: TcpStream) -> Result<()> {
                         if version != 1 {
                             bail!("expected version 1, got {}", version);
                         }
+
+                        let found = {
+                            let mut host = host.lock().await;
+                            let found = host.find_region(region);
+                            if let Some((region_uuid, _)) = &found {
+                                host.connection_upstairs(
+                                    conn_id, uuid, *region_uuid
+                                );
+                            }
+                            found
+                        };
+                        let (region_uuid, d) = match found {
+                            Some(found) => found,
+                            None => {
+                                println!("upstairs {:?} asked for unknown \
+                                    region {:?}", uuid, region);
+                                let mut fw = fw.lock().await;
+                                fw.send(Message::UnknownRegion(region))
+                                    .await?;
+                                return Ok(());
+                            }
+                        };
+
                         negotiated = 1;
                         upstairs_uuid = Some(uuid);
-                        println!("upstairs {:?} connected",
-                            upstairs_uuid.unwrap());
+                        println!("upstairs {:?} connected to region {:?}",
+                            uuid, region_uuid);
+                        let read_only = d.lock().await.region.read_only();
+                        ads = Some(d);
+
                         let mut fw = fw.lock().await;
-                        fw.send(Message::YesItsMe(1)).await?;
+                        fw.send(Message::YesItsMe(1, read_only)).await?;
                     }
                     Some(Message::PromoteToActive(uuid)) => {
                         if negotiated != 1 {
@@ -702,13 +1258,18 @@ async fn proc(ads: &mut Arc<Mutex<Downstairs>>, sock: TcpStream) -> Result<()> {
                              * XXX
                              */
                         } else {
-                            {
-                                let mut ds = ads.lock().await;
+                            /*
+                             * A read-only region can be shared, so there
+                             * is nobody to kick out.
+                             */
+                            let mut ds = ads.as_ref().unwrap().lock().await;
+                            if !ds.region.read_only() {
                                 ds.promote_to_active(
                                     uuid,
                                     another_upstairs_active_tx.clone()
                                 ).await;
                             }
+                            drop(ds);
                             negotiated = 2;
 
                             let mut fw = fw.lock().await;
@@ -722,7 +1283,7 @@ async fn proc(ads: &mut Arc<Mutex<Downstairs>>, sock: TcpStream) -> Result<()> {
                         }
                         negotiated = 3;
                         let rd = {
-                            let ds = ads.lock().await;
+                            let ds = ads.as_ref().unwrap().lock().await;
                             ds.region.def()
                         };
 
@@ -736,12 +1297,14 @@ async fn proc(ads: &mut Arc<Mutex<Downstairs>>, sock: TcpStream) -> Result<()> {
                         }
                         negotiated = 4;
                         {
-                            let ds = ads.lock().await;
-                            let mut work = ds.work_lock(
-                                upstairs_uuid.unwrap()
-                            ).await?;
-                            work.last_flush = last_flush;
-                            println!("Set last flush {}", last_flush);
+                            let ds = ads.as_ref().unwrap().lock().await;
+                            if !ds.region.read_only() {
+                                let mut work = ds.work_lock(
+                                    upstairs_uuid.unwrap()
+                                ).await?;
+                                work.last_flush = last_flush;
+                                println!("Set last flush {}", last_flush);
+                            }
                         }
 
                         let mut fw = fw.lock().await;
@@ -757,7 +1320,7 @@ async fn proc(ads: &mut Arc<Mutex<Downstairs>>, sock: TcpStream) -> Result<()> {
                                 negotiated);
                         }
                         negotiated = 4;
-                        let ds = ads.lock().await;
+                        let ds = ads.as_ref().unwrap().lock().await;
                         let flush_numbers = ds.region.flush_numbers()?;
                         let generation_numbers = ds.region.gen_numbers()?;
                         let dirty_bits = ds.region.dirty()?;
@@ -776,6 +1339,27 @@ async fn proc(ads: &mut Arc<Mutex<Downstairs>>, sock: TcpStream) -> Result<()> {
                          * the loop and move forward with receiving IOs
                          */
                     }
+                    Some(m @ Message::ExtentFetch(..))
+                    | Some(m @ Message::ExtentPush(..))
+                    | Some(m @ Message::ExtentPushDone(..)) => {
+                        /*
+                         * A peer downstairs repairing extents stops here
+                         * after HereIAm, it never goes active.
+                         */
+                        if negotiated != 1 {
+                            bail!("Received extent repair out of order {}",
+                                negotiated);
+                        }
+                        let reply = proc_repair(
+                            ads.as_ref().unwrap(),
+                            upstairs_uuid.unwrap(),
+                            m,
+                        )
+                        .await;
+
+                        let mut fw = fw.lock().await;
+                        fw.send(reply).await?;
+                    }
                     Some(_msg) => {
                         println!("Ignored message received during negotiation");
                     }
@@ -787,8 +1371,17 @@ async fn proc(ads: &mut Arc<Mutex<Downstairs>>, sock: TcpStream) -> Result<()> {
     println!("Downstairs has completed Negotiation");
     assert!(upstairs_uuid.is_some());
     let u_uuid = upstairs_uuid.unwrap();
-
-    resp_loop(ads, fr, fw, another_upstairs_active_rx, u_uuid).await
+    let mut ads = ads.unwrap();
+
+    resp_loop(
+        &mut ads,
+        fr,
+        fw,
+        another_upstairs_active_rx,
+        disconnect_rx,
+        u_uuid,
+    )
+    .await
 }
 
 /*
@@ -801,11 +1394,36 @@ async fn resp_loop(
     mut fr: FramedRead<OwnedReadHalf, CrucibleDecoder>,
     mut fw: Arc<Mutex<FramedWrite<OwnedWriteHalf, CrucibleEncoder>>>,
     mut another_upstairs_active_rx: mpsc::Receiver<u64>,
+    mut disconnect_rx: Receiver<Disconnect>,
     upstairs_uuid: Uuid,
 ) -> Result<()> {
     let mut lossy_interval = deadline_secs(5);
     let mut more_work_interval = deadline_secs(5);
 
+    /*
+     * How many messages this upstairs has sent us, for fault injection.
+     */
+    let mut messages: u64 = 0;
+
+    /*
+     * A read or write that is over the QoS limits waits here until it may
+     * go.  While it waits we stop reading from the upstairs, but still
+     * answer to a disconnect or another upstairs taking over.  Everything
+     * behind it waits too, flushes included: we don't move a flush ahead
+     * of IO the upstairs sent before it.
+     */
+    let mut held: Option<Message> = None;
+    let mut held_until = Instant::now();
+
+    /*
+     * IO for a read-only region does not use the work queue, so there
+     * is never anything to unblock.
+     */
+    let read_only = {
+        let ds = ads.lock().await;
+        ds.region.read_only()
+    };
+
     // XXX flow control size to 100?
     let (_job_channel_tx, job_channel_rx) = channel(100);
     let job_channel_tx = Arc::new(Mutex::new(_job_channel_tx));
@@ -842,13 +1460,13 @@ async fn resp_loop(
                     //show_work(&ds);
                     ds.lossy
                 };
-                if lossy {
+                if lossy && !read_only {
                     let ds = ads.lock().await;
                     ds.unblock_jobs(upstairs_uuid, &job_channel_tx).await?;
                 }
                 lossy_interval = deadline_secs(5);
             }
-            _ = sleep_until(more_work_interval) => {
+            _ = sleep_until(more_work_interval), if !read_only => {
                 /*
                  * Unblock any stuck jobs. XXX how does this happen?
                  */
@@ -862,9 +1480,36 @@ async fn resp_loop(
              * XXX Timeouts, timeouts: always wrong!  Some too short and
              * some too long.
              */
-            _ = sleep_until(deadline_secs(50)) => {
+            _ = sleep_until(deadline_secs(50)), if held.is_none() => {
                 bail!("inactivity timeout");
             }
+            /*
+             * An operator has asked us to drop this upstairs, or we are
+             * shutting down.  When shutting down, we stop taking new IO
+             * and let the work we already have finish and be acked
+             * before we tell the upstairs we are going away.
+             */
+            why = disconnect_rx.recv() => {
+                if why == Some(Disconnect::Shutdown) {
+                    drain(ads, upstairs_uuid).await;
+                }
+
+                let mut ds = ads.lock().await;
+                println!(
+                    "Disconnecting upstairs {:?}: {:?}, {} jobs left",
+                    upstairs_uuid, why, ds.jobs().await,
+                );
+                if ds.is_active(upstairs_uuid) {
+                    ds.clear_active();
+                }
+                drop(ds);
+
+                if why == Some(Disconnect::Shutdown) {
+                    let mut fw = fw.lock().await;
+                    fw.send(Message::ShuttingDown).await?;
+                }
+                return Ok(());
+            }
             /*
              * This Upstairs' thread will receive this signal when another
              * Upstairs promotes itself to active. The only way this path is
@@ -890,7 +1535,21 @@ async fn resp_loop(
 
                 return Ok(());
             }
-            new_read = fr.next() => {
+            /*
+             * The IO we were holding back for QoS can go now.
+             */
+            _ = sleep_until(held_until), if held.is_some() => {
+                more_work_interval = deadline_secs(5);
+                resp_frame(
+                    upstairs_uuid,
+                    ads,
+                    held.take().unwrap(),
+                    &mut fw,
+                    &job_channel_tx,
+                    read_only,
+                ).await?;
+            }
+            new_read = fr.next(), if held.is_none() => {
                 // When the downstairs responds, push the deadlines
                 more_work_interval = deadline_secs(5);
 
@@ -912,42 +1571,387 @@ async fn resp_loop(
                         return Ok(());
                     }
                     Some(msg) => {
-                        proc_frame(
+                        messages += 1;
+                        let disconnect_after = {
+                            let ds = ads.lock().await;
+                            let faults = ds.faults.lock().await;
+                            faults.disconnect_after()
+                        };
+                        if matches!(disconnect_after, Some(n) if messages >= n) {
+                            bail!("Injected disconnect after {} messages",
+                                messages);
+                        }
+
+                        /*
+                         * Hold back IO that is over the limits for this
+                         * region.  We don't read anything more from the
+                         * upstairs until it goes, which pushes back on it
+                         * through the connection.
+                         */
+                        let delay = qos_delay(ads, &msg).await;
+                        if delay > Duration::from_secs(0) {
+                            held = Some(msg);
+                            held_until = Instant::now() + delay;
+                            continue;
+                        }
+
+                        resp_frame(
                             upstairs_uuid,
                             ads,
-                            &msg,
+                            msg,
                             &mut fw,
-                            job_channel_tx.clone(),
+                            &job_channel_tx,
+                            read_only,
                         ).await?;
                     }
                 }
             }
         }
     }
-}
+}
+
+/*
+ * Do what the upstairs asked of us in a message, once it has been let in.
+ */
+async fn resp_frame(
+    upstairs_uuid: Uuid,
+    ads: &mut Arc<Mutex<Downstairs>>,
+    msg: Message,
+    fw: &mut Arc<Mutex<FramedWrite<OwnedWriteHalf, CrucibleEncoder>>>,
+    job_channel_tx: &Arc<Mutex<Sender<u64>>>,
+    read_only: bool,
+) -> Result<()> {
+    if matches!(msg, Message::ExtentVersionsPlease) {
+        /*
+         * The upstairs wants our current versions so it can bring a
+         * downstairs that was missing up to date from us.
+         */
+        let (gens, flush_numbers, dirty_bits) = {
+            let ds = ads.lock().await;
+            (
+                ds.region.gen_numbers()?,
+                ds.region.flush_numbers()?,
+                ds.region.dirty()?,
+            )
+        };
+        let mut fw = fw.lock().await;
+        fw.send(Message::ExtentVersions(gens, flush_numbers, dirty_bits))
+            .await?;
+    } else if matches!(
+        msg,
+        Message::ExtentFetch(..)
+            | Message::ExtentPush(..)
+            | Message::ExtentPushDone(..)
+    ) {
+        /*
+         * The upstairs is making us agree with the other downstairs,
+         * either before it goes active or because we were missing when
+         * it did.
+         */
+        let reply = proc_repair(ads, upstairs_uuid, msg).await;
+        let mut fw = fw.lock().await;
+        fw.send(reply).await?;
+    } else if read_only {
+        proc_frame_read_only(upstairs_uuid, ads, &msg, fw).await?;
+    } else {
+        proc_frame(upstairs_uuid, ads, &msg, fw, job_channel_tx.clone())
+            .await?;
+    }
+
+    Ok(())
+}
+
+/*
+ * How long a new message from the upstairs has to wait before the region
+ * QoS limits let it in.  Only reads and writes are limited.
+ */
+async fn qos_delay(ads: &Arc<Mutex<Downstairs>>, m: &Message) -> Duration {
+    let mut ds = ads.lock().await;
+    let bytes = match m {
+        Message::Write(_, _, _, _, _, data) => data.len(),
+        Message::ReadRequest(_, _, _, _, _, num_blocks) => {
+            *num_blocks as usize * ds.region.def().block_size() as usize
+        }
+        _ => return Duration::from_secs(0),
+    };
+    ds.qos.reserve(bytes)
+}
+
+/*
+ * Wait for the work we have for this upstairs to be done and acked.  We
+ * give up after a while, as anything left will be replayed by the upstairs
+ * when it reconnects.
+ */
+async fn drain(ads: &Arc<Mutex<Downstairs>>, upstairs_uuid: Uuid) {
+    let deadline = deadline_secs(30);
+
+    loop {
+        let jobs = {
+            let ds = ads.lock().await;
+            if !ds.is_active(upstairs_uuid) {
+                /*
+                 * Our work was thrown out when another upstairs took over.
+                 */
+                return;
+            }
+            ds.jobs().await
+        };
+
+        if jobs == 0 {
+            return;
+        }
+        if Instant::now() >= deadline {
+            println!(
+                "upstairs {:?} drain timed out with {} jobs left",
+                upstairs_uuid, jobs
+            );
+            return;
+        }
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+}
+
+/*
+ * Take on a new connection from an upstairs, and serve it until it goes
+ * away.
+ */
+async fn start_connection(
+    h: &Arc<Mutex<Host>>,
+    sock: TcpStream,
+    raddr: SocketAddr,
+) {
+    let hh = h.clone();
+    let (disconnect_tx, disconnect_rx) = channel(1);
+    let conn_id = h.lock().await.add_connection(raddr, disconnect_tx);
+
+    tokio::spawn(async move {
+        if let Err(e) = proc(&hh, conn_id, sock, disconnect_rx).await {
+            println!("ERROR: connection({}): {:?}", raddr, e);
+        } else {
+            println!("OK: connection({}): all done", raddr);
+        }
+        hh.lock().await.remove_connection(conn_id);
+    });
+}
+
+/*
+ * Overall structure for things the downstairs is tracking for a region.
+ * This includes the extents and their status as well as the
+ * downstairs work queue.
+ */
+#[derive(Debug)]
+struct Downstairs {
+    region: Region,
+    work: Mutex<Work>,
+    lossy: bool, // Test flag, enables pauses and skipped jobs
+    faults: Mutex<Faults>,
+    active_upstairs: Option<(Uuid, Arc<Sender<u64>>)>,
+    counters: IoCounters,
+    qos: Qos,
+}
+
+/*
+ * Everything this downstairs process is serving.  Each region has its
+ * own work queue and active upstairs, so regions never wait on each
+ * other.  An upstairs is matched with a region when it sends HereIAm.
+ */
+#[derive(Debug)]
+struct Host {
+    regions: HashMap<Uuid, Arc<Mutex<Downstairs>>>,
+    /*
+     * Every upstairs connection we have, indexed by a connection ID that
+     * is only used inside this downstairs.
+     */
+    connections: HashMap<u64, Connection>,
+    next_connection_id: u64,
+    /*
+     * How we serve regions, including those added after we start.
+     */
+    lossy: bool,
+    faults: FaultConfig,
+    max_open_extents: usize,
+    read_only: bool,
+    qos: QosLimits,
+}
+
+/*
+ * Why we are asking a connection to end.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Disconnect {
+    /*
+     * An operator asked for this upstairs to be disconnected.
+     */
+    Admin,
+    /*
+     * The downstairs is shutting down.
+     */
+    Shutdown,
+}
+
+/*
+ * A connection from an upstairs.  We don't know the UUID of the upstairs
+ * until it has sent us HereIAm.
+ */
+#[derive(Debug)]
+struct Connection {
+    address: SocketAddr,
+    upstairs_uuid: Option<Uuid>,
+    region: Option<Uuid>,
+    disconnect_tx: Sender<Disconnect>,
+}
+
+impl Host {
+    fn new(
+        lossy: bool,
+        faults: FaultConfig,
+        max_open_extents: usize,
+        read_only: bool,
+        qos: QosLimits,
+    ) -> Self {
+        Host {
+            regions: HashMap::new(),
+            connections: HashMap::new(),
+            next_connection_id: 0,
+            lossy,
+            faults,
+            max_open_extents,
+            read_only,
+            qos,
+        }
+    }
+
+    /*
+     * Open the region in this directory and start serving it.
+     */
+    fn add_region<P: AsRef<Path>>(&mut self, dir: P) -> Result<Uuid> {
+        let region =
+            Region::open(&dir, Default::default(), true, self.read_only)?;
+        region.set_max_open_extents(self.max_open_extents);
+
+        let uuid = region.def().uuid();
+        if self.regions.contains_key(&uuid) {
+            bail!("Region {:?} is already being served", uuid);
+        }
+
+        println!("UUID: {:?}", uuid);
+        if self.read_only {
+            println!("Serving region read-only");
+        }
+        println!(
+            "Blocks per extent:{} Total Extents: {}",
+            region.def().extent_size().value,
+            region.def().extent_count(),
+        );
+
+        let d =
+            Downstairs::new(region, self.lossy, self.faults.clone(), self.qos);
+        self.regions.insert(uuid, Arc::new(Mutex::new(d)));
+        Ok(uuid)
+    }
+
+    /*
+     * Stop serving a region.  Any upstairs using it has to be
+     * disconnected first.
+     */
+    fn remove_region(&mut self, uuid: Uuid) -> Result<()> {
+        if !self.regions.contains_key(&uuid) {
+            bail!("Region {:?} is not being served", uuid);
+        }
+        let users = self
+            .connections
+            .values()
+            .filter(|c| c.region == Some(uuid))
+            .count();
+        if users > 0 {
+            bail!("Region {:?} has {} connections", uuid, users);
+        }
+
+        self.regions.remove(&uuid);
+        println!("Stopped serving region {:?}", uuid);
+        Ok(())
+    }
+
+    /*
+     * Find the region an upstairs asked for.  If it did not ask for one,
+     * we can only choose for it if we have just one region.
+     */
+    fn find_region(
+        &self,
+        uuid: Option<Uuid>,
+    ) -> Option<(Uuid, Arc<Mutex<Downstairs>>)> {
+        match uuid {
+            Some(uuid) => self.regions.get(&uuid).map(|d| (uuid, d.clone())),
+            None if self.regions.len() == 1 => self
+                .regions
+                .iter()
+                .next()
+                .map(|(uuid, d)| (*uuid, d.clone())),
+            None => None,
+        }
+    }
+
+    fn add_connection(
+        &mut self,
+        address: SocketAddr,
+        disconnect_tx: Sender<Disconnect>,
+    ) -> u64 {
+        let id = self.next_connection_id;
+        self.next_connection_id += 1;
+        self.connections.insert(
+            id,
+            Connection {
+                address,
+                upstairs_uuid: None,
+                region: None,
+                disconnect_tx,
+            },
+        );
+        id
+    }
+
+    fn connection_upstairs(
+        &mut self,
+        id: u64,
+        upstairs_uuid: Uuid,
+        region: Uuid,
+    ) {
+        if let Some(c) = self.connections.get_mut(&id) {
+            c.upstairs_uuid = Some(upstairs_uuid);
+            c.region = Some(region);
+        }
+    }
 
-/*
- * Overall structure for things the downstairs is tracking.
- * This includes the extents and their status as well as the
- * downstairs work queue.
- */
-#[derive(Debug)]
-struct Downstairs {
-    region: Region,
-    work: Mutex<Work>,
-    lossy: bool,         // Test flag, enables pauses and skipped jobs
-    return_errors: bool, // Test flag
-    active_upstairs: Option<(Uuid, Arc<Sender<u64>>)>,
+    fn remove_connection(&mut self, id: u64) {
+        self.connections.remove(&id);
+    }
+
+    /*
+     * Tell every connection we are shutting down.
+     */
+    fn shutdown_connections(&self) {
+        for c in self.connections.values() {
+            let _ = c.disconnect_tx.try_send(Disconnect::Shutdown);
+        }
+    }
 }
 
 impl Downstairs {
-    fn new(region: Region, lossy: bool, return_errors: bool) -> Self {
+    fn new(
+        region: Region,
+        lossy: bool,
+        faults: FaultConfig,
+        qos: QosLimits,
+    ) -> Self {
         Downstairs {
             region,
             work: Mutex::new(Work::default()),
             lossy,
-            return_errors,
+            faults: Mutex::new(Faults::new(faults)),
             active_upstairs: None,
+            counters: IoCounters::default(),
+            qos: Qos::new(qos),
         }
     }
 
@@ -1068,28 +2072,40 @@ impl Downstairs {
     /*
      * Complete work by:
      *
-     * - notifying the upstairs with the response
+     * - queueing the response for the upstairs
      * - removing the job from active
      * - removing the response
      * - putting the id on the completed list.
      */
-    async fn complete_work(
+    async fn complete_work<W: AsyncWrite + Unpin>(
         &mut self,
         ds_id: u64,
-        fw: &mut Arc<Mutex<FramedWrite<OwnedWriteHalf, CrucibleEncoder>>>,
+        fw: &mut FramedWrite<W, CrucibleEncoder>,
         job_channel_tx: &Arc<Mutex<Sender<u64>>>,
     ) -> Result<()> {
         let mut work = self.work.lock().await;
 
         let m = work.responses.get(&ds_id).unwrap();
 
-        // Notify the upstairs
-        let mut fw = fw.lock().await;
-        fw.send(m).await?;
+        /*
+         * Notify the upstairs.  This only buffers the response, the caller
+         * flushes once it has completed everything that is ready.
+         */
+        fw.feed(m).await?;
 
         // Complete the job
         let is_flush = matches!(m, Message::FlushAck(_, _, _));
 
+        /*
+         * A write or flush that failed has not happened.  The upstairs
+         * will send it again, and anything that depends on it must wait
+         * until then.
+         */
+        let failed = matches!(
+            m,
+            Message::WriteAck(_, _, Err(_)) | Message::FlushAck(_, _, Err(_))
+        );
+
         let upstairs_uuid = match &m {
             Message::WriteAck(uuid, _, _) => *uuid,
             Message::ReadResponse(uuid, _, _, _) => *uuid,
@@ -1105,7 +2121,9 @@ impl Downstairs {
         let existing = work.responses.remove(&ds_id);
         assert!(existing.is_some());
 
-        if is_flush {
+        if failed {
+            println!("{} failed, waiting for it to be sent again", ds_id);
+        } else if is_flush {
             work.last_flush = ds_id;
             work.completed = Vec::with_capacity(32);
         } else {
@@ -1464,14 +2482,16 @@ impl Work {
                  * Any error from an IO should be intercepted here and passed
                  * back to the upstairs.
                  */
-                let result = if ds.return_errors && random() && random() {
-                    println!("returning error on read!");
-                    Err(CrucibleError::GenericError("test error".to_string()))
+                let fault =
+                    ds.faults.lock().await.io_error(FaultOp::Read, Some(*eid));
+                let result = if let Some(e) = fault {
+                    Err(e)
                 } else if !ds.is_active(job.upstairs_uuid) {
                     Err(CrucibleError::UpstairsInactive)
                 } else {
                     ds.region.region_read(*eid, *offset, &mut data)
                 };
+                ds.counters.read(sz, &result);
 
                 let existing = self.responses.insert(
                     job_id,
@@ -1492,14 +2512,16 @@ impl Work {
                 offset,
                 data,
             } => {
-                let result = if ds.return_errors && random() && random() {
-                    println!("returning error on write!");
-                    Err(CrucibleError::GenericError("test error".to_string()))
+                let fault =
+                    ds.faults.lock().await.io_error(FaultOp::Write, Some(*eid));
+                let result = if let Some(e) = fault {
+                    Err(e)
                 } else if !ds.is_active(job.upstairs_uuid) {
                     Err(CrucibleError::UpstairsInactive)
                 } else {
                     ds.region.region_write(*eid, *offset, data)
                 };
+                ds.counters.write(data.len(), &result);
 
                 let existing = self.responses.insert(
                     job_id,
@@ -1513,14 +2535,16 @@ impl Work {
                 dependencies: _dependencies,
                 flush_number,
             } => {
-                let result = if ds.return_errors && random() && random() {
-                    println!("returning error on flush!");
-                    Err(CrucibleError::GenericError("test error".to_string()))
+                let fault =
+                    ds.faults.lock().await.io_error(FaultOp::Flush, None);
+                let result = if let Some(e) = fault {
+                    Err(e)
                 } else if !ds.is_active(job.upstairs_uuid) {
                     Err(CrucibleError::UpstairsInactive)
                 } else {
                     ds.region.region_flush(*flush_number)
                 };
+                ds.counters.flush(&result);
 
                 let existing = self.responses.insert(
                     job_id,
@@ -1538,7 +2562,7 @@ impl Work {
  * We may not need Done or Error.  At the moment all we actually look
  * at is New or InProgress.
  */
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize)]
 pub enum WorkState {
     New,
     DepWait,
@@ -1585,6 +2609,7 @@ async fn main() -> Result<()> {
             extent_size,
             extent_count,
             import_path,
+            key,
             uuid,
         } => {
             /*
@@ -1603,7 +2628,8 @@ async fn main() -> Result<()> {
             region.extend(extent_count as u32)?;
 
             if let Some(ref ip) = import_path {
-                downstairs_import(&mut region, ip).unwrap();
+                let context = encryption_context(key, &region)?;
+                downstairs_import(&mut region, ip, context.as_ref()).unwrap();
                 /*
                  * The region we just created should now have a flush so the
                  * new data and inital flush number is written to disk.
@@ -1619,6 +2645,17 @@ async fn main() -> Result<()> {
             );
             Ok(())
         }
+        Args::Clone {
+            data,
+            output,
+            reset_meta,
+            uuid,
+        } => {
+            let source = Region::open(&data, Default::default(), true, true)?;
+            let region = source.clone_to(&output, uuid, reset_meta)?;
+            println!("UUID: {:?}", region.def().uuid());
+            Ok(())
+        }
         Args::Dump { data, extent } => {
             dump_region(data, extent)?;
             Ok(())
@@ -1627,35 +2664,118 @@ async fn main() -> Result<()> {
             count,
             data,
             export_path,
+            key,
+            since_flush,
             skip,
         } => {
-            region = Region::open(&data, Default::default(), true)?;
+            let verbose = !is_stdio(&export_path);
+            region = Region::open(&data, Default::default(), verbose, true)?;
+
+            if let Some(since_flush) = since_flush {
+                let extents = if is_stdio(&export_path) {
+                    let stdout = std::io::stdout();
+                    let mut out = std::io::BufWriter::new(stdout.lock());
+                    export_delta(&region, since_flush, &mut out)?
+                } else {
+                    let mut out =
+                        std::io::BufWriter::new(File::create(&export_path)?);
+                    export_delta(&region, since_flush, &mut out)?
+                };
+                eprintln!(
+                    "Exported {} extents flushed since {}",
+                    extents, since_flush
+                );
+                return Ok(());
+            }
+
+            let context = encryption_context(key, &region)?;
+            downstairs_export(
+                &mut region,
+                export_path,
+                skip,
+                count,
+                context.as_ref(),
+            )
+            .unwrap();
+            Ok(())
+        }
+        Args::ImportDelta { data, import_path } => {
+            region = Region::open(&data, Default::default(), true, false)?;
 
-            downstairs_export(&mut region, export_path, skip, count).unwrap();
+            let extents = if is_stdio(&import_path) {
+                let stdin = std::io::stdin();
+                let mut input = std::io::BufReader::new(stdin.lock());
+                import_delta(&mut region, &mut input)?
+            } else {
+                let mut input =
+                    std::io::BufReader::new(File::open(&import_path)?);
+                import_delta(&mut region, &mut input)?
+            };
+            println!("Replaced {} extents", extents);
             Ok(())
         }
         Args::Run {
             address,
+            admin_socket,
+            bandwidth_limit,
             data,
+            faults,
+            iops_limit,
             lossy,
+            max_open_extents,
             port,
+            read_only,
+            region_dir,
             return_errors,
             trace_endpoint,
         } => {
-            region = Region::open(&data, Default::default(), true)?;
+            if max_open_extents == 0 {
+                bail!("--max-open-extents must be at least 1");
+            }
 
-            println!("UUID: {:?}", region.def().uuid());
-            println!(
-                "Blocks per extent:{} Total Extents: {}",
-                region.def().extent_size().value,
-                region.def().extent_count(),
-            );
+            let mut faults: FaultConfig = match faults {
+                Some(path) => read_json(&path)?,
+                None => Default::default(),
+            };
+            if return_errors {
+                faults.read_error_rate = 0.25;
+                faults.write_error_rate = 0.25;
+                faults.flush_error_rate = 0.25;
+            }
+            faults.validate()?;
+            if faults != FaultConfig::default() {
+                println!("Injecting faults: {:?}", faults);
+            }
+
+            let qos = QosLimits {
+                iops: iops_limit,
+                bandwidth: bandwidth_limit,
+            };
+            let mut host =
+                Host::new(lossy, faults, max_open_extents, read_only, qos);
+            for dir in data.iter() {
+                host.add_region(dir)?;
+            }
+            if let Some(region_dir) = region_dir {
+                let mut dirs = std::fs::read_dir(&region_dir)?
+                    .map(|e| e.map(|e| e.path()))
+                    .collect::<std::io::Result<Vec<_>>>()?;
+                dirs.sort();
+                for dir in dirs.iter().filter(|d| Region::exists(d)) {
+                    host.add_region(dir)?;
+                }
+            }
+
+            /*
+             * With an admin socket, regions can be added once we are
+             * running.  Without one, there is nothing we could do.
+             */
+            if host.regions.is_empty() && admin_socket.is_none() {
+                bail!("No regions to serve");
+            }
+            println!("Serving {} regions", host.regions.len());
 
-            let d = Arc::new(Mutex::new(Downstairs::new(
-                region,
-                lossy,
-                return_errors,
-            )));
+            let h = Arc::new(Mutex::new(host));
 
             /*
              * If any of our async tasks in our runtime panic, then we should
@@ -1689,6 +2809,15 @@ async fn main() -> Result<()> {
             let listen_on = SocketAddrV4::new(address, port);
             let listener = TcpListener::bind(&listen_on).await?;
 
+            if let Some(path) = admin_socket {
+                let hh = h.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = admin_listen(path, hh).await {
+                        println!("ERROR: admin socket: {:?}", e);
+                    }
+                });
+            }
+
             /*
              * We now loop listening for a connection from the Upstairs.
              * When we get one, we then spawn the proc() function to handle
@@ -1696,21 +2825,654 @@ async fn main() -> Result<()> {
              * multiple Upstairs connecting but only one active one.
              */
             println!("listening on {}", listen_on);
+            let mut sigterm = signal(SignalKind::terminate())?;
             loop {
-                let (sock, raddr) = listener.accept().await?;
+                let (sock, raddr) = tokio::select! {
+                    accepted = listener.accept() => accepted?,
+                    _ = sigterm.recv() => {
+                        println!("SIGTERM received");
+                        break;
+                    }
+                    _ = tokio::signal::ctrl_c() => {
+                        println!("SIGINT received");
+                        break;
+                    }
+                };
 
                 println!("connection from {:?}", raddr);
+                start_connection(&h, sock, raddr).await;
+            }
 
-                let mut dd = d.clone();
+            /*
+             * Stop taking new connections, then let each upstairs finish
+             * what it has sent us before we hang up on it.
+             */
+            drop(listener);
+            shutdown(&h).await
+        }
+        Args::Relayout {
+            data,
+            output,
+            block_size,
+            extent_size,
+            key,
+            unencrypted: _,
+        } => {
+            let source = Region::open(&data, Default::default(), true, true)?;
+            let def = source.def();
+            let block_size = block_size.unwrap_or_else(|| def.block_size());
+            /*
+             * If only the block size changes, keep extents the same size
+             * in bytes.
+             */
+            let extent_size = extent_size.unwrap_or_else(|| {
+                (def.block_size() * def.extent_size().value / block_size).max(1)
+            });
+            relayout_region(&source, &output, block_size, extent_size, key)?;
+            Ok(())
+        }
+        Args::Verify { data } => {
+            let report = verify_regions(&data)?;
+            println!("{}", serde_json::to_string_pretty(&report)?);
+            if !report.matches() {
+                bail!(
+                    "{} of {} extents do not match",
+                    report.mismatched.len(),
+                    report.extent_count
+                );
+            }
+            Ok(())
+        }
+        Args::Repair { data, dry_run } => {
+            let report = repair_regions(&data, dry_run)?;
+            println!("{}", serde_json::to_string_pretty(&report)?);
+            Ok(())
+        }
+    }
+}
 
-                tokio::spawn(async move {
-                    if let Err(e) = proc(&mut dd, sock).await {
-                        println!("ERROR: connection({}): {:?}", raddr, e);
-                    } else {
-                        println!("OK: connection({}): all done", raddr);
+/*
+ * Shut down the downstairs after we have stopped listening for new
+ * connections.  Each connected upstairs is told we are going away once
+ * its outstanding work is done, so it can take us offline right away
+ * instead of waiting for a timeout.
+ */
+async fn shutdown(h: &Arc<Mutex<Host>>) -> Result<()> {
+    {
+        let host = h.lock().await;
+        println!(
+            "Shutting down, closing {} connections",
+            host.connections.len()
+        );
+        host.shutdown_connections();
+    }
+
+    /*
+     * Each connection gets its own time limit to drain, this is just to
+     * keep us from waiting forever on one that is stuck.
+     */
+    let deadline = deadline_secs(60);
+    loop {
+        let remaining = h.lock().await.connections.len();
+        if remaining == 0 {
+            break;
+        }
+        if Instant::now() >= deadline {
+            println!("Gave up waiting on {} connections", remaining);
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+
+    let host = h.lock().await;
+    for d in host.regions.values() {
+        d.lock().await.region.sync_dirty_extents()?;
+    }
+    println!("Downstairs shutdown complete");
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bytes::Bytes;
+    use crucible_common::RegionOptions;
+    use tempfile::{tempdir, TempDir};
+    use tokio_util::codec::Decoder;
+
+    type TestRead = FramedRead<OwnedReadHalf, CrucibleDecoder>;
+    type TestWrite = FramedWrite<OwnedWriteHalf, CrucibleEncoder>;
+
+    /*
+     * Make a new region of three extents, each of ten 512 byte blocks.
+     */
+    fn new_region() -> Result<(TempDir, Uuid)> {
+        new_region_sized(10)
+    }
+
+    fn new_region_sized(extent_blocks: u64) -> Result<(TempDir, Uuid)> {
+        let dir = tempdir()?;
+        let mut options: RegionOptions = Default::default();
+        options.set_block_size(512);
+        options.set_extent_size(Block::new(extent_blocks, 9));
+        options.set_uuid(Uuid::new_v4());
+        let mut region = Region::create(&dir, options)?;
+        region.extend(3)?;
+        Ok((dir, region.def().uuid()))
+    }
+
+    fn test_host(faults: FaultConfig) -> Host {
+        Host::new(false, faults, 8, false, QosLimits::default())
+    }
+
+    /*
+     * Take connections on a local port, the way `run` does.
+     */
+    async fn serve(h: &Arc<Mutex<Host>>) -> Result<SocketAddr> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let h = h.clone();
+        tokio::spawn(async move {
+            while let Ok((sock, raddr)) = listener.accept().await {
+                start_connection(&h, sock, raddr).await;
+            }
+        });
+        Ok(addr)
+    }
+
+    async fn recv(fr: &mut TestRead) -> Result<Message> {
+        match tokio::time::timeout(Duration::from_secs(10), fr.next()).await {
+            Err(_) => bail!("timed out waiting for the downstairs"),
+            Ok(None) => bail!("downstairs hung up"),
+            Ok(Some(m)) => m,
+        }
+    }
+
+    /*
+     * Connect as an upstairs and say which region we want.
+     */
+    async fn connect(
+        addr: SocketAddr,
+        upstairs: Uuid,
+        region: Option<Uuid>,
+    ) -> Result<(TestRead, TestWrite, Message)> {
+        let sock = TcpStream::connect(addr).await?;
+        let (read, write) = sock.into_split();
+        let mut fr = FramedRead::new(read, CrucibleDecoder::new());
+        let mut fw = FramedWrite::new(write, CrucibleEncoder::new());
+
+        fw.send(Message::HereIAm(1, upstairs, region)).await?;
+        let reply = recv(&mut fr).await?;
+        Ok((fr, fw, reply))
+    }
+
+    /*
+     * Connect as an upstairs and go active, ready to send IO.
+     */
+    async fn connect_active(
+        addr: SocketAddr,
+        upstairs: Uuid,
+        region: Option<Uuid>,
+    ) -> Result<(TestRead, TestWrite, RegionDefinition)> {
+        let (mut fr, mut fw, reply) = connect(addr, upstairs, region).await?;
+        assert_eq!(reply, Message::YesItsMe(1, false));
+
+        fw.send(Message::PromoteToActive(upstairs)).await?;
+        assert_eq!(recv(&mut fr).await?, Message::YouAreNowActive(upstairs));
+
+        fw.send(Message::RegionInfoPlease).await?;
+        let def = match recv(&mut fr).await? {
+            Message::RegionInfo(def) => def,
+            m => bail!("expected RegionInfo, got {:?}", m),
+        };
+
+        fw.send(Message::LastFlush(0)).await?;
+        assert_eq!(recv(&mut fr).await?, Message::LastFlushAck(0));
+        Ok((fr, fw, def))
+    }
+
+    #[tokio::test]
+    async fn shutdown_acks_queued_work_first() -> Result<()> {
+        /*
+         * Each write takes a while, so they are still queued when we
+         * start shutting down.
+         */
+        let (dir, uuid) = new_region()?;
+        let mut host = test_host(FaultConfig {
+            delay_rate: 1.0,
+            delay_ms: 200,
+            ..Default::default()
+        });
+        host.add_region(&dir)?;
+        let h = Arc::new(Mutex::new(host));
+        let addr = serve(&h).await?;
+
+        let upstairs = Uuid::new_v4();
+        let (mut fr, mut fw, _) = connect_active(addr, upstairs, None).await?;
+        for ds_id in 1000..1003 {
+            fw.send(Message::Write(
+                upstairs,
+                ds_id,
+                0,
+                vec![],
+                Block::new_512(ds_id - 1000),
+                Bytes::from(vec![1; 512]),
+            ))
+            .await?;
+        }
+
+        let d = h.lock().await.regions[&uuid].clone();
+        while d.lock().await.jobs().await < 3 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        let hh = h.clone();
+        let shutdown = tokio::spawn(async move { shutdown(&hh).await });
+
+        let mut acked = Vec::new();
+        loop {
+            match recv(&mut fr).await? {
+                Message::WriteAck(_, ds_id, result) => {
+                    result?;
+                    acked.push(ds_id);
+                }
+                Message::ShuttingDown => break,
+                m => bail!("unexpected {:?}", m),
+            }
+        }
+        assert_eq!(acked, vec![1000, 1001, 1002]);
+
+        shutdown.await??;
+        assert!(h.lock().await.connections.is_empty());
+        assert_eq!(d.lock().await.jobs().await, 0);
+        Ok(())
+    }
+
+    /*
+     * Collects what is written to it, split up at each flush.
+     */
+    #[derive(Default)]
+    struct FlushRecorder {
+        pending: Vec<u8>,
+        flushed: Vec<Vec<u8>>,
+    }
+
+    impl AsyncWrite for FlushRecorder {
+        fn poll_write(
+            mut self: std::pin::Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+            buf: &[u8],
+        ) -> std::task::Poll<std::io::Result<usize>> {
+            self.pending.extend_from_slice(buf);
+            std::task::Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(
+            mut self: std::pin::Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            if !self.pending.is_empty() {
+                let batch = std::mem::take(&mut self.pending);
+                self.flushed.push(batch);
+            }
+            std::task::Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(
+            self: std::pin::Pin<&mut Self>,
+            cx: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            self.poll_flush(cx)
+        }
+    }
+
+    #[tokio::test]
+    async fn acks_go_out_in_batches() -> Result<()> {
+        let (dir, _) = new_region()?;
+        let region = Region::open(&dir, Default::default(), false, false)?;
+        let mut ds = Downstairs::new(
+            region,
+            false,
+            FaultConfig::default(),
+            QosLimits::default(),
+        );
+        let upstairs = Uuid::new_v4();
+        let (terminate_tx, _terminate_rx) = channel(1);
+        ds.promote_to_active(upstairs, Arc::new(terminate_tx)).await;
+
+        /*
+         * Every job has finished before the ack sender gets to run, so
+         * they should go out in as few writes as the batch limit allows.
+         */
+        let count = MAX_ACK_BATCH as u64 + 10;
+        let (ack_ready_tx, ack_ready_rx) = channel(count as usize);
+        {
+            let mut work = ds.work.lock().await;
+            for ds_id in 1000..1000 + count {
+                work.responses
+                    .insert(ds_id, Message::WriteAck(upstairs, ds_id, Ok(())));
+                ack_ready_tx.send(ds_id).await?;
+            }
+        }
+        drop(ack_ready_tx);
+
+        let ads = Arc::new(Mutex::new(ds));
+        let mut fw = Arc::new(Mutex::new(FramedWrite::new(
+            FlushRecorder::default(),
+            CrucibleEncoder::new(),
+        )));
+        let (job_tx, _job_rx) = channel(1);
+        let job_tx = Arc::new(Mutex::new(job_tx));
+        ack_sender(&ads, &mut fw, &job_tx, ack_ready_rx).await?;
+
+        let fw = fw.lock().await;
+        let mut acked = Vec::new();
+        let mut batches = Vec::new();
+        for batch in fw.get_ref().flushed.iter() {
+            let mut buf = BytesMut::from(&batch[..]);
+            let mut decoder = CrucibleDecoder::new();
+            let mut n = 0;
+            while let Some(m) = decoder.decode(&mut buf)? {
+                match m {
+                    Message::WriteAck(_, ds_id, result) => {
+                        result?;
+                        acked.push(ds_id);
                     }
-                });
+                    m => bail!("unexpected {:?}", m),
+                }
+                n += 1;
+            }
+            assert!(buf.is_empty());
+            batches.push(n);
+        }
+
+        assert_eq!(acked, (1000..1000 + count).collect::<Vec<_>>());
+        assert_eq!(batches, vec![MAX_ACK_BATCH, 10]);
+        assert!(ads.lock().await.work.lock().await.responses.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn host_serves_each_region() -> Result<()> {
+        let (dir1, uuid1) = new_region()?;
+        let (dir2, uuid2) = new_region()?;
+        let mut host = test_host(Default::default());
+        assert_eq!(host.add_region(&dir1)?, uuid1);
+        assert_eq!(host.add_region(&dir2)?, uuid2);
+        assert!(host.add_region(&dir1).is_err());
+        let h = Arc::new(Mutex::new(host));
+        let addr = serve(&h).await?;
+
+        /*
+         * Each upstairs gets the region it asked for.
+         */
+        for uuid in [uuid1, uuid2] {
+            let upstairs = Uuid::new_v4();
+            let (_fr, _fw, def) =
+                connect_active(addr, upstairs, Some(uuid)).await?;
+            assert_eq!(def.uuid(), uuid);
+
+            let host = h.lock().await;
+            assert!(host.connections.values().any(|c| {
+                c.upstairs_uuid == Some(upstairs) && c.region == Some(uuid)
+            }));
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn host_refuses_unknown_region() -> Result<()> {
+        let (dir1, _) = new_region()?;
+        let (dir2, _) = new_region()?;
+        let mut host = test_host(Default::default());
+        host.add_region(&dir1)?;
+        host.add_region(&dir2)?;
+        let h = Arc::new(Mutex::new(host));
+        let addr = serve(&h).await?;
+
+        /*
+         * With more than one region, the upstairs has to say which.
+         */
+        let (mut fr, _fw, reply) = connect(addr, Uuid::new_v4(), None).await?;
+        assert_eq!(reply, Message::UnknownRegion(None));
+        assert!(fr.next().await.is_none());
+
+        let unknown = Uuid::new_v4();
+        let (mut fr, _fw, reply) =
+            connect(addr, Uuid::new_v4(), Some(unknown)).await?;
+        assert_eq!(reply, Message::UnknownRegion(Some(unknown)));
+        assert!(fr.next().await.is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn qos_held_io_does_not_block_takeover() -> Result<()> {
+        let (dir, _) = new_region()?;
+        let mut host = Host::new(
+            false,
+            Default::default(),
+            8,
+            false,
+            QosLimits {
+                iops: None,
+                bandwidth: Some(100),
+            },
+        );
+        host.add_region(&dir)?;
+        let h = Arc::new(Mutex::new(host));
+        let addr = serve(&h).await?;
+
+        /*
+         * The first write puts us well over the limit, so the second is
+         * held back for seconds.
+         */
+        let upstairs = Uuid::new_v4();
+        let (mut fr, mut fw, _) = connect_active(addr, upstairs, None).await?;
+        for ds_id in 1000..1002 {
+            fw.send(Message::Write(
+                upstairs,
+                ds_id,
+                0,
+                vec![],
+                Block::new_512(ds_id - 1000),
+                Bytes::from(vec![1; 512]),
+            ))
+            .await?;
+        }
+
+        /*
+         * Another upstairs taking over is still noticed right away.
+         */
+        let other = Uuid::new_v4();
+        let _other = connect_active(addr, other, None).await?;
+        let start = Instant::now();
+        loop {
+            match recv(&mut fr).await? {
+                Message::WriteAck(_, 1000, _) => {}
+                Message::UuidMismatch(uuid) => {
+                    assert_eq!(uuid, other);
+                    break;
+                }
+                m => bail!("unexpected {:?}", m),
+            }
+        }
+        assert!(start.elapsed() < Duration::from_secs(2));
+        Ok(())
+    }
+
+    /*
+     * Serve one region of our own, and hand back the Downstairs for it.
+     */
+    async fn serve_region(
+        dir: &TempDir,
+    ) -> Result<(SocketAddr, Arc<Mutex<Downstairs>>)> {
+        let mut host = test_host(Default::default());
+        let uuid = host.add_region(dir)?;
+        let d = host.regions[&uuid].clone();
+        let addr = serve(&Arc::new(Mutex::new(host))).await?;
+        Ok((addr, d))
+    }
+
+    #[tokio::test]
+    async fn peer_repair_in_pieces() -> Result<()> {
+        /*
+         * Extents big enough that they go over in more than one piece.
+         */
+        let blocks = EXTENT_CHUNK_SIZE / 512 * 3 / 2;
+        let (dir_a, uuid_a) = new_region_sized(blocks)?;
+        let (dir_b, _) = new_region_sized(blocks)?;
+        let (dir_c, uuid_c) = new_region_sized(blocks)?;
+
+        let (addr_a, da) = serve_region(&dir_a).await?;
+        let (_, db) = serve_region(&dir_b).await?;
+        let (addr_c, dc) = serve_region(&dir_c).await?;
+
+        {
+            let ds = da.lock().await;
+            ds.region.region_write(1, Block::new_512(2), &[1; 512])?;
+            ds.region
+                .region_write(1, Block::new_512(blocks - 1), &[2; 512])?;
+            ds.region.region_flush(5)?;
+        }
+
+        /*
+         * B fetches from A, and A pushes to C.
+         */
+        repair::fetch_extents(&db, addr_a, Some(uuid_a), &[1]).await?;
+        repair::push_extents(&da, addr_c, Some(uuid_c), &[1]).await?;
+
+        for d in [&db, &dc] {
+            let ds = d.lock().await;
+            assert_eq!(ds.region.flush_numbers()?, vec![0, 5, 0]);
+            assert_eq!(ds.region.dirty()?, vec![false; 3]);
+
+            let mut buffer = BytesMut::with_capacity(512);
+            buffer.resize(512, 0);
+            ds.region.region_read(1, Block::new_512(2), &mut buffer)?;
+            assert_eq!(&buffer[..], &[1; 512][..]);
+            ds.region.region_read(
+                1,
+                Block::new_512(blocks - 1),
+                &mut buffer,
+            )?;
+            assert_eq!(&buffer[..], &[2; 512][..]);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn zero_runs_all_zero() {
+        let buf = vec![0u8; 512 * 4];
+        assert_eq!(zero_runs(&buf, 512), vec![(true, 0..2048)]);
+    }
+
+    #[test]
+    fn zero_runs_no_zero() {
+        let buf = vec![7u8; 512 * 4];
+        assert_eq!(zero_runs(&buf, 512), vec![(false, 0..2048)]);
+    }
+
+    #[test]
+    fn zero_runs_mixed() {
+        /*
+         * One byte set is enough to make a block not zero.
+         */
+        let mut buf = vec![0u8; 512 * 6];
+        buf[512 * 2 + 100] = 1;
+        buf[512 * 3] = 1;
+        buf[512 * 6 - 1] = 1;
+        assert_eq!(
+            zero_runs(&buf, 512),
+            vec![
+                (true, 0..1024),
+                (false, 1024..2048),
+                (true, 2048..2560),
+                (false, 2560..3072),
+            ]
+        );
+        assert!(zero_runs(&[], 512).is_empty());
+    }
+
+    /*
+     * A region a bit over two chunks, with data that runs across the
+     * chunk boundaries and across extents.
+     */
+    fn chunky_region() -> Result<(TempDir, Region, Vec<u8>)> {
+        let dir = tempdir()?;
+        let mut options: RegionOptions = Default::default();
+        options.set_block_size(512);
+        options.set_extent_size(Block::new(8192, 9));
+        options.set_uuid(Uuid::new_v4());
+        let mut region = Region::create(&dir, options)?;
+        let size = CHUNK_SIZE * 2 + 4 * 1024 * 1024;
+        region.extend((size / (8192 * 512)) as u32)?;
+
+        let mut image = vec![0u8; size];
+        for boundary in [CHUNK_SIZE, CHUNK_SIZE * 2] {
+            for (i, b) in image[boundary - 1536..boundary + 1024]
+                .iter_mut()
+                .enumerate()
+            {
+                *b = (i % 251) as u8 + 1;
             }
         }
+        image[size - 1] = 9;
+        import_from(&mut region, &mut &image[..], None)?;
+        Ok((dir, region, image))
+    }
+
+    #[test]
+    fn export_import_stream_round_trip() -> Result<()> {
+        let (_dir, mut region, image) = chunky_region()?;
+        let blocks = (image.len() / 512) as u64;
+
+        let mut out = Vec::new();
+        export_to(&mut region, ExportOut::Stream(&mut out), 0, blocks, None)?;
+        assert_eq!(out.len(), image.len());
+        assert!(out == image);
+
+        /*
+         * And back in again, from the stream, to a region with nothing
+         * in it.
+         */
+        let (_dir2, mut copy) = {
+            let dir = tempdir()?;
+            let mut options: RegionOptions = Default::default();
+            options.set_block_size(512);
+            options.set_extent_size(Block::new(8192, 9));
+            options.set_uuid(Uuid::new_v4());
+            let copy = Region::create(&dir, options)?;
+            (dir, copy)
+        };
+        import_from(&mut copy, &mut &out[..], None)?;
+        assert_eq!(copy.def().extent_count(), region.def().extent_count());
+
+        let mut again = Vec::new();
+        export_to(&mut copy, ExportOut::Stream(&mut again), 0, blocks, None)?;
+        assert!(again == image);
+        Ok(())
+    }
+
+    #[test]
+    fn export_file_keeps_holes_across_chunks() -> Result<()> {
+        let (dir, mut region, image) = chunky_region()?;
+        let blocks = (image.len() / 512) as u64;
+
+        /*
+         * A file export skips over zeros, but has to come out the same.
+         */
+        let path = dir.path().join("export");
+        let file = File::create(&path)?;
+        export_to(&mut region, ExportOut::File(file), 0, blocks, None)?;
+        assert!(std::fs::read(&path)? == image);
+
+        /*
+         * Stopping on a hole still leaves the file the full length.
+         */
+        let file = File::create(&path)?;
+        export_to(&mut region, ExportOut::File(file), 0, 4, None)?;
+        assert_eq!(std::fs::read(&path)?, vec![0u8; 2048]);
+        Ok(())
     }
 }
@@ -0,0 +1,260 @@
+// Copyright 2021 Oxide Computer Company
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use tokio::time::Instant;
+
+/*
+ * Limits on how much IO a region will accept.  Either limit can be left
+ * off.  Flushes are not limited themselves, as they only move data we
+ * have already admitted.  They are not moved ahead of held back IO either,
+ * so a flush sent after a read or write over the limits waits for it.
+ */
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct QosLimits {
+    /*
+     * Reads and writes per second.
+     */
+    pub iops: Option<u64>,
+    /*
+     * Bytes read or written per second.
+     */
+    pub bandwidth: Option<u64>,
+}
+
+/*
+ * A token bucket that refills at `rate` tokens a second, and holds at most
+ * one second worth of tokens.  An IO may take more tokens than are in the
+ * bucket, which leaves it in debt.  Whoever comes next has to wait for the
+ * debt to be paid off, so a large IO is never starved by small ones.
+ */
+#[derive(Debug)]
+struct TokenBucket {
+    rate: u64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: u64, tokens: f64, now: Instant) -> Self {
+        TokenBucket {
+            rate,
+            tokens,
+            last: now,
+        }
+    }
+
+    /*
+     * Move to a new rate, keeping whatever the bucket holds (or owes) as
+     * of now.
+     */
+    fn set_rate(&mut self, rate: u64, now: Instant) {
+        self.refill(now);
+        self.rate = rate;
+        self.tokens = self.tokens.min(rate as f64);
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last);
+        self.last = now;
+        self.tokens = (self.tokens + elapsed.as_secs_f64() * self.rate as f64)
+            .min(self.rate as f64);
+    }
+
+    /*
+     * Take `n` tokens, and return how long the caller has to wait before
+     * its IO can go.
+     */
+    fn reserve(&mut self, n: u64, now: Instant) -> Duration {
+        self.refill(now);
+        let wait = if self.tokens >= 0.0 {
+            Duration::from_secs(0)
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.rate as f64)
+        };
+        self.tokens -= n as f64;
+        wait
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Qos {
+    limits: QosLimits,
+    iops: Option<TokenBucket>,
+    bandwidth: Option<TokenBucket>,
+}
+
+impl Qos {
+    /*
+     * Buckets start out full, so the first second of IO is let in at once.
+     */
+    pub fn new(limits: QosLimits) -> Self {
+        let now = Instant::now();
+        let full = |r: u64| TokenBucket::new(r, r as f64, now);
+        Qos {
+            limits,
+            iops: limits.iops.filter(|r| *r > 0).map(full),
+            bandwidth: limits.bandwidth.filter(|r| *r > 0).map(full),
+        }
+    }
+
+    pub fn limits(&self) -> QosLimits {
+        self.limits
+    }
+
+    /*
+     * Change the limits.  A bucket we already have keeps what it holds, or
+     * what it owes, so changing the limits never hands out a new burst.  A
+     * limit that was not set before starts with an empty bucket.
+     */
+    pub fn set_limits(&mut self, limits: QosLimits) {
+        let now = Instant::now();
+        self.limits = limits;
+        Self::set_rate(&mut self.iops, limits.iops, now);
+        Self::set_rate(&mut self.bandwidth, limits.bandwidth, now);
+    }
+
+    fn set_rate(
+        bucket: &mut Option<TokenBucket>,
+        rate: Option<u64>,
+        now: Instant,
+    ) {
+        match (bucket.as_mut(), rate.filter(|r| *r > 0)) {
+            (_, None) => *bucket = None,
+            (Some(b), Some(r)) => b.set_rate(r, now),
+            (None, Some(r)) => *bucket = Some(TokenBucket::new(r, 0.0, now)),
+        }
+    }
+
+    /*
+     * Account for a read or write of this many bytes, and return how long
+     * to wait before we take it.
+     */
+    pub fn reserve(&mut self, bytes: usize) -> Duration {
+        self.reserve_at(bytes, Instant::now())
+    }
+
+    fn reserve_at(&mut self, bytes: usize, now: Instant) -> Duration {
+        let iops_wait = self
+            .iops
+            .as_mut()
+            .map(|b| b.reserve(1, now))
+            .unwrap_or_default();
+        let bandwidth_wait = self
+            .bandwidth
+            .as_mut()
+            .map(|b| b.reserve(bytes as u64, now))
+            .unwrap_or_default();
+        iops_wait.max(bandwidth_wait)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn qos_unlimited() {
+        let mut qos = Qos::new(QosLimits::default());
+        let now = Instant::now();
+        for _ in 0..10000 {
+            assert_eq!(qos.reserve_at(1 << 20, now), Duration::from_secs(0));
+        }
+    }
+
+    #[test]
+    fn qos_iops_limit() {
+        let mut qos = Qos::new(QosLimits {
+            iops: Some(10),
+            bandwidth: None,
+        });
+        let now = Instant::now();
+
+        /*
+         * A full bucket lets one second of IO through right away, and
+         * after that each IO waits its turn.
+         */
+        for _ in 0..10 {
+            assert_eq!(qos.reserve_at(512, now), Duration::from_secs(0));
+        }
+        assert_eq!(qos.reserve_at(512, now), Duration::from_secs(0));
+        assert_eq!(qos.reserve_at(512, now), Duration::from_millis(100));
+        assert_eq!(qos.reserve_at(512, now), Duration::from_millis(200));
+
+        /*
+         * Once time has passed, the debt is paid off.
+         */
+        let later = now + Duration::from_secs(1);
+        assert_eq!(qos.reserve_at(512, later), Duration::from_secs(0));
+    }
+
+    #[test]
+    fn qos_bandwidth_limit() {
+        let mut qos = Qos::new(QosLimits {
+            iops: None,
+            bandwidth: Some(1000),
+        });
+        let now = Instant::now();
+
+        /*
+         * One IO larger than the bucket goes through, but leaves the next
+         * one waiting for the whole thing to be paid for.
+         */
+        assert_eq!(qos.reserve_at(3000, now), Duration::from_secs(0));
+        assert_eq!(qos.reserve_at(1, now), Duration::from_secs(2));
+    }
+
+    #[test]
+    fn qos_set_limits() {
+        let mut qos = Qos::new(QosLimits {
+            iops: Some(1),
+            bandwidth: None,
+        });
+        let now = Instant::now();
+        qos.reserve_at(512, now);
+        qos.reserve_at(512, now);
+        assert!(qos.reserve_at(512, now) > Duration::from_secs(0));
+
+        qos.set_limits(QosLimits::default());
+        assert_eq!(qos.limits(), QosLimits::default());
+        assert_eq!(qos.reserve(512), Duration::from_secs(0));
+    }
+
+    #[test]
+    fn qos_set_limits_keeps_debt() {
+        let mut qos = Qos::new(QosLimits {
+            iops: Some(10),
+            bandwidth: None,
+        });
+        let now = Instant::now();
+        for _ in 0..12 {
+            qos.reserve_at(512, now);
+        }
+
+        /*
+         * Changing the rate does not forgive the two IOs we are behind,
+         * they are just paid off at the new rate.
+         */
+        qos.set_limits(QosLimits {
+            iops: Some(20),
+            bandwidth: None,
+        });
+        let wait = qos.reserve(512);
+        assert!(wait > Duration::from_millis(50));
+        assert!(wait <= Duration::from_millis(100));
+    }
+
+    #[test]
+    fn qos_new_limit_starts_empty() {
+        let mut qos = Qos::new(QosLimits::default());
+        qos.set_limits(QosLimits {
+            iops: Some(10),
+            bandwidth: None,
+        });
+
+        /*
+         * No burst: the first IO goes, and the one after it waits.
+         */
+        assert_eq!(qos.reserve(512), Duration::from_secs(0));
+        assert!(qos.reserve(512) > Duration::from_millis(50));
+    }
+}
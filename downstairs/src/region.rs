@@ -1,9 +1,10 @@
 // Copyright 2021 Oxide Computer Company
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fs::{File, OpenOptions};
 use std::io::{Read, Seek, SeekFrom, Write};
 use std::os::unix::io::AsRawFd;
 use std::path::{Path, PathBuf};
-use std::sync::{Mutex, MutexGuard};
+use std::sync::{Arc, Mutex, MutexGuard};
 
 use anyhow::{bail, Result};
 use bytes::BytesMut;
@@ -11,6 +12,7 @@ use crucible_common::*;
 use rusqlite::{params, Connection};
 use serde::{Deserialize, Serialize};
 use tracing::instrument;
+use uuid::Uuid;
 
 #[derive(Debug)]
 pub struct Extent {
@@ -27,22 +29,6 @@ pub struct Inner {
 }
 
 impl Inner {
-    pub fn gen_number(&self) -> Result<u64> {
-        let mut stmt = self
-            .metadb
-            .prepare("SELECT value FROM metadata where name='gen_number'")?;
-        let gen_number_iter = stmt.query_map([], |row| row.get(0))?;
-
-        let mut gen_number_values: Vec<u64> = vec![];
-        for gen_number_value in gen_number_iter {
-            gen_number_values.push(gen_number_value?);
-        }
-
-        assert!(gen_number_values.len() == 1);
-
-        Ok(gen_number_values[0])
-    }
-
     fn _set_gen_number(&self, new_gen: u64) -> Result<()> {
         let mut stmt = self
             .metadb
@@ -53,22 +39,6 @@ impl Inner {
         Ok(())
     }
 
-    pub fn flush_number(&self) -> Result<u64> {
-        let mut stmt = self
-            .metadb
-            .prepare("SELECT value FROM metadata where name='flush_number'")?;
-        let flush_number_iter = stmt.query_map([], |row| row.get(0))?;
-
-        let mut flush_number_values: Vec<u64> = vec![];
-        for flush_number_value in flush_number_iter {
-            flush_number_values.push(flush_number_value?);
-        }
-
-        assert!(flush_number_values.len() == 1);
-
-        Ok(flush_number_values[0])
-    }
-
     fn set_flush_number(&self, new_flush: u64) -> Result<()> {
         let mut stmt = self.metadb.prepare(
             "UPDATE metadata SET value=?1 WHERE name='flush_number'",
@@ -109,6 +79,72 @@ impl Inner {
             .execute("UPDATE metadata SET value=1 WHERE name='dirty'", [])?;
         Ok(())
     }
+
+    pub fn meta(&self) -> Result<ExtentMeta> {
+        read_meta(&self.metadb)
+    }
+}
+
+/**
+ * Create the metadata table in a new metadata db, and fill it in.
+ */
+fn create_meta(metadb: &Connection, meta: &ExtentMeta) -> Result<()> {
+    metadb.execute(
+        "CREATE TABLE metadata (
+            name TEXT PRIMARY KEY,
+            value INTEGER NOT NULL
+        )",
+        [],
+    )?;
+
+    metadb.execute(
+        "INSERT INTO metadata
+        (name, value) VALUES (?1, ?2)",
+        params!["ext_version", meta.ext_version],
+    )?;
+    metadb.execute(
+        "INSERT INTO metadata
+        (name, value) VALUES (?1, ?2)",
+        params!["gen_number", meta.gen_number],
+    )?;
+    metadb.execute(
+        "INSERT INTO metadata (name, value) VALUES (?1, ?2)",
+        params!["flush_number", meta.flush_number],
+    )?;
+    metadb.execute(
+        "INSERT INTO metadata (name, value) VALUES (?1, ?2)",
+        params!["dirty", meta.dirty],
+    )?;
+
+    Ok(())
+}
+
+/**
+ * Read all the metadata values for an extent out of its metadata db.
+ */
+fn read_meta(metadb: &Connection) -> Result<ExtentMeta> {
+    let mut meta = ExtentMeta::default();
+    let mut found = 0;
+
+    let mut stmt = metadb.prepare("SELECT name, value FROM metadata")?;
+    let mut rows = stmt.query([])?;
+    while let Some(row) = rows.next()? {
+        let name: String = row.get(0)?;
+        match name.as_str() {
+            "ext_version" => meta.ext_version = row.get(1)?,
+            "gen_number" => meta.gen_number = row.get(1)?,
+            "flush_number" => meta.flush_number = row.get(1)?,
+            "dirty" => meta.dirty = row.get(1)?,
+            _ => continue,
+        }
+        found += 1;
+    }
+
+    if found != 4 {
+        bail!("Expected 4 metadata values, found {}", found);
+    }
+
+    Ok(meta)
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -223,6 +259,21 @@ impl Extent {
         })
     }
 
+    /**
+     * Read the metadata for an extent without opening the extent data
+     * file.  The connection to the metadata db is closed before we return.
+     */
+    fn open_meta<P: AsRef<Path>>(dir: P, number: u32) -> Result<ExtentMeta> {
+        let mut path = extent_path(dir, number);
+        path.set_extension("db");
+        if !path.exists() {
+            bail!("No extent metadata found for {:?}", path);
+        }
+
+        let metadb = Connection::open(&path)?;
+        read_meta(&metadb)
+    }
+
     /**
      * Create an extent at the location requested.
      * Start off with the default meta data.
@@ -267,37 +318,7 @@ impl Extent {
         assert!(metadb.is_autocommit());
         metadb.pragma_update(None, "journal_mode", &"WAL")?;
 
-        /*
-         * Create tables and insert base data
-         */
-        metadb.execute(
-            "CREATE TABLE metadata (
-                name TEXT PRIMARY KEY,
-                value INTEGER NOT NULL
-            )",
-            [],
-        )?;
-
-        let meta = ExtentMeta::default();
-
-        metadb.execute(
-            "INSERT INTO metadata
-            (name, value) VALUES (?1, ?2)",
-            params!["ext_version", meta.ext_version],
-        )?;
-        metadb.execute(
-            "INSERT INTO metadata
-            (name, value) VALUES (?1, ?2)",
-            params!["gen_number", meta.gen_number],
-        )?;
-        metadb.execute(
-            "INSERT INTO metadata (name, value) VALUES (?1, ?2)",
-            params!["flush_number", meta.flush_number],
-        )?;
-        metadb.execute(
-            "INSERT INTO metadata (name, value) VALUES (?1, ?2)",
-            params!["dirty", meta.dirty],
-        )?;
+        create_meta(&metadb, &ExtentMeta::default())?;
 
         /*
          * Complete the construction of our new extent
@@ -397,6 +418,26 @@ impl Extent {
     }
 
     #[instrument]
+    /**
+     * Make sure all data written to this extent is on disk, without
+     * changing the flush number or the dirty bit.
+     */
+    pub fn sync(&self) -> Result<(), CrucibleError> {
+        let inner = self.inner.lock().unwrap();
+
+        if unsafe { fsync(inner.file.as_raw_fd()) } == -1 {
+            let e = std::io::Error::last_os_error();
+            crucible_bail!(
+                IoError,
+                "extent {}: fsync failure: {:?}",
+                self.number,
+                e
+            );
+        }
+
+        Ok(())
+    }
+
     pub fn flush_block(&self, new_flush: u64) -> Result<(), CrucibleError> {
         let mut inner = self.inner.lock().unwrap();
 
@@ -435,6 +476,225 @@ impl Extent {
 
 extern "C" {
     fn fsync(fildes: i32) -> i32;
+    fn flock(fd: i32, operation: i32) -> i32;
+}
+
+/**
+ * Copy a file to a new path.  Where the filesystem can, the copy is a
+ * reflink that shares its blocks with the original.  Returns true if it
+ * is.
+ */
+fn clone_file(from: &Path, to: &Path) -> Result<bool> {
+    let mut src = File::open(from)?;
+    let mut dst = OpenOptions::new().write(true).create_new(true).open(to)?;
+
+    let reflinked = reflink(&src, &dst);
+    if !reflinked {
+        std::io::copy(&mut src, &mut dst)?;
+    }
+    dst.sync_all()?;
+
+    Ok(reflinked)
+}
+
+#[cfg(target_os = "linux")]
+fn reflink(src: &File, dst: &File) -> bool {
+    extern "C" {
+        fn ioctl(fd: i32, request: u64, ...) -> i32;
+    }
+    const FICLONE: u64 = 0x4004_9409;
+
+    unsafe { ioctl(dst.as_raw_fd(), FICLONE, src.as_raw_fd()) == 0 }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn reflink(_src: &File, _dst: &File) -> bool {
+    false
+}
+
+const LOCK_SH: i32 = 1;
+const LOCK_EX: i32 = 2;
+const LOCK_NB: i32 = 4;
+
+/**
+ * Produce a PathBuf that refers to the lock file for a region.
+ */
+fn lock_path<P: AsRef<Path>>(dir: P) -> PathBuf {
+    let mut out = dir.as_ref().to_path_buf();
+    out.push("region.lock");
+    out
+}
+
+/**
+ * Take the advisory lock for the region in the given directory.  Anyone
+ * who will change the region must hold the lock exclusively, while tools
+ * that only look at the region can share it with each other.
+ *
+ * The lock is held for as long as the returned File is open.  An
+ * exclusive holder writes its pid into the lock file so whoever it is
+ * keeping out can tell who has the region.  Shared holders leave the file
+ * alone, as there may be more than one of them.
+ */
+fn lock_region<P: AsRef<Path>>(dir: P, shared: bool) -> Result<File> {
+    let lp = lock_path(dir);
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&lp)?;
+
+    let operation = if shared { LOCK_SH } else { LOCK_EX };
+    if unsafe { flock(file.as_raw_fd(), operation | LOCK_NB) } == -1 {
+        let e = std::io::Error::last_os_error();
+        if e.kind() != std::io::ErrorKind::WouldBlock {
+            bail!("Failed to lock {:?}: {:?}", lp, e);
+        }
+
+        /*
+         * If we could share the lock, what is in our way is one or more
+         * read only holders, and any pid in the file is stale.
+         */
+        if !shared && unsafe { flock(file.as_raw_fd(), LOCK_SH | LOCK_NB) } == 0
+        {
+            bail!("Region {:?} is open read only by another process", lp);
+        }
+
+        let mut owner = String::new();
+        file.read_to_string(&mut owner)?;
+        match owner.trim().parse::<u32>() {
+            Ok(pid) => bail!("Region {:?} is in use by pid {}", lp, pid),
+            Err(_) => bail!("Region {:?} is in use by another process", lp),
+        }
+    }
+
+    if !shared {
+        file.set_len(0)?;
+        file.write_all(format!("{}\n", std::process::id()).as_bytes())?;
+        file.sync_all()?;
+    }
+
+    Ok(file)
+}
+
+/**
+ * The default limit on how many extents a region will keep open at
+ * one time.  Each open extent holds a file descriptor for the extent data
+ * as well as the connection to its metadata db.
+ */
+pub const DEFAULT_MAX_OPEN_EXTENTS: usize = 1024;
+
+/**
+ * A bounded cache of open extents, indexed by extent number.
+ *
+ * Extents are opened on demand, and when we have more open than the
+ * limit allows, the least recently used extents are closed.  An extent
+ * that is still in use by someone else (someone is holding a reference
+ * to it) will not be closed, so it's possible to briefly go over the
+ * limit when there is a lot of outstanding IO.
+ */
+#[derive(Debug)]
+struct ExtentCache {
+    max_open: usize,
+    /*
+     * Increases on every access, used to order extents by last use.
+     */
+    tick: u64,
+    open: HashMap<u32, (Arc<Extent>, u64)>,
+    lru: BTreeMap<u64, u32>,
+}
+
+impl ExtentCache {
+    fn new(max_open: usize) -> ExtentCache {
+        assert!(max_open > 0);
+        ExtentCache {
+            max_open,
+            tick: 0,
+            open: HashMap::new(),
+            lru: BTreeMap::new(),
+        }
+    }
+
+    #[cfg(test)]
+    fn len(&self) -> usize {
+        self.open.len()
+    }
+
+    /**
+     * Return the extent if it is open, without changing its place in the
+     * LRU order.
+     */
+    fn peek(&self, eid: u32) -> Option<Arc<Extent>> {
+        self.open.get(&eid).map(|(e, _)| e.clone())
+    }
+
+    /**
+     * Return the extent if it is open and mark it as most recently used.
+     */
+    fn get(&mut self, eid: u32) -> Option<Arc<Extent>> {
+        self.tick += 1;
+        let tick = self.tick;
+
+        let (extent, last_use) = self.open.get_mut(&eid)?;
+        self.lru.remove(last_use);
+        *last_use = tick;
+        self.lru.insert(tick, eid);
+
+        Some(extent.clone())
+    }
+
+    fn insert(&mut self, eid: u32, extent: Arc<Extent>) {
+        self.tick += 1;
+        if let Some((_, old)) = self.open.insert(eid, (extent, self.tick)) {
+            self.lru.remove(&old);
+        }
+        self.lru.insert(self.tick, eid);
+
+        self.evict();
+    }
+
+    /**
+     * Close least recently used extents until we are back under the
+     * limit, skipping any that are still in use.
+     */
+    fn evict(&mut self) {
+        if self.open.len() <= self.max_open {
+            return;
+        }
+
+        let mut over = self.open.len() - self.max_open;
+        let mut closing = Vec::with_capacity(over);
+        for (tick, eid) in self.lru.iter() {
+            if over == 0 {
+                break;
+            }
+            let (extent, _) = self.open.get(eid).unwrap();
+            if Arc::strong_count(extent) == 1 {
+                closing.push((*tick, *eid));
+                over -= 1;
+            }
+        }
+
+        for (tick, eid) in closing {
+            self.lru.remove(&tick);
+            self.open.remove(&eid);
+        }
+    }
+
+    /**
+     * Stop tracking an extent, and return it if it was open.
+     */
+    fn remove(&mut self, eid: u32) -> Option<Arc<Extent>> {
+        let (extent, last_use) = self.open.remove(&eid)?;
+        self.lru.remove(&last_use);
+        Some(extent)
+    }
+
+    fn set_max_open(&mut self, max_open: usize) {
+        assert!(max_open > 0);
+        self.max_open = max_open;
+        self.evict();
+    }
 }
 
 /**
@@ -444,7 +704,27 @@ extern "C" {
 pub struct Region {
     dir: PathBuf,
     def: RegionDefinition,
-    pub extents: Vec<Extent>,
+    /*
+     * Extents are only opened when IO needs them, and only a limited
+     * number are kept open at once.
+     */
+    extents: Mutex<ExtentCache>,
+    /*
+     * The extents that may have been written to since the last flush.
+     * This is None until we know which extents on disk are dirty, which
+     * we only figure out (by reading the metadata for every extent)
+     * the first time we need to flush.
+     */
+    dirty_extents: Mutex<Option<HashSet<u32>>>,
+    /*
+     * A region opened read only holds a shared lock, and will refuse any
+     * request that would change it.
+     */
+    read_only: bool,
+    /*
+     * The open region lock file.  The lock is released when this closes.
+     */
+    _lock: File,
 }
 
 impl Region {
@@ -462,40 +742,58 @@ impl Region {
          * If the file exists, then exit now with error.  If the caller
          * wants a new region, they have to delete the old one first.
          */
+        mkdir_for_file(&cp)?;
+        let lock = lock_region(dir.as_ref(), false)?;
         if Path::new(&cp).exists() {
             bail!("Config file already exists {:?}", cp);
         }
-        mkdir_for_file(&cp)?;
 
         let def = RegionDefinition::from_options(&options).unwrap();
         write_json(&cp, &def, false)?;
         println!("Created new region file {:?}", cp);
 
         /*
-         * Open every extent that presently exists.
+         * Create every extent that the region definition describes.
+         * A new region has no dirty extents.
          */
-        let mut region = Region {
+        let region = Region {
             dir: dir.as_ref().to_path_buf(),
             def,
-            extents: Vec::new(),
+            extents: Mutex::new(ExtentCache::new(DEFAULT_MAX_OPEN_EXTENTS)),
+            dirty_extents: Mutex::new(Some(HashSet::new())),
+            read_only: false,
+            _lock: lock,
         };
 
-        region.open_extents(true)?;
+        region.create_extents(0)?;
 
         Ok(region)
     }
 
     /**
-     * Open an existing region file
+     * Does this directory hold a region?
+     */
+    pub fn exists<P: AsRef<Path>>(dir: P) -> bool {
+        config_path(dir).exists()
+    }
+
+    /**
+     * Open an existing region file.  If read_only is set, we only take a
+     * shared lock on the region, and writes and flushes will be refused.
      */
     pub fn open<P: AsRef<Path>>(
         dir: P,
         options: RegionOptions,
         verbose: bool,
+        read_only: bool,
     ) -> Result<Region> {
         options.validate()?;
 
         let cp = config_path(dir.as_ref());
+        if !Path::new(&cp).exists() {
+            bail!("No region config found at {:?}", cp);
+        }
+        let lock = lock_region(dir.as_ref(), read_only)?;
         /*
          * We are expecting to find a region config file and extent files.
          * If we do not, then report error and exit.
@@ -508,155 +806,581 @@ impl Region {
         if verbose {
             println!("Opened existing region file {:?}", cp);
         }
+
         /*
-         * Open every extent that presently exists.
+         * Extents are not opened here, they will be opened as they are
+         * needed.  We don't know which extents were left dirty, so that
+         * will be worked out on the first flush.
          */
-        let mut region = Region {
+        let region = Region {
             dir: dir.as_ref().to_path_buf(),
             def,
-            extents: Vec::new(),
+            extents: Mutex::new(ExtentCache::new(DEFAULT_MAX_OPEN_EXTENTS)),
+            dirty_extents: Mutex::new(None),
+            read_only,
+            _lock: lock,
         };
 
-        region.open_extents(false)?;
-
         Ok(region)
     }
 
     /**
-     * If our extent_count is higher than the number of populated entries
-     * we have in our extents Vec, then open all the new extent files and
-     * load their content into the extent Vec.
-     *
-     * If create is false, we expect the extent files to exist at the
-     * expected location and will return error if they are not found.
-     *
-     * If create is true, we expect to create new extent files, and will
-     * return error if the file is already present.
-     */
-    fn open_extents(&mut self, create: bool) -> Result<()> {
-        let next_eid = self.extents.len() as u32;
-        for eid in next_eid..self.def.extent_count() {
-            let new_extent: Extent;
-            if create {
-                new_extent = Extent::create(&self.dir, &self.def, eid)?;
-            } else {
-                new_extent = Extent::open(&self.dir, &self.def, eid)?;
-            }
-            self.extents.push(new_extent);
-            assert_eq!(self.extents[eid as usize].number, eid);
+     * Change the limit on how many extents we keep open at once.
+     */
+    pub fn set_max_open_extents(&self, max_open: usize) {
+        self.extents.lock().unwrap().set_max_open(max_open);
+    }
+
+    /**
+     * Create the extent files for every extent from first_eid up to our
+     * extent_count.  We return error if any of the files are already
+     * present.  The new extents are not left open.
+     */
+    fn create_extents(&self, first_eid: u32) -> Result<()> {
+        for eid in first_eid..self.def.extent_count() {
+            let new_extent = Extent::create(&self.dir, &self.def, eid)?;
+            assert_eq!(new_extent.number(), eid);
         }
-        assert_eq!(self.def.extent_count() as usize, self.extents.len());
         Ok(())
     }
 
     /**
-     * if there is a difference between what our actual extent_count is
-     * and what is requested, go out and create the new extent files.
+     * Return the requested extent, opening it if it is not already open.
      */
-    pub fn extend(&mut self, newsize: u32) -> Result<()> {
-        if newsize < self.def.extent_count() {
+    pub fn extent(&self, eid: u32) -> Result<Arc<Extent>> {
+        if eid >= self.def.extent_count() {
             bail!(
-                "will not truncate {} -> {} for now",
-                self.def.extent_count(),
-                newsize
+                "Extent {} is past the last extent {}",
+                eid,
+                self.def.extent_count()
             );
         }
 
-        if newsize > self.def.extent_count() {
-            self.def.set_extent_count(newsize);
-            write_json(config_path(&self.dir), &self.def, true)?;
-            self.open_extents(true)?;
+        let mut extents = self.extents.lock().unwrap();
+        if let Some(extent) = extents.get(eid) {
+            return Ok(extent);
         }
-        Ok(())
-    }
 
-    pub fn region_def(&self) -> (u64, Block, u32) {
-        (
-            self.def.block_size(),
-            self.def.extent_size(),
-            self.def.extent_count(),
-        )
-    }
-    pub fn def(&self) -> RegionDefinition {
-        self.def
+        let extent = Arc::new(Extent::open(&self.dir, &self.def, eid)?);
+        extents.insert(eid, extent.clone());
+        Ok(extent)
     }
 
-    pub fn flush_numbers(&self) -> Result<Vec<u64>> {
-        let mut ver = self
-            .extents
-            .iter()
-            .map(|e| e.inner().flush_number())
-            .collect::<Result<Vec<_>>>()?;
-
-        if ver.len() > 12 {
-            ver = ver[0..12].to_vec();
+    /**
+     * Return the metadata for an extent.  If the extent is not open,
+     * we read the metadata directly and leave the extent closed.
+     */
+    pub fn extent_meta(&self, eid: u32) -> Result<ExtentMeta> {
+        if eid >= self.def.extent_count() {
+            bail!(
+                "Extent {} is past the last extent {}",
+                eid,
+                self.def.extent_count()
+            );
         }
-        println!("Current flush_numbers [0..12]: {:?}", ver);
 
-        self.extents
-            .iter()
-            .map(|e| e.inner().flush_number())
-            .collect::<Result<Vec<_>>>()
+        let open = self.extents.lock().unwrap().peek(eid);
+        match open {
+            Some(extent) => extent.inner().meta(),
+            None => Extent::open_meta(&self.dir, eid),
+        }
     }
 
-    pub fn gen_numbers(&self) -> Result<Vec<u64>> {
-        self.extents
-            .iter()
-            .map(|e| e.inner().gen_number())
+    /**
+     * Return the metadata for every extent in the region, in extent order.
+     */
+    pub fn extent_metas(&self) -> Result<Vec<ExtentMeta>> {
+        (0..self.def.extent_count())
+            .map(|eid| self.extent_meta(eid))
             .collect::<Result<Vec<_>>>()
     }
-    pub fn dirty(&self) -> Result<Vec<bool>> {
-        self.extents
-            .iter()
-            .map(|e| e.inner().dirty())
-            .collect::<Result<Vec<_>>>()
+
+    /**
+     * Read all the data and the metadata of an extent, so it can be copied
+     * to another region.
+     */
+    pub fn extent_copy(&self, eid: u32) -> Result<(ExtentMeta, BytesMut)> {
+        self.extent_read(eid, 0, self.extent_bytes())
     }
 
-    #[instrument]
-    pub fn region_write(
+    /**
+     * Read part of an extent, starting at byte `offset`, along with the
+     * extent metadata.  We return less than `len` bytes when the extent
+     * ends first, and none at all when `offset` is the end of the extent.
+     */
+    pub fn extent_read(
         &self,
-        eid: u64,
-        offset: Block,
-        data: &[u8],
-    ) -> Result<(), CrucibleError> {
-        let extent = &self.extents[eid as usize];
-        extent.write(offset, data)?;
-        Ok(())
+        eid: u32,
+        offset: u64,
+        len: u64,
+    ) -> Result<(ExtentMeta, BytesMut)> {
+        let extent = self.extent(eid)?;
+        let size = self.extent_bytes();
+        if offset > size {
+            bail!("Offset {} is past the end of extent {}", offset, eid);
+        }
+        let len = len.min(size - offset) as usize;
+        let mut data = BytesMut::with_capacity(len);
+        data.resize(len, 0);
+
+        /*
+         * Hold the extent lock while we read both, so the metadata matches
+         * the data.
+         */
+        let mut inner = extent.inner();
+        let meta = inner.meta()?;
+        inner.file.seek(SeekFrom::Start(offset))?;
+        inner.file.read_exact(&mut data)?;
+
+        Ok((meta, data))
     }
 
-    #[instrument]
-    pub fn region_read(
-        &self,
-        eid: u64,
-        offset: Block,
-        data: &mut BytesMut,
-    ) -> Result<(), CrucibleError> {
-        let extent = &self.extents[eid as usize];
-        extent.read(offset, data)?;
+    fn extent_bytes(&self) -> u64 {
+        self.def.block_size() * self.def.extent_size().value
+    }
+
+    fn check_replace(&self, eid: u32) -> Result<()> {
+        if self.read_only {
+            bail!(CrucibleError::ModifyingReadOnlyRegion);
+        }
+        if eid >= self.def.extent_count() {
+            bail!(
+                "Extent {} is past the last extent {}",
+                eid,
+                self.def.extent_count()
+            );
+        }
         Ok(())
     }
 
-    /*
-     * Send a flush to all extents. The provided flush number is
-     * what an extent should use if a flush is required.
+    /**
+     * Replace an extent with a copy from another region.  See
+     * stage_extent() and commit_extent(), this is both at once.
      */
-    #[instrument]
-    pub fn region_flush(&self, flush_number: u64) -> Result<(), CrucibleError> {
-        // XXX How to we convert between usize and u32 correctly?
-        for eid in 0..self.def.extent_count() {
-            let extent = &self.extents[eid as usize];
-            extent.flush_block(flush_number)?;
+    pub fn replace_extent(
+        &self,
+        eid: u32,
+        meta: &ExtentMeta,
+        data: &[u8],
+    ) -> Result<()> {
+        self.check_replace(eid)?;
+        let size = self.extent_bytes();
+        if data.len() as u64 != size {
+            bail!(
+                "Extent {} replacement is {} bytes, expected {}",
+                eid,
+                data.len(),
+                size
+            );
         }
-        Ok(())
+        self.stage_extent(eid, 0, data)?;
+        self.commit_extent(eid, meta)
     }
-}
 
-#[cfg(test)]
+    /**
+     * Write part of a replacement for an extent, starting at byte
+     * `offset`.  The replacement is kept in a temporary file next to the
+     * extent, and the pieces must come in order.  Starting again at
+     * offset zero throws out whatever was there.
+     */
+    pub fn stage_extent(
+        &self,
+        eid: u32,
+        offset: u64,
+        data: &[u8],
+    ) -> Result<()> {
+        self.check_replace(eid)?;
+        let size = self.extent_bytes();
+        if offset + data.len() as u64 > size {
+            bail!(
+                "Extent {} replacement of {} bytes at {} is past its end",
+                eid,
+                data.len(),
+                offset
+            );
+        }
+
+        let new_data = extent_path(&self.dir, eid).with_extension("replace");
+        let mut file = if offset == 0 {
+            if new_data.exists() {
+                std::fs::remove_file(&new_data)?;
+            }
+            OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(&new_data)?
+        } else {
+            let file = match OpenOptions::new().append(true).open(&new_data) {
+                Ok(file) => file,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                    bail!("Extent {} replacement was not started", eid);
+                }
+                Err(e) => return Err(e.into()),
+            };
+            let have = file.metadata()?.len();
+            if have != offset {
+                bail!(
+                    "Extent {} replacement has {} bytes, not {}",
+                    eid,
+                    have,
+                    offset
+                );
+            }
+            file
+        };
+        file.write_all(data)?;
+        Ok(())
+    }
+
+    /**
+     * Put a replacement written with stage_extent() in place, with this
+     * metadata.  The metadata is written to a temporary db next to the
+     * extent, then both are renamed over it.
+     *
+     * The data file is renamed first.  If we crash before the metadata
+     * is renamed, the extent has the new data with its old metadata,
+     * which will only make it look out of date and in need of repair.
+     */
+    pub fn commit_extent(&self, eid: u32, meta: &ExtentMeta) -> Result<()> {
+        self.check_replace(eid)?;
+        let size = self.extent_bytes();
+
+        let path = extent_path(&self.dir, eid);
+        let new_data = path.with_extension("replace");
+        let new_db = path.with_extension("replace.db");
+
+        let file = match OpenOptions::new().write(true).open(&new_data) {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                bail!("Extent {} has no replacement to put in place", eid);
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let have = file.metadata()?.len();
+        if have != size {
+            bail!(
+                "Extent {} replacement is {} bytes, expected {}",
+                eid,
+                have,
+                size
+            );
+        }
+        file.sync_all()?;
+        drop(file);
+
+        if new_db.exists() {
+            std::fs::remove_file(&new_db)?;
+        }
+
+        let metadb = Connection::open(&new_db)?;
+        create_meta(&metadb, meta)?;
+        drop(metadb);
+
+        /*
+         * Hold the extent cache lock until we are done, so nobody can
+         * open the extent while we are swapping out its files.
+         */
+        let mut extents = self.extents.lock().unwrap();
+        if let Some(extent) = extents.remove(eid) {
+            if Arc::strong_count(&extent) > 1 {
+                extents.insert(eid, extent);
+                bail!("Extent {} is in use", eid);
+            }
+        }
+
+        /*
+         * Closing the extent should have cleaned up its write ahead log,
+         * but if not, we must not let it be applied to the new db.
+         */
+        for ext in ["db-wal", "db-shm"] {
+            let p = path.with_extension(ext);
+            if p.exists() {
+                std::fs::remove_file(p)?;
+            }
+        }
+
+        std::fs::rename(&new_data, &path)?;
+        std::fs::rename(&new_db, path.with_extension("db"))?;
+        File::open(path.parent().unwrap())?.sync_all()?;
+        drop(extents);
+
+        let mut dirty_extents = self.dirty_extents.lock().unwrap();
+        if let Some(dirty) = dirty_extents.as_mut() {
+            if meta.dirty {
+                dirty.insert(eid);
+            } else {
+                dirty.remove(&eid);
+            }
+        }
+
+        Ok(())
+    }
+
+    /**
+     * Throw out a replacement written with stage_extent() that will not
+     * be put in place after all.  The extent itself is left alone.
+     */
+    pub fn discard_extent(&self, eid: u32) -> Result<()> {
+        let new_data = extent_path(&self.dir, eid).with_extension("replace");
+        match std::fs::remove_file(&new_data) {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /**
+     * Make a copy of this region in a new directory, under a new UUID.
+     *
+     * The extent data files are cloned with reflinks where the filesystem
+     * supports it, so the copy shares blocks with us until either side is
+     * written, and are copied otherwise.  With reset_meta, every extent in
+     * the copy starts over at generation and flush number zero, and is
+     * clean.  Otherwise the copy keeps our metadata.
+     *
+     * The region config is written last, so a copy that fails part way
+     * through is not mistaken for a region.
+     */
+    pub fn clone_to<P: AsRef<Path>>(
+        &self,
+        dir: P,
+        uuid: Uuid,
+        reset_meta: bool,
+    ) -> Result<Region> {
+        let cp = config_path(dir.as_ref());
+        mkdir_for_file(&cp)?;
+        let lock = lock_region(dir.as_ref(), false)?;
+        if Path::new(&cp).exists() {
+            bail!("Config file already exists {:?}", cp);
+        }
+
+        let mut reflinks = 0;
+        for eid in 0..self.def.extent_count() {
+            let meta = if reset_meta {
+                ExtentMeta::default()
+            } else {
+                self.extent_meta(eid)?
+            };
+
+            let from = extent_path(&self.dir, eid);
+            let to = extent_path(dir.as_ref(), eid);
+            mkdir_for_file(&to)?;
+            if clone_file(&from, &to)? {
+                reflinks += 1;
+            }
+
+            let metadb = Connection::open(to.with_extension("db"))?;
+            create_meta(&metadb, &meta)?;
+        }
+
+        let mut def = self.def;
+        def.set_uuid(uuid);
+        write_json(&cp, &def, false)?;
+        println!(
+            "Cloned {} extents to {:?}, {} with reflinks",
+            def.extent_count(),
+            cp,
+            reflinks
+        );
+
+        Ok(Region {
+            dir: dir.as_ref().to_path_buf(),
+            def,
+            extents: Mutex::new(ExtentCache::new(DEFAULT_MAX_OPEN_EXTENTS)),
+            dirty_extents: Mutex::new(None),
+            read_only: false,
+            _lock: lock,
+        })
+    }
+
+    /**
+     * if there is a difference between what our actual extent_count is
+     * and what is requested, go out and create the new extent files.
+     */
+    pub fn extend(&mut self, newsize: u32) -> Result<()> {
+        if newsize < self.def.extent_count() {
+            bail!(
+                "will not truncate {} -> {} for now",
+                self.def.extent_count(),
+                newsize
+            );
+        }
+
+        if newsize > self.def.extent_count() {
+            if self.read_only {
+                bail!(CrucibleError::ModifyingReadOnlyRegion);
+            }
+            let first_eid = self.def.extent_count();
+            self.def.set_extent_count(newsize);
+            write_json(config_path(&self.dir), &self.def, true)?;
+            self.create_extents(first_eid)?;
+        }
+        Ok(())
+    }
+
+    pub fn region_def(&self) -> (u64, Block, u32) {
+        (
+            self.def.block_size(),
+            self.def.extent_size(),
+            self.def.extent_count(),
+        )
+    }
+    pub fn def(&self) -> RegionDefinition {
+        self.def
+    }
+
+    pub fn read_only(&self) -> bool {
+        self.read_only
+    }
+
+    pub fn flush_numbers(&self) -> Result<Vec<u64>> {
+        let ver = self
+            .extent_metas()?
+            .iter()
+            .map(|m| m.flush_number)
+            .collect::<Vec<_>>();
+
+        if ver.len() > 12 {
+            println!("Current flush_numbers [0..12]: {:?}", &ver[0..12]);
+        } else {
+            println!("Current flush_numbers: {:?}", ver);
+        }
+
+        Ok(ver)
+    }
+
+    pub fn gen_numbers(&self) -> Result<Vec<u64>> {
+        Ok(self.extent_metas()?.iter().map(|m| m.gen_number).collect())
+    }
+    pub fn dirty(&self) -> Result<Vec<bool>> {
+        Ok(self.extent_metas()?.iter().map(|m| m.dirty).collect())
+    }
+
+    /**
+     * Record that an extent is about to be written to, so the next flush
+     * knows it has to visit it.
+     */
+    fn mark_dirty(&self, eid: u32) {
+        if let Some(dirty) = self.dirty_extents.lock().unwrap().as_mut() {
+            dirty.insert(eid);
+        }
+    }
+
+    /**
+     * Return a sorted list of the extents that need to be flushed.  The
+     * first time this is called on a region we opened, we have to look
+     * at the metadata for every extent to find any left dirty from before.
+     */
+    fn dirty_extents(&self) -> Result<Vec<u32>> {
+        let mut dirty_extents = self.dirty_extents.lock().unwrap();
+        if dirty_extents.is_none() {
+            let mut dirty = HashSet::new();
+            for eid in 0..self.def.extent_count() {
+                if self.extent_meta(eid)?.dirty {
+                    dirty.insert(eid);
+                }
+            }
+            *dirty_extents = Some(dirty);
+        }
+
+        let mut result = dirty_extents
+            .as_ref()
+            .unwrap()
+            .iter()
+            .cloned()
+            .collect::<Vec<u32>>();
+        result.sort_unstable();
+        Ok(result)
+    }
+
+    /**
+     * Get the data for every dirty extent onto disk.
+     *
+     * Only the upstairs decides on flush numbers, so we don't flush the
+     * extents here.  They stay dirty, and are flushed when an upstairs
+     * next sends us a flush.
+     */
+    pub fn sync_dirty_extents(&self) -> Result<(), CrucibleError> {
+        if self.read_only {
+            return Ok(());
+        }
+
+        for eid in self.dirty_extents()? {
+            self.extent(eid)?.sync()?;
+        }
+        Ok(())
+    }
+
+    #[instrument]
+    pub fn region_write(
+        &self,
+        eid: u64,
+        offset: Block,
+        data: &[u8],
+    ) -> Result<(), CrucibleError> {
+        if self.read_only {
+            crucible_bail!(ModifyingReadOnlyRegion);
+        }
+        let extent = self.extent(eid as u32)?;
+        self.mark_dirty(eid as u32);
+        extent.write(offset, data)?;
+        Ok(())
+    }
+
+    #[instrument]
+    pub fn region_read(
+        &self,
+        eid: u64,
+        offset: Block,
+        data: &mut BytesMut,
+    ) -> Result<(), CrucibleError> {
+        let extent = self.extent(eid as u32)?;
+        extent.read(offset, data)?;
+        Ok(())
+    }
+
+    /*
+     * Send a flush to all dirty extents. The provided flush number is
+     * what an extent should use if a flush is required.
+     */
+    #[instrument]
+    pub fn region_flush(&self, flush_number: u64) -> Result<(), CrucibleError> {
+        if self.read_only {
+            crucible_bail!(ModifyingReadOnlyRegion);
+        }
+        for eid in self.dirty_extents()? {
+            /*
+             * Take the extent out of the dirty set before we flush it, so
+             * a write that comes in while we are flushing will put it back.
+             * If the flush fails, we put it back ourselves so the next
+             * flush will try it again.
+             */
+            if let Some(dirty) = self.dirty_extents.lock().unwrap().as_mut() {
+                dirty.remove(&eid);
+            }
+
+            let res = self
+                .extent(eid)
+                .map_err(CrucibleError::from)
+                .and_then(|extent| extent.flush_block(flush_number));
+            if res.is_err() {
+                self.mark_dirty(eid);
+            }
+            res?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
 mod test {
     use super::extent_path;
     use super::*;
     use crate::dump::dump_region;
+    use crate::repair::repair_regions;
+    use crate::verify::{verify_regions, BlockRange};
     use bytes::BufMut;
+    use crucible_common::ExtentFix;
     use std::path::PathBuf;
     use tempfile::tempdir;
     use uuid::Uuid;
@@ -713,7 +1437,7 @@ mod test {
     fn new_existing_region() -> Result<()> {
         let dir = tempdir()?;
         let _ = Region::create(&dir, new_region_options());
-        let _ = Region::open(&dir, new_region_options(), false);
+        let _ = Region::open(&dir, new_region_options(), false, false);
         Ok(())
     }
 
@@ -724,6 +1448,7 @@ mod test {
             &"/tmp/12345678-1111-2222-3333-123456789999/notadir",
             new_region_options(),
             false,
+            false,
         )
         .unwrap();
         ()
@@ -854,6 +1579,7 @@ mod test {
         let dir = tempdir()?;
         let mut r1 = Region::create(&dir, new_region_options()).unwrap();
         r1.extend(2)?;
+        drop(r1);
 
         /*
          * Build the Vec for our region dir
@@ -884,6 +1610,8 @@ mod test {
         let mut r2 = Region::create(&dir2, new_region_options()).unwrap();
         r1.extend(2)?;
         r2.extend(2)?;
+        drop(r1);
+        drop(r2);
 
         /*
          * Build the Vec for our region dirs
@@ -917,6 +1645,8 @@ mod test {
         r1.extend(3)?;
         let mut r2 = Region::create(&dir2, new_region_options()).unwrap();
         r2.extend(3)?;
+        drop(r1);
+        drop(r2);
 
         /*
          * Build the Vec for our region dirs
@@ -934,4 +1664,511 @@ mod test {
 
         Ok(())
     }
+
+    #[test]
+    fn verify_matching_regions() -> Result<()> {
+        let dir = tempdir()?;
+        let dir2 = tempdir()?;
+        let mut r1 = Region::create(&dir, new_region_options())?;
+        let mut r2 = Region::create(&dir2, new_region_options())?;
+        r1.extend(2)?;
+        r2.extend(2)?;
+        drop(r1);
+        drop(r2);
+
+        let dvec = vec![dir.path().to_path_buf(), dir2.path().to_path_buf()];
+        let report = verify_regions(&dvec)?;
+        assert!(report.matches());
+        assert_eq!(report.extent_count, 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn verify_mismatched_regions() -> Result<()> {
+        let dir = tempdir()?;
+        let dir2 = tempdir()?;
+        let dir3 = tempdir()?;
+        let mut r1 = Region::create(&dir, new_region_options())?;
+        let mut r2 = Region::create(&dir2, new_region_options())?;
+        let mut r3 = Region::create(&dir3, new_region_options())?;
+        r1.extend(3)?;
+        r2.extend(3)?;
+        r3.extend(3)?;
+
+        /*
+         * Blocks 2, 3 and 7 of extent 1 are different on the second
+         * region.  The third region has the same data as the first, but
+         * a different flush number.
+         */
+        let mut data = BytesMut::with_capacity(512 * 2);
+        data.put(&[9; 512 * 2][..]);
+        r2.region_write(1, Block::new_512(2), &data)?;
+        r2.region_write(1, Block::new_512(7), &data[..512])?;
+        r3.region_write(1, Block::new_512(0), &[0; 512])?;
+        r2.region_flush(0)?;
+        r3.region_flush(4)?;
+        drop(r1);
+        drop(r2);
+        drop(r3);
+
+        let dvec = vec![
+            dir.path().to_path_buf(),
+            dir2.path().to_path_buf(),
+            dir3.path().to_path_buf(),
+        ];
+        let report = verify_regions(&dvec)?;
+        assert!(!report.matches());
+        assert_eq!(report.mismatched.len(), 1);
+
+        let m = &report.mismatched[0];
+        assert_eq!(m.extent, 1);
+        assert!(m.meta_differs);
+        assert_eq!(
+            m.blocks,
+            vec![
+                BlockRange { start: 2, count: 2 },
+                BlockRange { start: 7, count: 1 },
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn repair_three_regions() -> Result<()> {
+        let dir = tempdir()?;
+        let dir2 = tempdir()?;
+        let dir3 = tempdir()?;
+        let mut r1 = Region::create(&dir, new_region_options())?;
+        let mut r2 = Region::create(&dir2, new_region_options())?;
+        let mut r3 = Region::create(&dir3, new_region_options())?;
+        r1.extend(3)?;
+        r2.extend(3)?;
+        r3.extend(3)?;
+
+        /*
+         * The second region has the newest copy of extent 1, and the
+         * third region has the newest copy of extent 2.
+         */
+        r2.region_write(1, Block::new_512(4), &[7; 512])?;
+        r2.region_flush(5)?;
+        r3.region_write(2, Block::new_512(0), &[8; 512])?;
+        r3.region_flush(3)?;
+        drop(r1);
+        drop(r2);
+        drop(r3);
+
+        let dvec = vec![
+            dir.path().to_path_buf(),
+            dir2.path().to_path_buf(),
+            dir3.path().to_path_buf(),
+        ];
+
+        /*
+         * A dry run reports the repairs, but leaves the regions alone.
+         */
+        let report = repair_regions(&dvec, true)?;
+        assert_eq!(report.extents.len(), 2);
+        assert_eq!(report.extents[0].extent, 1);
+        assert_eq!(
+            report.extents[0].fix,
+            ExtentFix {
+                source: 1,
+                repair: vec![0, 2]
+            }
+        );
+        assert_eq!(report.extents[1].extent, 2);
+        assert_eq!(
+            report.extents[1].fix,
+            ExtentFix {
+                source: 2,
+                repair: vec![0, 1]
+            }
+        );
+        assert!(!verify_regions(&dvec)?.matches());
+
+        let report = repair_regions(&dvec, false)?;
+        assert_eq!(report.extents.len(), 2);
+        assert!(verify_regions(&dvec)?.matches());
+        assert!(repair_regions(&dvec, false)?.extents.is_empty());
+
+        let r1 = Region::open(&dir, new_region_options(), false, true)?;
+        assert_eq!(r1.flush_numbers()?, vec![0, 5, 3]);
+        let mut buffer = BytesMut::with_capacity(512);
+        buffer.resize(512, 0);
+        r1.region_read(1, Block::new_512(4), &mut buffer)?;
+        assert_eq!(&buffer[..], &[7; 512][..]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn repair_dirty_regions_once() -> Result<()> {
+        let dir = tempdir()?;
+        let dir2 = tempdir()?;
+        let dir3 = tempdir()?;
+        let mut r1 = Region::create(&dir, new_region_options())?;
+        let mut r2 = Region::create(&dir2, new_region_options())?;
+        let mut r3 = Region::create(&dir3, new_region_options())?;
+        r1.extend(3)?;
+        r2.extend(3)?;
+        r3.extend(3)?;
+
+        /*
+         * Every copy of extent 1 has a different write that was never
+         * flushed.
+         */
+        r1.region_write(1, Block::new_512(4), &[1; 512])?;
+        r2.region_write(1, Block::new_512(4), &[2; 512])?;
+        r3.region_write(1, Block::new_512(4), &[3; 512])?;
+        drop(r1);
+        drop(r2);
+        drop(r3);
+
+        let dvec = vec![
+            dir.path().to_path_buf(),
+            dir2.path().to_path_buf(),
+            dir3.path().to_path_buf(),
+        ];
+
+        let report = repair_regions(&dvec, false)?;
+        assert_eq!(report.extents.len(), 1);
+        assert_eq!(
+            report.extents[0].fix,
+            ExtentFix {
+                source: 0,
+                repair: vec![1, 2]
+            }
+        );
+        assert!(verify_regions(&dvec)?.matches());
+
+        /*
+         * Now they are all the same, and all clean, so there is nothing
+         * left to repair.
+         */
+        assert!(repair_regions(&dvec, false)?.extents.is_empty());
+        for d in dvec.iter() {
+            let r = Region::open(d, new_region_options(), false, true)?;
+            assert_eq!(r.dirty()?, vec![false, false, false]);
+            let mut buffer = BytesMut::with_capacity(512);
+            buffer.resize(512, 0);
+            r.region_read(1, Block::new_512(4), &mut buffer)?;
+            assert_eq!(&buffer[..], &[1; 512][..]);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn clone_region() -> Result<()> {
+        let dir = tempdir()?;
+        let mut region = Region::create(&dir, new_region_options())?;
+        region.extend(3)?;
+        region.region_write(1, Block::new_512(3), &[5; 512])?;
+        region.region_flush(7)?;
+        region.region_write(2, Block::new_512(0), &[6; 512])?;
+
+        let uuid = Uuid::new_v4();
+        let dir2 = tempdir()?;
+        let copy = region.clone_to(dir2.path(), uuid, false)?;
+        assert_eq!(copy.def().uuid(), uuid);
+        assert_eq!(copy.def().extent_count(), 3);
+        assert_eq!(copy.flush_numbers()?, vec![0, 7, 0]);
+        assert_eq!(copy.dirty()?, vec![false, false, true]);
+
+        let mut buffer = BytesMut::with_capacity(512);
+        buffer.resize(512, 0);
+        copy.region_read(1, Block::new_512(3), &mut buffer)?;
+        assert_eq!(&buffer[..], &[5; 512][..]);
+
+        /*
+         * Writes to the copy don't show up in the original.
+         */
+        copy.region_write(1, Block::new_512(3), &[1; 512])?;
+        region.region_read(1, Block::new_512(3), &mut buffer)?;
+        assert_eq!(&buffer[..], &[5; 512][..]);
+
+        let dir3 = tempdir()?;
+        let copy = region.clone_to(dir3.path(), Uuid::new_v4(), true)?;
+        assert_eq!(copy.flush_numbers()?, vec![0, 0, 0]);
+        assert_eq!(copy.dirty()?, vec![false, false, false]);
+        copy.region_read(2, Block::new_512(0), &mut buffer)?;
+        assert_eq!(&buffer[..], &[6; 512][..]);
+
+        /*
+         * We won't clone over an existing region.
+         */
+        assert!(region.clone_to(dir3.path(), Uuid::new_v4(), true).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn region_open_extent_limit() -> Result<()> {
+        /*
+         * Write to more extents than we allow to be open at once, and
+         * verify the cache stays at its limit.
+         */
+        let dir = tempdir()?;
+        let mut region = Region::create(&dir, new_region_options())?;
+        region.extend(5)?;
+        region.set_max_open_extents(2);
+
+        let mut data = BytesMut::with_capacity(512);
+        data.put(&[9; 512][..]);
+        for eid in 0..5 {
+            region.region_write(eid, Block::new_512(0), &data)?;
+            assert!(region.extents.lock().unwrap().len() <= 2);
+        }
+
+        /*
+         * A closed extent still has what we wrote to it.
+         */
+        let mut buffer = BytesMut::with_capacity(512);
+        buffer.resize(512, 0);
+        region.region_read(0, Block::new_512(0), &mut buffer)?;
+        assert_eq!(&buffer[..], &data[..]);
+
+        assert_eq!(region.dirty()?, vec![true; 5]);
+        region.region_flush(7)?;
+        assert_eq!(region.dirty()?, vec![false; 5]);
+        assert_eq!(region.flush_numbers()?, vec![7; 5]);
+        assert!(region.extents.lock().unwrap().len() <= 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn region_flush_after_reopen() -> Result<()> {
+        /*
+         * Extents left dirty by a previous open of the region should be
+         * found and flushed, even though they are not opened at startup.
+         */
+        let dir = tempdir()?;
+        let mut region = Region::create(&dir, new_region_options())?;
+        region.extend(3)?;
+
+        let mut data = BytesMut::with_capacity(512);
+        data.put(&[3; 512][..]);
+        region.region_write(1, Block::new_512(2), &data)?;
+        drop(region);
+
+        let region = Region::open(&dir, new_region_options(), false, false)?;
+        assert_eq!(region.extents.lock().unwrap().len(), 0);
+        assert_eq!(region.dirty()?, vec![false, true, false]);
+
+        region.region_flush(4)?;
+        assert_eq!(region.dirty()?, vec![false; 3]);
+        assert_eq!(region.flush_numbers()?, vec![0, 4, 0]);
+
+        /*
+         * Only the extent we had to flush was opened.
+         */
+        assert_eq!(region.extents.lock().unwrap().len(), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn region_extent_out_of_range() -> Result<()> {
+        let dir = tempdir()?;
+        let mut region = Region::create(&dir, new_region_options())?;
+        region.extend(2)?;
+
+        assert!(region.extent(2).is_err());
+        assert!(region.extent_meta(2).is_err());
+
+        let mut data = BytesMut::with_capacity(512);
+        data.put(&[1; 512][..]);
+        assert!(region.region_write(2, Block::new_512(0), &data).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn region_lock_exclusive() -> Result<()> {
+        /*
+         * While a region is open, nobody else can open it.
+         */
+        let dir = tempdir()?;
+        let region = Region::create(&dir, new_region_options())?;
+
+        let err = Region::open(&dir, new_region_options(), false, false)
+            .unwrap_err()
+            .to_string();
+        let pid = format!("pid {}", std::process::id());
+        assert!(err.contains(&pid), "{}", err);
+        assert!(Region::open(&dir, new_region_options(), false, true).is_err());
+
+        /*
+         * Once the region is closed, we can open it again.
+         */
+        drop(region);
+        let _region = Region::open(&dir, new_region_options(), false, false)?;
+        Ok(())
+    }
+
+    #[test]
+    fn region_lock_shared() -> Result<()> {
+        /*
+         * Any number of read only opens can share a region, but they keep
+         * out anyone who wants to change it.
+         */
+        let dir = tempdir()?;
+        let mut region = Region::create(&dir, new_region_options())?;
+        region.extend(2)?;
+        drop(region);
+
+        let r1 = Region::open(&dir, new_region_options(), false, true)?;
+        let r2 = Region::open(&dir, new_region_options(), false, true)?;
+
+        /*
+         * The pid left by create is stale, so it is not the one blamed.
+         */
+        let err = Region::open(&dir, new_region_options(), false, false)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("read only"), "{}", err);
+        assert!(!err.contains("pid"), "{}", err);
+
+        let mut data = BytesMut::with_capacity(512);
+        data.put(&[1; 512][..]);
+        assert_eq!(
+            r1.region_write(0, Block::new_512(0), &data),
+            Err(CrucibleError::ModifyingReadOnlyRegion)
+        );
+        assert_eq!(
+            r2.region_flush(1),
+            Err(CrucibleError::ModifyingReadOnlyRegion)
+        );
+
+        let mut buffer = BytesMut::with_capacity(512);
+        buffer.resize(512, 0);
+        r2.region_read(1, Block::new_512(0), &mut buffer)?;
+
+        drop(r1);
+        drop(r2);
+        let _region = Region::open(&dir, new_region_options(), false, false)?;
+        Ok(())
+    }
+
+    #[test]
+    fn region_replace_extent() -> Result<()> {
+        let dir1 = tempdir()?;
+        let mut r1 = Region::create(&dir1, new_region_options())?;
+        r1.extend(3)?;
+        let dir2 = tempdir()?;
+        let mut r2 = Region::create(&dir2, new_region_options())?;
+        r2.extend(3)?;
+
+        let mut data = BytesMut::with_capacity(512);
+        data.put(&[5; 512][..]);
+        r1.region_write(1, Block::new_512(4), &data)?;
+        r1.region_flush(6)?;
+
+        /*
+         * Have the extent we are replacing open, and dirty, so we know
+         * both are dealt with.
+         */
+        let mut other = BytesMut::with_capacity(512);
+        other.put(&[8; 512][..]);
+        r2.region_write(1, Block::new_512(0), &other)?;
+        assert_eq!(r2.extents.lock().unwrap().len(), 1);
+
+        let (meta, copy) = r1.extent_copy(1)?;
+        assert_eq!(meta.flush_number, 6);
+        assert!(!meta.dirty);
+        r2.replace_extent(1, &meta, &copy)?;
+        assert_eq!(r2.extents.lock().unwrap().len(), 0);
+
+        assert_eq!(r2.flush_numbers()?, vec![0, 6, 0]);
+        assert_eq!(r2.dirty()?, vec![false; 3]);
+
+        let mut buffer = BytesMut::with_capacity(512);
+        buffer.resize(512, 0);
+        r2.region_read(1, Block::new_512(4), &mut buffer)?;
+        assert_eq!(&buffer[..], &data[..]);
+        r2.region_read(1, Block::new_512(0), &mut buffer)?;
+        assert_eq!(&buffer[..], &[0; 512][..]);
+
+        /*
+         * The replacement is still there after the region is reopened.
+         */
+        drop(r2);
+        let r2 = Region::open(&dir2, new_region_options(), false, false)?;
+        assert_eq!(r2.flush_numbers()?, vec![0, 6, 0]);
+        r2.region_read(1, Block::new_512(4), &mut buffer)?;
+        assert_eq!(&buffer[..], &data[..]);
+
+        /*
+         * The data has to be the size of an extent.
+         */
+        assert!(r2.replace_extent(0, &meta, &copy[..512]).is_err());
+        assert!(r2.replace_extent(3, &meta, &copy).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn region_replace_extent_in_pieces() -> Result<()> {
+        let dir1 = tempdir()?;
+        let mut r1 = Region::create(&dir1, new_region_options())?;
+        r1.extend(3)?;
+        let dir2 = tempdir()?;
+        let mut r2 = Region::create(&dir2, new_region_options())?;
+        r2.extend(3)?;
+
+        let mut data = BytesMut::with_capacity(512);
+        data.put(&[5; 512][..]);
+        r1.region_write(2, Block::new_512(7), &data)?;
+        r1.region_flush(4)?;
+
+        /*
+         * Nothing can be put in place before anything is written, or
+         * before all of it is.
+         */
+        let (mut meta, first) = r1.extent_read(2, 0, 1024)?;
+        meta.ext_version = 2;
+        assert_eq!(first.len(), 1024);
+        assert!(r2.commit_extent(2, &meta).is_err());
+        r2.stage_extent(2, 0, &first)?;
+        assert!(r2.commit_extent(2, &meta).is_err());
+
+        /*
+         * Pieces have to come in order.
+         */
+        assert!(r2.stage_extent(2, 2048, &first).is_err());
+
+        let mut offset = first.len() as u64;
+        loop {
+            let (m, piece) = r1.extent_read(2, offset, 1024)?;
+            assert_eq!(m.flush_number, meta.flush_number);
+            if piece.is_empty() {
+                break;
+            }
+            r2.stage_extent(2, offset, &piece)?;
+            offset += piece.len() as u64;
+        }
+        assert!(r2.stage_extent(2, offset, &first).is_err());
+        r2.commit_extent(2, &meta)?;
+
+        /*
+         * The metadata we sent is what the extent ends up with.
+         */
+        let new_meta = r2.extent_meta(2)?;
+        assert_eq!(new_meta.ext_version, 2);
+        assert_eq!(new_meta.flush_number, 4);
+        assert_eq!(r2.flush_numbers()?, vec![0, 0, 4]);
+
+        let mut buffer = BytesMut::with_capacity(512);
+        buffer.resize(512, 0);
+        r2.region_read(2, Block::new_512(7), &mut buffer)?;
+        assert_eq!(&buffer[..], &data[..]);
+
+        /*
+         * A replacement is only put in place once.
+         */
+        assert!(r2.commit_extent(2, &meta).is_err());
+        Ok(())
+    }
 }
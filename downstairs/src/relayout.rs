@@ -0,0 +1,342 @@
+// Copyright 2021 Oxide Computer Company
+use super::*;
+use crate::region::ExtentMeta;
+use crucible_common::RegionOptions;
+
+/*
+ * Rewrite a region into a new directory with a different block size or
+ * extent size, keeping the same UUID.
+ *
+ * The region is treated as one run of bytes, which is cut up again into
+ * extents of the new size.  The last new extent is padded with zeros if
+ * the region does not fill it.  Each new extent gets the highest
+ * generation and flush number of the old extents it covers, and is dirty
+ * if any of them are, so replicas that agreed before still agree after.
+ *
+ * The upstairs encrypts each block with its offset in the extent, so an
+ * encrypted region can only be laid out again if we are given the key.
+ * The data is then decrypted with the old layout and encrypted with the
+ * new one.
+ */
+
+/*
+ * Reads a region from start to end as one stream of bytes, an extent at
+ * a time, decrypting it if we have a context.  Reads past the end of
+ * the region return zeros.
+ */
+struct RegionStream<'a> {
+    region: &'a Region,
+    context: Option<EncryptionContext>,
+    next_eid: u32,
+    buf: BytesMut,
+    pos: usize,
+}
+
+impl<'a> RegionStream<'a> {
+    fn new(
+        region: &'a Region,
+        context: Option<EncryptionContext>,
+    ) -> RegionStream<'a> {
+        RegionStream {
+            region,
+            context,
+            next_eid: 0,
+            buf: BytesMut::new(),
+            pos: 0,
+        }
+    }
+
+    fn read(&mut self, out: &mut [u8]) -> Result<()> {
+        let mut filled = 0;
+        while filled < out.len() {
+            if self.pos == self.buf.len() {
+                if self.next_eid == self.region.def().extent_count() {
+                    for b in out[filled..].iter_mut() {
+                        *b = 0;
+                    }
+                    return Ok(());
+                }
+                let (_, mut data) = self.region.extent_copy(self.next_eid)?;
+                if let Some(context) = &self.context {
+                    context.decrypt_in_place(&mut data, 0);
+                }
+                self.buf = data;
+                self.pos = 0;
+                self.next_eid += 1;
+            }
+
+            let n = (out.len() - filled).min(self.buf.len() - self.pos);
+            out[filled..filled + n]
+                .copy_from_slice(&self.buf[self.pos..self.pos + n]);
+            filled += n;
+            self.pos += n;
+        }
+        Ok(())
+    }
+}
+
+/*
+ * The new region is written to dir, which must be empty or not exist yet.
+ * If anything goes wrong, whatever we wrote there is removed again, so a
+ * region that was only partly laid out is never left behind to be used.
+ */
+pub fn relayout_region(
+    source: &Region,
+    dir: &Path,
+    block_size: u64,
+    extent_size: u64,
+    key: Option<String>,
+) -> Result<Region> {
+    let created = !dir.exists();
+    if !created && std::fs::read_dir(dir)?.next().is_some() {
+        bail!("Output directory {:?} is not empty", dir);
+    }
+
+    match relayout_into(source, dir, block_size, extent_size, key) {
+        Ok(region) => Ok(region),
+        Err(e) => {
+            if dir.exists() {
+                for entry in std::fs::read_dir(dir)? {
+                    let path = entry?.path();
+                    if path.is_dir() {
+                        std::fs::remove_dir_all(path)?;
+                    } else {
+                        std::fs::remove_file(path)?;
+                    }
+                }
+                if created {
+                    std::fs::remove_dir(dir)?;
+                }
+            }
+            Err(e)
+        }
+    }
+}
+
+fn relayout_into(
+    source: &Region,
+    dir: &Path,
+    block_size: u64,
+    extent_size: u64,
+    key: Option<String>,
+) -> Result<Region> {
+    let def = source.def();
+    let total = def.total_size();
+    if total % block_size != 0 {
+        bail!(
+            "Region size {} is not a multiple of block size {}",
+            total,
+            block_size
+        );
+    }
+
+    let mut options: RegionOptions = Default::default();
+    options.set_block_size(block_size);
+    options
+        .set_extent_size(Block::new(extent_size, block_size.trailing_zeros()));
+    options.set_uuid(def.uuid());
+    options.validate()?;
+
+    let old_bytes = def.block_size() * def.extent_size().value;
+    let new_bytes = block_size * extent_size;
+    let new_count = (total + new_bytes - 1) / new_bytes;
+
+    let mut region = Region::create(dir, options)?;
+    region.extend(new_count as u32)?;
+
+    let old_metas = source.extent_metas()?;
+    let mut from =
+        RegionStream::new(source, encryption_context(key.clone(), source)?);
+    let context = encryption_context(key, &region)?;
+
+    let mut data = vec![0u8; new_bytes as usize];
+    for eid in 0..new_count {
+        from.read(&mut data)?;
+        if let Some(context) = &context {
+            context.encrypt_in_place(&mut data, 0);
+        }
+
+        /*
+         * The old extents that hold any of the bytes of this one.
+         */
+        let start = eid * new_bytes;
+        let first = (start / old_bytes) as usize;
+        let last = (((start + new_bytes).min(total) - 1) / old_bytes) as usize;
+        let mut meta = ExtentMeta::default();
+        for old in old_metas[first..=last].iter() {
+            meta.gen_number = meta.gen_number.max(old.gen_number);
+            meta.flush_number = meta.flush_number.max(old.flush_number);
+            meta.dirty |= old.dirty;
+        }
+
+        region.replace_extent(eid as u32, &meta, &data)?;
+    }
+
+    /*
+     * Read both regions back and make sure they hold the same bytes.
+     */
+    let mut from = RegionStream::new(source, from.context);
+    let mut to = RegionStream::new(&region, context);
+    let mut old_data = vec![0u8; CHUNK_SIZE];
+    let mut new_data = vec![0u8; CHUNK_SIZE];
+    let mut offset = 0;
+    while offset < new_count * new_bytes {
+        from.read(&mut old_data)?;
+        to.read(&mut new_data)?;
+        if old_data != new_data {
+            bail!(
+                "Relayout of {:?} differs from the source near offset {}",
+                dir,
+                offset
+            );
+        }
+        offset += CHUNK_SIZE as u64;
+    }
+
+    println!(
+        "Relaid out {} extents of {} blocks of {} bytes into {} extents \
+        of {} blocks of {} bytes",
+        def.extent_count(),
+        def.extent_size().value,
+        def.block_size(),
+        new_count,
+        extent_size,
+        block_size
+    );
+
+    Ok(region)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tempfile::tempdir;
+
+    /*
+     * The bytes 0 through 31, base64 encoded.
+     */
+    const KEY: &str = "AAECAwQFBgcICQoLDA0ODxAREhMUFRYXGBkaGxwdHh8=";
+
+    fn region_options(block_size: u64, extent_size: u64) -> RegionOptions {
+        let mut options: RegionOptions = Default::default();
+        options.set_block_size(block_size);
+        options.set_extent_size(Block::new(
+            extent_size,
+            block_size.trailing_zeros(),
+        ));
+        options.set_uuid(Uuid::new_v4());
+        options
+    }
+
+    fn read_all(region: &Region, key: Option<&str>) -> Result<Vec<u8>> {
+        let context = encryption_context(key.map(String::from), region)?;
+        let mut data = vec![0u8; region.def().total_size() as usize];
+        RegionStream::new(region, context).read(&mut data)?;
+        Ok(data)
+    }
+
+    #[test]
+    fn relayout_bigger_blocks() -> Result<()> {
+        let dir = tempdir()?;
+        let mut source = Region::create(&dir, region_options(512, 10))?;
+        source.extend(4)?;
+        for eid in 0..4 {
+            source.region_write(
+                eid,
+                Block::new_512(eid as u64 + 2),
+                &[eid as u8 + 1; 512],
+            )?;
+        }
+        source.region_flush(3)?;
+        source.region_write(1, Block::new_512(9), &[9; 512])?;
+        source.region_flush(5)?;
+
+        /*
+         * 20K of data makes two and a half 8K extents.
+         */
+        let dir2 = tempdir()?;
+        let region = relayout_region(&source, dir2.path(), 4096, 2, None)?;
+        assert_eq!(region.def().uuid(), source.def().uuid());
+        assert_eq!(region.def().extent_count(), 3);
+        assert_eq!(region.flush_numbers()?, vec![5, 5, 3]);
+
+        let old = read_all(&source, None)?;
+        let new = read_all(&region, None)?;
+        assert_eq!(&new[..old.len()], &old[..]);
+        assert!(new[old.len()..].iter().all(|b| *b == 0));
+
+        Ok(())
+    }
+
+    #[test]
+    fn relayout_block_size_must_fit() -> Result<()> {
+        let dir = tempdir()?;
+        let mut source = Region::create(&dir, region_options(512, 10))?;
+        source.extend(3)?;
+
+        let dir2 = tempdir()?;
+        assert!(relayout_region(&source, dir2.path(), 4096, 4, None).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn relayout_failure_leaves_nothing() -> Result<()> {
+        let dir = tempdir()?;
+        let mut source = Region::create(&dir, region_options(512, 10))?;
+        source.extend(2)?;
+
+        /*
+         * The key is only looked at once the new region is started.
+         */
+        let dir2 = tempdir()?;
+        let output = dir2.path().join("new");
+        let bad_key = Some("not a key".to_string());
+        assert!(relayout_region(&source, &output, 1024, 5, bad_key.clone())
+            .is_err());
+        assert!(!output.exists());
+
+        assert!(
+            relayout_region(&source, dir2.path(), 1024, 5, bad_key).is_err()
+        );
+        assert!(dir2.path().exists());
+        assert_eq!(std::fs::read_dir(dir2.path())?.count(), 0);
+
+        /*
+         * Something that is already there is not touched.
+         */
+        std::fs::write(dir2.path().join("keep"), b"keep")?;
+        assert!(relayout_region(&source, dir2.path(), 1024, 5, None).is_err());
+        assert_eq!(std::fs::read(dir2.path().join("keep"))?, b"keep");
+
+        Ok(())
+    }
+
+    #[test]
+    fn relayout_encrypted() -> Result<()> {
+        let dir = tempdir()?;
+        let mut source = Region::create(&dir, region_options(512, 10))?;
+        source.extend(2)?;
+
+        let context = encryption_context(Some(KEY.to_string()), &source)?;
+        let mut data = vec![7u8; 512 * 4];
+        context.unwrap().encrypt_in_place(&mut data, 6);
+        source.region_write(1, Block::new_512(6), &data)?;
+
+        let dir2 = tempdir()?;
+        let region = relayout_region(
+            &source,
+            dir2.path(),
+            1024,
+            5,
+            Some(KEY.to_string()),
+        )?;
+        assert_eq!(region.def().extent_count(), 2);
+
+        let old = read_all(&source, Some(KEY))?;
+        assert_eq!(read_all(&region, Some(KEY))?, old);
+        assert_eq!(&old[512 * 16..512 * 20], &[7u8; 512 * 4][..]);
+
+        Ok(())
+    }
+}
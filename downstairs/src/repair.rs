@@ -0,0 +1,378 @@
+// Copyright 2021 Oxide Computer Company
+use super::*;
+use crate::region::ExtentMeta;
+use crate::verify::open_replicas;
+use bytes::Bytes;
+use crucible_common::{reconcile_extent, ExtentFix, ExtentVersion};
+
+/*
+ * Extent repair between downstairs.
+ *
+ * Repair can also be done offline, on two or three replicas of a region
+ * that are all on this host.
+ *
+ * We connect to a peer downstairs on its regular port, and only go as far
+ * in the negotiation as HereIAm.  After that we can fetch extents from the
+ * peer, or push extents to it, one at a time.
+ *
+ * An extent can only be replaced while no upstairs is active on the
+ * region, so the data can't change out from under anyone.
+ */
+
+type PeerRead = FramedRead<OwnedReadHalf, CrucibleDecoder>;
+type PeerWrite = FramedWrite<OwnedWriteHalf, CrucibleEncoder>;
+
+/*
+ * How long we wait on a peer for any one answer.
+ */
+const PEER_TIMEOUT: Duration = Duration::from_secs(50);
+
+async fn peer_recv(fr: &mut PeerRead, peer: SocketAddr) -> Result<Message> {
+    match tokio::time::timeout(PEER_TIMEOUT, fr.next()).await {
+        Err(_) => bail!("Timed out waiting on peer {}", peer),
+        Ok(None) => bail!("Peer {} hung up", peer),
+        Ok(Some(m)) => m,
+    }
+}
+
+async fn peer_connect(
+    peer: SocketAddr,
+    region: Option<Uuid>,
+) -> Result<(PeerRead, PeerWrite)> {
+    let sock = TcpStream::connect(peer).await?;
+    let (read, write) = sock.into_split();
+    let mut fr = FramedRead::new(read, CrucibleDecoder::new());
+    let mut fw = FramedWrite::new(write, CrucibleEncoder::new());
+
+    fw.send(Message::HereIAm(1, Uuid::new_v4(), region)).await?;
+    match peer_recv(&mut fr, peer).await? {
+        Message::YesItsMe(1, _) => {}
+        Message::UnknownRegion(r) => {
+            bail!("Peer {} does not have region {:?}", peer, r)
+        }
+        m => bail!("Peer {} answered HereIAm with {:?}", peer, m),
+    }
+
+    Ok((fr, fw))
+}
+
+impl From<&ExtentMeta> for ExtentInfo {
+    fn from(meta: &ExtentMeta) -> Self {
+        ExtentInfo {
+            ext_version: meta.ext_version,
+            gen_number: meta.gen_number,
+            flush_number: meta.flush_number,
+            dirty: meta.dirty,
+        }
+    }
+}
+
+impl From<ExtentInfo> for ExtentMeta {
+    fn from(info: ExtentInfo) -> Self {
+        ExtentMeta {
+            ext_version: info.ext_version,
+            gen_number: info.gen_number,
+            flush_number: info.flush_number,
+            dirty: info.dirty,
+        }
+    }
+}
+
+impl Downstairs {
+    /*
+     * Extents can't be replaced while an upstairs is active, unless that
+     * upstairs is the one asking, as it does while reconciling before it
+     * sends any IO.
+     */
+    fn repair_allowed(&self, from: Option<Uuid>) -> Result<(), CrucibleError> {
+        match self.active_upstairs() {
+            Some(active) if Some(active) != from => {
+                crucible_bail!(RepairRefused, "upstairs {} is active", active);
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /*
+     * Take one piece of an extent copied from another downstairs.
+     */
+    fn repair_stage(
+        &self,
+        from: Option<Uuid>,
+        eid: u32,
+        offset: u64,
+        data: &[u8],
+    ) -> Result<(), CrucibleError> {
+        self.repair_allowed(from)?;
+        self.region.stage_extent(eid, offset, data)?;
+        Ok(())
+    }
+
+    /*
+     * Once every piece is here, replace the extent with the copy.
+     */
+    fn repair_commit(
+        &self,
+        from: Option<Uuid>,
+        eid: u32,
+        meta: &ExtentMeta,
+    ) -> Result<(), CrucibleError> {
+        self.repair_allowed(from)?;
+        println!(
+            "Repairing extent {} to gen {} flush {}",
+            eid, meta.gen_number, meta.flush_number
+        );
+        self.region.commit_extent(eid, meta)?;
+        Ok(())
+    }
+}
+
+/*
+ * Answer a repair request from a peer downstairs, or from an upstairs
+ * reconciling its downstairs.
+ */
+pub async fn proc_repair(
+    ads: &Arc<Mutex<Downstairs>>,
+    from: Uuid,
+    m: Message,
+) -> Message {
+    let ds = ads.lock().await;
+    match m {
+        Message::ExtentFetch(eid, offset, len) => {
+            let len = len.min(EXTENT_CHUNK_SIZE);
+            match ds.region.extent_read(eid as u32, offset, len) {
+                Ok((meta, data)) => Message::ExtentData(
+                    eid,
+                    offset,
+                    ExtentInfo::from(&meta),
+                    data.freeze(),
+                ),
+                Err(e) => Message::ExtentError(eid, e.into()),
+            }
+        }
+        Message::ExtentPush(eid, offset, data) => Message::ExtentPushAck(
+            eid,
+            ds.repair_stage(Some(from), eid as u32, offset, &data),
+        ),
+        Message::ExtentPushDone(eid, info) => Message::ExtentPushAck(
+            eid,
+            ds.repair_commit(Some(from), eid as u32, &info.into()),
+        ),
+        m => panic!("{:?} is not a repair message", m),
+    }
+}
+
+/*
+ * Ask a peer for the piece of an extent at `offset`.
+ */
+async fn fetch_piece(
+    fr: &mut PeerRead,
+    fw: &mut PeerWrite,
+    peer: SocketAddr,
+    eid: u32,
+    offset: u64,
+) -> Result<(ExtentInfo, Bytes)> {
+    fw.send(Message::ExtentFetch(eid as u64, offset, EXTENT_CHUNK_SIZE))
+        .await?;
+    match peer_recv(fr, peer).await? {
+        Message::ExtentData(e, o, info, data)
+            if e == eid as u64 && o == offset =>
+        {
+            Ok((info, data))
+        }
+        Message::ExtentError(e, err) if e == eid as u64 => {
+            bail!("Peer {} could not send extent {}: {}", peer, eid, err)
+        }
+        m => bail!("Peer {} answered ExtentFetch with {:?}", peer, m),
+    }
+}
+
+/*
+ * Send a peer one message of an extent push, and wait for it to be taken.
+ */
+async fn push_piece(
+    fr: &mut PeerRead,
+    fw: &mut PeerWrite,
+    peer: SocketAddr,
+    eid: u32,
+    m: Message,
+) -> Result<()> {
+    fw.send(m).await?;
+    match peer_recv(fr, peer).await? {
+        Message::ExtentPushAck(e, result) if e == eid as u64 => {
+            if let Err(err) = result {
+                bail!("Peer {} refused extent {}: {}", peer, eid, err);
+            }
+            Ok(())
+        }
+        m => bail!("Peer {} answered ExtentPush with {:?}", peer, m),
+    }
+}
+
+/*
+ * Copy these extents from a peer downstairs into our region.
+ */
+pub async fn fetch_extents(
+    ads: &Arc<Mutex<Downstairs>>,
+    peer: SocketAddr,
+    peer_region: Option<Uuid>,
+    extents: &[u32],
+) -> Result<()> {
+    let (mut fr, mut fw) = peer_connect(peer, peer_region).await?;
+
+    for eid in extents {
+        let mut offset = 0;
+        let mut first = None;
+        loop {
+            let (info, data) =
+                fetch_piece(&mut fr, &mut fw, peer, *eid, offset).await?;
+            if *first.get_or_insert(info) != info {
+                bail!(
+                    "Peer {} extent {} changed while we copied it",
+                    peer,
+                    eid
+                );
+            }
+
+            /*
+             * We don't hold our region while waiting on the peer, the peer
+             * may be another region in this same downstairs.
+             */
+            ads.lock().await.repair_stage(None, *eid, offset, &data)?;
+            offset += data.len() as u64;
+            if (data.len() as u64) < EXTENT_CHUNK_SIZE {
+                break;
+            }
+        }
+
+        let meta = ExtentMeta::from(first.unwrap());
+        ads.lock().await.repair_commit(None, *eid, &meta)?;
+    }
+
+    Ok(())
+}
+
+/*
+ * Copy these extents from our region to a peer downstairs.
+ */
+pub async fn push_extents(
+    ads: &Arc<Mutex<Downstairs>>,
+    peer: SocketAddr,
+    peer_region: Option<Uuid>,
+    extents: &[u32],
+) -> Result<()> {
+    let (mut fr, mut fw) = peer_connect(peer, peer_region).await?;
+
+    for eid in extents {
+        let mut offset = 0;
+        let mut first = None;
+        loop {
+            let (meta, data) = ads.lock().await.region.extent_read(
+                *eid,
+                offset,
+                EXTENT_CHUNK_SIZE,
+            )?;
+            let info = ExtentInfo::from(&meta);
+            if *first.get_or_insert(info) != info {
+                bail!("Extent {} changed while we copied it", eid);
+            }
+
+            let len = data.len() as u64;
+            let m = Message::ExtentPush(*eid as u64, offset, data.freeze());
+            push_piece(&mut fr, &mut fw, peer, *eid, m).await?;
+            offset += len;
+            if len < EXTENT_CHUNK_SIZE {
+                break;
+            }
+        }
+
+        let m = Message::ExtentPushDone(*eid as u64, first.unwrap());
+        push_piece(&mut fr, &mut fw, peer, *eid, m).await?;
+    }
+
+    Ok(())
+}
+
+/*
+ * The result of reconciling the replicas of a region offline.  Only the
+ * extents that needed repair are listed.
+ */
+#[derive(Debug, Serialize)]
+pub struct RepairReport {
+    pub regions: Vec<PathBuf>,
+    pub dry_run: bool,
+    pub extents: Vec<ExtentRepair>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ExtentRepair {
+    pub extent: u32,
+    /*
+     * The version of each copy before the repair, in the order the
+     * regions were given.
+     */
+    pub versions: Vec<ExtentVersion>,
+    #[serde(flatten)]
+    pub fix: ExtentFix,
+}
+
+/*
+ * Make every extent of two or three replicas of a region agree, picking
+ * the copy to keep the same way the upstairs does.  The winning copy is
+ * copied whole over the others.  With dry_run, nothing is changed and we
+ * only report what would be done.
+ */
+pub fn repair_regions(
+    region_dir: &[PathBuf],
+    dry_run: bool,
+) -> Result<RepairReport> {
+    let regions = open_replicas(region_dir, dry_run)?;
+
+    let mut extents = Vec::new();
+    for eid in 0..regions[0].def().extent_count() {
+        let versions = regions
+            .iter()
+            .map(|region| {
+                let meta = region.extent_meta(eid)?;
+                Ok(ExtentVersion {
+                    gen: meta.gen_number,
+                    flush: meta.flush_number,
+                    dirty: meta.dirty,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let fix = match reconcile_extent(&versions) {
+            Some(fix) => fix,
+            None => continue,
+        };
+
+        /*
+         * Once every copy is the same, none of them is dirty any more.
+         * The winner is flushed at the flush number it already has, or
+         * it would be picked to repair the others all over again.
+         */
+        if !dry_run {
+            let (mut meta, data) = regions[fix.source].extent_copy(eid)?;
+            meta.dirty = false;
+            for i in fix.repair.iter() {
+                regions[*i].replace_extent(eid, &meta, &data)?;
+            }
+            regions[fix.source]
+                .extent(eid)?
+                .flush_block(meta.flush_number)?;
+        }
+
+        extents.push(ExtentRepair {
+            extent: eid,
+            versions,
+            fix,
+        });
+    }
+
+    Ok(RepairReport {
+        regions: region_dir.to_vec(),
+        dry_run,
+        extents,
+    })
+}
@@ -0,0 +1,141 @@
+// Copyright 2021 Oxide Computer Company
+use super::*;
+use crate::region::ExtentMeta;
+
+/*
+ * The result of comparing the replicas of a region.  Only extents that
+ * differ are listed.
+ */
+#[derive(Debug, Serialize)]
+pub struct VerifyReport {
+    pub regions: Vec<PathBuf>,
+    pub extent_count: u32,
+    pub mismatched: Vec<ExtentMismatch>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ExtentMismatch {
+    pub extent: u32,
+    /*
+     * The metadata from each region, in the order the regions were given.
+     */
+    pub meta: Vec<ExtentMeta>,
+    pub meta_differs: bool,
+    /*
+     * Runs of blocks in the extent where the data differs.
+     */
+    pub blocks: Vec<BlockRange>,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct BlockRange {
+    pub start: u64,
+    pub count: u64,
+}
+
+impl VerifyReport {
+    pub fn matches(&self) -> bool {
+        self.mismatched.is_empty()
+    }
+}
+
+/*
+ * Open two or three replicas of a region, and make sure they all have the
+ * same layout.
+ */
+pub fn open_replicas(
+    region_dir: &[PathBuf],
+    read_only: bool,
+) -> Result<Vec<Region>> {
+    if region_dir.len() < 2 || region_dir.len() > 3 {
+        bail!("Need two or three region directories");
+    }
+
+    let regions = region_dir
+        .iter()
+        .map(|dir| Region::open(&dir, Default::default(), false, read_only))
+        .collect::<Result<Vec<_>>>()?;
+
+    let def = regions[0].def();
+    for (dir, region) in region_dir.iter().zip(regions.iter()).skip(1) {
+        let other = region.def();
+        if other.block_size() != def.block_size()
+            || other.extent_size().value != def.extent_size().value
+            || other.extent_count() != def.extent_count()
+        {
+            bail!(
+                "Region at {:?} has a different layout than {:?}",
+                dir,
+                region_dir[0]
+            );
+        }
+    }
+
+    Ok(regions)
+}
+
+/*
+ * Compare the metadata and the data of every extent of two or three
+ * replicas of a region.
+ */
+pub fn verify_regions(region_dir: &[PathBuf]) -> Result<VerifyReport> {
+    let regions = open_replicas(region_dir, true)?;
+    let def = regions[0].def();
+
+    let block_size = def.block_size() as usize;
+    let mut mismatched = Vec::new();
+    for eid in 0..def.extent_count() {
+        let mut meta = Vec::with_capacity(regions.len());
+        let mut data = Vec::with_capacity(regions.len());
+        for region in regions.iter() {
+            let (m, d) = region.extent_copy(eid)?;
+            meta.push(m);
+            data.push(d);
+        }
+
+        let meta_differs = meta.iter().skip(1).any(|m| {
+            m.gen_number != meta[0].gen_number
+                || m.flush_number != meta[0].flush_number
+                || m.dirty != meta[0].dirty
+        });
+
+        /*
+         * Walk the extent a block at a time, and gather up the blocks
+         * that differ into runs.
+         */
+        let mut blocks: Vec<BlockRange> = Vec::new();
+        for block in 0..def.extent_size().value {
+            let start = block as usize * block_size;
+            let end = start + block_size;
+            let differs = data
+                .iter()
+                .skip(1)
+                .any(|d| d[start..end] != data[0][start..end]);
+            if !differs {
+                continue;
+            }
+            match blocks.last_mut() {
+                Some(run) if run.start + run.count == block => run.count += 1,
+                _ => blocks.push(BlockRange {
+                    start: block,
+                    count: 1,
+                }),
+            }
+        }
+
+        if meta_differs || !blocks.is_empty() {
+            mismatched.push(ExtentMismatch {
+                extent: eid,
+                meta,
+                meta_differs,
+                blocks,
+            });
+        }
+    }
+
+    Ok(VerifyReport {
+        regions: region_dir.to_vec(),
+        extent_count: def.extent_count(),
+        mismatched,
+    })
+}
@@ -1,7 +1,6 @@
 // Copyright 2021 Oxide Computer Company
 #![feature(with_options)]
 
-use std::net::SocketAddrV4;
 use std::sync::Arc;
 
 use anyhow::{bail, Result};
@@ -24,8 +23,8 @@ fn do_vecs_match<T: PartialEq>(a: &[T], b: &[T]) -> bool {
 #[derive(Debug, StructOpt)]
 #[structopt(about = "volume-side storage component")]
 pub struct Opt {
-    #[structopt(short, long, default_value = "127.0.0.1:9000")]
-    target: Vec<SocketAddrV4>,
+    #[structopt(flatten)]
+    crucible: CrucibleOpts,
 
     /*
      * Verify that writes don't extend before or after the actual location.
@@ -35,16 +34,13 @@ pub struct Opt {
 
     #[structopt(long)]
     tracing_endpoint: Option<String>,
-
-    #[structopt(short, long)]
-    key: Option<String>,
 }
 
 pub fn opts() -> Result<Opt> {
     let opt: Opt = Opt::from_args();
     println!("raw options: {:?}", opt);
 
-    if opt.target.is_empty() {
+    if opt.crucible.target.is_empty() {
         bail!("must specify at least one --target");
     }
 
@@ -53,11 +49,8 @@ pub fn opts() -> Result<Opt> {
 
 fn main() -> Result<()> {
     let opt = opts()?;
-    let crucible_opts = CrucibleOpts {
-        target: opt.target,
-        lossy: false,
-        key: opt.key,
-    };
+    let crucible_opts = opt.crucible;
+    crucible_opts.quorum()?;
 
     if let Some(tracing_endpoint) = opt.tracing_endpoint {
         let tracer = opentelemetry_jaeger::new_pipeline()
@@ -1,5 +1,4 @@
 // Copyright 2021 Oxide Computer Company
-use std::net::SocketAddrV4;
 use std::sync::Arc;
 
 use anyhow::{bail, Result};
@@ -22,7 +21,7 @@ fn handle_nbd_client(
 ) -> Result<()> {
     let e = Export {
         size: cpf.sz(),
-        readonly: false,
+        readonly: cpf.read_only(),
         ..Default::default()
     };
     handshake(&mut stream, &e)?;
@@ -33,18 +32,15 @@ fn handle_nbd_client(
 #[derive(Debug, StructOpt)]
 #[structopt(about = "volume-side storage component")]
 pub struct Opt {
-    #[structopt(short, long, default_value = "127.0.0.1:9000")]
-    target: Vec<SocketAddrV4>,
-
-    #[structopt(short, long)]
-    key: Option<String>,
+    #[structopt(flatten)]
+    crucible: CrucibleOpts,
 }
 
 pub fn opts() -> Result<Opt> {
     let opt: Opt = Opt::from_args();
     println!("raw options: {:?}", opt);
 
-    if opt.target.is_empty() {
+    if opt.crucible.target.is_empty() {
         bail!("must specify at least one --target");
     }
 
@@ -53,11 +49,8 @@ pub fn opts() -> Result<Opt> {
 
 fn main() -> Result<()> {
     let opt = opts()?;
-    let crucible_opts = CrucibleOpts {
-        target: opt.target,
-        lossy: false,
-        key: opt.key,
-    };
+    let crucible_opts = opt.crucible;
+    crucible_opts.quorum()?;
 
     /*
      * Crucible needs a runtime as it will create several async tasks to
@@ -91,6 +84,9 @@ fn main() -> Result<()> {
 
     // sent to NBD client during handshake through Export struct
     println!("NBD advertised size as {} bytes", cpf.sz());
+    if cpf.read_only() {
+        println!("NBD export is read-only");
+    }
 
     for stream in listener.incoming() {
         println!("waiting on nbd traffic");
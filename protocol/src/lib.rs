@@ -7,12 +7,42 @@ use uuid::Uuid;
 
 const MAX_FRM_LEN: usize = 100 * 1024 * 1024; // 100M
 
+/*
+ * The most extent data we send in one repair message.
+ */
+pub const EXTENT_CHUNK_SIZE: u64 = 1024 * 1024;
+
 use crucible_common::{Block, CrucibleError, RegionDefinition};
 
+/*
+ * The metadata for an extent that is copied along with its data.
+ */
+#[derive(Debug, PartialEq, Clone, Copy, Serialize, Deserialize)]
+pub struct ExtentInfo {
+    pub ext_version: u32,
+    pub gen_number: u64,
+    pub flush_number: u64,
+    pub dirty: bool,
+}
+
 #[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
 pub enum Message {
-    HereIAm(u32, Uuid),
-    YesItsMe(u32),
+    /*
+     * The upstairs sends its version, its UUID, and the UUID of the region
+     * it wants.  If no region is named, the downstairs must be serving
+     * exactly one region, and that is the one we get.
+     */
+    HereIAm(u32, Uuid, Option<Uuid>),
+    /*
+     * The downstairs answers with its version, and if it is serving a
+     * read-only region.
+     */
+    YesItsMe(u32, bool),
+    /*
+     * The downstairs does not have the region the upstairs asked for, or
+     * the upstairs did not name one and the downstairs has more than one.
+     */
+    UnknownRegion(Option<Uuid>),
 
     /*
      * Forcefully tell this downstairs to promote us (an Upstairs) to
@@ -32,6 +62,12 @@ pub enum Message {
     Ruok,
     Imok,
 
+    /*
+     * The downstairs is shutting down.  Everything it received has been
+     * completed and acked, and it will hang up after sending this.
+     */
+    ShuttingDown,
+
     RegionInfoPlease,
     RegionInfo(RegionDefinition),
     ExtentVersionsPlease,
@@ -45,6 +81,24 @@ pub enum Message {
     FlushAck(Uuid, u64, Result<(), CrucibleError>),
     ReadRequest(Uuid, u64, Vec<u64>, u64, Block, u64),
     ReadResponse(Uuid, u64, bytes::Bytes, Result<(), CrucibleError>),
+
+    /*
+     * Extent repair between downstairs.  A downstairs connects to a peer
+     * and sends HereIAm, after which it can fetch an extent from the peer,
+     * or push an extent to it.
+     *
+     * An extent is fetched a piece at a time, by offset and length, and
+     * each piece comes back with the extent metadata.  It is pushed the
+     * same way, and the metadata is sent last with ExtentPushDone, which
+     * is what puts the new copy in place.
+     */
+    ExtentFetch(u64, u64, u64),
+    ExtentData(u64, u64, ExtentInfo, bytes::Bytes),
+    ExtentError(u64, CrucibleError),
+    ExtentPush(u64, u64, bytes::Bytes),
+    ExtentPushDone(u64, ExtentInfo),
+    ExtentPushAck(u64, Result<(), CrucibleError>),
+
     Unknown(u32, BytesMut),
 }
 
@@ -182,14 +236,35 @@ mod tests {
 
     #[test]
     fn rt_here_i_am() -> Result<()> {
-        let input = Message::HereIAm(2, Uuid::new_v4());
+        let input = Message::HereIAm(2, Uuid::new_v4(), None);
+        assert_eq!(input, round_trip(&input)?);
+        Ok(())
+    }
+
+    #[test]
+    fn rt_here_i_am_region() -> Result<()> {
+        let input = Message::HereIAm(1, Uuid::new_v4(), Some(Uuid::new_v4()));
+        assert_eq!(input, round_trip(&input)?);
+        Ok(())
+    }
+
+    #[test]
+    fn rt_unknown_region() -> Result<()> {
+        let input = Message::UnknownRegion(Some(Uuid::new_v4()));
         assert_eq!(input, round_trip(&input)?);
         Ok(())
     }
 
     #[test]
     fn rt_yes_its_me() -> Result<()> {
-        let input = Message::YesItsMe(20000);
+        let input = Message::YesItsMe(20000, false);
+        assert_eq!(input, round_trip(&input)?);
+        Ok(())
+    }
+
+    #[test]
+    fn rt_yes_its_me_read_only() -> Result<()> {
+        let input = Message::YesItsMe(1, true);
         assert_eq!(input, round_trip(&input)?);
         Ok(())
     }
@@ -208,6 +283,85 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn rt_shutting_down() -> Result<()> {
+        let input = Message::ShuttingDown;
+        assert_eq!(input, round_trip(&input)?);
+        Ok(())
+    }
+
+    #[test]
+    fn rt_extent_fetch() -> Result<()> {
+        let input = Message::ExtentFetch(7, EXTENT_CHUNK_SIZE, 4096);
+        assert_eq!(input, round_trip(&input)?);
+        Ok(())
+    }
+
+    #[test]
+    fn rt_extent_data() -> Result<()> {
+        let input = Message::ExtentData(
+            7,
+            4096,
+            ExtentInfo {
+                ext_version: 1,
+                gen_number: 2,
+                flush_number: 35,
+                dirty: false,
+            },
+            bytes::Bytes::from(vec![9u8; 4096]),
+        );
+        assert_eq!(input, round_trip(&input)?);
+        Ok(())
+    }
+
+    #[test]
+    fn rt_extent_push() -> Result<()> {
+        let input =
+            Message::ExtentPush(7, 8192, bytes::Bytes::from(vec![3u8; 4096]));
+        assert_eq!(input, round_trip(&input)?);
+        Ok(())
+    }
+
+    #[test]
+    fn rt_extent_push_done() -> Result<()> {
+        let input = Message::ExtentPushDone(
+            7,
+            ExtentInfo {
+                ext_version: 1,
+                gen_number: 3,
+                flush_number: 36,
+                dirty: true,
+            },
+        );
+        assert_eq!(input, round_trip(&input)?);
+        Ok(())
+    }
+
+    #[test]
+    fn rt_extent_chunk_fits_frame() -> Result<()> {
+        /*
+         * The biggest piece of an extent we send has to fit in a frame.
+         */
+        let input = Message::ExtentPush(
+            7,
+            0,
+            bytes::Bytes::from(vec![0u8; EXTENT_CHUNK_SIZE as usize]),
+        );
+        assert!((bincode::serialized_size(&input)? as usize) < MAX_FRM_LEN);
+        assert_eq!(input, round_trip(&input)?);
+        Ok(())
+    }
+
+    #[test]
+    fn rt_extent_push_ack() -> Result<()> {
+        let input = Message::ExtentPushAck(
+            3,
+            Err(CrucibleError::RepairRefused("busy".to_string())),
+        );
+        assert_eq!(input, round_trip(&input)?);
+        Ok(())
+    }
+
     #[test]
     fn rt_evp() -> Result<()> {
         let input = Message::ExtentVersionsPlease;
@@ -3,13 +3,13 @@
 #![allow(clippy::mutex_atomic)]
 
 use std::clone::Clone;
-use std::collections::{HashMap, VecDeque};
+use std::collections::{BTreeSet, HashMap, VecDeque};
 use std::fmt;
 use std::fmt::{Debug, Formatter};
 use std::io::{Read, Result as IOResult, Seek, SeekFrom, Write};
 use std::net::SocketAddrV4;
 use std::sync::mpsc as std_mpsc;
-use std::sync::{Arc, Mutex, MutexGuard, RwLock};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, RwLock};
 use std::time::Duration;
 
 pub use crucible_common::*;
@@ -21,9 +21,11 @@ use futures::{SinkExt, StreamExt};
 use rand::prelude::*;
 use ringbuffer::{AllocRingBuffer, RingBufferExt, RingBufferWrite};
 use serde::Serialize;
+use structopt::clap::arg_enum;
+use structopt::StructOpt;
 use tokio::net::tcp::WriteHalf;
 use tokio::net::{TcpSocket, TcpStream};
-use tokio::sync::{mpsc, watch, Notify};
+use tokio::sync::{mpsc, oneshot, watch, Notify};
 use tokio::time::{sleep_until, Instant};
 use tokio_util::codec::{FramedRead, FramedWrite};
 use tracing::{instrument, span, Level};
@@ -51,31 +53,217 @@ mod cdt {
     fn gw_flush_end(_: u64) {}
 }
 
-#[derive(Debug, Clone)]
+/*
+ * How to reach the downstairs, and how to treat them once we have.  Each
+ * program that runs an upstairs takes these as its own options, with
+ * #[structopt(flatten)].
+ */
+#[derive(Debug, Clone, StructOpt)]
 pub struct CrucibleOpts {
+    #[structopt(short, long, default_value = "127.0.0.1:9000")]
     pub target: Vec<SocketAddrV4>,
+
+    /*
+     * The UUID of the region we want from each target, in the same order.
+     * This may be left empty if each downstairs only serves one region.
+     */
+    #[structopt(long)]
+    pub region: Vec<Uuid>,
+
+    /*
+     * For testing dependencies only, now and then hold back IO we could
+     * send to a downstairs.  This is not an option here, a program that
+     * wants it has to set it.
+     */
+    #[structopt(skip)]
     pub lossy: bool,
+
+    #[structopt(short, long)]
     pub key: Option<String>,
+
+    /*
+     * How many downstairs must finish a write or flush, or return the
+     * data for a read, before we ack it back to the guest.  If not given,
+     * a write needs a majority of the targets and a read needs one.
+     */
+    #[structopt(long)]
+    pub write_quorum: Option<usize>,
+
+    #[structopt(long)]
+    pub read_quorum: Option<usize>,
+
+    /*
+     * How long, in seconds, a downstairs may be offline, and how many
+     * bytes of writes we will hold to replay to it, before we give up on
+     * it.  If not given, we use DEFAULT_RECONNECT_DEADLINE_SECS and
+     * DEFAULT_REPLAY_MAX_BYTES.
+     */
+    #[structopt(long)]
+    pub reconnect_deadline: Option<u64>,
+
+    #[structopt(long)]
+    pub replay_max_bytes: Option<u64>,
+
+    /*
+     * How many times to send an IO again to a downstairs that returned a
+     * transient error for it.  If a write or flush still fails after that,
+     * the downstairs is Failed, unless the others need it for a write
+     * quorum.  Defaults to DEFAULT_IO_RETRIES.
+     */
+    #[structopt(long)]
+    pub io_retries: Option<u32>,
+
+    /*
+     * Which downstairs a read is sent to, and how long, in seconds, we
+     * wait for an answer before also sending it to another one.  Defaults
+     * to ReadPolicy::LeastOutstanding and DEFAULT_READ_TIMEOUT_SECS.
+     */
+    #[structopt(
+        long,
+        possible_values = &ReadPolicy::variants(),
+        case_insensitive = true
+    )]
+    pub read_policy: Option<ReadPolicy>,
+
+    #[structopt(long)]
+    pub read_timeout: Option<u64>,
+
+    /*
+     * Send every read to every downstairs, and compare what they return
+     * before we give it to the guest.  A difference is always logged, and
+     * with paranoid_fail the read fails as well.
+     */
+    #[structopt(long)]
+    pub paranoid_reads: bool,
+
+    #[structopt(long)]
+    pub paranoid_fail: bool,
+
+    /*
+     * How many bytes of writes the guest may have queued before
+     * Guest::write waits for some of them to be retired.  Defaults to
+     * DEFAULT_WRITE_BACKLOG_BYTES.
+     */
+    #[structopt(long)]
+    pub write_backlog: Option<u64>,
+}
+
+arg_enum! {
+    /*
+     * How we choose the downstairs to send a read to.  Only as many as
+     * the read quorum are picked, except for All, which sends every read
+     * to every downstairs and takes the first good answer.
+     */
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub enum ReadPolicy {
+        All,
+        RoundRobin,
+        LeastOutstanding,
+        LowestLatency,
+    }
+}
+
+/*
+ * Decode an encryption key given as base64, the same way --key is.
+ */
+pub fn decode_key(key: &str) -> Result<Vec<u8>> {
+    let decoded_key = match base64::decode(key) {
+        Ok(k) => k,
+        Err(e) => bail!("could not base64 decode key: {}", e),
+    };
+
+    // For xts, key size must be 32 bytes
+    if decoded_key.len() != 32 {
+        bail!("Key length must be 32 bytes!");
+    }
+
+    Ok(decoded_key)
 }
 
 impl CrucibleOpts {
     pub fn key_bytes(&self) -> Option<Vec<u8>> {
-        if let Some(key) = &self.key {
-            // For xts, key size must be 32 bytes
-            let decoded_key =
-                base64::decode(key).expect("could not base64 decode key!");
+        self.key
+            .as_ref()
+            .map(|key| decode_key(key).expect("invalid key"))
+    }
 
-            if decoded_key.len() != 32 {
-                panic!("Key length must be 32 bytes!");
-            }
+    /*
+     * Return the write and read quorum for our number of targets, after
+     * making sure each one is something we can reach.
+     */
+    pub fn quorum(&self) -> Result<(usize, usize)> {
+        let replicas = self.target.len();
+        if replicas == 0 {
+            bail!("At least one target is required");
+        }
 
-            Some(decoded_key)
-        } else {
-            None
+        let write_quorum = self.write_quorum.unwrap_or(replicas / 2 + 1);
+        let read_quorum = self.read_quorum.unwrap_or(1);
+        if write_quorum == 0 || write_quorum > replicas {
+            bail!(
+                "Write quorum {} is not possible with {} targets",
+                write_quorum,
+                replicas
+            );
+        }
+        if read_quorum == 0 || read_quorum > replicas {
+            bail!(
+                "Read quorum {} is not possible with {} targets",
+                read_quorum,
+                replicas
+            );
+        }
+
+        Ok((write_quorum, read_quorum))
+    }
+
+    fn reconnect_policy(&self) -> ReconnectPolicy {
+        ReconnectPolicy {
+            deadline: Duration::from_secs(
+                self.reconnect_deadline
+                    .unwrap_or(DEFAULT_RECONNECT_DEADLINE_SECS),
+            ),
+            max_bytes: self
+                .replay_max_bytes
+                .unwrap_or(DEFAULT_REPLAY_MAX_BYTES),
         }
     }
 }
 
+pub const DEFAULT_RECONNECT_DEADLINE_SECS: u64 = 120;
+pub const DEFAULT_REPLAY_MAX_BYTES: u64 = 512 * 1024 * 1024;
+pub const DEFAULT_IO_RETRIES: u32 = 3;
+pub const DEFAULT_READ_TIMEOUT_SECS: u64 = 2;
+pub const DEFAULT_WRITE_BACKLOG_BYTES: u64 = 1024 * 1024 * 1024;
+
+/*
+ * How many jobs we start out letting a downstairs have in flight, and
+ * how far that window can shrink or grow as we watch how long its jobs
+ * take.
+ */
+const FLOW_WINDOW_START: usize = 100;
+const FLOW_WINDOW_MIN: usize = 16;
+const FLOW_WINDOW_MAX: usize = 1000;
+
+/*
+ * We try to reconnect to a downstairs after this long, doubling the wait
+ * each time we fail, up to the max.
+ */
+const RECONNECT_BACKOFF_MIN_SECS: u64 = 1;
+const RECONNECT_BACKOFF_MAX_SECS: u64 = 32;
+
+/*
+ * While a downstairs is Offline, we hold every job it has not done so we
+ * can replay it when it comes back.  If it is gone for longer than the
+ * deadline, or the writes we hold for it grow past max_bytes, we fail it
+ * instead and let that work go.
+ */
+#[derive(Debug, Clone, Copy)]
+struct ReconnectPolicy {
+    deadline: Duration,
+    max_bytes: u64,
+}
+
 pub fn deadline_secs(secs: u64) -> Instant {
     Instant::now()
         .checked_add(Duration::from_secs(secs))
@@ -85,7 +273,7 @@ pub fn deadline_secs(secs: u64) -> Instant {
 async fn process_message(
     u: &Arc<Upstairs>,
     m: &Message,
-    up_coms: UpComs,
+    up_coms: &UpComs,
 ) -> Result<()> {
     match m {
         Message::Imok => Ok(()),
@@ -103,7 +291,7 @@ async fn process_message(
                 *ds_id,
                 up_coms.client_id,
                 None,
-                up_coms.ds_done_tx,
+                &up_coms.ds_done_tx,
                 result.clone(),
             )
             .await?)
@@ -122,7 +310,7 @@ async fn process_message(
                 *ds_id,
                 up_coms.client_id,
                 None,
-                up_coms.ds_done_tx,
+                &up_coms.ds_done_tx,
                 result.clone(),
             )
             .await?)
@@ -141,7 +329,7 @@ async fn process_message(
                 *ds_id,
                 up_coms.client_id,
                 Some(data.clone()),
-                up_coms.ds_done_tx,
+                &up_coms.ds_done_tx,
                 result.clone(),
             )
             .await?)
@@ -235,16 +423,14 @@ pub fn extent_from_offset(
 }
 
 /*
- * Decide what to do with a downstairs that has just connected and has
- * sent us information about its extents.
- *
- * XXX At the moment we are doing both wait quorum and verify consistency
- * in the same function. This will soon break out into two separate places
- * where we then decide what to do with each downstairs.
+ * Record the extent information a downstairs has just sent us.  Nothing
+ * is decided here, the versions from all downstairs are compared once
+ * they have all connected, see reconcile().
  */
 fn process_downstairs(
     target: &SocketAddrV4,
     u: &Arc<Upstairs>,
+    client_id: u8,
     gens: Vec<u64>,
     versions: Vec<u64>,
     dirty: Vec<bool>,
@@ -259,52 +445,48 @@ fn process_downstairs(
         println!("{}  dirty: {:?}", target, dirty);
     }
 
-    let mut fi = u.flush_info.lock().unwrap();
-    if fi.flush_numbers.is_empty() {
-        /*
-         * This is the first version list we have, so
-         * we will make it the original and compare
-         * whatever comes next.
-         * XXX This is not the final way to do it, but works for now.
-         */
-        fi.flush_numbers = versions;
-        fi.next_flush = *fi.flush_numbers.iter().max().unwrap() + 1;
-        print!("Set initial Extent versions to");
-        if fi.flush_numbers.len() > 12 {
-            println!(" [0..12]{:?}", fi.flush_numbers[0..12].to_vec());
-        } else {
-            println!("{:?}", fi.flush_numbers);
-        }
-        println!("Next flush: {}", fi.next_flush);
-    } else if fi.flush_numbers.len() != versions.len() {
-        /*
-         * I don't think there is much we can do here, the expected number
-         * of flush numbers does not match. Possibly we have grown one but
-         * not the rest of the downstairs?
-         */
-        panic!(
-            "Expected downstairs version \
-              len:{:?} does not match new \
-              downstairs:{:?}",
-            fi.flush_numbers.len(),
-            versions.len()
+    if gens.len() != versions.len() || dirty.len() != versions.len() {
+        bail!(
+            "{} sent {} gens, {} flush numbers and {} dirty bits",
+            target,
+            gens.len(),
+            versions.len(),
+            dirty.len()
         );
-    } else {
-        /*
-         * We already have a list of versions to compare with. Make that
-         * comparison now against this new list
-         */
-        let ver_cmp = fi.flush_numbers.iter().eq(versions.iter());
-        if !ver_cmp {
-            println!(
-                "{} MISMATCH expected: {:?} != new: {:?}",
-                target, fi.flush_numbers, versions
+    }
+
+    let mut ds = u.downstairs.lock().unwrap();
+    for (other, other_versions) in ds.ds_versions.iter().enumerate() {
+        if other != client_id as usize
+            && !other_versions.is_empty()
+            && other_versions.len() != versions.len()
+        {
+            /*
+             * I don't think there is much we can do here, the expected
+             * number of extents does not match. Possibly we have grown one
+             * but not the rest of the downstairs?
+             */
+            panic!(
+                "Expected downstairs version \
+                  len:{:?} does not match new \
+                  downstairs:{:?}",
+                other_versions.len(),
+                versions.len()
             );
-            // XXX Recovery process should start here
-            println!("{} Ignoring this downstairs version info", target);
         }
     }
 
+    ds.ds_versions[client_id as usize] = gens
+        .iter()
+        .zip(versions.iter())
+        .zip(dirty.iter())
+        .map(|((gen, flush), dirty)| ExtentVersion {
+            gen: *gen,
+            flush: *flush,
+            dirty: *dirty,
+        })
+        .collect();
+
     Ok(())
 }
 
@@ -319,7 +501,7 @@ async fn io_completed(
     ds_id: u64,
     client_id: u8,
     data: Option<Bytes>,
-    ds_done_tx: mpsc::Sender<u64>,
+    ds_done_tx: &mpsc::Sender<u64>,
     result: Result<(), CrucibleError>,
 ) -> Result<()> {
     if up.complete(ds_id, client_id, data, result)? {
@@ -360,8 +542,8 @@ async fn io_send(
      * transmit and receive, we can starve the receive side by spending
      * all our time sending work.
      *
-     * This XXX is for coming back here and making a better job of
-     * flow control.
+     * How many we let it have in flight is its window, which
+     * adjust_window() tunes as its jobs complete.
      */
     let mut new_work = u.downstairs.lock().unwrap().new_work(client_id);
 
@@ -378,10 +560,21 @@ async fn io_send(
      */
     new_work.sort_unstable();
 
-    let mut active_count =
-        u.downstairs.lock().unwrap().submitted_work(client_id);
+    let (mut active_count, window) = {
+        let ds = u.downstairs.lock().unwrap();
+        (
+            ds.submitted_work(client_id),
+            ds.ds_window[client_id as usize],
+        )
+    };
     for new_id in new_work.iter() {
-        if active_count >= 100 {
+        /*
+         * A job we are sending again was counted when it first went out,
+         * and the jobs after it may be waiting for it, so flow control
+         * does not hold it back.
+         */
+        let retry = u.downstairs.lock().unwrap().is_retry(*new_id, client_id);
+        if active_count >= window && !retry {
             // Flow control enacted, stop sending work
             return Ok(true);
         }
@@ -450,7 +643,11 @@ async fn io_send(
             }
         }
     }
-    Ok(false)
+
+    /*
+     * A job waiting out its retry backoff still has to be sent.
+     */
+    Ok(u.downstairs.lock().unwrap().retry_waiting(client_id))
 }
 
 /*
@@ -488,7 +685,8 @@ async fn proc(
     /*
      * As the "client", we must begin the negotiation.
      */
-    fw.send(Message::HereIAm(1, up.uuid)).await?;
+    fw.send(Message::HereIAm(1, up.uuid, up_coms.region))
+        .await?;
 
     /*
      * Used to track where we are in the current negotiation.
@@ -512,7 +710,10 @@ async fn proc(
      *
      *          Upstairs             Downstairs
      * 0:          HereIAm(v)  --->
-     *                         <---  YesItsMe(v)
+     *                         <---  YesItsMe(v, read_only)
+     *
+     * If the downstairs is serving a read-only region, then this upstairs
+     * will be read-only as well.  All downstairs must agree on this.
      *
      * At this point, a downstairs will wait for a "PromoteToActive" message
      * to be sent to it.  If this is a new upstairs that has not yet
@@ -534,8 +735,7 @@ async fn proc(
      *    For "New" or "Disconnected" it means this downstairs never was
      *    "Active" and we have to go through the full compare of this
      *    downstairs with other downstairs and make sure they are
-     *    consistent (This code still TBW).  The New/Disconnected steps
-     *    continue here:
+     *    consistent.  The New/Disconnected steps continue here:
      *
      *          Upstairs             Downstairs
      * 4: ExtentVersionsPlease --->
@@ -546,6 +746,34 @@ async fn proc(
      *    we set the downstairs to DsState::WaitQuorum and we exit the
      *    while loop.
      *
+     *    Once all downstairs are in WaitQuorum, or enough of them and
+     *    the rest have had a few seconds to turn up, up_listen() moves
+     *    them to DsState::Verifying and compares their extent versions.
+     *    Any extent that does not match is copied from the downstairs with
+     *    the right version to the others over this same connection, a
+     *    piece of at most EXTENT_CHUNK_SIZE bytes at a time:
+     *
+     *                Upstairs               Downstairs
+     *      ExtentFetch(eid, off, len)  --->
+     *                                  <---  ExtentData(eid, off, meta,
+     * data)       ExtentPush(eid, off, data) --->
+     *                                  <---  ExtentPushAck(eid, result)
+     *                 ... until the whole extent is sent ...
+     *       ExtentPushDone(eid, meta)  --->
+     *                                  <---  ExtentPushAck(eid, result)
+     *
+     *    If that works, those downstairs go Active.  Otherwise we ask
+     *    them for their extent versions again, and try again later.
+     *
+     *    A downstairs that reaches WaitQuorum after we went active is out
+     *    of date.  catch_up() asks an active downstairs for its versions,
+     *    and copies over every extent that differs, one at a time, while
+     *    guest IO goes on to the active downstairs:
+     *
+     *          Upstairs          Active Downstairs
+     *    ExtentVersionsPlease --->
+     *                         <---  ExtentVersions(g, v, d)
+     *
      *    For the "Offline" state, the downstairs was connected and verified
      *    and after that point the connection was lost.  To handle this
      *    condition we follow these final steps to get this downstairs
@@ -596,7 +824,7 @@ async fn proc(
                         up.ds_missing(up_coms.client_id);
                         return Ok(())
                     }
-                    Some(Message::YesItsMe(version)) => {
+                    Some(Message::YesItsMe(version, read_only)) => {
                         if negotiated != 0 {
                             bail!("Got version already!");
                         }
@@ -614,6 +842,8 @@ async fn proc(
                             bail!("expected version 1, got {}", version);
                         }
 
+                        up.set_read_only(up_coms.client_id, read_only)?;
+
                         negotiated = 1;
                         if up.is_active() {
                             /*
@@ -725,17 +955,25 @@ async fn proc(
                             let state = &up.downstairs.lock().unwrap().ds_state;
                             state[up_coms.client_id as usize]
                         };
-                        assert_eq!(my_state, DsState::New);
+                        assert!(matches!(
+                            my_state,
+                            DsState::New |
+                            DsState::Failed |
+                            DsState::Disconnected
+                        ));
                         /*
-                         * XXX This logic may move to a different location
-                         * when we actually get the code written to handle
-                         * mismatch between downstairs extent versions.
-                         * For comparing the region data, we need to collect
-                         * version and dirty bit info from all three
-                         * downstairs, and make the decision on which data is
-                         * correct once we have everything.
+                         * We need the version and dirty bit info from all
+                         * downstairs before we can decide which data
+                         * is correct, so for now we just save it.
                          */
-                        process_downstairs(target, up, gen, flush, dirty)?;
+                        process_downstairs(
+                            target,
+                            up,
+                            up_coms.client_id,
+                            gen,
+                            flush,
+                            dirty,
+                        )?;
 
                         negotiated = 5;
                         up.ds_transition(
@@ -749,6 +987,7 @@ async fn proc(
                         *connected = true;
                         up_coms.ds_status_tx.send(Condition {
                             target: *target,
+                            client_id: up_coms.client_id,
                             connected: true,
                         }).await
                         .unwrap();
@@ -761,6 +1000,20 @@ async fn proc(
                             up.uuid, expected_uuid,
                         );
                     }
+                    Some(Message::UnknownRegion(region)) => {
+                        bail!(
+                            "[{}] {} does not have region {:?}",
+                            up_coms.client_id, target, region,
+                        );
+                    }
+                    Some(Message::ShuttingDown) => {
+                        println!(
+                            "[{}] downstairs is shutting down during \
+                            negotiation",
+                            up_coms.client_id
+                        );
+                        return Ok(());
+                    }
                     Some(m) => {
                         bail!(
                             "unexpected command {:?} received in state {:?}",
@@ -824,6 +1077,13 @@ async fn cmd_loop(
      */
     let mut more_work = up.ds_replay_active(up_coms.client_id);
 
+    /*
+     * Where to send the answer to the extent repair message we have
+     * outstanding, if any.
+     */
+    let mut repair_reply: Option<oneshot::Sender<Message>> = None;
+    let mut repair_open = true;
+
     /*
      * To keep things alive, initiate a ping any time we have been idle for
      * 10 seconds.
@@ -858,14 +1118,68 @@ async fn cmd_loop(
                             up.uuid, expected_uuid
                         );
                     }
+                    Some(Message::ShuttingDown) => {
+                        /*
+                         * The downstairs has acked everything it received
+                         * from us and is about to hang up.  Anything else
+                         * we have for it will be replayed when it returns.
+                         */
+                        println!(
+                            "[{}] downstairs is shutting down",
+                            up_coms.client_id
+                        );
+                        return Ok(());
+                    }
+                    Some(m @ Message::ExtentData(..))
+                    | Some(m @ Message::ExtentError(..))
+                    | Some(m @ Message::ExtentPushAck(..))
+                    | Some(m @ Message::ExtentVersions(..)) => {
+                        match repair_reply.take() {
+                            Some(reply) => {
+                                let _ = reply.send(m);
+                            }
+                            None => bail!("unexpected repair answer {:?}", m),
+                        }
+                    }
                     Some(m) => {
                         /*
                          * TODO: Add a check here to make sure we are
                          * connected and in the proper state before we
                          * accept any commands.
                          */
-                        process_message(up, &m, up_coms.clone()).await?;
+                        process_message(up, &m, up_coms).await?;
+
+                        if up.ds_state(up_coms.client_id) == DsState::Failed {
+                            bail!("[{}] has failed", up_coms.client_id);
+                        }
+
+                        /*
+                         * If that reply put a job back to wait for a
+                         * retry, make sure we come back to send it even
+                         * when no other work shows up.
+                         */
+                        if !more_work
+                            && up
+                                .downstairs
+                                .lock()
+                                .unwrap()
+                                .retry_waiting(up_coms.client_id)
+                        {
+                            more_work = true;
+                            more_work_interval = deadline_secs(1);
+                        }
+                    }
+                }
+            }
+            req = up_coms.ds_repair_rx.recv(),
+                if repair_open && repair_reply.is_none() =>
+            {
+                match req {
+                    Some(req) => {
+                        fw.send(req.message).await?;
+                        repair_reply = Some(req.reply);
                     }
+                    None => repair_open = false,
                 }
             }
             _ = up_coms.ds_work_rx.changed() => {
@@ -941,12 +1255,15 @@ async fn cmd_loop(
  * Things that allow the various tasks of Upstairs to communicate
  * with each other.
  */
-#[derive(Clone)]
 struct UpComs {
     /**
      * The client ID who will be using these channels.
      */
     client_id: u8,
+    /**
+     * The region we ask this downstairs for, if one was given.
+     */
+    region: Option<Uuid>,
     /**
      * This channel is used to receive a notification that new work has
      * (possibly) arrived on the work queue and this client should go
@@ -968,6 +1285,21 @@ struct UpComs {
      * promote this downstairs to active.
      */
     ds_active_rx: watch::Receiver<bool>,
+    /**
+     * This channel is used to receive extent repair messages to send to
+     * this downstairs while we are reconciling or catching it up.
+     */
+    ds_repair_rx: mpsc::Receiver<RepairRequest>,
+}
+
+/*
+ * A message to send to a downstairs while reconciling, and where to send
+ * the answer.
+ */
+#[derive(Debug)]
+struct RepairRequest {
+    message: Message,
+    reply: oneshot::Sender<Message>,
 }
 
 /*
@@ -982,12 +1314,15 @@ async fn looper(
 ) {
     let mut firstgo = true;
     let mut connected = false;
+    let mut backoff = Duration::from_secs(RECONNECT_BACKOFF_MIN_SECS);
 
     'outer: loop {
         if firstgo {
             firstgo = false;
         } else {
-            tokio::time::sleep(Duration::from_secs(1)).await;
+            reconnect_wait(up, &mut up_coms, backoff).await;
+            backoff = (backoff * 2)
+                .min(Duration::from_secs(RECONNECT_BACKOFF_MAX_SECS));
         }
 
         /*
@@ -1062,10 +1397,134 @@ async fn looper(
             "{0}[{1}] connection to {0} closed",
             target, up_coms.client_id
         );
+
+        /*
+         * If we got far enough to take IO, start over with a short wait.
+         */
+        if connected {
+            backoff = Duration::from_secs(RECONNECT_BACKOFF_MIN_SECS);
+        }
         connected = false;
     }
 }
 
+/*
+ * Wait before we try to connect to a downstairs again.  If it is Offline
+ * we are holding its work to replay, so while we wait, check it against
+ * the reconnect policy as time passes and as new work comes in.
+ */
+async fn reconnect_wait(
+    up: &Arc<Upstairs>,
+    up_coms: &mut UpComs,
+    wait: Duration,
+) {
+    let done = tokio::time::sleep(wait);
+    tokio::pin!(done);
+    let mut check = tokio::time::interval(Duration::from_secs(1));
+
+    loop {
+        tokio::select! {
+            _ = &mut done => {
+                return;
+            }
+            _ = check.tick() => {}
+            res = up_coms.ds_work_rx.changed() => {
+                if res.is_err() {
+                    (&mut done).await;
+                    return;
+                }
+            }
+        }
+
+        let ackable = up.offline_check(up_coms.client_id);
+        if let Some(ds_id) = ackable.last() {
+            let _ = up_coms.ds_done_tx.send(*ds_id).await;
+        }
+    }
+}
+
+/*
+ * A downstairs that catch_up() is bringing up to date.  While it has this
+ * it belongs to that task, which tries again if it fails.
+ */
+#[derive(Debug, Clone, Default)]
+struct CatchUp {
+    /*
+     * Set while we are copying extents to it, and not while we wait to
+     * try again.
+     */
+    running: bool,
+    /*
+     * Extents that may still differ from the active downstairs.  Writes
+     * to these are not sent, as the extent is copied over later.
+     */
+    pending: BTreeSet<u64>,
+    /*
+     * Extents that had a write skipped here since we started, so they
+     * are copied whatever their version says.
+     */
+    missed: BTreeSet<u64>,
+    /*
+     * The extent we are copying now, and the first job that came in after
+     * we started on it.  Writes to this extent and flushes from that job
+     * on are held back until the copy is done.
+     */
+    copying: Option<(u64, u64)>,
+}
+
+impl CatchUp {
+    /*
+     * Return true if the downstairs should be sent this job.  It gets a
+     * write to an extent it has a good copy of, or to the one being
+     * copied, as that write is held back until the copy is done.  It
+     * gets every flush, and no reads until it is caught up.
+     */
+    fn takes(&mut self, io: &DownstairsIO) -> bool {
+        if !self.running {
+            return false;
+        }
+        match &io.work {
+            IOop::Write {
+                dependencies: _,
+                eid,
+                offset: _,
+                data: _,
+            } => {
+                if !self.pending.contains(eid)
+                    || matches!(self.copying, Some((e, _)) if e == *eid)
+                {
+                    true
+                } else {
+                    self.missed.insert(*eid);
+                    false
+                }
+            }
+            IOop::Flush {
+                dependencies: _,
+                flush_number: _,
+            } => true,
+            IOop::Read {
+                dependencies: _,
+                eid: _,
+                offset: _,
+                num_blocks: _,
+            } => false,
+        }
+    }
+
+    /*
+     * Return true if this job has to wait for the extent we are copying.
+     */
+    fn holds(&self, io: &DownstairsIO) -> bool {
+        match self.copying {
+            Some((eid, first_held)) => {
+                io.ds_id >= first_held && io.touches(eid)
+            }
+            None => false,
+        }
+    }
+}
+
 /*
  * The structure that tracks information about the three downstairs
  * connections as well as the work that each is doing.
@@ -1087,6 +1546,79 @@ struct Downstairs {
      * The last flush ID that this downstairs has acked.
      */
     ds_last_flush: Vec<u64>,
+    /*
+     * The version of every extent, as each downstairs reported it when it
+     * connected.  These are compared to decide what needs repair before
+     * we go active.
+     */
+    ds_versions: Vec<Vec<ExtentVersion>>,
+    /*
+     * Set for a downstairs that was not there when we went active.  It
+     * gets no IO, other than what catch_up() lets through while it works,
+     * until it has been brought up to date.
+     */
+    ds_out_of_date: Vec<bool>,
+    /*
+     * Set for a downstairs that failed a write or flush, but that we did
+     * not give up on as the others needed it for a write quorum.  It still
+     * takes writes, but it may be missing data, so it gets no reads until
+     * it has been caught up.
+     */
+    ds_missed_write: Vec<bool>,
+    /*
+     * When each Offline downstairs went away.  This is not reset if it
+     * drops again while replaying, so one that keeps coming and going
+     * still runs into the reconnect deadline.
+     */
+    ds_offline_since: Vec<Option<Instant>>,
+    /*
+     * The first job each downstairs could have seen since it was last
+     * caught up.  Anything before that was repaired into it, not sent.
+     */
+    ds_first_job: Vec<u64>,
+    /*
+     * How far catch_up() has got with each downstairs it is bringing up
+     * to date while IO goes on to the others.
+     */
+    ds_catch_up: Vec<Option<CatchUp>>,
+    /*
+     * How many downstairs must complete a write or flush, or return
+     * data for a read, before we can ack it back to the guest.
+     */
+    write_quorum: u64,
+    read_quorum: u64,
+    /*
+     * How we pick the downstairs a read goes to, where RoundRobin will
+     * start looking next, and how long recent reads took on each
+     * downstairs.
+     */
+    read_policy: ReadPolicy,
+    next_reader: usize,
+    ds_read_latency: Vec<Option<Duration>>,
+    /*
+     * Set when a read was given to another downstairs, which has to be
+     * told there is work for it.
+     */
+    reads_moved: bool,
+    /*
+     * Set when every read waits for every downstairs, so we can compare
+     * what they return, and how many reads they have not agreed on.
+     */
+    paranoid: bool,
+    paranoid_fail: bool,
+    read_mismatches: u64,
+    /*
+     * How many jobs each downstairs may have in flight, and the quickest
+     * and the recent time its jobs have taken, which we tune that by.
+     */
+    ds_window: Vec<usize>,
+    ds_min_latency: Vec<Option<Duration>>,
+    ds_latency: Vec<Option<Duration>>,
+    /*
+     * Shared with the guest, which waits on it when the writes we hold
+     * have grown too large.
+     */
+    backlog: Arc<WriteBacklog>,
     downstairs_errors: HashMap<u8, u64>, // client id -> errors
     active: HashMap<u64, DownstairsIO>,
     next_id: u64,
@@ -1113,19 +1645,74 @@ impl WorkCounts {
 
 impl Default for Downstairs {
     fn default() -> Self {
+        Self::new(3, 2, 1)
+    }
+}
+
+impl Downstairs {
+    fn new(replicas: usize, write_quorum: usize, read_quorum: usize) -> Self {
+        assert!(write_quorum >= 1 && write_quorum <= replicas);
+        assert!(read_quorum >= 1 && read_quorum <= replicas);
         Self {
             ds_uuid: HashMap::new(),
-            ds_state: vec![DsState::New; 3],
-            ds_last_flush: vec![0; 3],
+            ds_state: vec![DsState::New; replicas],
+            ds_last_flush: vec![0; replicas],
+            ds_versions: vec![Vec::new(); replicas],
+            ds_out_of_date: vec![false; replicas],
+            ds_missed_write: vec![false; replicas],
+            ds_offline_since: vec![None; replicas],
+            ds_first_job: vec![0; replicas],
+            ds_catch_up: vec![None; replicas],
+            write_quorum: write_quorum as u64,
+            read_quorum: read_quorum as u64,
+            read_policy: ReadPolicy::All,
+            next_reader: 0,
+            ds_read_latency: vec![None; replicas],
+            reads_moved: false,
+            paranoid: false,
+            paranoid_fail: false,
+            read_mismatches: 0,
+            ds_window: vec![FLOW_WINDOW_START; replicas],
+            ds_min_latency: vec![None; replicas],
+            ds_latency: vec![None; replicas],
+            backlog: Arc::new(WriteBacklog::new()),
             downstairs_errors: HashMap::new(),
             active: HashMap::new(),
             completed: AllocRingBuffer::with_capacity(2048),
             next_id: 1000,
         }
     }
-}
 
-impl Downstairs {
+    /**
+     * The number of downstairs we replicate to.
+     */
+    fn replicas(&self) -> u64 {
+        self.ds_state.len() as u64
+    }
+
+    /**
+     * How many downstairs must be there before we can go active.  We
+     * need enough to make a write quorum, and enough that at least one
+     * of them has every write that was acked to the guest, which is
+     * every set larger than the downstairs a write can be missing from.
+     */
+    fn activation_quorum(&self) -> u64 {
+        self.write_quorum
+            .max(self.replicas() - self.write_quorum + 1)
+    }
+
+    /**
+     * The client IDs of the downstairs in this state.
+     */
+    fn clients_in(&self, state: DsState) -> Vec<usize> {
+        self.ds_state
+            .iter()
+            .enumerate()
+            .filter(|(_, s)| **s == state)
+            .map(|(cid, _)| cid)
+            .collect()
+    }
+
     /**
      * Assign a new downstairs ID.
      */
@@ -1154,13 +1741,48 @@ impl Downstairs {
 
         let oldstate = job.state.insert(client_id, newstate.clone());
         assert_eq!(oldstate, Some(IOState::New));
+        job.issued.insert(client_id, Instant::now());
 
-        match newstate {
-            IOState::Skipped => None,
-            IOState::InProgress => Some(job.work.clone()),
+        let mut work = match newstate {
+            IOState::Skipped => return None,
+            IOState::InProgress => job.work.clone(),
             _ => panic!("bad state in in_progress!"),
-        }
-    }
+        };
+
+        /*
+         * This downstairs will never see a job it skipped, or one from
+         * before it was caught up, so it can't wait on one.  A job that
+         * has been retired is already done everywhere it is going to be.
+         */
+        let first_job = self.ds_first_job[client_id as usize];
+        let dependencies = match &mut work {
+            IOop::Write {
+                dependencies,
+                eid: _,
+                offset: _,
+                data: _,
+            } => dependencies,
+            IOop::Read {
+                dependencies,
+                eid: _,
+                offset: _,
+                num_blocks: _,
+            } => dependencies,
+            IOop::Flush {
+                dependencies,
+                flush_number: _,
+            } => dependencies,
+        };
+        dependencies.retain(|dep| {
+            *dep >= first_job
+                && matches!(
+                    self.active.get(dep).and_then(|j| j.state.get(&client_id)),
+                    Some(state) if *state != IOState::Skipped
+                )
+        });
+
+        Some(work)
+    }
 
     /**
      * We have disconnected from a downstairs. Move every job since the
@@ -1192,6 +1814,16 @@ impl Downstairs {
 
             let job = self.active.get_mut(ds_id).unwrap();
 
+            /*
+             * A job this downstairs skipped went to the others instead,
+             * or was copied into it when it was caught up.  It does not
+             * need replay, and an old write sent now could land on top
+             * of newer data.
+             */
+            if job.state.get(&client_id) == Some(&IOState::Skipped) {
+                continue;
+            }
+
             // We don't need to send anything before our last good flush
             if *ds_id <= lf {
                 assert_eq!(Some(&IOState::Done), job.state.get(&client_id));
@@ -1216,18 +1848,29 @@ impl Downstairs {
                  */
                 if job.ack_status == AckStatus::AckReady {
                     if is_read {
+                        /*
+                         * Without this downstairs we may no longer have a
+                         * read quorum.  If it was the only one to return
+                         * data, that data goes too.
+                         */
+                        if jobs_completed_ok <= self.read_quorum
+                            || self.paranoid
+                        {
+                            println!("Remove AckReady for R {}", ds_id);
+                            job.ack_status = AckStatus::NotAcked;
+                        }
                         if jobs_completed_ok == 1 {
                             println!("Remove read data for {}", ds_id);
                             job.data = None;
-                            job.ack_status = AckStatus::NotAcked;
                         }
                     } else {
                         /*
-                         * For a write or flush, if we have 3 completed,
-                         * then we can leave this job as AckReady, if not,
-                         * then we have to undo the AckReady.
+                         * For a write or flush, if we still have a quorum
+                         * without this downstairs, then we can leave this
+                         * job as AckReady, if not, then we have to undo
+                         * the AckReady.
                          */
-                        if jobs_completed_ok < 3 {
+                        if jobs_completed_ok <= self.write_quorum {
                             println!("Remove AckReady for W/F {}", ds_id);
                             job.ack_status = AckStatus::NotAcked;
                         }
@@ -1239,16 +1882,175 @@ impl Downstairs {
         }
     }
 
+    /**
+     * The bytes of write data we are holding for this client that it has
+     * not yet done.
+     */
+    fn pending_bytes(&self, client_id: u8) -> u64 {
+        self.active
+            .values()
+            .filter(|job| {
+                matches!(
+                    job.state.get(&client_id),
+                    Some(IOState::New) | Some(IOState::InProgress)
+                )
+            })
+            .map(|job| job.write_bytes())
+            .sum()
+    }
+
+    /**
+     * Return true if this client is Offline and has gone past what the
+     * reconnect policy allows.
+     */
+    fn offline_exceeded(
+        &self,
+        client_id: u8,
+        policy: &ReconnectPolicy,
+        now: Instant,
+    ) -> bool {
+        let cid = client_id as usize;
+        if self.ds_state[cid] != DsState::Offline {
+            return false;
+        }
+
+        if let Some(since) = self.ds_offline_since[cid] {
+            if now.duration_since(since) >= policy.deadline {
+                println!(
+                    "[{}] Offline for longer than {:?}",
+                    client_id, policy.deadline
+                );
+                return true;
+            }
+        }
+
+        let bytes = self.pending_bytes(client_id);
+        if bytes > policy.max_bytes {
+            println!(
+                "[{}] Offline with {} bytes to replay, more than {}",
+                client_id, bytes, policy.max_bytes
+            );
+            return true;
+        }
+        false
+    }
+
+    /**
+     * Return true if the other active downstairs can still make a write
+     * quorum without this one.
+     */
+    fn can_lose(&self, client_id: u8) -> bool {
+        let others = self
+            .ds_state
+            .iter()
+            .enumerate()
+            .filter(|(cid, state)| {
+                *cid != client_id as usize && **state == DsState::Active
+            })
+            .count();
+        others as u64 >= self.write_quorum
+    }
+
+    /**
+     * Give up on a downstairs.  It goes to Failed and is out of date, so
+     * it has to be repaired before it gets IO again.  Every job it had
+     * not done is Skipped, which lets the other downstairs finish those
+     * jobs without it.  Return the jobs that are now ready to ack.
+     */
+    fn fail_client(&mut self, client_id: u8) -> Vec<u64> {
+        let cid = client_id as usize;
+        println!(
+            "[{}] Transition from {:?} to {:?}",
+            client_id,
+            self.ds_state[cid],
+            DsState::Failed
+        );
+        self.ds_state[cid] = DsState::Failed;
+        self.ds_out_of_date[cid] = true;
+        self.ds_offline_since[cid] = None;
+        self.skip_client(client_id)
+    }
+
+    /**
+     * Mark every job this downstairs had not done as Skipped, which lets
+     * the other downstairs finish those jobs without it.  Return the jobs
+     * that are now ready to ack.
+     */
+    fn skip_client(&mut self, client_id: u8) -> Vec<u64> {
+        let mut kvec: Vec<u64> =
+            self.active.keys().cloned().collect::<Vec<u64>>();
+        kvec.sort_unstable();
+
+        let mut ackable = Vec::new();
+        for ds_id in kvec.iter() {
+            let job = self.active.get_mut(ds_id).unwrap();
+            if matches!(
+                job.state.get(&client_id),
+                Some(IOState::New) | Some(IOState::InProgress)
+            ) {
+                job.state.insert(client_id, IOState::Skipped);
+
+                /*
+                 * A read that was only waiting on this downstairs can
+                 * go to another one.
+                 */
+                if job.state_count().active == 0 {
+                    self.move_read(*ds_id);
+                }
+            }
+
+            let job = self.active.get_mut(ds_id).unwrap();
+
+            /*
+             * A job that was only waiting on this downstairs can now
+             * go back to the guest, with an error if too few finished it.
+             */
+            if job.ack_status == AckStatus::NotAcked
+                && job.state_count().active == 0
+            {
+                job.ack_status = AckStatus::AckReady;
+                ackable.push(*ds_id);
+            }
+        }
+
+        /*
+         * A flush we have already acked may have been all that kept the
+         * jobs before it around.  Retire what we can, up to the first job
+         * that is not yet acked or not yet done everywhere.
+         */
+        for ds_id in kvec.iter() {
+            let job = match self.active.get(ds_id) {
+                Some(job) => job,
+                None => continue,
+            };
+            if job.ack_status != AckStatus::Acked
+                || job.state_count().active != 0
+            {
+                break;
+            }
+            self.retire_check(*ds_id);
+        }
+
+        ackable
+    }
+
     /**
      * Return a list of downstairs request IDs that represent unissued
      * requests for this client.
      */
     fn new_work(&self, client_id: u8) -> Vec<u64> {
+        let now = Instant::now();
         self.active
             .values()
             .filter_map(|job| {
+                if self.ds_catch_up.iter().flatten().any(|c| c.holds(job)) {
+                    return None;
+                }
                 if let Some(IOState::New) = job.state.get(&client_id) {
-                    Some(job.ds_id)
+                    match job.retries.get(&client_id) {
+                        Some((_, retry_at)) if *retry_at > now => None,
+                        _ => Some(job.ds_id),
+                    }
                 } else {
                     None
                 }
@@ -1256,6 +2058,71 @@ impl Downstairs {
             .collect()
     }
 
+    /**
+     * Return true if this job failed on this client, and is being sent
+     * to it again.
+     */
+    fn is_retry(&self, ds_id: u64, client_id: u8) -> bool {
+        matches!(
+            self.active.get(&ds_id),
+            Some(job) if job.retries.contains_key(&client_id)
+        )
+    }
+
+    /**
+     * Return true if a job for this client is waiting until it can be
+     * retried.
+     */
+    fn retry_waiting(&self, client_id: u8) -> bool {
+        let now = Instant::now();
+        self.active.values().any(|job| {
+            job.state.get(&client_id) == Some(&IOState::New)
+                && matches!(
+                    job.retries.get(&client_id),
+                    Some((_, retry_at)) if *retry_at > now
+                )
+        })
+    }
+
+    /**
+     * This job returned an error from this client.  If we have not yet
+     * sent it again as many times as we allow, put it back to New, to be
+     * sent once its backoff has passed, and return true.  The backoff
+     * starts at one second and doubles each time, up to 32.
+     */
+    fn retry(
+        &mut self,
+        ds_id: u64,
+        client_id: u8,
+        max_retries: u32,
+    ) -> Result<bool> {
+        let job = self
+            .active
+            .get_mut(&ds_id)
+            .ok_or_else(|| anyhow!("reqid {} is not active", ds_id))?;
+
+        let retries = job.retries.get(&client_id).map_or(0, |r| r.0);
+        if retries >= max_retries {
+            return Ok(false);
+        }
+
+        let backoff = Duration::from_secs(1 << retries.min(5));
+        println!(
+            "[{}] job {} failed, retry {} of {} in {:?}",
+            client_id,
+            ds_id,
+            retries + 1,
+            max_retries,
+            backoff
+        );
+        let oldstate = job.state.insert(client_id, IOState::New);
+        assert_eq!(oldstate, Some(IOState::InProgress));
+        job.retries
+            .insert(client_id, (retries + 1, Instant::now() + backoff));
+
+        Ok(true)
+    }
+
     /**
      * Return a count of downstairs request IDs of work we have sent
      * for this client, but don't yet have a response.
@@ -1268,6 +2135,44 @@ impl Downstairs {
             })
             .count()
     }
+    /**
+     * Adjust how many jobs this downstairs may have in flight, by how
+     * long its last one took.  The quickest job we have seen is about
+     * what one takes with nothing queued ahead of it.  While jobs take
+     * little longer than that, the downstairs keeps up and we let it
+     * have more.  Once they take much longer, they are waiting behind
+     * each other, and we hold more of them back here, where a read can
+     * still go to another downstairs.
+     */
+    fn adjust_window(&mut self, client_id: u8, took: Duration) {
+        let cid = client_id as usize;
+        let min = self.ds_min_latency[cid].map_or(took, |m| m.min(took));
+        self.ds_min_latency[cid] = Some(min);
+        let latency = match self.ds_latency[cid] {
+            Some(l) => (l * 7 + took) / 8,
+            None => took,
+        };
+        self.ds_latency[cid] = Some(latency);
+
+        let window = &mut self.ds_window[cid];
+        if latency <= min * 2 {
+            *window = (*window + 1).min(FLOW_WINDOW_MAX);
+        } else if latency > min * 8 {
+            *window = (*window - 1).max(FLOW_WINDOW_MIN);
+        }
+    }
+
+    /**
+     * Start this downstairs over with the window we give a new one, as
+     * whatever we learned about it may not hold when it comes back.
+     */
+    fn reset_window(&mut self, client_id: u8) {
+        let cid = client_id as usize;
+        self.ds_window[cid] = FLOW_WINDOW_START;
+        self.ds_min_latency[cid] = None;
+        self.ds_latency[cid] = None;
+    }
+
     /**
      * Build a list of jobs that are ready to be acked.
      */
@@ -1284,10 +2189,250 @@ impl Downstairs {
     /**
      * Enqueue a new downstairs request.
      */
-    fn enqueue(&mut self, io: DownstairsIO) {
+    fn enqueue(&mut self, mut io: DownstairsIO) {
+        /*
+         * A read only goes to as many downstairs as it needs answers
+         * from.  If there are not that many active, it goes to all of
+         * them, as everything else does.
+         */
+        let is_read = matches!(
+            io.work,
+            IOop::Read {
+                dependencies: _,
+                eid: _,
+                offset: _,
+                num_blocks: _,
+            }
+        );
+        let mut readers = None;
+        if self.read_policy != ReadPolicy::All && is_read {
+            let mut candidates = (0..self.replicas() as u8)
+                .filter(|cl| self.can_read(*cl))
+                .collect::<Vec<u8>>();
+            if candidates.len() as u64 >= self.read_quorum {
+                let mut picked = Vec::new();
+                while (picked.len() as u64) < self.read_quorum {
+                    let cl = self.pick_reader(&candidates).unwrap();
+                    candidates.retain(|c| *c != cl);
+                    picked.push(cl);
+                }
+                readers = Some(picked);
+            }
+        }
+
+        let now = Instant::now();
+        for cl in 0..self.replicas() as u8 {
+            let takes = match self.ds_catch_up[cl as usize].as_mut() {
+                Some(c) => c.takes(&io),
+                None => false,
+            };
+            let out_of_date = self.ds_out_of_date[cl as usize] && !takes;
+            let missed_write = is_read && self.ds_missed_write[cl as usize];
+            let state = if out_of_date
+                || missed_write
+                || matches!(&readers, Some(r) if !r.contains(&cl))
+            {
+                IOState::Skipped
+            } else {
+                io.issued.insert(cl, now);
+                IOState::New
+            };
+            io.state.insert(cl, state);
+        }
+        self.backlog.hold(io.write_bytes());
         self.active.insert(io.ds_id, io);
     }
 
+    /**
+     * Return true if this downstairs can be sent a read.
+     */
+    fn can_read(&self, client_id: u8) -> bool {
+        let cid = client_id as usize;
+        self.ds_state[cid] == DsState::Active
+            && !self.ds_out_of_date[cid]
+            && !self.ds_missed_write[cid]
+            && !self.downstairs_errors.contains_key(&client_id)
+    }
+
+    /**
+     * Return true if there is a downstairs a read could go to, now or once
+     * it is back, that has every write.
+     */
+    fn has_reader(&self) -> bool {
+        self.ds_out_of_date
+            .iter()
+            .zip(self.ds_missed_write.iter())
+            .any(|(out_of_date, missed_write)| !out_of_date && !missed_write)
+    }
+
+    /**
+     * Return true if a job from before we started to copy an extent to
+     * this downstairs, that changes that extent, has yet to be done by
+     * it or by an active downstairs we could copy from.
+     */
+    fn extent_busy(&self, client_id: u8) -> bool {
+        let (eid, first_held) = match self.ds_catch_up[client_id as usize]
+            .as_ref()
+            .and_then(|c| c.copying)
+        {
+            Some(copying) => copying,
+            None => return false,
+        };
+        self.active.values().any(|job| {
+            job.ds_id < first_held
+                && job.touches(eid)
+                && job.state.iter().any(|(cl, state)| {
+                    (*cl == client_id
+                        || self.ds_state[*cl as usize] == DsState::Active)
+                        && matches!(state, IOState::New | IOState::InProgress)
+                })
+        })
+    }
+
+    /**
+     * The number of jobs this downstairs has not yet done.
+     */
+    fn outstanding(&self, client_id: u8) -> usize {
+        self.active
+            .values()
+            .filter(|job| {
+                matches!(
+                    job.state.get(&client_id),
+                    Some(IOState::New) | Some(IOState::InProgress)
+                )
+            })
+            .count()
+    }
+
+    /**
+     * Choose one of these downstairs to send a read to, by our read
+     * policy.  We look at them in round robin order, so when the policy
+     * has no preference the reads are still spread out.
+     */
+    fn pick_reader(&mut self, candidates: &[u8]) -> Option<u8> {
+        let replicas = self.replicas() as usize;
+        let start = self.next_reader;
+        let mut order = candidates.to_vec();
+        order.sort_by_key(|cl| (*cl as usize + replicas - start) % replicas);
+
+        let pick = match self.read_policy {
+            ReadPolicy::All | ReadPolicy::RoundRobin => order.first(),
+            ReadPolicy::LeastOutstanding => {
+                order.iter().min_by_key(|cl| self.outstanding(**cl))
+            }
+            /*
+             * One we have no time for yet comes first, so we get one.
+             */
+            ReadPolicy::LowestLatency => order
+                .iter()
+                .min_by_key(|cl| self.ds_read_latency[**cl as usize]),
+        }
+        .copied()?;
+
+        self.next_reader = (pick as usize + 1) % replicas;
+        Some(pick)
+    }
+
+    /**
+     * Send a read that is still waiting for an answer to one more
+     * downstairs that has not had it yet.  We only pick one that has not
+     * yet been sent a write after this read that overlaps it, as that
+     * would change what the read returns.  Return true if we found one.
+     */
+    fn move_read(&mut self, ds_id: u64) -> bool {
+        let job = match self.active.get(&ds_id) {
+            Some(job) => job,
+            None => return false,
+        };
+        let (eid, start, end) = match &job.work {
+            IOop::Read {
+                dependencies: _,
+                eid,
+                offset,
+                num_blocks,
+            } => (*eid, offset.value, offset.value + num_blocks),
+            _ => return false,
+        };
+        if job.ack_status != AckStatus::NotAcked {
+            return false;
+        }
+
+        let candidates = (0..self.replicas() as u8)
+            .filter(|cl| {
+                job.state.get(cl) == Some(&IOState::Skipped)
+                    && ds_id >= self.ds_first_job[*cl as usize]
+                    && self.can_read(*cl)
+                    && !self.active.iter().any(|(id, later)| {
+                        *id > ds_id
+                            && matches!(
+                                later.state.get(cl),
+                                Some(IOState::InProgress)
+                                    | Some(IOState::Done)
+                                    | Some(IOState::Error(_))
+                            )
+                            && match &later.work {
+                                IOop::Write {
+                                    dependencies: _,
+                                    eid: weid,
+                                    offset,
+                                    data,
+                                } => {
+                                    let blocks =
+                                        data.len() as u64 >> offset.shift;
+                                    *weid == eid
+                                        && offset.value < end
+                                        && start < offset.value + blocks
+                                }
+                                _ => false,
+                            }
+                    })
+            })
+            .collect::<Vec<u8>>();
+
+        let cl = match self.pick_reader(&candidates) {
+            Some(cl) => cl,
+            None => return false,
+        };
+        println!("[{}] read {} sent here as well", cl, ds_id);
+        let job = self.active.get_mut(&ds_id).unwrap();
+        job.state.insert(cl, IOState::New);
+        job.issued.insert(cl, Instant::now());
+        self.reads_moved = true;
+        true
+    }
+
+    /**
+     * Give every read that has waited longer than the timeout on all the
+     * downstairs it went to one more downstairs to try.  Return true if
+     * any read has moved since we last looked.
+     */
+    fn read_timeouts(&mut self, timeout: Duration, now: Instant) -> bool {
+        let mut late = self
+            .active
+            .values()
+            .filter(|job| {
+                let last_issued = job
+                    .state
+                    .iter()
+                    .filter(|(_, state)| {
+                        matches!(state, IOState::New | IOState::InProgress)
+                    })
+                    .filter_map(|(cl, _)| job.issued.get(cl))
+                    .max();
+                job.ack_status == AckStatus::NotAcked
+                    && matches!(last_issued,
+                        Some(at) if now.duration_since(*at) >= timeout)
+            })
+            .map(|job| job.ds_id)
+            .collect::<Vec<u64>>();
+        late.sort_unstable();
+
+        for ds_id in late {
+            self.move_read(ds_id);
+        }
+        std::mem::take(&mut self.reads_moved)
+    }
+
     /**
      * Collect the state of the jobs from each client.
      */
@@ -1325,46 +2470,52 @@ impl Downstairs {
          * the Guest
          *
          * Not ok:
-         * - 2+ errors for Write/Flush
-         * - 3+ errors for Reads
+         * - Fewer than a write quorum completed, for Write/Flush
+         * - Fewer than a read quorum completed, for Reads
+         *
+         * Count what completed rather than what failed, as a downstairs
+         * we skipped did neither.
          *
          * TODO: this doesn't tell the Guest what the error(s) were?
-         * TODO: Add retries here as well.
          */
         let wc = self.state_count(ds_id).unwrap();
+        let replicas = self.replicas();
 
         let job = self
             .active
             .get_mut(&ds_id)
             .ok_or_else(|| anyhow!("reqid {} is not active", ds_id))?;
 
-        /*
-         * XXX: this code assumes that 3 downstairs is the max that we'll
-         * ever support.
-         */
+        if job.mismatch && self.paranoid_fail {
+            return Err(CrucibleError::IoError(format!(
+                "downstairs returned different data for read {}",
+                ds_id
+            )));
+        }
+
         let bad_job = match &job.work {
             IOop::Read {
                 dependencies: _dependencies,
                 eid: _eid,
                 offset: _offset,
                 num_blocks: _num_blocks,
-            } => wc.error == 3,
+            } => wc.completed_ok() < self.read_quorum,
             IOop::Write {
                 dependencies: _dependencies,
                 eid: _eid,
                 data: _data,
                 offset: _offset,
-            } => wc.error >= 2,
+            } => wc.completed_ok() < self.write_quorum,
             IOop::Flush {
                 dependencies: _dependencies,
                 flush_number: _flush_number,
-            } => wc.error >= 2,
+            } => wc.completed_ok() < self.write_quorum,
         };
 
         if bad_job {
             Err(CrucibleError::IoError(format!(
-                "{} out of 3 downstairs returned an error",
-                wc.error
+                "{} out of {} downstairs returned an error, {} skipped",
+                wc.error, replicas, wc.skipped
             )))
         } else {
             Ok(())
@@ -1438,6 +2589,31 @@ impl Downstairs {
          */
         let wc = self.state_count(ds_id)?;
         let mut jobs_completed_ok = wc.completed_ok();
+        let replicas = self.replicas();
+
+        /*
+         * How long this job took here tunes how much work we send this
+         * downstairs, and for a read, which downstairs we read from.  A
+         * flush waits on the disk, not on the jobs ahead of it, so it
+         * does not say much about either.
+         */
+        let took = self
+            .active
+            .get(&ds_id)
+            .and_then(|job| job.issued.get(&client_id))
+            .map(|issued| issued.elapsed());
+        if let (Some(took), Ok(())) = (took, &result) {
+            if !self.is_flush(ds_id)? {
+                self.adjust_window(client_id, took);
+            }
+            if read_data.is_some() {
+                let latency = &mut self.ds_read_latency[client_id as usize];
+                *latency = Some(match latency {
+                    Some(l) => (*l * 7 + took) / 8,
+                    None => took,
+                });
+            }
+        }
 
         let job = self
             .active
@@ -1502,14 +2678,37 @@ impl Downstairs {
             match &job.work {
                 IOop::Read {
                     dependencies: _dependencies,
-                    eid: _eid,
-                    offset: _offset,
+                    eid,
+                    offset,
                     num_blocks: _num_blocks,
                 } => {
                     assert!(read_data.is_some());
-                    if jobs_completed_ok == 1 {
-                        assert!(job.data.is_none());
+                    if let (Some(have), Some(from), Some(data)) =
+                        (&job.data, job.data_from, &read_data)
+                    {
+                        if let Some((first, last)) =
+                            mismatched_blocks(offset, have, data)
+                        {
+                            println!(
+                                "[{}] read {} of extent {} blocks {}..={} \
+                                does not match downstairs {}",
+                                client_id, ds_id, eid, first, last, from
+                            );
+                            if !job.mismatch {
+                                self.read_mismatches += 1;
+                            }
+                            job.mismatch = true;
+                        }
+                    }
+                    if job.data.is_none() {
                         job.data = read_data;
+                        job.data_from = Some(client_id);
+                    }
+                    /*
+                     * A paranoid read waits until every downstairs has
+                     * answered, below.
+                     */
+                    if jobs_completed_ok == self.read_quorum && !self.paranoid {
                         notify_guest = true;
                         assert_eq!(job.ack_status, AckStatus::NotAcked);
                         job.ack_status = AckStatus::AckReady;
@@ -1522,7 +2721,7 @@ impl Downstairs {
                     offset: _offset,
                 } => {
                     assert!(read_data.is_none());
-                    if jobs_completed_ok == 2 {
+                    if jobs_completed_ok == self.write_quorum {
                         notify_guest = true;
                         job.ack_status = AckStatus::AckReady;
                     }
@@ -1532,7 +2731,7 @@ impl Downstairs {
                     flush_number: _flush_number,
                 } => {
                     assert!(read_data.is_none());
-                    if jobs_completed_ok == 2 {
+                    if jobs_completed_ok == self.write_quorum {
                         notify_guest = true;
                         job.ack_status = AckStatus::AckReady;
                     }
@@ -1541,7 +2740,7 @@ impl Downstairs {
             }
         }
         /*
-         * If all 3 jobs are done, we can check here to see if we can
+         * If all jobs are done, we can check here to see if we can
          * remove this job from the DS list. If we have completed the ack
          * to the guest, then there will be no more work on this job
          * but messages may still be unprocessed.
@@ -1554,7 +2753,7 @@ impl Downstairs {
             // double count three done and return true if we already have
             // AckReady set.
             let wc = job.state_count();
-            if (wc.error + wc.skipped + wc.done) == 3 {
+            if (wc.error + wc.skipped + wc.done) == replicas {
                 notify_guest = true;
                 job.ack_status = AckStatus::AckReady;
             }
@@ -1565,20 +2764,42 @@ impl Downstairs {
 
     /**
      * This request is now complete on all peers, but is is ready to retire?
-     * Only when a flush is complete on all three do we check
+     * Only when a flush is complete on all downstairs do we check
      * to see if we can remove the job.  When we remove a job, we
      * also take all the previous jobs out of the queue as well.
-     * Double check that all previous jobs have finished and panic
-     * if not.
+     * If one of them has not finished yet, or is not yet acked, we
+     * leave them all until it has.
      */
     fn retire_check(&mut self, ds_id: u64) {
         // Only a completed flush will remove work from the active queue.
         if !self.is_flush(ds_id).unwrap() {
+            if !self.is_read(ds_id).unwrap() {
+                return;
+            }
+            /*
+             * A flush does not wait for a read on a downstairs that was
+             * not sent it, so a flush after this read may have finished
+             * first and been left for us.
+             */
+            let mut flushes = self
+                .active
+                .keys()
+                .cloned()
+                .filter(|&x| x > ds_id && self.is_flush(x).unwrap())
+                .collect::<Vec<u64>>();
+            flushes.sort_unstable();
+            for flush in flushes {
+                if self.active.contains_key(&flush)
+                    && self.active[&flush].ack_status == AckStatus::Acked
+                {
+                    self.retire_check(flush);
+                }
+            }
             return;
         }
         // Sort the job list, and retire all the work that is older than us.
         let wc = self.state_count(ds_id).unwrap();
-        if (wc.error + wc.skipped + wc.done) == 3 {
+        if (wc.error + wc.skipped + wc.done) == self.replicas() {
             assert!(!self.completed.contains(&ds_id));
             assert_eq!(wc.active, 0);
 
@@ -1593,16 +2814,30 @@ impl Downstairs {
                 .filter(|&x| x <= ds_id)
                 .collect::<Vec<u64>>();
 
+            /*
+             * A read from before this flush may not be acked, or may
+             * have gone to another downstairs after the flush did.  Wait
+             * for it.
+             */
+            if kvec.iter().any(|id| {
+                let job = &self.active[id];
+                job.ack_status != AckStatus::Acked
+                    || job.state_count().active != 0
+            }) {
+                return;
+            }
+
             kvec.sort_unstable();
             for id in kvec.iter() {
                 assert!(*id <= ds_id);
                 let wc = self.state_count(*id).unwrap();
                 assert_eq!(wc.active, 0);
-                assert_eq!(wc.error + wc.skipped + wc.done, 3);
+                assert_eq!(wc.error + wc.skipped + wc.done, self.replicas());
                 assert!(!self.completed.contains(id));
 
                 let oj = self.active.remove(id).unwrap();
                 assert_eq!(oj.ack_status, AckStatus::Acked);
+                self.backlog.release(oj.write_bytes());
                 self.completed.push(*id);
             }
         }
@@ -1646,6 +2881,27 @@ impl Downstairs {
     }
 }
 
+/*
+ * Compare two answers to the same read, block by block.  If they differ,
+ * return the first and last block that does.
+ */
+fn mismatched_blocks(offset: &Block, a: &[u8], b: &[u8]) -> Option<(u64, u64)> {
+    let bs = 1 << offset.shift;
+    if a.len() != b.len() {
+        let blocks = a.len().max(b.len()) as u64 / bs as u64;
+        return Some((offset.value, offset.value + blocks.max(1) - 1));
+    }
+
+    let mut differ = a
+        .chunks(bs)
+        .zip(b.chunks(bs))
+        .enumerate()
+        .filter(|(_, (a, b))| a != b)
+        .map(|(i, _)| offset.value + i as u64);
+    let first = differ.next()?;
+    Some((first, differ.next_back().unwrap_or(first)))
+}
+
 /// Implement XTS encryption
 /// See: https://en.wikipedia.org/wiki/Disk_encryption_theory#XEX-based_\
 /// tweaked-codebook_mode_with_ciphertext_stealing_(XTS)
@@ -1791,32 +3047,40 @@ pub struct Upstairs {
      * queue was not a flush.
      */
     need_flush: Mutex<bool>,
+
+    /*
+     * Set once the first downstairs tells us if it is serving a read-only
+     * region.  Every other downstairs must then match it.  A read-only
+     * upstairs refuses writes and flushes from the guest.
+     */
+    read_only: Mutex<Option<bool>>,
+
+    /*
+     * How long we wait for, and how much we hold for, a downstairs that
+     * has gone Offline.
+     */
+    reconnect: ReconnectPolicy,
+
+    /*
+     * How many times we send an IO again after a downstairs returns an
+     * error for it.
+     */
+    io_retries: u32,
+
+    /*
+     * How long a read waits on the downstairs it was sent to before we
+     * send it to another one as well.
+     */
+    read_timeout: Duration,
 }
 
 impl Upstairs {
-    pub fn default() -> Arc<Self> {
-        let opts = CrucibleOpts {
-            target: vec![],
-            lossy: false,
-            key: None,
-        };
-        Self::new(
-            &opts,
-            RegionDefinition::default(),
-            Arc::new(Guest::default()),
-        )
-    }
-
     pub fn new(
         opt: &CrucibleOpts,
         def: RegionDefinition,
         guest: Arc<Guest>,
     ) -> Arc<Upstairs> {
-        /*
-         * XXX Make sure we have three and only three downstairs
-         */
-        #[cfg(not(test))]
-        assert_eq!(opt.target.len(), 3);
+        let (write_quorum, read_quorum) = opt.quorum().unwrap();
 
         // create an encryption context if a key is supplied.
         let encryption_context = opt.key_bytes().map(|key| {
@@ -1835,30 +3099,82 @@ impl Upstairs {
             )
         });
 
+        let mut downstairs =
+            Downstairs::new(opt.target.len(), write_quorum, read_quorum);
+        downstairs.read_policy =
+            opt.read_policy.unwrap_or(ReadPolicy::LeastOutstanding);
+        if opt.paranoid_reads || opt.paranoid_fail {
+            println!("Paranoid reads, every read is compared");
+            downstairs.read_policy = ReadPolicy::All;
+            downstairs.paranoid = true;
+            downstairs.paranoid_fail = opt.paranoid_fail;
+        }
+        guest.backlog.set_limit(
+            opt.write_backlog.unwrap_or(DEFAULT_WRITE_BACKLOG_BYTES),
+        );
+        downstairs.backlog = guest.backlog.clone();
+
         Arc::new(Upstairs {
             active: Mutex::new(false),
             uuid: Uuid::new_v4(), // XXX get from Nexus?
             guest,
-            downstairs: Mutex::new(Downstairs::default()),
+            downstairs: Mutex::new(downstairs),
             flush_info: Mutex::new(FlushInfo::new()),
             ddef: Mutex::new(def),
             encryption_context,
             need_flush: Mutex::new(false),
+            read_only: Mutex::new(None),
+            reconnect: opt.reconnect_policy(),
+            io_retries: opt.io_retries.unwrap_or(DEFAULT_IO_RETRIES),
+            read_timeout: Duration::from_secs(
+                opt.read_timeout.unwrap_or(DEFAULT_READ_TIMEOUT_SECS),
+            ),
         })
     }
 
     fn set_active(&self) {
         let mut active = self.active.lock().unwrap();
         *active = true;
+        self.guest.backlog.set_closed(false);
     }
 
     fn set_inactive(&self) {
         let mut active = self.active.lock().unwrap();
         *active = false;
+        self.guest.backlog.set_closed(true);
+    }
+
+    fn is_active(&self) -> bool {
+        *self.active.lock().unwrap()
+    }
+
+    /*
+     * Record if a downstairs reported it is serving a read-only region.
+     * It's an error for a downstairs to disagree with what we have
+     * already heard from the others.
+     */
+    fn set_read_only(&self, client_id: u8, read_only: bool) -> Result<()> {
+        let mut ro = self.read_only.lock().unwrap();
+        match *ro {
+            None => {
+                println!("[{}] Setting read-only to {}", client_id, read_only);
+                *ro = Some(read_only);
+            }
+            Some(expected) if expected != read_only => {
+                bail!(
+                    "[{}] downstairs read-only {} does not match {}",
+                    client_id,
+                    read_only,
+                    expected
+                );
+            }
+            Some(_) => {}
+        }
+        Ok(())
     }
 
-    fn is_active(&self) -> bool {
-        *self.active.lock().unwrap()
+    fn is_read_only(&self) -> bool {
+        self.read_only.lock().unwrap().unwrap_or(false)
     }
 
     /*
@@ -1903,6 +3219,9 @@ impl Upstairs {
         if !self.is_active() {
             crucible_bail!(UpstairsInactive);
         }
+        if self.is_read_only() {
+            crucible_bail!(ModifyingReadOnlyRegion);
+        }
 
         /*
          * Lock first the guest_work struct where this new job will go,
@@ -1979,6 +3298,9 @@ impl Upstairs {
         if !self.is_active() {
             crucible_bail!(UpstairsInactive);
         }
+        if self.is_read_only() {
+            crucible_bail!(ModifyingReadOnlyRegion);
+        }
 
         /*
          * Get the next ID for the guest work struct we will make at the
@@ -2096,6 +3418,9 @@ impl Upstairs {
         if !self.is_active() {
             crucible_bail!(UpstairsInactive);
         }
+        if self.is_read_only() {
+            crucible_bail!(ModifyingReadOnlyRegion);
+        }
 
         /*
          * Get the next ID for the guest work struct we will make at the
@@ -2104,6 +3429,14 @@ impl Upstairs {
          */
         let mut gw = self.guest.guest_work.lock().unwrap();
         let mut downstairs = self.downstairs.lock().unwrap();
+
+        /*
+         * A read can't be sent to a downstairs that missed a write, and
+         * if that is all we have, it would never be answered.
+         */
+        if !downstairs.has_reader() {
+            crucible_bail!(IoError, "no downstairs has every write to read");
+        }
         self.set_flush_need();
         /*
          * Given the offset and buffer size, figure out what extent and
@@ -2205,6 +3538,7 @@ impl Upstairs {
             DsState::Active => DsState::Offline,
             DsState::Replay => DsState::Offline,
             DsState::Offline => DsState::Offline,
+            DsState::Failed => DsState::Failed,
             DsState::_Migrating => DsState::Failed,
             _ => {
                 /*
@@ -2220,13 +3554,23 @@ impl Upstairs {
             client_id, current, new_state,
         );
         ds.ds_state[client_id as usize] = new_state;
+        ds.reset_window(client_id);
+        if new_state == DsState::Offline
+            && ds.ds_offline_since[client_id as usize].is_none()
+        {
+            ds.ds_offline_since[client_id as usize] = Some(Instant::now());
+        }
 
         /*
          * Mark any in progress jobs since the last good flush back to New,
          * as we are now disconnected from this downstairs and will need to
          * replay (or eventually discard) any work that it still needs to do.
+         * A downstairs that is out of date was never sent any, and will
+         * be repaired instead.
          */
-        ds.re_new(client_id);
+        if !ds.ds_out_of_date[client_id as usize] {
+            ds.re_new(client_id);
+        }
     }
 
     /*
@@ -2238,6 +3582,7 @@ impl Upstairs {
         if ds.ds_state[client_id as usize] == DsState::Replay {
             println!("[{}] Transition from Replay to Active", client_id);
             ds.ds_state[client_id as usize] = DsState::Active;
+            ds.ds_offline_since[client_id as usize] = None;
             return true;
         }
         false
@@ -2316,11 +3661,26 @@ impl Upstairs {
         let mut ds = self.downstairs.lock().unwrap();
 
         ds.ds_state.iter_mut().for_each(|ds_state| {
-            println!("Transition from {:?} to {:?}", *ds_state, new_state,);
+            let old_state = *ds_state;
             match new_state {
-                DsState::Active => {
-                    assert_eq!(*ds_state, DsState::WaitQuorum);
-                    *ds_state = new_state;
+                /*
+                 * Only the downstairs waiting for us take part in going
+                 * active.  Any others are brought up to date later, see
+                 * catch_up().
+                 */
+                DsState::Verifying => {
+                    if *ds_state == DsState::WaitQuorum {
+                        *ds_state = new_state;
+                    }
+                }
+                /*
+                 * A downstairs that went away while we were repairing
+                 * has already moved on.
+                 */
+                DsState::Active | DsState::FailedRepair => {
+                    if *ds_state == DsState::Verifying {
+                        *ds_state = new_state;
+                    }
                 }
                 DsState::Deactivated => {
                     *ds_state = new_state;
@@ -2332,9 +3692,293 @@ impl Upstairs {
                     );
                 }
             }
+            if *ds_state != old_state {
+                println!("Transition from {:?} to {:?}", old_state, new_state);
+            }
+        });
+    }
+
+    /*
+     * Compare the extent versions from every downstairs we are verifying,
+     * and return each extent that does not match, along with which
+     * downstairs has the copy to keep and which need it.
+     */
+    fn reconcile_plan(&self) -> Vec<(u64, ExtentFix)> {
+        let ds = self.downstairs.lock().unwrap();
+        let clients = ds.clients_in(DsState::Verifying);
+        let extent_count = ds.ds_versions[clients[0]].len();
+
+        (0..extent_count)
+            .filter_map(|eid| {
+                let versions = clients
+                    .iter()
+                    .map(|cid| ds.ds_versions[*cid][eid])
+                    .collect::<Vec<ExtentVersion>>();
+                reconcile_extent(&versions).map(|fix| {
+                    let fix = ExtentFix {
+                        source: clients[fix.source],
+                        repair: fix
+                            .repair
+                            .iter()
+                            .map(|i| clients[*i])
+                            .collect(),
+                    };
+                    (eid as u64, fix)
+                })
+            })
+            .collect()
+    }
+
+    /*
+     * Every extent where a downstairs we are catching up does not have
+     * the same version as the active downstairs we copy from, or where
+     * the active copy is dirty, as the same version could then hold
+     * different writes.
+     */
+    fn catch_up_plan(
+        &self,
+        source: usize,
+        client_id: usize,
+    ) -> Vec<(u64, ExtentFix)> {
+        let ds = self.downstairs.lock().unwrap();
+        ds.ds_versions[source]
+            .iter()
+            .zip(ds.ds_versions[client_id].iter())
+            .enumerate()
+            .filter(|(_, (ours, theirs))| ours.dirty || ours != theirs)
+            .map(|(eid, _)| {
+                let fix = ExtentFix {
+                    source,
+                    repair: vec![client_id],
+                };
+                (eid as u64, fix)
+            })
+            .collect()
+    }
+
+    /*
+     * The downstairs in fix.repair now have the same copy of this extent
+     * as fix.source.  With clean, that copy is clean everywhere, the
+     * source included.
+     */
+    fn extent_repaired(&self, eid: u64, fix: &ExtentFix, clean: bool) {
+        let mut ds = self.downstairs.lock().unwrap();
+        let mut version = ds.ds_versions[fix.source][eid as usize];
+        if clean {
+            version.dirty = false;
+            ds.ds_versions[fix.source][eid as usize] = version;
+        }
+        for client_id in fix.repair.iter() {
+            ds.ds_versions[*client_id][eid as usize] = version;
+        }
+    }
+
+    /*
+     * Any downstairs not taking part in going active is out of date from
+     * here on.  Return how many there are.
+     */
+    fn mark_out_of_date(&self) -> usize {
+        let mut ds = self.downstairs.lock().unwrap();
+        let mut count = 0;
+        for cid in 0..ds.ds_state.len() {
+            /*
+             * This may be a second try, after a reconcile that failed.
+             */
+            let out_of_date = ds.ds_state[cid] != DsState::Verifying;
+            if out_of_date {
+                println!("[{}] is out of date", cid);
+                count += 1;
+            }
+            ds.ds_out_of_date[cid] = out_of_date;
+        }
+        count
+    }
+
+    fn is_out_of_date(&self, client_id: u8) -> bool {
+        self.downstairs.lock().unwrap().ds_out_of_date[client_id as usize]
+    }
+
+    /*
+     * Start to bring this downstairs up to date.  Until we know better,
+     * every extent may differ, and so may any extent a write we have not
+     * finished goes to.  It can be sent jobs from here on.
+     */
+    fn catch_up_start(&self, client_id: u8) {
+        self.ds_transition(client_id, DsState::Verifying);
+
+        let mut ds = self.downstairs.lock().unwrap();
+        let cid = client_id as usize;
+        let extent_count = ds.ds_versions[cid].len() as u64;
+        let missed = ds
+            .active
+            .values()
+            .filter_map(|job| match &job.work {
+                IOop::Write {
+                    dependencies: _,
+                    eid,
+                    offset: _,
+                    data: _,
+                } => Some(*eid),
+                _ => None,
+            })
+            .collect();
+        ds.ds_first_job[cid] = ds.next_id;
+        ds.ds_catch_up[cid] = Some(CatchUp {
+            running: true,
+            pending: (0..extent_count).collect(),
+            missed,
+            copying: None,
         });
     }
 
+    /*
+     * Now we know what differs, the extents we copy to this downstairs
+     * are the ones in the plan, and any it has missed a write to since
+     * we started.  Return how many there are.
+     */
+    fn catch_up_pending(
+        &self,
+        client_id: u8,
+        plan: &[(u64, ExtentFix)],
+    ) -> usize {
+        let mut ds = self.downstairs.lock().unwrap();
+        let c = ds.ds_catch_up[client_id as usize].as_mut().unwrap();
+        let mut pending: BTreeSet<u64> =
+            plan.iter().map(|(eid, _)| *eid).collect();
+        pending.extend(c.missed.iter());
+        c.pending = pending;
+        c.pending.len()
+    }
+
+    /*
+     * Pick the next extent to copy to this downstairs, and hold back IO
+     * to it from here on.  Return None when there are no more.
+     */
+    fn catch_up_next(&self, client_id: u8) -> Result<Option<u64>> {
+        let mut ds = self.downstairs.lock().unwrap();
+        let state = ds.ds_state[client_id as usize];
+        if state != DsState::Verifying {
+            bail!("[{}] is {:?}, not being caught up", client_id, state);
+        }
+        let next_id = ds.next_id;
+        let c = ds.ds_catch_up[client_id as usize].as_mut().unwrap();
+        let eid = match c.pending.iter().next() {
+            Some(eid) => *eid,
+            None => return Ok(None),
+        };
+        c.copying = Some((eid, next_id));
+        Ok(Some(eid))
+    }
+
+    fn extent_busy(&self, client_id: u8) -> bool {
+        self.downstairs.lock().unwrap().extent_busy(client_id)
+    }
+
+    /*
+     * This extent has been copied, so IO to it goes to this downstairs
+     * as well from now on, starting with what we held back.
+     */
+    fn catch_up_copied(&self, client_id: u8, eid: u64) {
+        let mut ds = self.downstairs.lock().unwrap();
+        let c = ds.ds_catch_up[client_id as usize].as_mut().unwrap();
+        c.pending.remove(&eid);
+        c.copying = None;
+    }
+
+    /*
+     * Catching up this downstairs failed.  Let go of the IO we held back,
+     * and of every job it has not done, as it gets nothing more until we
+     * try again.  Return the jobs that are now ready to ack.
+     */
+    fn catch_up_failed(&self, client_id: u8) -> Vec<u64> {
+        let mut ds = self.downstairs.lock().unwrap();
+        let cid = client_id as usize;
+        ds.ds_catch_up[cid] = Some(CatchUp::default());
+        if ds.ds_state[cid] == DsState::Verifying {
+            println!("[{}] Transition from Verifying to FailedRepair", cid);
+            ds.ds_state[cid] = DsState::FailedRepair;
+        }
+        ds.skip_client(client_id)
+    }
+
+    /*
+     * We will not try to catch up this downstairs again.
+     */
+    fn catch_up_stopped(&self, client_id: u8) {
+        self.downstairs.lock().unwrap().ds_catch_up[client_id as usize] = None;
+    }
+
+    /*
+     * Return true if a catch_up() task has this downstairs, either
+     * working on it or waiting to try again.
+     */
+    fn catching_up(&self, client_id: u8) -> bool {
+        self.downstairs.lock().unwrap().ds_catch_up[client_id as usize]
+            .is_some()
+    }
+
+    /*
+     * This downstairs now matches the active ones, so it can go active
+     * and take all IO.
+     */
+    fn caught_up(&self, client_id: u8) {
+        let mut ds = self.downstairs.lock().unwrap();
+        assert_eq!(ds.ds_state[client_id as usize], DsState::Verifying);
+        println!("[{}] Transition from Verifying to Active", client_id);
+        ds.ds_state[client_id as usize] = DsState::Active;
+        ds.ds_out_of_date[client_id as usize] = false;
+        ds.ds_missed_write[client_id as usize] = false;
+        ds.ds_catch_up[client_id as usize] = None;
+        ds.downstairs_errors.remove(&client_id);
+    }
+
+    /*
+     * We are active, but not every downstairs is.
+     */
+    fn is_degraded(&self) -> bool {
+        self.is_active() && !self.all_ds_state_match(DsState::Active)
+    }
+
+    /**
+     * Send reads that have waited too long to another downstairs.
+     * Return true if any read has moved, so the downstairs tasks need to
+     * look for new work.
+     */
+    fn read_check(&self) -> bool {
+        self.downstairs
+            .lock()
+            .unwrap()
+            .read_timeouts(self.read_timeout, Instant::now())
+    }
+
+    /*
+     * If this downstairs is Offline and past our reconnect policy, fail
+     * it and let go of the work we were holding for it.  Return the jobs
+     * that are now ready to ack.
+     */
+    fn offline_check(&self, client_id: u8) -> Vec<u64> {
+        let mut ds = self.downstairs.lock().unwrap();
+        if ds.offline_exceeded(client_id, &self.reconnect, Instant::now()) {
+            ds.fail_client(client_id)
+        } else {
+            Vec::new()
+        }
+    }
+
+    /*
+     * Once the downstairs all agree, take our flush numbers from them.
+     * The next flush has to be past any flush an extent has seen.
+     */
+    fn set_flush_numbers(&self) {
+        let ds = self.downstairs.lock().unwrap();
+        let mut fi = self.flush_info.lock().unwrap();
+        let source = ds.clients_in(DsState::Verifying)[0];
+        fi.flush_numbers =
+            ds.ds_versions[source].iter().map(|v| v.flush).collect();
+        fi.next_flush = fi.flush_numbers.iter().max().unwrap_or(&0) + 1;
+        println!("Next flush: {}", fi.next_flush);
+    }
+
     /*
      * Check the region information for a downstairs and decide if we should
      * allow this downstairs to be added.
@@ -2418,12 +4062,27 @@ impl Upstairs {
         result: Result<(), CrucibleError>,
     ) -> Result<bool> {
         let mut work = self.downstairs.lock().unwrap();
+        let is_read = work.is_read(ds_id)?;
+
+        /*
+         * A transient error may not last, so send the job again before we
+         * count it, until we have done so io_retries times.  A read that
+         * has another downstairs to go to goes there instead.
+         */
+        if let Err(err) = &result {
+            if *err != CrucibleError::UpstairsInactive
+                && !(is_read && work.move_read(ds_id))
+                && err.is_transient()
+                && work.retry(ds_id, client_id, self.io_retries)?
+            {
+                return Ok(false);
+            }
+        }
 
         // Mark this ds_id for the client_id as completed.
-        let notify_guest =
+        let mut notify_guest =
             work.complete(ds_id, client_id, data, result.clone())?;
 
-        // Mark this downstairs as bad if this was a write or flush
         if let Some(err) = result.err() {
             if err == CrucibleError::UpstairsInactive {
                 drop(work);
@@ -2434,26 +4093,44 @@ impl Upstairs {
                 );
                 self.ds_transition(client_id, DsState::Deactivated);
                 self.set_inactive();
+            } else if !is_read && !work.can_lose(client_id) {
+                /*
+                 * Without this downstairs the others can't make a write
+                 * quorum, so failing it would stop all IO.  The guest gets
+                 * the error for this job, and it keeps getting the other
+                 * writes, but no reads until it is caught up.
+                 */
+                println!(
+                    "[{}] job {} failed: {}, kept for the write quorum",
+                    client_id, ds_id, err
+                );
+                work.downstairs_errors.remove(&client_id);
+                work.ds_missed_write[client_id as usize] = true;
+            } else if !is_read {
+                /*
+                 * A write or flush that failed, after all its retries if
+                 * the error was one to retry, means this downstairs no
+                 * longer matches the others.  Give up on it; cmd_loop
+                 * will see it is Failed and hang up, and it is caught up
+                 * when it reconnects.
+                 */
+                println!("[{}] job {} failed: {}", client_id, ds_id, err);
+                if !work.fail_client(client_id).is_empty() {
+                    notify_guest = true;
+                }
             }
+        } else if work.ds_missed_write[client_id as usize]
+            && work.ds_state[client_id as usize] == DsState::Active
+            && work.can_lose(client_id)
+        {
             /*
-             * After work.complete, it's possible that the job is gone
-             * due to a retire check
+             * We kept this one for the write quorum after it missed a
+             * write.  Now the others can do without it, give up on it so
+             * it is caught up, and can be read from again.
              */
-            else if let Some(job) = work.active.get_mut(&ds_id) {
-                if matches!(
-                    job.work,
-                    IOop::Write {
-                        dependencies: _,
-                        eid: _,
-                        data: _,
-                        offset: _
-                    } | IOop::Flush {
-                        dependencies: _,
-                        flush_number: _
-                    }
-                ) {
-                    self.ds_transition(client_id, DsState::Failed);
-                }
+            println!("[{}] missed a write, no longer needed", client_id);
+            if !work.fail_client(client_id).is_empty() {
+                notify_guest = true;
             }
         }
 
@@ -2520,13 +4197,14 @@ enum DsState {
      */
     Disconnected,
     /*
-     * Comparing downstairs for consistency.
+     * Comparing downstairs for consistency, and repairing any extents
+     * that do not match.
      */
-    _Verifying,
+    Verifying,
     /*
      * Failed when attempting to make consistent.
      */
-    _FailedRepair,
+    FailedRepair,
     /*
      * Ready for and/or currently receiving IO
      */
@@ -2571,7 +4249,8 @@ struct DownstairsIO {
      * hash value is the current state of the IO request with respect to the
      * upstairs.
      * The length and keys on this hashmap will be used to determine which
-     * downstairs will receive the IO request.
+     * downstairs will receive the IO request.  Downstairs::enqueue
+     * adds an entry for every downstairs.
      * XXX Determine if it is required for all downstairs to get an entry
      * or if by not putting a downstairs in the hash, if that is valid.
      */
@@ -2584,6 +4263,21 @@ struct DownstairsIO {
      * If the operation is a Read, this holds the resulting buffer
      */
     data: Option<Bytes>,
+    /*
+     * For each downstairs this job has failed on, how many times we have
+     * sent it again, and when it can next be sent.
+     */
+    retries: HashMap<u8, (u32, Instant)>,
+    /*
+     * When each downstairs was given this job, or last sent it.
+     */
+    issued: HashMap<u8, Instant>,
+    /*
+     * For a read, the downstairs the data came from, and if another one
+     * has since returned something different.
+     */
+    data_from: Option<u8>,
+    mismatch: bool,
 }
 
 impl DownstairsIO {
@@ -2601,6 +4295,46 @@ impl DownstairsIO {
 
         wc
     }
+
+    /*
+     * The bytes of write data this job carries.
+     */
+    fn write_bytes(&self) -> u64 {
+        match &self.work {
+            IOop::Write {
+                dependencies: _,
+                eid: _,
+                offset: _,
+                data,
+            } => data.len() as u64,
+            _ => 0,
+        }
+    }
+
+    /*
+     * Return true if this job changes this extent, which a flush does to
+     * every extent.
+     */
+    fn touches(&self, extent: u64) -> bool {
+        match &self.work {
+            IOop::Write {
+                dependencies: _,
+                eid,
+                offset: _,
+                data: _,
+            } => *eid == extent,
+            IOop::Flush {
+                dependencies: _,
+                flush_number: _,
+            } => true,
+            IOop::Read {
+                dependencies: _,
+                eid: _,
+                offset: _,
+                num_blocks: _,
+            } => false,
+        }
+    }
 }
 
 /*
@@ -2671,26 +4405,30 @@ impl fmt::Display for IOState {
 
 #[derive(Debug)]
 struct IOStateCount {
-    new: [u32; 3],
-    in_progress: [u32; 3],
-    done: [u32; 3],
-    skipped: [u32; 3],
-    error: [u32; 3],
+    new: Vec<u32>,
+    in_progress: Vec<u32>,
+    done: Vec<u32>,
+    skipped: Vec<u32>,
+    error: Vec<u32>,
 }
 
 impl IOStateCount {
-    fn new() -> IOStateCount {
+    fn new(replicas: usize) -> IOStateCount {
         IOStateCount {
-            new: [0; 3],
-            in_progress: [0; 3],
-            done: [0; 3],
-            skipped: [0; 3],
-            error: [0; 3],
+            new: vec![0; replicas],
+            in_progress: vec![0; replicas],
+            done: vec![0; replicas],
+            skipped: vec![0; replicas],
+            error: vec![0; replicas],
         }
     }
 
     fn show_all(&mut self) {
-        println!("   STATES      DS:0   DS:1   DS:2   TOTAL");
+        print!("   STATES      ");
+        for cid in 0..self.new.len() {
+            print!("DS:{}   ", cid);
+        }
+        println!("TOTAL");
         self.show(IOState::New);
         self.show(IOState::InProgress);
         self.show(IOState::Done);
@@ -2700,31 +4438,31 @@ impl IOStateCount {
     }
 
     fn show(&mut self, state: IOState) {
-        let state_stat;
+        let state_stat: &[u32];
         match state {
             IOState::New => {
-                state_stat = self.new;
+                state_stat = &self.new;
                 print!("    New        ");
             }
             IOState::InProgress => {
-                state_stat = self.in_progress;
+                state_stat = &self.in_progress;
                 print!("    Sent       ");
             }
             IOState::Done => {
-                state_stat = self.done;
+                state_stat = &self.done;
                 print!("    Done       ");
             }
             IOState::Skipped => {
-                state_stat = self.skipped;
+                state_stat = &self.skipped;
                 print!("    Skipped    ");
             }
             IOState::Error(_) => {
-                state_stat = self.error;
+                state_stat = &self.error;
                 print!("    Error      ");
             }
         }
         let mut sum = 0;
-        for ds_stat in state_stat {
+        for ds_stat in state_stat.iter() {
             print!("{:4}   ", ds_stat);
             sum += ds_stat;
         }
@@ -2732,8 +4470,8 @@ impl IOStateCount {
     }
 
     pub fn incr(&mut self, state: &IOState, cid: u8) {
-        assert!(cid < 3);
         let cid = cid as usize;
+        assert!(cid < self.new.len());
         match state {
             IOState::New => {
                 self.new[cid] += 1;
@@ -2884,6 +4622,8 @@ enum BlockOp {
     QueryTotalSize { data: Arc<Mutex<u64>> },
     QueryUpstairsActive { data: Arc<Mutex<bool>> },
     QueryUpstairsUuid { data: Arc<Mutex<Uuid>> },
+    QueryReadOnly { data: Arc<Mutex<bool>> },
+    QueryDegraded { data: Arc<Mutex<bool>> },
     // Begin testing options.
     QueryExtentSize { data: Arc<Mutex<Block>> },
     QueryWorkQueue { data: Arc<Mutex<usize>> },
@@ -3208,23 +4948,151 @@ impl BlockReqWaiter {
         Self { recv }
     }
 
-    pub fn block_wait(&mut self) -> Result<(), CrucibleError> {
-        match self.recv.recv() {
-            Ok(v) => v,
-            Err(_) => crucible_bail!(RecvDisconnected),
-        }
+    pub fn block_wait(&mut self) -> Result<(), CrucibleError> {
+        match self.recv.recv() {
+            Ok(v) => v,
+            Err(_) => crucible_bail!(RecvDisconnected),
+        }
+    }
+
+    pub fn try_wait(&mut self) -> Option<Result<(), CrucibleError>> {
+        match self.recv.try_recv() {
+            Ok(v) => Some(v),
+            Err(e) => match e {
+                std_mpsc::TryRecvError::Empty => None,
+                std_mpsc::TryRecvError::Disconnected => {
+                    Some(Err(CrucibleError::RecvDisconnected))
+                }
+            },
+        }
+    }
+}
+
+/*
+ * The bytes of write data the guest has given us that we still hold.  A
+ * write is queued from when Guest::write takes it until the upstairs
+ * picks it up, and then held in the downstairs work queue until it is
+ * retired, which can be well after it was acked if one downstairs is
+ * behind.  The guest waits here once that has grown past the limit.
+ */
+#[derive(Debug)]
+struct WriteBacklog {
+    bytes: Mutex<BacklogBytes>,
+    retired: Condvar,
+}
+
+#[derive(Debug)]
+struct BacklogBytes {
+    queued: u64,
+    held: u64,
+    limit: u64,
+    /*
+     * Set when the upstairs stops taking IO, so a write waiting for room
+     * would never get it.
+     */
+    closed: bool,
+}
+
+impl BacklogBytes {
+    /*
+     * A write bigger than the limit still goes in once nothing else is
+     * waiting ahead of it.
+     */
+    fn has_room(&self, len: u64) -> bool {
+        let total = self.queued + self.held;
+        total == 0 || total + len <= self.limit
+    }
+}
+
+impl WriteBacklog {
+    fn new() -> WriteBacklog {
+        WriteBacklog {
+            bytes: Mutex::new(BacklogBytes {
+                queued: 0,
+                held: 0,
+                limit: DEFAULT_WRITE_BACKLOG_BYTES,
+                closed: false,
+            }),
+            retired: Condvar::new(),
+        }
+    }
+
+    fn set_limit(&self, limit: u64) {
+        self.bytes.lock().unwrap().limit = limit;
+        self.retired.notify_all();
+    }
+
+    /*
+     * Wait until there is room, then count len more bytes as queued.  If
+     * the upstairs stops taking IO first, return UpstairsInactive.
+     */
+    fn reserve(&self, len: u64) -> Result<(), CrucibleError> {
+        let mut bytes = self.bytes.lock().unwrap();
+        loop {
+            if bytes.closed {
+                return Err(CrucibleError::UpstairsInactive);
+            }
+            if bytes.has_room(len) {
+                break;
+            }
+            bytes = self.retired.wait(bytes).unwrap();
+        }
+        bytes.queued += len;
+        Ok(())
+    }
+
+    /*
+     * The upstairs has stopped, or started again, taking IO.  Any write
+     * waiting for room has to look again.
+     */
+    fn set_closed(&self, closed: bool) {
+        self.bytes.lock().unwrap().closed = closed;
+        self.retired.notify_all();
+    }
+
+    /*
+     * Count len more bytes as queued if there is room for them, and
+     * return false if there is not.
+     */
+    fn try_reserve(&self, len: u64) -> bool {
+        let mut bytes = self.bytes.lock().unwrap();
+        if !bytes.has_room(len) {
+            return false;
+        }
+        bytes.queued += len;
+        true
+    }
+
+    /*
+     * The upstairs has taken a write off the guest queue.
+     */
+    fn dequeue(&self, len: u64) {
+        self.bytes.lock().unwrap().queued -= len;
+        self.retired.notify_all();
+    }
+
+    /*
+     * A write job went on, or was retired from, the downstairs work
+     * queue.
+     */
+    fn hold(&self, len: u64) {
+        self.bytes.lock().unwrap().held += len;
     }
 
-    pub fn try_wait(&mut self) -> Option<Result<(), CrucibleError>> {
-        match self.recv.try_recv() {
-            Ok(v) => Some(v),
-            Err(e) => match e {
-                std_mpsc::TryRecvError::Empty => None,
-                std_mpsc::TryRecvError::Disconnected => {
-                    Some(Err(CrucibleError::RecvDisconnected))
-                }
-            },
+    fn release(&self, len: u64) {
+        if len == 0 {
+            return;
         }
+        self.bytes.lock().unwrap().held -= len;
+        self.retired.notify_all();
+    }
+
+    /*
+     * The bytes queued and held, in that order.
+     */
+    fn counts(&self) -> (u64, u64) {
+        let bytes = self.bytes.lock().unwrap();
+        (bytes.queued, bytes.held)
     }
 }
 
@@ -3286,6 +5154,12 @@ pub struct Guest {
      * required downstairs operations are completed.
      */
     guest_work: Mutex<GuestWork>,
+
+    /*
+     * The write data we have taken from the guest and not yet retired.
+     * The Upstairs shares this with its Downstairs.
+     */
+    backlog: Arc<WriteBacklog>,
 }
 
 /*
@@ -3312,6 +5186,7 @@ impl Guest {
                 next_gw_id: 1,
                 completed: AllocRingBuffer::with_capacity(2048),
             }),
+            backlog: Arc::new(WriteBacklog::new()),
         }
     }
 
@@ -3333,6 +5208,9 @@ impl Guest {
     async fn recv(&self) -> BlockReq {
         loop {
             if let Some(req) = self.reqs.lock().unwrap().pop_front() {
+                if let BlockOp::Write { offset: _, data } = &req.op {
+                    self.backlog.dequeue(data.len() as u64);
+                }
                 return req;
             }
             self.notify.notified().await;
@@ -3379,11 +5257,46 @@ impl Guest {
         Ok(self.send(rio))
     }
 
+    /*
+     * A write waits here while we hold more write data than the backlog
+     * limit allows, until enough of it has been retired.  If the upstairs
+     * stops taking IO while it waits, it gets UpstairsInactive.
+     */
     pub fn write(
         &self,
         offset: Block,
         data: Bytes,
     ) -> Result<BlockReqWaiter, CrucibleError> {
+        self.check_write(offset, &data)?;
+        self.backlog.reserve(data.len() as u64)?;
+
+        let wio = BlockOp::Write { offset, data };
+        Ok(self.send(wio))
+    }
+
+    /*
+     * Like write, but when the backlog is full return UpstairsBusy
+     * instead of waiting.
+     */
+    pub fn try_write(
+        &self,
+        offset: Block,
+        data: Bytes,
+    ) -> Result<BlockReqWaiter, CrucibleError> {
+        self.check_write(offset, &data)?;
+        if !self.backlog.try_reserve(data.len() as u64) {
+            crucible_bail!(UpstairsBusy);
+        }
+
+        let wio = BlockOp::Write { offset, data };
+        Ok(self.send(wio))
+    }
+
+    fn check_write(
+        &self,
+        offset: Block,
+        data: &Bytes,
+    ) -> Result<(), CrucibleError> {
         if !self.is_active() {
             return Err(CrucibleError::UpstairsInactive);
         }
@@ -3398,8 +5311,7 @@ impl Guest {
             crucible_bail!(BlockSizeMismatch);
         }
 
-        let wio = BlockOp::Write { offset, data };
-        Ok(self.send(wio))
+        Ok(())
     }
 
     /*
@@ -3509,6 +5421,38 @@ impl Guest {
         return Ok(*data.lock().map_err(|_| CrucibleError::DataLockError)?);
     }
 
+    /*
+     * Returns true if the downstairs are serving a read-only region, in
+     * which case all writes and flushes will be refused.
+     */
+    pub fn query_read_only(&self) -> Result<bool, CrucibleError> {
+        if !self.is_active() {
+            return Err(CrucibleError::UpstairsInactive);
+        }
+
+        let data = Arc::new(Mutex::new(false));
+        let ro_query = BlockOp::QueryReadOnly { data: data.clone() };
+        self.send(ro_query).block_wait()?;
+        return Ok(*data.lock().map_err(|_| CrucibleError::DataLockError)?);
+    }
+
+    /*
+     * Returns true if we are active but not every downstairs is, either
+     * because it was missing when we went active and has not yet been
+     * brought up to date, or because it has gone away since.  IO still
+     * works, with fewer copies.
+     */
+    pub fn query_degraded(&self) -> Result<bool, CrucibleError> {
+        if !self.is_active() {
+            return Err(CrucibleError::UpstairsInactive);
+        }
+
+        let data = Arc::new(Mutex::new(false));
+        let degraded_query = BlockOp::QueryDegraded { data: data.clone() };
+        self.send(degraded_query).block_wait()?;
+        return Ok(*data.lock().map_err(|_| CrucibleError::DataLockError)?);
+    }
+
     pub fn query_extent_size(&self) -> Result<Block, CrucibleError> {
         if !self.is_active() {
             return Err(CrucibleError::UpstairsInactive);
@@ -3580,15 +5524,16 @@ impl Default for Guest {
 }
 
 pub struct Target {
-    #[allow(dead_code)]
     target: SocketAddrV4,
     ds_work_tx: watch::Sender<u64>,
     ds_active_tx: watch::Sender<bool>,
+    ds_repair_tx: mpsc::Sender<RepairRequest>,
 }
 
 #[derive(Debug)]
 struct Condition {
     target: SocketAddrV4,
+    client_id: u8,
     connected: bool,
 }
 
@@ -3750,6 +5695,14 @@ async fn process_new_io(
             *data.lock().unwrap() = up.uuid;
             let _ = req.send.send(Ok(()));
         }
+        BlockOp::QueryReadOnly { data } => {
+            *data.lock().unwrap() = up.is_read_only();
+            let _ = req.send.send(Ok(()));
+        }
+        BlockOp::QueryDegraded { data } => {
+            *data.lock().unwrap() = up.is_degraded();
+            let _ = req.send.send(Ok(()));
+        }
         // Testing options
         BlockOp::QueryExtentSize { data } => {
             // Yes, test only
@@ -3797,10 +5750,381 @@ fn stat_update(up: &Arc<Upstairs>, msg: &str) {
 }
 
 /*
- * This task will loop forever and wait for three downstairs to get into the
+ * Send one extent repair message to a downstairs, and wait for the
+ * answer.
+ */
+async fn repair_request(
+    dst: &Target,
+    client_id: usize,
+    message: Message,
+) -> Result<Message> {
+    let (reply, answer) = oneshot::channel();
+    if dst
+        .ds_repair_tx
+        .send(RepairRequest { message, reply })
+        .await
+        .is_err()
+    {
+        bail!("[{}] downstairs task has gone away", client_id);
+    }
+
+    match tokio::time::timeout(Duration::from_secs(50), answer).await {
+        Err(_) => bail!("[{}] timed out waiting on repair", client_id),
+        Ok(Err(_)) => bail!("[{}] connection lost during repair", client_id),
+        Ok(Ok(m)) => Ok(m),
+    }
+}
+
+/*
+ * With the downstairs that are connected and waiting, compare the
+ * versions they reported for each extent.  Where they disagree, decide
+ * which copy is the right one (see reconcile_extent()), read it from that
+ * downstairs, and write it to the others.  We must not go active until
+ * this is done, or reads could return different data depending on which
+ * downstairs answers.
+ */
+async fn reconcile(up: &Arc<Upstairs>, dst: &[Target]) -> Result<()> {
+    up.ds_transition_all(DsState::Verifying);
+    let missing = up.mark_out_of_date();
+    if missing > 0 {
+        println!(
+            "{} of {} downstairs are missing, going active degraded",
+            missing,
+            dst.len()
+        );
+    }
+
+    let plan = up.reconcile_plan();
+    if plan.is_empty() {
+        println!("All extents match");
+    } else {
+        println!("{} extents need repair", plan.len());
+    }
+    repair_extents(up, dst, &plan, true).await?;
+
+    up.set_flush_numbers();
+    Ok(())
+}
+
+/*
+ * How long to wait after a failed reconcile before we try again.
+ */
+const RECONCILE_RETRY_SECS: u64 = 5;
+
+/*
+ * Reconcile has failed, and the downstairs that took part may have had
+ * some of their extents replaced by then.  Ask each one for its extent
+ * versions again, and put it back to waiting for the next try.  One that
+ * does not answer stays in FailedRepair, and is not used.
+ */
+async fn reconcile_failed(up: &Arc<Upstairs>, dst: &[Target]) {
+    up.ds_transition_all(DsState::FailedRepair);
+    let clients = up
+        .downstairs
+        .lock()
+        .unwrap()
+        .clients_in(DsState::FailedRepair);
+
+    for client_id in clients {
+        match refresh_versions(up, dst, client_id).await {
+            Err(e) => {
+                println!("[{}] can't retry reconcile: {:?}", client_id, e)
+            }
+            /*
+             * It may have gone away while we asked.
+             */
+            Ok(()) if up.ds_state(client_id as u8) == DsState::FailedRepair => {
+                up.ds_transition(client_id as u8, DsState::WaitQuorum);
+            }
+            Ok(()) => {}
+        }
+    }
+}
+
+/*
+ * Ask a downstairs for the version of every extent it has now.
+ */
+async fn refresh_versions(
+    up: &Arc<Upstairs>,
+    dst: &[Target],
+    client_id: usize,
+) -> Result<()> {
+    match repair_request(
+        &dst[client_id],
+        client_id,
+        Message::ExtentVersionsPlease,
+    )
+    .await?
+    {
+        Message::ExtentVersions(gen, flush, dirty) => process_downstairs(
+            &dst[client_id].target,
+            up,
+            client_id as u8,
+            gen,
+            flush,
+            dirty,
+        ),
+        m => {
+            bail!("[{}] answered ExtentVersionsPlease with {:?}", client_id, m)
+        }
+    }
+}
+
+/*
+ * A downstairs that was missing when we went active has connected.  It
+ * has seen none of the IO since then, so ask an active downstairs for its
+ * versions, and copy over every extent where they differ, one at a time.
+ * Guest IO goes on to the active downstairs while we do this.  Writes to
+ * an extent this one already has a good copy of go to it as well, and IO
+ * to the extent being copied waits until that copy is done.
+ */
+async fn catch_up(
+    up: &Arc<Upstairs>,
+    dst: &[Target],
+    client_id: u8,
+) -> Result<()> {
+    up.catch_up_start(client_id);
+
+    let source = match up
+        .downstairs
+        .lock()
+        .unwrap()
+        .clients_in(DsState::Active)
+        .first()
+    {
+        Some(source) => *source,
+        None => bail!("[{}] no active downstairs to copy from", client_id),
+    };
+    refresh_versions(up, dst, source).await?;
+
+    let plan = up.catch_up_plan(source, client_id as usize);
+    let count = up.catch_up_pending(client_id, &plan);
+    println!(
+        "[{}] {} extents to copy from [{}]",
+        client_id, count, source
+    );
+
+    while let Some(eid) = up.catch_up_next(client_id)? {
+        /*
+         * What was sent to this extent before we held its IO back has to
+         * be done before we read it.
+         */
+        let deadline = deadline_secs(50);
+        while up.extent_busy(client_id) {
+            if Instant::now() > deadline {
+                bail!(
+                    "[{}] timed out waiting for IO to extent {}",
+                    client_id,
+                    eid
+                );
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        if up.ds_state(source as u8) != DsState::Active {
+            bail!("[{}] [{}] is no longer active", client_id, source);
+        }
+
+        let fix = ExtentFix {
+            source,
+            repair: vec![client_id as usize],
+        };
+        repair_extents(up, dst, &[(eid, fix)], false).await?;
+        up.catch_up_copied(client_id, eid);
+        send_work(dst, 1);
+    }
+
+    up.caught_up(client_id);
+    Ok(())
+}
+
+/*
+ * Catch up a downstairs in a task of its own, so guest IO does not wait
+ * for it.  If that fails, we try again after a while: with the versions
+ * it sent if it has reconnected since, or after asking it for them again
+ * if it is still connected.  We give up once the upstairs is inactive.
+ */
+fn spawn_catch_up(
+    up: &Arc<Upstairs>,
+    dst: &Arc<Vec<Target>>,
+    client_id: u8,
+    ds_done_tx: &mpsc::Sender<u64>,
+) {
+    let up = Arc::clone(up);
+    let dst = Arc::clone(dst);
+    let ds_done_tx = ds_done_tx.clone();
+    tokio::spawn(async move {
+        loop {
+            match catch_up(&up, &dst, client_id).await {
+                Ok(()) => {
+                    up.ds_state_show();
+                    stat_update(&up, "catch up");
+                    return;
+                }
+                Err(e) => println!("[{}] catch up failed: {:?}", client_id, e),
+            }
+
+            let ackable = up.catch_up_failed(client_id);
+            if let Some(ds_id) = ackable.last() {
+                let _ = ds_done_tx.send(*ds_id).await;
+            }
+            send_work(&dst, 1);
+            up.ds_state_show();
+
+            loop {
+                tokio::time::sleep(Duration::from_secs(RECONCILE_RETRY_SECS))
+                    .await;
+                if !up.is_active() {
+                    up.catch_up_stopped(client_id);
+                    return;
+                }
+                match up.ds_state(client_id) {
+                    DsState::WaitQuorum => break,
+                    DsState::FailedRepair => {
+                        match refresh_versions(&up, &dst, client_id as usize)
+                            .await
+                        {
+                            Ok(()) => break,
+                            Err(e) => println!(
+                                "[{}] can't retry catch up: {:?}",
+                                client_id, e
+                            ),
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+    });
+}
+
+/*
+ * Send a downstairs one message of an extent push, and check it was taken.
+ */
+async fn repair_push(
+    dst: &Target,
+    client_id: usize,
+    eid: u64,
+    message: Message,
+) -> Result<()> {
+    match repair_request(dst, client_id, message).await? {
+        Message::ExtentPushAck(e, Ok(())) if e == eid => Ok(()),
+        Message::ExtentPushAck(e, Err(err)) if e == eid => {
+            bail!("[{}] could not repair extent {}: {}", client_id, eid, err)
+        }
+        m => bail!("[{}] answered ExtentPush with {:?}", client_id, m),
+    }
+}
+
+/*
+ * Copy each extent in the plan from its source downstairs to the ones
+ * that need it.  The extent is read and written a piece at a time, and
+ * the metadata is only sent once all the data is there.
+ *
+ * With clean, nothing else is being written while we repair, so once the
+ * copies are done they all hold the same data.  The copies are then
+ * marked clean, and a dirty source is written back to itself clean, or
+ * the same extent would be repaired all over again next time.  Otherwise
+ * the copies keep the dirty bit, so they are flushed along with the
+ * source.
+ */
+async fn repair_extents(
+    up: &Arc<Upstairs>,
+    dst: &[Target],
+    plan: &[(u64, ExtentFix)],
+    clean: bool,
+) -> Result<()> {
+    for (eid, fix) in plan.iter() {
+        let mut offset = 0;
+        let mut first: Option<ExtentInfo> = None;
+        let mut targets = fix.repair.clone();
+        loop {
+            let m = repair_request(
+                &dst[fix.source],
+                fix.source,
+                Message::ExtentFetch(*eid, offset, EXTENT_CHUNK_SIZE),
+            )
+            .await?;
+            let (info, data) = match m {
+                Message::ExtentData(e, o, info, data)
+                    if e == *eid && o == offset =>
+                {
+                    (info, data)
+                }
+                Message::ExtentError(e, err) if e == *eid => {
+                    bail!(
+                        "[{}] could not read extent {}: {}",
+                        fix.source,
+                        eid,
+                        err
+                    )
+                }
+                m => {
+                    bail!("[{}] answered ExtentFetch with {:?}", fix.source, m)
+                }
+            };
+            if first.is_none() && clean && info.dirty {
+                targets.push(fix.source);
+            }
+            if *first.get_or_insert(info) != info {
+                bail!("[{}] extent {} changed during repair", fix.source, eid);
+            }
+
+            let len = data.len() as u64;
+            for client_id in targets.iter() {
+                repair_push(
+                    &dst[*client_id],
+                    *client_id,
+                    *eid,
+                    Message::ExtentPush(*eid, offset, data.clone()),
+                )
+                .await?;
+            }
+            offset += len;
+            if len < EXTENT_CHUNK_SIZE {
+                break;
+            }
+        }
+
+        let mut info = first.unwrap();
+        if clean {
+            info.dirty = false;
+        }
+        for client_id in targets.iter() {
+            println!(
+                "[{}] Repair extent {} from [{}] gen {} flush {} dirty {}",
+                client_id,
+                eid,
+                fix.source,
+                info.gen_number,
+                info.flush_number,
+                info.dirty
+            );
+            repair_push(
+                &dst[*client_id],
+                *client_id,
+                *eid,
+                Message::ExtentPushDone(*eid, info),
+            )
+            .await?;
+        }
+        up.extent_repaired(*eid, fix, clean);
+    }
+    Ok(())
+}
+
+/*
+ * How long to wait for the rest of the downstairs once enough are here
+ * to go active without them.
+ */
+const DEGRADED_WAIT_SECS: u64 = 5;
+
+/*
+ * This task will loop forever and wait for the downstairs to get into the
  * ready state. We are notified of that through the ds_status_rx channel.
- * Once we have three connections, we then also listen for work requests
- * to come over the guest channel.
+ * Once we have every connection, or enough of them and the rest have had
+ * DEGRADED_WAIT_SECS to show up, we then also listen for work requests
+ * to come over the guest channel.  A downstairs that shows up after that
+ * is brought up to date by catch_up(), in a task of its own.
  * If we lose a connection to downstairs, we just panic. XXX Eventually we
  * will handle that situation.
  */
@@ -3808,43 +6132,78 @@ async fn up_listen(
     up: &Arc<Upstairs>,
     dst: Vec<Target>,
     mut ds_status_rx: mpsc::Receiver<Condition>,
+    ds_done_tx: mpsc::Sender<u64>,
 ) {
-    println!("Wait for all three downstairs to come online");
-    let mut ds_count = 0u32;
+    let dst = Arc::new(dst);
+    let quorum = up.downstairs.lock().unwrap().activation_quorum() as usize;
+    println!(
+        "Wait for {} of {} downstairs to come online",
+        quorum,
+        dst.len()
+    );
     let mut lastcast = 1;
 
     stat_update(up, "start");
 
     loop {
         /*
-         * For now, we need all three connections to proceed.
+         * Wait for all connections, or a quorum of them and then a while
+         * longer for the rest.
          */
-        loop {
+        let mut waiting = up
+            .downstairs
+            .lock()
+            .unwrap()
+            .clients_in(DsState::WaitQuorum)
+            .len();
+        let mut degraded_wait = deadline_secs(DEGRADED_WAIT_SECS);
+        while waiting < dst.len() {
             tokio::select! {
                 c = ds_status_rx.recv() => {
                     if let Some(c) = c {
+                        let was_waiting = waiting;
+                        waiting = up
+                            .downstairs
+                            .lock()
+                            .unwrap()
+                            .clients_in(DsState::WaitQuorum)
+                            .len();
                         if c.connected {
-                            ds_count += 1;
                             println!(
-                                "#### {:?} #### CONNECTED ######## {}/??",
-                                c.target, ds_count,
+                                "#### {:?} #### CONNECTED ######## {}/{}",
+                                c.target, waiting, dst.len(),
                             );
 
-                            if ds_count == 3 {
+                            if waiting == dst.len() {
                                 break;
                             }
+                            if was_waiting < quorum && waiting >= quorum {
+                                degraded_wait =
+                                    deadline_secs(DEGRADED_WAIT_SECS);
+                            }
                         } else {
                             println!(
                                 "#### {:?} #### DISCONNECTED! ####",
                                 c.target
                             );
-                            ds_count -= 1;
                         }
                     } else {
+                        /*
+                         * Every downstairs task has gone, so nothing more
+                         * can connect.
+                         */
                         println!("#### ? #### DISCONNECTED due to None! ####");
-                        ds_count -= 1;
+                        return;
                     }
                 }
+                _ = sleep_until(degraded_wait), if waiting >= quorum => {
+                    println!(
+                        "Only {} of {} downstairs are online",
+                        waiting,
+                        dst.len()
+                    );
+                    break;
+                }
                 req = up.guest.recv() => {
                     // Wait for the pre-activate related messages
                     if matches!(req.op,
@@ -3865,32 +6224,42 @@ async fn up_listen(
         }
         stat_update(up, "loop end");
 
-        println!("All expected targets are online, Now accepting IO requests");
-        /*
-         * XXX The real check to transition from WaitQuorum to Active can
-         * happen now, as all three are connected and ready. Right now this
-         * happens in process_downstairs() and in the wrong way.
-         */
+        println!("Enough targets are online, Now accepting IO requests");
 
         /*
          * Consider how the DsState::Failed is handled here, if necessary
          *
          * TODO: This logic does not cover the case where downstairs are
          * going up and down after the initial connection state.
-         * Eventually the check here needs to verify that all downstairs
-         * are still connected, and the actual work of making sure all
-         * downstairs are in sync is done only with all downstairs
-         * connected.
          */
-        if !up.all_ds_state_match(DsState::WaitQuorum) {
+        let waiting = up
+            .downstairs
+            .lock()
+            .unwrap()
+            .clients_in(DsState::WaitQuorum)
+            .len();
+        if waiting < quorum {
             up.ds_state_show();
             panic!(
-                "{} about to set all to active but not \
-                all state is WaitQuorum!!",
-                up.uuid
+                "{} about to go active but only {} of {} \
+                downstairs are in WaitQuorum!!",
+                up.uuid, waiting, quorum
             );
         }
 
+        /*
+         * If we could not make the downstairs agree, we stay inactive and
+         * try again after a while, with whatever downstairs are waiting
+         * by then.
+         */
+        if let Err(e) = reconcile(up, &dst).await {
+            println!("{} reconcile failed: {:?}", up.uuid, e);
+            reconcile_failed(up, &dst).await;
+            up.ds_state_show();
+            tokio::time::sleep(Duration::from_secs(RECONCILE_RETRY_SECS)).await;
+            continue;
+        }
+
         up.ds_transition_all(DsState::Active);
         up.ds_state_show();
 
@@ -3898,7 +6267,7 @@ async fn up_listen(
 
         stat_update(up, "active");
         /*
-         * We have three connections, so we can now start listening for
+         * We have all connections, so we can now start listening for
          * more IO to come in. We also need to make sure our downstairs
          * stay connected, and we watch the ds_status_rx.recv() for that
          * to change which is our notification that a disconnect has
@@ -3910,6 +6279,7 @@ async fn up_listen(
          */
         let mut flush_check = deadline_secs(5);
         let mut show_work_interval = deadline_secs(5);
+        let mut read_check = deadline_secs(1);
 
         loop {
             tokio::select! {
@@ -3924,6 +6294,14 @@ async fn up_listen(
                     if let Some(c) = &c {
                         if !c.connected {
                             println!("{} offline, pause IO ", c.target);
+                        } else if up.catching_up(c.client_id) {
+                            println!(
+                                "{} online, catch up will try again",
+                                c.target
+                            );
+                        } else if up.is_out_of_date(c.client_id) {
+                            println!("{} online, catching up", c.target);
+                            spawn_catch_up(up, &dst, c.client_id, &ds_done_tx);
                         } else {
                             println!("{} online", c.target);
                         }
@@ -3936,6 +6314,7 @@ async fn up_listen(
                             "Saw None in up_listen, draining in-flight IO"
                         );
                         show_all_work(up);
+                        up.guest.backlog.set_closed(true);
 
                         // Terminate all in-flight IO
                         loop {
@@ -3963,6 +6342,16 @@ async fn up_listen(
                 req = up.guest.recv() => {
                     process_new_io(up, &dst, req, &mut lastcast).await;
                 }
+                _ = sleep_until(read_check) => {
+                    /*
+                     * Reads can move to another downstairs when they
+                     * take too long, or when the one they were on fails.
+                     */
+                    if up.read_check() {
+                        send_work(&dst, 1);
+                    }
+                    read_check = deadline_secs(1);
+                }
                 _ = sleep_until(flush_check) => {
                     /*
                      * This must fire every "flush_check" seconds to make sure
@@ -4010,6 +6399,21 @@ pub async fn up_main(opt: CrucibleOpts, guest: Arc<Guest>) -> Result<()> {
         }
     }
 
+    if !opt.region.is_empty() && opt.region.len() != opt.target.len() {
+        bail!(
+            "{} regions given for {} targets",
+            opt.region.len(),
+            opt.target.len()
+        );
+    }
+    let (write_quorum, read_quorum) = opt.quorum()?;
+    println!(
+        "{} targets, write quorum {}, read quorum {}",
+        opt.target.len(),
+        write_quorum,
+        read_quorum
+    );
+
     let lossy = opt.lossy;
     /*
      * Build the Upstairs struct that we use to share data between
@@ -4056,14 +6460,19 @@ pub async fn up_main(opt: CrucibleOpts, guest: Arc<Guest>) -> Result<()> {
             // Notify when it's time to go active.
             let (ds_active_tx, ds_active_rx) = watch::channel(false);
 
+            // Extent repair messages, only used while reconciling.
+            let (ds_repair_tx, ds_repair_rx) = mpsc::channel(1);
+
             let up = Arc::clone(&up);
             let t0 = *dst;
             let up_coms = UpComs {
                 client_id,
+                region: opt.region.get(client_id as usize).copied(),
                 ds_work_rx,
                 ds_status_tx: ds_status_tx.clone(),
                 ds_done_tx: ds_done_tx.clone(),
                 ds_active_rx,
+                ds_repair_rx,
             };
             tokio::spawn(async move {
                 looper(t0, &up, up_coms, lossy).await;
@@ -4074,12 +6483,12 @@ pub async fn up_main(opt: CrucibleOpts, guest: Arc<Guest>) -> Result<()> {
                 target: *dst,
                 ds_work_tx,
                 ds_active_tx,
+                ds_repair_tx,
             }
         })
         .collect::<Vec<_>>();
 
     // Drop here, otherwise receivers will be kept waiting if looper quits
-    drop(ds_done_tx);
     drop(ds_status_tx);
 
     /*
@@ -4088,7 +6497,7 @@ pub async fn up_main(opt: CrucibleOpts, guest: Arc<Guest>) -> Result<()> {
      * Once connected, we then take work requests from the guest and
      * submit them into the upstairs
      */
-    up_listen(&up, dst, ds_status_rx).await;
+    up_listen(&up, dst, ds_status_rx, ds_done_tx).await;
 
     Ok(())
 }
@@ -4116,18 +6525,17 @@ fn create_write_eob(
         data,
     };
 
-    let mut state = HashMap::new();
-    for cl in 0..3 {
-        state.insert(cl, IOState::New);
-    }
-
     DownstairsIO {
         ds_id,
         guest_id: gw_id,
         work: awrite,
-        state,
+        state: HashMap::new(),
         ack_status: AckStatus::NotAcked,
         data: None,
+        retries: HashMap::new(),
+        issued: HashMap::new(),
+        data_from: None,
+        mismatch: false,
     }
 }
 
@@ -4151,18 +6559,17 @@ fn create_read_eob(
         num_blocks,
     };
 
-    let mut state = HashMap::new();
-    for cl in 0..3 {
-        state.insert(cl, IOState::New);
-    }
-
     DownstairsIO {
         ds_id,
         guest_id: gw_id,
         work: aread,
-        state,
+        state: HashMap::new(),
         ack_status: AckStatus::NotAcked,
         data: None,
+        retries: HashMap::new(),
+        issued: HashMap::new(),
+        data_from: None,
+        mismatch: false,
     }
 }
 
@@ -4180,29 +6587,30 @@ fn create_flush(
         flush_number,
     };
 
-    let mut state = HashMap::new();
-    for cl in 0..3 {
-        state.insert(cl, IOState::New);
-    }
     DownstairsIO {
         ds_id,
         guest_id,
         work: flush,
-        state,
+        state: HashMap::new(),
         ack_status: AckStatus::NotAcked,
         data: None,
+        retries: HashMap::new(),
+        issued: HashMap::new(),
+        data_from: None,
+        mismatch: false,
     }
 }
 
 /*
- * Debug function to display the work hashmap with status for all three of
- * the clients.
+ * Debug function to display the work hashmap with status for all of the
+ * clients.
  */
 fn show_all_work(up: &Arc<Upstairs>) -> WQCounts {
-    let mut iosc: IOStateCount = IOStateCount::new();
     let up_count = up.guest.guest_work.lock().unwrap().active.len();
 
     let work = up.downstairs.lock().unwrap();
+    let replicas = work.replicas() as u8;
+    let mut iosc: IOStateCount = IOStateCount::new(replicas as usize);
     let mut kvec: Vec<u64> = work.active.keys().cloned().collect::<Vec<u64>>();
     println!(
         "----------------------------------------------------------------"
@@ -4217,10 +6625,11 @@ fn show_all_work(up: &Arc<Upstairs>) -> WQCounts {
             show_guest_work(&up.guest);
         }
     } else {
-        println!(
-            "GW_ID      ACK   DSID   TYPE   ExtID BL_OFF BL_LEN \
-            DS:0 DS:1 DS:2"
-        );
+        print!("GW_ID      ACK   DSID   TYPE   ExtID BL_OFF BL_LEN ");
+        for cid in 0..replicas {
+            print!("DS:{} ", cid);
+        }
+        println!();
         kvec.sort_unstable();
         for id in kvec.iter() {
             let mut io_eid = 0;
@@ -4264,7 +6673,7 @@ fn show_all_work(up: &Arc<Upstairs>) -> WQCounts {
                 job.guest_id, ack, id, job_type, io_eid, io_offset, io_len
             );
 
-            for cid in 0..3 {
+            for cid in 0..replicas {
                 let state = job.state.get(&cid);
                 match state {
                     Some(state) => {
@@ -4284,6 +6693,16 @@ fn show_all_work(up: &Arc<Upstairs>) -> WQCounts {
             print!("{} ", lf);
         }
         println!();
+        print!("Flow windows: ");
+        for w in work.ds_window.iter() {
+            print!("{} ", w);
+        }
+        println!();
+        let (queued, held) = work.backlog.counts();
+        println!("Write backlog: {} queued {} held", queued, held);
+        if work.paranoid {
+            println!("Read mismatches: {}", work.read_mismatches);
+        }
     }
 
     let done = work.completed.to_vec();
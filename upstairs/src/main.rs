@@ -1,5 +1,4 @@
 // Copyright 2021 Oxide Computer Company
-use std::net::SocketAddrV4;
 use std::sync::Arc;
 
 use anyhow::{bail, Result};
@@ -15,18 +14,15 @@ use crucible::*;
 #[derive(Debug, StructOpt)]
 #[structopt(about = "volume-side storage component")]
 pub struct Opt {
-    #[structopt(short, long, default_value = "127.0.0.1:9000")]
-    target: Vec<SocketAddrV4>,
-
-    #[structopt(short, long)]
-    key: Option<String>,
+    #[structopt(flatten)]
+    crucible: CrucibleOpts,
 }
 
 pub fn opts() -> Result<Opt> {
     let opt: Opt = Opt::from_args();
     println!("raw options: {:?}", opt);
 
-    if opt.target.is_empty() {
+    if opt.crucible.target.is_empty() {
         bail!("must specify at least one --target");
     }
 
@@ -44,7 +40,7 @@ impl Opt {
     pub fn from_string(args: String) -> Result<Opt> {
         let opt: Opt = Opt::from_iter(args.split(' '));
 
-        if opt.target.is_empty() {
+        if opt.crucible.target.is_empty() {
             bail!("must specify at least one --target");
         }
 
@@ -63,15 +59,15 @@ mod tests {
             "-- -t 192.168.1.1:3801 -t 192.168.1.2:3801".to_string(),
         )
         .unwrap();
-        assert_eq!(opt.target.is_empty(), false);
-        assert_eq!(opt.target.len(), 2);
+        assert_eq!(opt.crucible.target.is_empty(), false);
+        assert_eq!(opt.crucible.target.len(), 2);
 
         assert_eq!(
-            opt.target[0],
+            opt.crucible.target[0],
             SocketAddrV4::new(Ipv4Addr::new(192, 168, 1, 1), 3801)
         );
         assert_eq!(
-            opt.target[1],
+            opt.crucible.target[1],
             SocketAddrV4::new(Ipv4Addr::new(192, 168, 1, 2), 3801)
         );
     }
@@ -89,7 +85,7 @@ mod tests {
         )
         .unwrap();
 
-        if let Some(key) = &opt.key {
+        if let Some(key) = &opt.crucible.key {
             assert_eq!(
                 base64::decode(key).expect("base64 decode failed"),
                 key_bytes
@@ -98,11 +94,7 @@ mod tests {
             panic!("failed to decode base64 key");
         }
 
-        let crucible_opts = crucible::CrucibleOpts {
-            target: opt.target,
-            lossy: false,
-            key: opt.key,
-        };
+        let crucible_opts = opt.crucible;
 
         if let Some(key) = crucible_opts.key_bytes() {
             assert_eq!(key_bytes, key);
@@ -114,11 +106,8 @@ mod tests {
 
 fn main() -> Result<()> {
     let opt = opts()?;
-    let crucible_opts = CrucibleOpts {
-        target: opt.target,
-        lossy: false,
-        key: opt.key,
-    };
+    let crucible_opts = opt.crucible;
+    crucible_opts.quorum()?;
 
     let runtime = Builder::new_multi_thread()
         .worker_threads(10)
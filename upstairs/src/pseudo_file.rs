@@ -124,6 +124,7 @@ pub struct CruciblePseudoFile {
     block_size: u64,
     rmw_lock: RwLock<bool>,
     upstairs_uuid: Uuid,
+    read_only: bool,
 }
 
 impl CruciblePseudoFile {
@@ -136,6 +137,7 @@ impl CruciblePseudoFile {
             block_size: 0,
             rmw_lock: RwLock::new(false),
             upstairs_uuid: Uuid::default(),
+            read_only: false,
         })
     }
 
@@ -153,6 +155,7 @@ impl CruciblePseudoFile {
         self.sz = self.guest.query_total_size()? as u64;
         self.block_size = self.guest.query_block_size()? as u64;
         self.upstairs_uuid = self.guest.query_upstairs_uuid()?;
+        self.read_only = self.guest.query_read_only()?;
 
         self.active = true;
 
@@ -166,6 +169,10 @@ impl CruciblePseudoFile {
     pub fn upstairs_uuid(&self) -> Uuid {
         self.upstairs_uuid
     }
+
+    pub fn read_only(&self) -> bool {
+        self.read_only
+    }
 }
 
 /*
@@ -193,6 +200,12 @@ impl Write for CruciblePseudoFile {
                 CrucibleError::UpstairsInactive,
             ));
         }
+        if self.read_only {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::PermissionDenied,
+                CrucibleError::ModifyingReadOnlyRegion,
+            ));
+        }
 
         self._write(buf).map_err(|e| e.into())
     }
@@ -204,6 +217,12 @@ impl Write for CruciblePseudoFile {
                 CrucibleError::UpstairsInactive,
             ));
         }
+        if self.read_only {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::PermissionDenied,
+                CrucibleError::ModifyingReadOnlyRegion,
+            ));
+        }
 
         self._flush().map_err(|e| e.into())
     }
@@ -8,6 +8,7 @@ mod test {
     use super::*;
     use pseudo_file::IOSpan;
     use ringbuffer::RingBuffer;
+    use std::net::Ipv4Addr;
 
     fn extent_tuple(eid: u64, offset: u64, len: u64) -> (u64, Block, Block) {
         (eid, Block::new_512(offset), Block::new_512(len))
@@ -146,13 +147,1314 @@ mod test {
         def.set_extent_size(Block::new_512(100));
         def.set_extent_count(10);
 
-        let opts = CrucibleOpts {
-            target: vec![],
-            lossy: false,
-            key: None,
+        Upstairs::new(&quorum_opts(3, None, None), def, Arc::new(Guest::new()))
+    }
+
+    #[test]
+    fn read_only_refuses_write_and_flush() {
+        let up = make_upstairs();
+        up.set_active();
+        up.set_read_only(0, true).unwrap();
+
+        let (send, _recv) = std_mpsc::channel();
+        assert_eq!(
+            up.submit_write(
+                Block::new_512(0),
+                Bytes::from(vec![1; 512]),
+                send.clone()
+            ),
+            Err(CrucibleError::ModifyingReadOnlyRegion)
+        );
+        assert_eq!(
+            up.submit_flush(Some(send)),
+            Err(CrucibleError::ModifyingReadOnlyRegion)
+        );
+
+        /*
+         * Nothing should have been put on the work queue.
+         */
+        assert_eq!(up.downstairs.lock().unwrap().active.len(), 0);
+    }
+
+    #[test]
+    fn read_only_downstairs_must_agree() {
+        let up = make_upstairs();
+        up.set_read_only(0, true).unwrap();
+        up.set_read_only(1, true).unwrap();
+        assert!(up.set_read_only(2, false).is_err());
+        assert!(up.is_read_only());
+    }
+
+    #[test]
+    fn reconcile_plan_repairs_mismatch() {
+        let up = make_upstairs();
+        let target = "127.0.0.1:3801".parse().unwrap();
+
+        /*
+         * Downstairs 1 has a newer flush of extent 1, and has extent 2
+         * dirty.
+         */
+        let gens = vec![1, 1, 1];
+        let clean = vec![false, false, false];
+        process_downstairs(
+            &target,
+            &up,
+            0,
+            gens.clone(),
+            vec![3, 4, 0],
+            clean.clone(),
+        )
+        .unwrap();
+        process_downstairs(
+            &target,
+            &up,
+            1,
+            gens.clone(),
+            vec![3, 5, 0],
+            vec![false, false, true],
+        )
+        .unwrap();
+        process_downstairs(&target, &up, 2, gens, vec![3, 4, 0], clean)
+            .unwrap();
+        for cid in 0..3 {
+            up.ds_transition(cid, DsState::WaitQuorum);
+        }
+        up.ds_transition_all(DsState::Verifying);
+
+        let plan = up.reconcile_plan();
+        assert_eq!(
+            plan,
+            vec![
+                (
+                    1,
+                    ExtentFix {
+                        source: 1,
+                        repair: vec![0, 2]
+                    }
+                ),
+                (
+                    2,
+                    ExtentFix {
+                        source: 0,
+                        repair: vec![1]
+                    }
+                ),
+            ]
+        );
+
+        for (eid, fix) in plan.iter() {
+            up.extent_repaired(*eid, fix, true);
+        }
+        assert!(up.reconcile_plan().is_empty());
+
+        up.set_flush_numbers();
+        assert_eq!(up.next_flush_id(), 6);
+    }
+
+    #[test]
+    fn reconcile_dirty_winner_is_repaired_once() {
+        let up = make_upstairs();
+        let target = "127.0.0.1:3801".parse().unwrap();
+
+        /*
+         * Every copy of extent 0 has writes that were never flushed, so
+         * they may all hold something different.
+         */
+        for cid in 0..3 {
+            process_downstairs(
+                &target,
+                &up,
+                cid,
+                vec![1, 1],
+                vec![3, 3],
+                vec![true, false],
+            )
+            .unwrap();
+            up.ds_transition(cid, DsState::WaitQuorum);
+        }
+        up.ds_transition_all(DsState::Verifying);
+
+        let plan = up.reconcile_plan();
+        assert_eq!(
+            plan,
+            vec![(
+                0,
+                ExtentFix {
+                    source: 0,
+                    repair: vec![1, 2]
+                }
+            )]
+        );
+
+        /*
+         * Once they all have the same copy, it is clean everywhere, and
+         * there is nothing left to do.
+         */
+        for (eid, fix) in plan.iter() {
+            up.extent_repaired(*eid, fix, true);
+        }
+        assert!(up.reconcile_plan().is_empty());
+    }
+
+    #[test]
+    fn activation_quorum() {
+        assert_eq!(Downstairs::new(3, 2, 1).activation_quorum(), 2);
+        assert_eq!(Downstairs::new(3, 1, 1).activation_quorum(), 3);
+        assert_eq!(Downstairs::new(2, 2, 1).activation_quorum(), 2);
+        assert_eq!(Downstairs::new(2, 1, 1).activation_quorum(), 2);
+        assert_eq!(Downstairs::new(5, 3, 1).activation_quorum(), 3);
+        assert_eq!(Downstairs::new(1, 1, 1).activation_quorum(), 1);
+    }
+
+    #[test]
+    fn degraded_activation_and_catch_up() {
+        let up = make_upstairs();
+        let target = "127.0.0.1:3801".parse().unwrap();
+
+        /*
+         * Downstairs 1 is missing when we go active.  The other two
+         * disagree about extent 1.
+         */
+        let gens = vec![1, 1, 1];
+        let clean = vec![false, false, false];
+        process_downstairs(
+            &target,
+            &up,
+            0,
+            gens.clone(),
+            vec![3, 4, 0],
+            clean.clone(),
+        )
+        .unwrap();
+        process_downstairs(
+            &target,
+            &up,
+            2,
+            gens.clone(),
+            vec![3, 5, 0],
+            clean.clone(),
+        )
+        .unwrap();
+        up.ds_transition(0, DsState::WaitQuorum);
+        up.ds_transition(2, DsState::WaitQuorum);
+
+        up.ds_transition_all(DsState::Verifying);
+        assert_eq!(up.mark_out_of_date(), 1);
+        assert!(up.is_out_of_date(1));
+        assert!(!up.is_out_of_date(0));
+
+        let plan = up.reconcile_plan();
+        assert_eq!(
+            plan,
+            vec![(
+                1,
+                ExtentFix {
+                    source: 2,
+                    repair: vec![0]
+                }
+            )]
+        );
+        for (eid, fix) in plan.iter() {
+            up.extent_repaired(*eid, fix, true);
+        }
+        up.set_flush_numbers();
+        assert_eq!(up.next_flush_id(), 6);
+
+        up.ds_transition_all(DsState::Active);
+        assert_eq!(up.ds_state(1), DsState::New);
+        up.set_active();
+        assert!(up.is_degraded());
+
+        /*
+         * IO skips the missing downstairs, and can still be acked and
+         * retired by the other two.
+         */
+        {
+            let mut work = up.downstairs.lock().unwrap();
+            let next_id = work.next_id();
+            let op = create_flush(next_id, vec![], 6, 0);
+            work.enqueue(op);
+            let job = work.active.get(&next_id).unwrap();
+            assert_eq!(job.state.get(&1), Some(&IOState::Skipped));
+
+            work.in_progress(next_id, 0);
+            work.in_progress(next_id, 2);
+            assert_eq!(work.complete(next_id, 0, None, Ok(())).unwrap(), false);
+            assert_eq!(work.complete(next_id, 2, None, Ok(())).unwrap(), true);
+            assert!(work.result(next_id).is_ok());
+            work.ack(next_id);
+            work.retire_check(next_id);
+            assert_eq!(work.completed.len(), 1);
+        }
+
+        /*
+         * Now it shows up.  It only needs the extents that differ from an
+         * active downstairs, whatever its own flush numbers say.
+         */
+        process_downstairs(&target, &up, 1, gens, vec![3, 9, 1], clean)
+            .unwrap();
+        up.ds_transition(1, DsState::WaitQuorum);
+        up.ds_transition(1, DsState::Verifying);
+        let plan = up.catch_up_plan(0, 1);
+        assert_eq!(plan.len(), 2);
+        assert_eq!(plan[0].0, 1);
+        assert_eq!(plan[1].0, 2);
+        for (eid, fix) in plan.iter() {
+            up.extent_repaired(*eid, fix, false);
+        }
+        assert!(up.catch_up_plan(0, 1).is_empty());
+
+        up.caught_up(1);
+        assert!(!up.is_out_of_date(1));
+        assert!(!up.is_degraded());
+
+        let mut work = up.downstairs.lock().unwrap();
+        let next_id = work.next_id();
+        let op = create_flush(next_id, vec![], 7, 0);
+        work.enqueue(op);
+        let job = work.active.get(&next_id).unwrap();
+        assert_eq!(job.state.get(&1), Some(&IOState::New));
+    }
+
+    #[test]
+    fn work_write_skipped_and_error_equals_fail() {
+        let mut work = Downstairs::default();
+        work.ds_out_of_date[1] = true;
+
+        let next_id = work.next_id();
+        let op = create_write_eob(
+            next_id,
+            vec![],
+            10,
+            0,
+            Block::new_512(7),
+            Bytes::from(vec![1]),
+        );
+        work.enqueue(op);
+
+        work.in_progress(next_id, 0);
+        work.in_progress(next_id, 2);
+
+        assert_eq!(work.complete(next_id, 0, None, Ok(())).unwrap(), false);
+        assert_eq!(
+            work.complete(
+                next_id,
+                2,
+                None,
+                Err(CrucibleError::GenericError(format!("bad")))
+            )
+            .unwrap(),
+            true
+        );
+        assert!(work.result(next_id).is_err());
+    }
+
+    #[test]
+    fn skipped_jobs_are_not_dependencies() {
+        // A downstairs that was caught up after skipping a job must not
+        // be told to wait for it.
+        let mut work = Downstairs::default();
+        work.ds_out_of_date[1] = true;
+
+        let id1 = work.next_id();
+        let op = create_write_eob(
+            id1,
+            vec![],
+            10,
+            0,
+            Block::new_512(7),
+            Bytes::from(vec![1]),
+        );
+        work.enqueue(op);
+
+        work.ds_out_of_date[1] = false;
+        let id2 = work.next_id();
+        let op = create_flush(id2, vec![id1, id1 - 1], 10, 0);
+        work.enqueue(op);
+
+        assert!(work.in_progress(id1, 0).is_some());
+        match work.in_progress(id2, 0).unwrap() {
+            IOop::Flush {
+                dependencies,
+                flush_number: _,
+            } => assert_eq!(dependencies, vec![id1]),
+            _ => panic!("not a flush"),
+        }
+        match work.in_progress(id2, 1).unwrap() {
+            IOop::Flush {
+                dependencies,
+                flush_number: _,
+            } => assert!(dependencies.is_empty()),
+            _ => panic!("not a flush"),
+        }
+    }
+
+    #[test]
+    fn caught_up_drops_older_dependencies() {
+        // A job done on an earlier connection to a downstairs is not a
+        // dependency once it has been caught up on a new one.
+        let upstairs = make_upstairs();
+        let id1 = {
+            let mut work = upstairs.downstairs.lock().unwrap();
+            let id1 = work.next_id();
+            let op = create_write_eob(
+                id1,
+                vec![],
+                10,
+                0,
+                Block::new_512(7),
+                Bytes::from(vec![1]),
+            );
+            work.enqueue(op);
+            for cid in 0..3 {
+                work.in_progress(id1, cid);
+                work.complete(id1, cid, None, Ok(())).unwrap();
+            }
+            work.ds_state[0] = DsState::WaitQuorum;
+            id1
+        };
+        upstairs.catch_up_start(0);
+        upstairs.caught_up(0);
+
+        let mut work = upstairs.downstairs.lock().unwrap();
+        let id2 = work.next_id();
+        let op = create_flush(id2, vec![id1], 10, 0);
+        work.enqueue(op);
+
+        match work.in_progress(id2, 0).unwrap() {
+            IOop::Flush {
+                dependencies,
+                flush_number: _,
+            } => assert!(dependencies.is_empty()),
+            _ => panic!("not a flush"),
+        }
+        match work.in_progress(id2, 1).unwrap() {
+            IOop::Flush {
+                dependencies,
+                flush_number: _,
+            } => assert_eq!(dependencies, vec![id1]),
+            _ => panic!("not a flush"),
+        }
+    }
+
+    fn enqueue_write(work: &mut Downstairs, eid: u64) -> u64 {
+        let id = work.next_id();
+        let op = create_write_eob(
+            id,
+            vec![],
+            10,
+            eid,
+            Block::new_512(0),
+            Bytes::from(vec![1]),
+        );
+        work.enqueue(op);
+        id
+    }
+
+    #[test]
+    fn catch_up_holds_io_to_copied_extent() {
+        // While a downstairs is caught up it gets writes to the extents
+        // it already has, and IO to the extent being copied waits for
+        // the copy.
+        let upstairs = make_upstairs();
+        let id1 = {
+            let mut work = upstairs.downstairs.lock().unwrap();
+            work.ds_state =
+                vec![DsState::Active, DsState::WaitQuorum, DsState::Active];
+            work.ds_out_of_date[1] = true;
+            work.ds_versions[1] = vec![
+                ExtentVersion {
+                    gen: 1,
+                    flush: 1,
+                    dirty: false,
+                };
+                10
+            ];
+            enqueue_write(&mut work, 2)
+        };
+
+        upstairs.catch_up_start(1);
+        let id2 = enqueue_write(&mut upstairs.downstairs.lock().unwrap(), 5);
+        let fix = ExtentFix {
+            source: 0,
+            repair: vec![1],
+        };
+        assert_eq!(upstairs.catch_up_pending(1, &[(4, fix)]), 3);
+
+        let mut work = upstairs.downstairs.lock().unwrap();
+        let id3 = enqueue_write(&mut work, 7);
+        for (id, state) in [
+            (id1, IOState::Skipped),
+            (id2, IOState::Skipped),
+            (id3, IOState::New),
+        ] {
+            assert_eq!(work.active[&id].state[&1], state);
+        }
+        drop(work);
+
+        assert_eq!(upstairs.catch_up_next(1).unwrap(), Some(2));
+        let mut work = upstairs.downstairs.lock().unwrap();
+        let id4 = enqueue_write(&mut work, 2);
+        let id5 = work.next_id();
+        work.enqueue(create_flush(id5, vec![], 10, 0));
+        assert_eq!(work.active[&id4].state[&1], IOState::New);
+        assert_eq!(work.active[&id5].state[&1], IOState::New);
+        assert!(!work.new_work(0).contains(&id4));
+        assert!(!work.new_work(0).contains(&id5));
+        assert!(work.new_work(0).contains(&id1));
+        assert!(work.new_work(1).contains(&id3));
+
+        /*
+         * The write from before has to finish before we copy.
+         */
+        assert!(work.extent_busy(1));
+        for cid in [0, 2] {
+            work.in_progress(id1, cid);
+            work.complete(id1, cid, None, Ok(())).unwrap();
+        }
+        assert!(!work.extent_busy(1));
+        drop(work);
+
+        upstairs.catch_up_copied(1, 2);
+        {
+            let work = upstairs.downstairs.lock().unwrap();
+            assert!(work.new_work(0).contains(&id4));
+            assert!(work.new_work(0).contains(&id5));
+            assert!(work.new_work(1).contains(&id4));
+        }
+
+        /*
+         * If it fails, it gets nothing more until we try again.
+         */
+        upstairs.catch_up_failed(1);
+        assert_eq!(upstairs.ds_state(1), DsState::FailedRepair);
+        assert!(upstairs.catching_up(1));
+        {
+            let mut work = upstairs.downstairs.lock().unwrap();
+            for id in [id3, id4, id5] {
+                assert_eq!(work.active[&id].state[&1], IOState::Skipped);
+            }
+            let id6 = enqueue_write(&mut work, 7);
+            assert_eq!(work.active[&id6].state[&1], IOState::Skipped);
+        }
+        upstairs.catch_up_stopped(1);
+        assert!(!upstairs.catching_up(1));
+    }
+
+    #[test]
+    fn offline_past_deadline_fails_downstairs() {
+        // A downstairs that stays Offline past the reconnect deadline is
+        // Failed, and the write that was waiting on it can go back to
+        // the guest.
+        let upstairs = make_upstairs();
+        let next_id = {
+            let mut work = upstairs.downstairs.lock().unwrap();
+            work.ds_state = vec![DsState::Active; 3];
+
+            let next_id = work.next_id();
+            let op = create_write_eob(
+                next_id,
+                vec![],
+                10,
+                0,
+                Block::new_512(7),
+                Bytes::from(vec![1]),
+            );
+            work.enqueue(op);
+
+            work.in_progress(next_id, 0);
+            work.in_progress(next_id, 1);
+            work.in_progress(next_id, 2);
+
+            assert_eq!(work.complete(next_id, 1, None, Ok(())).unwrap(), false);
+            assert_eq!(
+                work.complete(
+                    next_id,
+                    2,
+                    None,
+                    Err(CrucibleError::GenericError(format!("bad")))
+                )
+                .unwrap(),
+                false
+            );
+            next_id
         };
 
-        Upstairs::new(&opts, def, Arc::new(Guest::new()))
+        upstairs.ds_missing(0);
+        assert_eq!(upstairs.ds_state(0), DsState::Offline);
+
+        let mut work = upstairs.downstairs.lock().unwrap();
+        let policy = upstairs.reconnect;
+        let now = Instant::now();
+        assert!(!work.offline_exceeded(0, &policy, now));
+        assert!(work.offline_exceeded(0, &policy, now + policy.deadline));
+
+        assert_eq!(work.fail_client(0), vec![next_id]);
+        assert_eq!(work.ds_state[0], DsState::Failed);
+        assert!(work.ds_out_of_date[0]);
+        assert!(work.result(next_id).is_err());
+
+        // New work skips it until it has been caught up.
+        let next_id = work.next_id();
+        let op = create_flush(next_id, vec![], 10, 0);
+        work.enqueue(op);
+        assert!(work.new_work(0).is_empty());
+    }
+
+    #[test]
+    fn offline_replay_over_limit_fails_downstairs() {
+        let mut work = Downstairs::default();
+        work.ds_state[0] = DsState::Offline;
+        let policy = ReconnectPolicy {
+            deadline: Duration::from_secs(3600),
+            max_bytes: 1000,
+        };
+
+        for _ in 0..2 {
+            assert!(!work.offline_exceeded(0, &policy, Instant::now()));
+            let next_id = work.next_id();
+            let op = create_write_eob(
+                next_id,
+                vec![],
+                10,
+                0,
+                Block::new_512(7),
+                Bytes::from(vec![1; 512]),
+            );
+            work.enqueue(op);
+        }
+
+        assert_eq!(work.pending_bytes(0), 1024);
+        assert!(work.offline_exceeded(0, &policy, Instant::now()));
+    }
+
+    #[test]
+    fn failed_downstairs_lets_acked_flush_retire() {
+        // A write and flush that are done and acked on two downstairs
+        // are retired once we give up on the third.
+        let mut work = Downstairs::default();
+        work.ds_state = vec![DsState::Active; 3];
+
+        let write_id = work.next_id();
+        let op = create_write_eob(
+            write_id,
+            vec![],
+            10,
+            0,
+            Block::new_512(7),
+            Bytes::from(vec![1]),
+        );
+        work.enqueue(op);
+        let flush_id = work.next_id();
+        let op = create_flush(flush_id, vec![write_id], 10, 0);
+        work.enqueue(op);
+
+        for ds_id in [write_id, flush_id] {
+            work.in_progress(ds_id, 1);
+            work.in_progress(ds_id, 2);
+            assert_eq!(work.complete(ds_id, 1, None, Ok(())).unwrap(), false);
+            assert_eq!(work.complete(ds_id, 2, None, Ok(())).unwrap(), true);
+            work.ack(ds_id);
+        }
+
+        work.ds_state[0] = DsState::Offline;
+        work.re_new(0);
+        assert_eq!(work.active.len(), 2);
+
+        assert!(work.fail_client(0).is_empty());
+        assert_eq!(work.active.len(), 0);
+        assert_eq!(work.completed.len(), 2);
+    }
+
+    /*
+     * Put a write on the queue and send it to every downstairs.
+     */
+    fn write_in_progress(upstairs: &Arc<Upstairs>) -> u64 {
+        let mut work = upstairs.downstairs.lock().unwrap();
+        work.ds_state = vec![DsState::Active; 3];
+
+        let next_id = work.next_id();
+        let op = create_write_eob(
+            next_id,
+            vec![],
+            10,
+            0,
+            Block::new_512(7),
+            Bytes::from(vec![1]),
+        );
+        work.enqueue(op);
+
+        for cid in 0..3 {
+            assert!(work.in_progress(next_id, cid).is_some());
+        }
+        next_id
+    }
+
+    /*
+     * Pretend the backoff for this retry has already passed.
+     */
+    fn retry_now(upstairs: &Arc<Upstairs>, ds_id: u64, client_id: u8) {
+        let mut work = upstairs.downstairs.lock().unwrap();
+        let job = work.active.get_mut(&ds_id).unwrap();
+        job.retries.get_mut(&client_id).unwrap().1 = Instant::now();
+    }
+
+    #[test]
+    fn write_error_is_retried() {
+        let upstairs = make_upstairs();
+        let next_id = write_in_progress(&upstairs);
+
+        let err = Err(CrucibleError::IoError("No space left".to_string()));
+        assert_eq!(upstairs.complete(next_id, 0, None, err).unwrap(), false);
+
+        // It goes back to New, but isn't sent again until its backoff.
+        {
+            let work = upstairs.downstairs.lock().unwrap();
+            let job = work.active.get(&next_id).unwrap();
+            assert_eq!(job.state.get(&0), Some(&IOState::New));
+            assert!(work.new_work(0).is_empty());
+            assert!(work.retry_waiting(0));
+        }
+
+        retry_now(&upstairs, next_id, 0);
+        {
+            let mut work = upstairs.downstairs.lock().unwrap();
+            assert_eq!(work.new_work(0), vec![next_id]);
+            assert!(work.in_progress(next_id, 0).is_some());
+        }
+
+        assert_eq!(upstairs.complete(next_id, 0, None, Ok(())).unwrap(), false);
+        assert_eq!(upstairs.complete(next_id, 1, None, Ok(())).unwrap(), true);
+        assert_eq!(upstairs.ds_state(0), DsState::Active);
+
+        let mut work = upstairs.downstairs.lock().unwrap();
+        assert!(work.result(next_id).is_ok());
+        assert!(work.downstairs_errors.get(&0).is_none());
+    }
+
+    #[test]
+    fn write_error_after_retries_fails_downstairs() {
+        let mut opts = quorum_opts(3, None, None);
+        opts.io_retries = Some(1);
+        let upstairs = Upstairs::new(
+            &opts,
+            RegionDefinition::default(),
+            Arc::new(Guest::new()),
+        );
+        let next_id = write_in_progress(&upstairs);
+
+        assert_eq!(upstairs.complete(next_id, 1, None, Ok(())).unwrap(), false);
+        assert_eq!(upstairs.complete(next_id, 2, None, Ok(())).unwrap(), true);
+
+        let err = Err(CrucibleError::IoError("No space left".to_string()));
+        assert_eq!(
+            upstairs.complete(next_id, 0, None, err.clone()).unwrap(),
+            false
+        );
+        assert_eq!(upstairs.ds_state(0), DsState::Active);
+
+        retry_now(&upstairs, next_id, 0);
+        assert!(upstairs
+            .downstairs
+            .lock()
+            .unwrap()
+            .in_progress(next_id, 0)
+            .is_some());
+        assert_eq!(upstairs.complete(next_id, 0, None, err).unwrap(), false);
+
+        // Out of retries, so this downstairs is Failed and must be
+        // caught up, but the guest still gets its write.
+        assert_eq!(upstairs.ds_state(0), DsState::Failed);
+        assert!(upstairs.is_out_of_date(0));
+        let mut work = upstairs.downstairs.lock().unwrap();
+        assert!(work.result(next_id).is_ok());
+    }
+
+    #[test]
+    fn write_error_that_will_not_go_away_is_not_retried() {
+        let upstairs = make_upstairs();
+        let next_id = write_in_progress(&upstairs);
+
+        assert_eq!(upstairs.complete(next_id, 1, None, Ok(())).unwrap(), false);
+        let err = Err(CrucibleError::OffsetInvalid);
+        assert_eq!(upstairs.complete(next_id, 0, None, err).unwrap(), false);
+        assert_eq!(upstairs.ds_state(0), DsState::Failed);
+
+        let work = upstairs.downstairs.lock().unwrap();
+        let job = work.active.get(&next_id).unwrap();
+        assert!(matches!(job.state.get(&0), Some(IOState::Error(_))));
+        assert!(job.retries.is_empty());
+    }
+
+    #[test]
+    fn write_error_keeps_downstairs_needed_for_quorum() {
+        // With one downstairs gone, the two left are a write quorum, and
+        // neither is failed for an error that outlasts its retries.
+        let mut opts = quorum_opts(3, None, None);
+        opts.io_retries = Some(0);
+        let upstairs = Upstairs::new(
+            &opts,
+            RegionDefinition::default(),
+            Arc::new(Guest::new()),
+        );
+        let next_id = write_in_progress(&upstairs);
+        upstairs.downstairs.lock().unwrap().fail_client(2);
+
+        assert_eq!(upstairs.complete(next_id, 1, None, Ok(())).unwrap(), false);
+        let err = Err(CrucibleError::IoError("No space left".to_string()));
+        assert_eq!(upstairs.complete(next_id, 0, None, err).unwrap(), true);
+        assert_eq!(upstairs.ds_state(0), DsState::Active);
+        assert!(upstairs.downstairs.lock().unwrap().result(next_id).is_err());
+
+        /*
+         * It still takes writes and flushes, but it may not have the data
+         * of that write, so no read goes to it, whatever the policy.
+         */
+        let (_, readers) = enqueue_read(&upstairs);
+        assert_eq!(readers, vec![1]);
+        upstairs.downstairs.lock().unwrap().read_policy = ReadPolicy::All;
+        let (_, readers) = enqueue_read(&upstairs);
+        assert_eq!(readers, vec![1]);
+
+        let id2 = {
+            let mut work = upstairs.downstairs.lock().unwrap();
+            let id2 = work.next_id();
+            let op = create_flush(id2, vec![next_id], 10, 0);
+            work.enqueue(op);
+            assert!(work.in_progress(id2, 0).is_some());
+            assert!(work.in_progress(id2, 1).is_some());
+            id2
+        };
+
+        /*
+         * Once the third is back, it is given up on, so it can be caught
+         * up too.
+         */
+        {
+            let mut work = upstairs.downstairs.lock().unwrap();
+            work.ds_state[2] = DsState::Verifying;
+        }
+        upstairs.caught_up(2);
+        assert_eq!(upstairs.complete(id2, 1, None, Ok(())).unwrap(), false);
+        upstairs.complete(id2, 0, None, Ok(())).unwrap();
+        assert_eq!(upstairs.ds_state(0), DsState::Failed);
+        assert!(upstairs.is_out_of_date(0));
+    }
+
+    #[test]
+    fn read_error_after_retries_does_not_fail_downstairs() {
+        let mut opts = quorum_opts(3, None, None);
+        opts.io_retries = Some(0);
+        let upstairs = Upstairs::new(
+            &opts,
+            RegionDefinition::default(),
+            Arc::new(Guest::new()),
+        );
+
+        let next_id = {
+            let mut work = upstairs.downstairs.lock().unwrap();
+            work.ds_state = vec![DsState::Active; 3];
+            let next_id = work.next_id();
+            let op =
+                create_read_eob(next_id, vec![], 10, 0, Block::new_512(7), 2);
+            work.enqueue(op);
+            assert!(work.in_progress(next_id, 0).is_some());
+            next_id
+        };
+
+        let err = Err(CrucibleError::GenericError("bad".to_string()));
+        assert_eq!(
+            upstairs
+                .complete(next_id, 0, Some(Bytes::from(vec![])), err)
+                .unwrap(),
+            false
+        );
+        assert_eq!(upstairs.ds_state(0), DsState::Active);
+    }
+
+    fn read_policy_upstairs(policy: ReadPolicy) -> Arc<Upstairs> {
+        let mut opts = quorum_opts(3, None, None);
+        opts.read_policy = Some(policy);
+        let upstairs = Upstairs::new(
+            &opts,
+            RegionDefinition::default(),
+            Arc::new(Guest::new()),
+        );
+        upstairs.downstairs.lock().unwrap().ds_state = vec![DsState::Active; 3];
+        upstairs
+    }
+
+    /*
+     * Enqueue a read of block 7, and return its ID and the downstairs
+     * it was given to.
+     */
+    fn enqueue_read(upstairs: &Arc<Upstairs>) -> (u64, Vec<u8>) {
+        let mut work = upstairs.downstairs.lock().unwrap();
+        let next_id = work.next_id();
+        let op = create_read_eob(next_id, vec![], 10, 0, Block::new_512(7), 1);
+        work.enqueue(op);
+
+        let job = work.active.get(&next_id).unwrap();
+        let mut readers = (0..3)
+            .filter(|cid| job.state.get(cid) == Some(&IOState::New))
+            .collect::<Vec<u8>>();
+        readers.sort_unstable();
+        (next_id, readers)
+    }
+
+    #[test]
+    fn read_round_robin() {
+        let upstairs = read_policy_upstairs(ReadPolicy::RoundRobin);
+
+        for cid in [0, 1, 2, 0].iter() {
+            let (next_id, readers) = enqueue_read(&upstairs);
+            assert_eq!(readers, vec![*cid]);
+
+            let work = upstairs.downstairs.lock().unwrap();
+            let job = work.active.get(&next_id).unwrap();
+            assert_eq!(job.state_count().skipped, 2);
+        }
+    }
+
+    #[test]
+    fn read_least_outstanding() {
+        let upstairs = read_policy_upstairs(ReadPolicy::LeastOutstanding);
+
+        let (_, readers) = enqueue_read(&upstairs);
+        assert_eq!(readers, vec![0]);
+        let (_, readers) = enqueue_read(&upstairs);
+        assert_eq!(readers, vec![1]);
+        let (next_id, readers) = enqueue_read(&upstairs);
+        assert_eq!(readers, vec![2]);
+
+        // Once 2 is done, it has the least to do, although it is not
+        // next in turn.
+        {
+            let mut work = upstairs.downstairs.lock().unwrap();
+            assert!(work.in_progress(next_id, 2).is_some());
+            work.complete(next_id, 2, Some(Bytes::from(vec![1])), Ok(()))
+                .unwrap();
+        }
+        let (_, readers) = enqueue_read(&upstairs);
+        assert_eq!(readers, vec![2]);
+    }
+
+    #[test]
+    fn read_lowest_latency() {
+        let upstairs = read_policy_upstairs(ReadPolicy::LowestLatency);
+        upstairs.downstairs.lock().unwrap().ds_read_latency = vec![
+            Some(Duration::from_millis(10)),
+            Some(Duration::from_millis(1)),
+            Some(Duration::from_millis(5)),
+        ];
+
+        for _ in 0..3 {
+            let (_, readers) = enqueue_read(&upstairs);
+            assert_eq!(readers, vec![1]);
+        }
+    }
+
+    #[test]
+    fn read_quorum_picks_that_many() {
+        let mut opts = quorum_opts(3, None, Some(2));
+        opts.read_policy = Some(ReadPolicy::RoundRobin);
+        let upstairs = Upstairs::new(
+            &opts,
+            RegionDefinition::default(),
+            Arc::new(Guest::new()),
+        );
+        upstairs.downstairs.lock().unwrap().ds_state = vec![DsState::Active; 3];
+
+        let (_, readers) = enqueue_read(&upstairs);
+        assert_eq!(readers, vec![0, 1]);
+        let (_, readers) = enqueue_read(&upstairs);
+        assert_eq!(readers, vec![0, 2]);
+    }
+
+    #[test]
+    fn read_error_moves_to_another_downstairs() {
+        let upstairs = read_policy_upstairs(ReadPolicy::RoundRobin);
+        let (next_id, readers) = enqueue_read(&upstairs);
+        assert_eq!(readers, vec![0]);
+        assert!(upstairs
+            .downstairs
+            .lock()
+            .unwrap()
+            .in_progress(next_id, 0)
+            .is_some());
+
+        let err = Err(CrucibleError::GenericError("bad".to_string()));
+        assert_eq!(
+            upstairs
+                .complete(next_id, 0, Some(Bytes::from(vec![])), err)
+                .unwrap(),
+            false
+        );
+
+        {
+            let mut work = upstairs.downstairs.lock().unwrap();
+            let job = work.active.get(&next_id).unwrap();
+            assert!(matches!(job.state.get(&0), Some(IOState::Error(_))));
+            assert_eq!(job.state.get(&1), Some(&IOState::New));
+            assert_eq!(job.state.get(&2), Some(&IOState::Skipped));
+            assert_eq!(job.ack_status, AckStatus::NotAcked);
+            assert!(job.retries.is_empty());
+            assert!(work.in_progress(next_id, 1).is_some());
+        }
+
+        assert_eq!(
+            upstairs
+                .complete(next_id, 1, Some(Bytes::from(vec![1])), Ok(()))
+                .unwrap(),
+            true
+        );
+        let mut work = upstairs.downstairs.lock().unwrap();
+        assert!(work.result(next_id).is_ok());
+    }
+
+    #[test]
+    fn read_timeout_moves_to_another_downstairs() {
+        let upstairs = read_policy_upstairs(ReadPolicy::RoundRobin);
+        let (next_id, _) = enqueue_read(&upstairs);
+
+        let mut work = upstairs.downstairs.lock().unwrap();
+        assert!(work.in_progress(next_id, 0).is_some());
+
+        let timeout = Duration::from_secs(2);
+        let now = Instant::now();
+        assert!(!work.read_timeouts(timeout, now));
+
+        let later = now + Duration::from_secs(3);
+        assert!(work.read_timeouts(timeout, later));
+        let job = work.active.get(&next_id).unwrap();
+        assert_eq!(job.state.get(&0), Some(&IOState::InProgress));
+        assert_eq!(job.state.get(&1), Some(&IOState::New));
+
+        // 1 has only just been given it, so we leave it alone.
+        assert!(!work.read_timeouts(timeout, Instant::now()));
+    }
+
+    #[test]
+    fn read_not_moved_past_overlapping_write() {
+        let upstairs = read_policy_upstairs(ReadPolicy::RoundRobin);
+        let (next_id, _) = enqueue_read(&upstairs);
+
+        let mut work = upstairs.downstairs.lock().unwrap();
+        assert!(work.in_progress(next_id, 0).is_some());
+
+        // A later write to the same block has already gone to 1 and 2.
+        let write_id = work.next_id();
+        let op = create_write_eob(
+            write_id,
+            vec![next_id],
+            10,
+            0,
+            Block::new_512(7),
+            Bytes::from(vec![1; 512]),
+        );
+        work.enqueue(op);
+        assert!(work.in_progress(write_id, 1).is_some());
+        assert!(work.in_progress(write_id, 2).is_some());
+
+        assert!(!work.move_read(next_id));
+        let job = work.active.get(&next_id).unwrap();
+        assert_eq!(job.state_count().skipped, 2);
+    }
+
+    #[test]
+    fn flush_retires_after_read_it_did_not_wait_for() {
+        let upstairs = read_policy_upstairs(ReadPolicy::RoundRobin);
+        let (read_id, _) = enqueue_read(&upstairs);
+
+        let mut work = upstairs.downstairs.lock().unwrap();
+        assert!(work.in_progress(read_id, 0).is_some());
+
+        let flush_id = work.next_id();
+        let op = create_flush(flush_id, vec![read_id], 10, 0);
+        work.enqueue(op);
+        for cid in 0..3 {
+            assert!(work.in_progress(flush_id, cid).is_some());
+        }
+
+        // 1 and 2 were not sent the read, so the flush can be acked
+        // before it.
+        assert_eq!(work.complete(flush_id, 1, None, Ok(())).unwrap(), false);
+        assert_eq!(work.complete(flush_id, 2, None, Ok(())).unwrap(), true);
+        work.ack(flush_id);
+        work.retire_check(flush_id);
+
+        let bytes = Some(Bytes::from(vec![1]));
+        assert_eq!(work.complete(read_id, 0, bytes, Ok(())).unwrap(), true);
+        assert_eq!(work.complete(flush_id, 0, None, Ok(())).unwrap(), false);
+
+        // The flush is done everywhere, but the read is not yet acked.
+        assert_eq!(work.completed.len(), 0);
+
+        work.ack(read_id);
+        work.retire_check(read_id);
+        assert!(work.active.is_empty());
+        assert_eq!(work.completed.len(), 2);
+    }
+
+    fn paranoid_upstairs(fail: bool) -> Arc<Upstairs> {
+        let mut opts = quorum_opts(3, None, None);
+        opts.paranoid_reads = true;
+        opts.paranoid_fail = fail;
+        let upstairs = Upstairs::new(
+            &opts,
+            RegionDefinition::default(),
+            Arc::new(Guest::new()),
+        );
+        upstairs.downstairs.lock().unwrap().ds_state = vec![DsState::Active; 3];
+        upstairs
+    }
+
+    /*
+     * Send a read to every downstairs, and have each return the block
+     * filled with the given byte.  Return the read's ID.
+     */
+    fn paranoid_read(upstairs: &Arc<Upstairs>, fill: [u8; 3]) -> u64 {
+        let (next_id, readers) = enqueue_read(upstairs);
+        assert_eq!(readers, vec![0, 1, 2]);
+
+        let mut work = upstairs.downstairs.lock().unwrap();
+        for cid in 0..3 {
+            assert!(work.in_progress(next_id, cid).is_some());
+        }
+        for cid in 0..3 {
+            let bytes = Some(Bytes::from(vec![fill[cid as usize]; 512]));
+            // Only the last answer makes the read ready.
+            assert_eq!(
+                work.complete(next_id, cid, bytes, Ok(())).unwrap(),
+                cid == 2
+            );
+        }
+        next_id
+    }
+
+    #[test]
+    fn paranoid_read_waits_for_every_downstairs() {
+        let upstairs = paranoid_upstairs(false);
+        let next_id = paranoid_read(&upstairs, [1, 1, 1]);
+
+        let mut work = upstairs.downstairs.lock().unwrap();
+        assert!(work.result(next_id).is_ok());
+        assert_eq!(work.read_mismatches, 0);
+    }
+
+    #[test]
+    fn paranoid_read_mismatch_is_counted() {
+        let upstairs = paranoid_upstairs(false);
+        let next_id = paranoid_read(&upstairs, [1, 1, 2]);
+
+        let mut work = upstairs.downstairs.lock().unwrap();
+        assert!(work.result(next_id).is_ok());
+        assert_eq!(work.read_mismatches, 1);
+        let job = work.active.get(&next_id).unwrap();
+        assert_eq!(job.data, Some(Bytes::from(vec![1; 512])));
+        assert_eq!(job.data_from, Some(0));
+    }
+
+    #[test]
+    fn paranoid_fail_read_mismatch_fails() {
+        let upstairs = paranoid_upstairs(true);
+        let next_id = paranoid_read(&upstairs, [2, 1, 1]);
+
+        // Both later answers differ from the first, but it is one read.
+        let mut work = upstairs.downstairs.lock().unwrap();
+        assert!(work.result(next_id).is_err());
+        assert_eq!(work.read_mismatches, 1);
+    }
+
+    #[test]
+    fn mismatched_block_range() {
+        let offset = Block::new_512(7);
+        let a = vec![0; 512 * 4];
+
+        assert_eq!(mismatched_blocks(&offset, &a, &a), None);
+
+        let mut b = a.clone();
+        b[512 + 10] = 1;
+        assert_eq!(mismatched_blocks(&offset, &a, &b), Some((8, 8)));
+
+        b[512 * 3] = 1;
+        assert_eq!(mismatched_blocks(&offset, &a, &b), Some((8, 10)));
+
+        assert_eq!(mismatched_blocks(&offset, &a, &a[..512]), Some((7, 10)));
+    }
+
+    #[test]
+    fn write_backlog_held_until_retired() {
+        // Write data counts against the backlog until the flush after it
+        // is retired, not just until the write is acked.
+        let mut work = Downstairs::default();
+        work.ds_state = vec![DsState::Active; 3];
+
+        let write_id = work.next_id();
+        let op = create_write_eob(
+            write_id,
+            vec![],
+            10,
+            0,
+            Block::new_512(7),
+            Bytes::from(vec![1; 512]),
+        );
+        work.enqueue(op);
+        assert_eq!(work.backlog.counts(), (0, 512));
+
+        for cid in 0..3 {
+            work.in_progress(write_id, cid);
+            work.complete(write_id, cid, None, Ok(())).unwrap();
+        }
+        work.ack(write_id);
+        assert_eq!(work.backlog.counts(), (0, 512));
+
+        let flush_id = work.next_id();
+        let op = create_flush(flush_id, vec![write_id], 10, 0);
+        work.enqueue(op);
+        for cid in 0..3 {
+            work.in_progress(flush_id, cid);
+            work.complete(flush_id, cid, None, Ok(())).unwrap();
+        }
+        work.ack(flush_id);
+        work.retire_check(flush_id);
+
+        assert_eq!(work.completed.len(), 2);
+        assert_eq!(work.backlog.counts(), (0, 0));
+    }
+
+    #[test]
+    fn write_backlog_full_is_busy() {
+        let backlog = WriteBacklog::new();
+        backlog.set_limit(1024);
+
+        // A write bigger than the limit still goes in on its own.
+        assert!(backlog.try_reserve(2048));
+        assert!(!backlog.try_reserve(512));
+        backlog.dequeue(2048);
+
+        // Both queued and held bytes count.
+        assert!(backlog.try_reserve(512));
+        backlog.dequeue(512);
+        backlog.hold(512);
+        assert!(backlog.try_reserve(512));
+        assert!(!backlog.try_reserve(512));
+
+        backlog.release(512);
+        assert!(backlog.try_reserve(512));
+        assert_eq!(backlog.counts(), (1024, 0));
+    }
+
+    #[test]
+    fn write_backlog_waits_for_retire() {
+        let backlog = Arc::new(WriteBacklog::new());
+        backlog.set_limit(1024);
+        backlog.hold(1024);
+
+        let (tx, rx) = std_mpsc::channel();
+        let waiter = backlog.clone();
+        let handle = std::thread::spawn(move || {
+            waiter.reserve(512).unwrap();
+            tx.send(()).unwrap();
+        });
+
+        assert!(rx.recv_timeout(Duration::from_millis(100)).is_err());
+        backlog.release(1024);
+        rx.recv().unwrap();
+        handle.join().unwrap();
+        assert_eq!(backlog.counts(), (512, 0));
+    }
+
+    #[test]
+    fn write_backlog_wait_ends_when_inactive() {
+        // A write waiting for room gives up once the upstairs stops
+        // taking IO, as none will be retired for it.
+        let up = make_upstairs();
+        up.set_active();
+        let backlog = up.guest.backlog.clone();
+        backlog.set_limit(1024);
+        backlog.hold(1024);
+
+        let (tx, rx) = std_mpsc::channel();
+        let waiter = backlog.clone();
+        let handle = std::thread::spawn(move || {
+            tx.send(waiter.reserve(512)).unwrap();
+        });
+
+        assert!(rx.recv_timeout(Duration::from_millis(100)).is_err());
+        up.set_inactive();
+        assert_eq!(rx.recv().unwrap(), Err(CrucibleError::UpstairsInactive));
+        handle.join().unwrap();
+        assert_eq!(backlog.counts(), (0, 1024));
+
+        // It waits again once we are active again.
+        up.set_active();
+        backlog.release(1024);
+        assert_eq!(backlog.reserve(512), Ok(()));
+    }
+
+    #[test]
+    fn flow_window_follows_latency() {
+        let mut work = Downstairs::default();
+        let ms = Duration::from_millis;
+
+        // Jobs that take about as long as the quickest one open it up.
+        work.adjust_window(0, ms(1));
+        assert_eq!(work.ds_window[0], FLOW_WINDOW_START + 1);
+        for _ in 0..FLOW_WINDOW_MAX {
+            work.adjust_window(0, ms(2));
+        }
+        assert_eq!(work.ds_window[0], FLOW_WINDOW_MAX);
+
+        // Jobs that take much longer close it down, to no less than the
+        // minimum.
+        for _ in 0..100 {
+            work.adjust_window(0, ms(50));
+        }
+        assert!(work.ds_window[0] < FLOW_WINDOW_MAX);
+        for _ in 0..FLOW_WINDOW_MAX {
+            work.adjust_window(0, ms(50));
+        }
+        assert_eq!(work.ds_window[0], FLOW_WINDOW_MIN);
+
+        // Only the downstairs that took that long is held back.
+        assert_eq!(work.ds_window[1], FLOW_WINDOW_START);
+
+        work.reset_window(0);
+        assert_eq!(work.ds_window[0], FLOW_WINDOW_START);
+        assert_eq!(work.ds_min_latency[0], None);
+    }
+
+    #[test]
+    fn completed_job_adjusts_window() {
+        let mut work = Downstairs::default();
+        work.ds_state = vec![DsState::Active; 3];
+
+        let next_id = work.next_id();
+        let op = create_write_eob(
+            next_id,
+            vec![],
+            10,
+            0,
+            Block::new_512(7),
+            Bytes::from(vec![1]),
+        );
+        work.enqueue(op);
+        work.in_progress(next_id, 0);
+        work.in_progress(next_id, 1);
+        work.complete(next_id, 0, None, Ok(())).unwrap();
+        work.complete(
+            next_id,
+            1,
+            None,
+            Err(CrucibleError::GenericError("bad".to_string())),
+        )
+        .unwrap();
+
+        // Only the good answer tells us how long a job takes.
+        assert!(work.ds_min_latency[0].is_some());
+        assert!(work.ds_min_latency[1].is_none());
+        assert!(work.ds_min_latency[2].is_none());
     }
 
     /*
@@ -348,9 +1650,59 @@ mod test {
         assert_ne!(block, orig_block);
     }
 
+    #[test]
+    pub fn test_decode_key() {
+        let key = decode_key("ClENKTXD2bCyXSHnKXY7GGnk+NvQKbwpatjWP2fJzk0=");
+        assert_eq!(key.unwrap().len(), 32);
+
+        // Valid base64, but only 16 bytes
+        assert!(decode_key("AAAAAAAAAAAAAAAAAAAAAA==").is_err());
+        assert!(decode_key("not base64!").is_err());
+    }
+
+    fn quorum_opts(
+        replicas: u16,
+        write_quorum: Option<usize>,
+        read_quorum: Option<usize>,
+    ) -> CrucibleOpts {
+        CrucibleOpts {
+            target: (0..replicas)
+                .map(|i| SocketAddrV4::new(Ipv4Addr::LOCALHOST, 3801 + i))
+                .collect(),
+            region: vec![],
+            lossy: false,
+            key: None,
+            write_quorum,
+            read_quorum,
+            reconnect_deadline: None,
+            replay_max_bytes: None,
+            io_retries: None,
+            read_policy: None,
+            read_timeout: None,
+            paranoid_reads: false,
+            paranoid_fail: false,
+            write_backlog: None,
+        }
+    }
+
+    #[test]
+    fn quorum_defaults_and_limits() {
+        assert_eq!(quorum_opts(3, None, None).quorum().unwrap(), (2, 1));
+        assert_eq!(quorum_opts(2, None, None).quorum().unwrap(), (2, 1));
+        assert_eq!(quorum_opts(5, None, None).quorum().unwrap(), (3, 1));
+        assert_eq!(quorum_opts(1, None, None).quorum().unwrap(), (1, 1));
+        assert_eq!(quorum_opts(2, Some(1), Some(2)).quorum().unwrap(), (1, 2));
+
+        assert!(quorum_opts(0, None, None).quorum().is_err());
+        assert!(quorum_opts(3, Some(4), None).quorum().is_err());
+        assert!(quorum_opts(3, Some(0), None).quorum().is_err());
+        assert!(quorum_opts(3, None, Some(4)).quorum().is_err());
+        assert!(quorum_opts(3, None, Some(0)).quorum().is_err());
+    }
+
     #[test]
     fn work_flush_three_ok() {
-        let upstairs = Upstairs::default();
+        let upstairs = make_upstairs();
         let mut work = upstairs.downstairs.lock().unwrap();
 
         let next_id = work.next_id();
@@ -382,7 +1734,7 @@ mod test {
 
     #[test]
     fn work_flush_one_error_then_ok() {
-        let upstairs = Upstairs::default();
+        let upstairs = make_upstairs();
         let mut work = upstairs.downstairs.lock().unwrap();
 
         let next_id = work.next_id();
@@ -423,7 +1775,7 @@ mod test {
 
     #[test]
     fn work_flush_two_errors_equals_fail() {
-        let upstairs = Upstairs::default();
+        let upstairs = make_upstairs();
         let mut work = upstairs.downstairs.lock().unwrap();
 
         let next_id = work.next_id();
@@ -471,9 +1823,169 @@ mod test {
         assert_eq!(work.completed.len(), 1);
     }
 
+    #[test]
+    fn work_flush_two_way_needs_both() {
+        let mut work = Downstairs::new(2, 2, 1);
+
+        let next_id = work.next_id();
+        let op = create_flush(next_id, vec![], 10, 0);
+        work.enqueue(op);
+        assert_eq!(work.active.get(&next_id).unwrap().state.len(), 2);
+
+        work.in_progress(next_id, 0);
+        work.in_progress(next_id, 1);
+
+        assert_eq!(work.complete(next_id, 0, None, Ok(())).unwrap(), false);
+        assert_eq!(work.ackable_work().len(), 0);
+
+        assert_eq!(work.complete(next_id, 1, None, Ok(())).unwrap(), true);
+        assert_eq!(work.ackable_work().len(), 1);
+        assert!(work.result(next_id).is_ok());
+
+        work.ack(next_id);
+        work.retire_check(next_id);
+
+        assert_eq!(work.completed.len(), 1);
+    }
+
+    #[test]
+    fn work_flush_two_way_one_error_equals_fail() {
+        let mut work = Downstairs::new(2, 2, 1);
+
+        let next_id = work.next_id();
+        let op = create_flush(next_id, vec![], 10, 0);
+        work.enqueue(op);
+
+        work.in_progress(next_id, 0);
+        work.in_progress(next_id, 1);
+
+        assert_eq!(work.complete(next_id, 0, None, Ok(())).unwrap(), false);
+        assert_eq!(
+            work.complete(
+                next_id,
+                1,
+                None,
+                Err(CrucibleError::GenericError(format!("bad")))
+            )
+            .unwrap(),
+            true
+        );
+        assert!(work.result(next_id).is_err());
+    }
+
+    #[test]
+    fn work_write_five_way_quorum() {
+        let mut work = Downstairs::new(5, 3, 1);
+
+        let next_id = work.next_id();
+        let op = create_write_eob(
+            next_id,
+            vec![],
+            10,
+            0,
+            Block::new_512(7),
+            Bytes::from(vec![1]),
+        );
+        work.enqueue(op);
+
+        for cid in 0..5 {
+            work.in_progress(next_id, cid);
+        }
+
+        /*
+         * Two errors still leave three downstairs to make a quorum.
+         */
+        for cid in 0..2 {
+            assert_eq!(
+                work.complete(
+                    next_id,
+                    cid,
+                    None,
+                    Err(CrucibleError::GenericError(format!("bad")))
+                )
+                .unwrap(),
+                false
+            );
+        }
+        assert_eq!(work.complete(next_id, 2, None, Ok(())).unwrap(), false);
+        assert_eq!(work.complete(next_id, 3, None, Ok(())).unwrap(), false);
+        assert_eq!(work.ackable_work().len(), 0);
+
+        assert_eq!(work.complete(next_id, 4, None, Ok(())).unwrap(), true);
+        assert_eq!(work.ackable_work().len(), 1);
+        assert!(work.result(next_id).is_ok());
+    }
+
+    #[test]
+    fn work_write_five_way_three_errors_equals_fail() {
+        let mut work = Downstairs::new(5, 3, 1);
+
+        let next_id = work.next_id();
+        let op = create_write_eob(
+            next_id,
+            vec![],
+            10,
+            0,
+            Block::new_512(7),
+            Bytes::from(vec![1]),
+        );
+        work.enqueue(op);
+
+        for cid in 0..5 {
+            work.in_progress(next_id, cid);
+        }
+
+        for cid in 0..3 {
+            work.complete(
+                next_id,
+                cid,
+                None,
+                Err(CrucibleError::GenericError(format!("bad"))),
+            )
+            .unwrap();
+        }
+        assert_eq!(work.complete(next_id, 3, None, Ok(())).unwrap(), false);
+        assert_eq!(work.complete(next_id, 4, None, Ok(())).unwrap(), true);
+        assert!(work.result(next_id).is_err());
+    }
+
+    #[test]
+    fn work_read_quorum_two() {
+        let mut work = Downstairs::new(3, 2, 2);
+
+        let next_id = work.next_id();
+        let op = create_read_eob(next_id, vec![], 10, 0, Block::new_512(7), 2);
+        work.enqueue(op);
+
+        work.in_progress(next_id, 0);
+        work.in_progress(next_id, 1);
+        work.in_progress(next_id, 2);
+
+        let bytes = Some(Bytes::from(vec![]));
+        assert_eq!(work.complete(next_id, 0, bytes, Ok(())).unwrap(), false);
+        assert_eq!(work.ackable_work().len(), 0);
+
+        let bytes = Some(Bytes::from(vec![]));
+        assert_eq!(work.complete(next_id, 1, bytes, Ok(())).unwrap(), true);
+        assert_eq!(work.ackable_work().len(), 1);
+
+        /*
+         * Losing one of the two reads takes us back below the quorum, but
+         * the other still holds the data.
+         */
+        work.re_new(1);
+        let job = work.active.get(&next_id).unwrap();
+        assert_eq!(job.ack_status, AckStatus::NotAcked);
+        assert!(job.data.is_some());
+
+        let bytes = Some(Bytes::from(vec![]));
+        assert_eq!(work.complete(next_id, 2, bytes, Ok(())).unwrap(), true);
+        assert_eq!(work.ackable_work().len(), 1);
+    }
+
     #[test]
     fn work_read_one_ok() {
-        let upstairs = Upstairs::default();
+        let upstairs = make_upstairs();
         let mut work = upstairs.downstairs.lock().unwrap();
 
         let next_id = work.next_id();
@@ -512,7 +2024,7 @@ mod test {
 
     #[test]
     fn work_read_one_bad_two_ok() {
-        let upstairs = Upstairs::default();
+        let upstairs = make_upstairs();
         let mut work = upstairs.downstairs.lock().unwrap();
 
         let next_id = work.next_id();
@@ -561,7 +2073,7 @@ mod test {
 
     #[test]
     fn work_read_two_bad_one_ok() {
-        let upstairs = Upstairs::default();
+        let upstairs = make_upstairs();
         let mut work = upstairs.downstairs.lock().unwrap();
 
         let next_id = work.next_id();
@@ -619,7 +2131,7 @@ mod test {
 
     #[test]
     fn work_read_three_bad() {
-        let upstairs = Upstairs::default();
+        let upstairs = make_upstairs();
         let mut work = upstairs.downstairs.lock().unwrap();
 
         let next_id = work.next_id();
@@ -685,7 +2197,7 @@ mod test {
 
     #[test]
     fn work_read_two_ok_one_bad() {
-        let upstairs = Upstairs::default();
+        let upstairs = make_upstairs();
 
         let next_id = {
             let mut work = upstairs.downstairs.lock().unwrap();
@@ -747,7 +2259,7 @@ mod test {
 
     #[test]
     fn work_assert_no_transfer_of_bad_read() {
-        let upstairs = Upstairs::default();
+        let upstairs = make_upstairs();
         let mut work = upstairs.downstairs.lock().unwrap();
 
         let next_id = work.next_id();
@@ -803,7 +2315,7 @@ mod test {
 
     #[test]
     fn work_assert_ok_transfer_of_read_after_downstairs_write_errors() {
-        let upstairs = Upstairs::default();
+        let upstairs = make_upstairs();
         let mut work = upstairs.downstairs.lock().unwrap();
 
         let next_id = work.next_id();
@@ -882,7 +2394,7 @@ mod test {
 
     #[test]
     fn work_assert_reads_do_not_cause_failure_state_transition() {
-        let upstairs = Upstairs::default();
+        let upstairs = make_upstairs();
         let mut work = upstairs.downstairs.lock().unwrap();
 
         let next_id = work.next_id();
@@ -998,7 +2510,7 @@ mod test {
     fn work_completed_read_flush() {
         // Verify that a read remains on the active queue until a flush
         // comes through and clears it.
-        let upstairs = Upstairs::default();
+        let upstairs = make_upstairs();
         let mut work = upstairs.downstairs.lock().unwrap();
 
         // Build our read, put it into the work queue
@@ -1076,7 +2588,7 @@ mod test {
         // comes through and clears it.  In this case, we only complete
         // 2/3 for each IO.  We later come back and finish the 3rd IO
         // and the flush, which then allows the work to be completed.
-        let upstairs = Upstairs::default();
+        let upstairs = make_upstairs();
         let mut work = upstairs.downstairs.lock().unwrap();
 
         // Create two writes, put them on the work queue
@@ -1176,7 +2688,7 @@ mod test {
     fn work_completed_write_flush() {
         // Verify that a write remains on the active queue until a flush
         // comes through and clears it.
-        let upstairs = Upstairs::default();
+        let upstairs = make_upstairs();
         let mut work = upstairs.downstairs.lock().unwrap();
 
         // Build our write IO.
@@ -1242,7 +2754,7 @@ mod test {
         // 2 of 3 for each IO.  We later come back and finish the 3rd IO
         // and the flush, which then allows the work to be completed.
         // Also, we mix up which client finishes which job first.
-        let upstairs = Upstairs::default();
+        let upstairs = make_upstairs();
         let mut work = upstairs.downstairs.lock().unwrap();
 
         // Build two writes, put them on the work queue.
@@ -1340,7 +2852,7 @@ mod test {
     #[test]
     fn work_completed_read_replay() {
         // Verify that a single read will replay and move back from AckReady
-        let upstairs = Upstairs::default();
+        let upstairs = make_upstairs();
         let mut work = upstairs.downstairs.lock().unwrap();
 
         // Build our read IO and submit it to the work queue.
@@ -1375,7 +2887,7 @@ mod test {
     fn work_completed_two_read_replay() {
         // Verify that a read will replay and move not back from AckReady if
         // there is more than one done read.
-        let upstairs = Upstairs::default();
+        let upstairs = make_upstairs();
         let mut work = upstairs.downstairs.lock().unwrap();
 
         // Build a read and put it on the work queue.
@@ -1424,7 +2936,7 @@ mod test {
     fn work_completed_ack_read_replay() {
         // Verify that a read we Acked will still replay if that downstairs
         // goes away. Make sure everything still finishes ok.
-        let upstairs = Upstairs::default();
+        let upstairs = make_upstairs();
         let mut work = upstairs.downstairs.lock().unwrap();
 
         // Create the read and put it on the work queue.
@@ -1478,7 +2990,7 @@ mod test {
         // Verify that a replay when we have two completed writes will
         // change state from AckReady back to NotAcked.
         // If we then redo the work, it should go back to AckReady.
-        let upstairs = Upstairs::default();
+        let upstairs = make_upstairs();
         let mut work = upstairs.downstairs.lock().unwrap();
 
         // Create the write and put it on the work queue.
@@ -1525,7 +3037,7 @@ mod test {
     fn work_completed_write_acked_replay() {
         // Verify that a replay when we have acked a write will not
         // undo that ack.
-        let upstairs = Upstairs::default();
+        let upstairs = make_upstairs();
         let mut work = upstairs.downstairs.lock().unwrap();
 
         // Create the write and put it on the work queue.
@@ -1571,4 +3083,316 @@ mod test {
         assert_eq!(work.complete(id1, 0, None, Ok(()),).unwrap(), false);
         assert_eq!(work.complete(id1, 2, None, Ok(()),).unwrap(), false);
     }
+
+    /*
+     * The extents a fake downstairs has, and whether it takes pushes.
+     */
+    #[derive(Debug)]
+    struct FakeRegion {
+        extents: Vec<(ExtentInfo, Vec<u8>)>,
+        staged: HashMap<u64, Vec<u8>>,
+        refuse: bool,
+    }
+
+    impl FakeRegion {
+        fn new(flush: u64) -> Arc<Mutex<FakeRegion>> {
+            let info = ExtentInfo {
+                ext_version: 1,
+                gen_number: 1,
+                flush_number: flush,
+                dirty: false,
+            };
+            Arc::new(Mutex::new(FakeRegion {
+                extents: vec![(info, vec![0; 512 * 100]); 10],
+                staged: HashMap::new(),
+                refuse: false,
+            }))
+        }
+
+        fn versions(&self) -> (Vec<u64>, Vec<u64>, Vec<bool>) {
+            (
+                self.extents.iter().map(|(i, _)| i.gen_number).collect(),
+                self.extents.iter().map(|(i, _)| i.flush_number).collect(),
+                self.extents.iter().map(|(i, _)| i.dirty).collect(),
+            )
+        }
+
+        fn answer(&mut self, m: Message) -> Message {
+            match m {
+                Message::ExtentVersionsPlease => {
+                    let (gen, flush, dirty) = self.versions();
+                    Message::ExtentVersions(gen, flush, dirty)
+                }
+                Message::ExtentFetch(eid, offset, len) => {
+                    let (info, data) = &self.extents[eid as usize];
+                    let start = (offset as usize).min(data.len());
+                    let end = (start + len as usize).min(data.len());
+                    Message::ExtentData(
+                        eid,
+                        offset,
+                        *info,
+                        Bytes::from(data[start..end].to_vec()),
+                    )
+                }
+                Message::ExtentPush(eid, offset, data) => {
+                    let staged = self.staged.entry(eid).or_default();
+                    if offset == 0 {
+                        staged.clear();
+                    }
+                    assert_eq!(staged.len() as u64, offset);
+                    staged.extend_from_slice(&data);
+                    Message::ExtentPushAck(eid, Ok(()))
+                }
+                Message::ExtentPushDone(eid, info) => {
+                    if self.refuse {
+                        return Message::ExtentPushAck(
+                            eid,
+                            Err(CrucibleError::RepairRefused(
+                                "test".to_string(),
+                            )),
+                        );
+                    }
+                    let data = self.staged.remove(&eid).unwrap();
+                    self.extents[eid as usize] = (info, data);
+                    Message::ExtentPushAck(eid, Ok(()))
+                }
+                m => panic!("unexpected {:?}", m),
+            }
+        }
+    }
+
+    /*
+     * A target whose downstairs task answers repair requests from a fake
+     * region, the way cmd_loop() passes them to a real one.
+     */
+    fn fake_target(region: &Arc<Mutex<FakeRegion>>) -> Target {
+        let (ds_work_tx, _) = watch::channel(1);
+        let (ds_active_tx, _) = watch::channel(false);
+        let (ds_repair_tx, mut ds_repair_rx) = mpsc::channel(1);
+        let region = region.clone();
+        tokio::spawn(async move {
+            while let Some(RepairRequest { message, reply }) =
+                ds_repair_rx.recv().await
+            {
+                let m = region.lock().unwrap().answer(message);
+                let _ = reply.send(m);
+            }
+        });
+        Target {
+            target: "127.0.0.1:3801".parse().unwrap(),
+            ds_work_tx,
+            ds_active_tx,
+            ds_repair_tx,
+        }
+    }
+
+    /*
+     * Three fake downstairs waiting to go active.  Extent 4 on the
+     * second one is newer than the others.
+     */
+    fn fake_downstairs(
+        up: &Arc<Upstairs>,
+    ) -> (Vec<Arc<Mutex<FakeRegion>>>, Vec<Target>) {
+        let regions =
+            vec![FakeRegion::new(3), FakeRegion::new(3), FakeRegion::new(3)];
+        regions[1].lock().unwrap().extents[4] = (
+            ExtentInfo {
+                ext_version: 2,
+                gen_number: 1,
+                flush_number: 5,
+                dirty: false,
+            },
+            vec![7; 512 * 100],
+        );
+
+        for (cid, region) in regions.iter().enumerate() {
+            let (gen, flush, dirty) = region.lock().unwrap().versions();
+            process_downstairs(
+                &"127.0.0.1:3801".parse().unwrap(),
+                up,
+                cid as u8,
+                gen,
+                flush,
+                dirty,
+            )
+            .unwrap();
+            up.ds_transition(cid as u8, DsState::WaitQuorum);
+        }
+        let targets = regions.iter().map(fake_target).collect();
+        (regions, targets)
+    }
+
+    #[tokio::test]
+    async fn reconcile_copies_extents() {
+        let up = make_upstairs();
+        let (regions, dst) = fake_downstairs(&up);
+
+        reconcile(&up, &dst).await.unwrap();
+
+        /*
+         * The newer extent, and its metadata, went to the other two.
+         */
+        let good = regions[1].lock().unwrap().extents[4].clone();
+        assert_eq!(good.0.ext_version, 2);
+        for cid in [0, 2] {
+            let region = regions[cid].lock().unwrap();
+            assert_eq!(region.extents[4], good);
+            assert_eq!(region.extents[3].0.flush_number, 3);
+            assert!(region.staged.is_empty());
+        }
+        assert!(up.reconcile_plan().is_empty());
+        assert_eq!(up.next_flush_id(), 6);
+    }
+
+    #[tokio::test]
+    async fn reconcile_cleans_dirty_winner() {
+        let up = make_upstairs();
+        let regions =
+            vec![FakeRegion::new(3), FakeRegion::new(3), FakeRegion::new(3)];
+        for (cid, region) in regions.iter().enumerate() {
+            let mut region = region.lock().unwrap();
+            region.extents[2].0.dirty = true;
+            region.extents[2].1 = vec![cid as u8; 512 * 100];
+            let (gen, flush, dirty) = region.versions();
+            process_downstairs(
+                &"127.0.0.1:3801".parse().unwrap(),
+                &up,
+                cid as u8,
+                gen,
+                flush,
+                dirty,
+            )
+            .unwrap();
+            up.ds_transition(cid as u8, DsState::WaitQuorum);
+        }
+        let dst = regions.iter().map(fake_target).collect::<Vec<_>>();
+
+        reconcile(&up, &dst).await.unwrap();
+
+        /*
+         * Every copy, the one it came from as well, is now the same and
+         * clean, so the next reconcile has nothing to do.
+         */
+        for region in regions.iter() {
+            let region = region.lock().unwrap();
+            assert!(!region.extents[2].0.dirty);
+            assert_eq!(region.extents[2].1, vec![0; 512 * 100]);
+            assert!(region.staged.is_empty());
+        }
+        for cid in 0..3 {
+            refresh_versions(&up, &dst, cid).await.unwrap();
+        }
+        assert!(up.reconcile_plan().is_empty());
+    }
+
+    #[tokio::test]
+    async fn reconcile_tries_again() {
+        let up = make_upstairs();
+        let (regions, dst) = fake_downstairs(&up);
+
+        /*
+         * Downstairs 2 won't take the extent once it has it all, so
+         * reconcile fails after downstairs 0 is repaired.
+         */
+        regions[2].lock().unwrap().refuse = true;
+        assert!(reconcile(&up, &dst).await.is_err());
+
+        /*
+         * The repair of downstairs 0 went through before the failure,
+         * which is what it says now.
+         */
+        reconcile_failed(&up, &dst).await;
+        for cid in 0..3 {
+            assert_eq!(up.ds_state(cid), DsState::WaitQuorum);
+        }
+        assert_eq!(up.downstairs.lock().unwrap().ds_versions[0][4].flush, 5);
+
+        regions[2].lock().unwrap().refuse = false;
+        reconcile(&up, &dst).await.unwrap();
+        let good = regions[1].lock().unwrap().extents[4].clone();
+        assert_eq!(regions[2].lock().unwrap().extents[4], good);
+        assert!(up.reconcile_plan().is_empty());
+        for cid in 0..3 {
+            assert!(!up.is_out_of_date(cid));
+        }
+    }
+
+    #[tokio::test]
+    async fn reconcile_failed_drops_silent_downstairs() {
+        let up = make_upstairs();
+        let (regions, mut dst) = fake_downstairs(&up);
+        regions[2].lock().unwrap().refuse = true;
+        assert!(reconcile(&up, &dst).await.is_err());
+
+        /*
+         * One that can't be asked for its versions is left out.
+         */
+        let (ds_repair_tx, _) = mpsc::channel(1);
+        dst[2].ds_repair_tx = ds_repair_tx;
+        reconcile_failed(&up, &dst).await;
+        assert_eq!(up.ds_state(0), DsState::WaitQuorum);
+        assert_eq!(up.ds_state(1), DsState::WaitQuorum);
+        assert_eq!(up.ds_state(2), DsState::FailedRepair);
+    }
+
+    #[tokio::test]
+    async fn catch_up_copies_extents_and_tries_again() {
+        let up = make_upstairs();
+        let (regions, dst) = fake_downstairs(&up);
+
+        /*
+         * Go active without downstairs 1, then change an extent on the
+         * others, and finish a write to another that is not yet flushed.
+         */
+        up.ds_transition(1, DsState::New);
+        reconcile(&up, &dst).await.unwrap();
+        up.ds_transition_all(DsState::Active);
+        up.set_active();
+        let newer = (
+            ExtentInfo {
+                ext_version: 1,
+                gen_number: 1,
+                flush_number: 4,
+                dirty: false,
+            },
+            vec![9; 512 * 100],
+        );
+        for cid in [0, 2] {
+            regions[cid].lock().unwrap().extents[6] = newer.clone();
+            regions[cid].lock().unwrap().extents[7].1 = vec![1; 512 * 100];
+        }
+        let id = enqueue_write(&mut up.downstairs.lock().unwrap(), 7);
+        for cid in [0, 2] {
+            let mut work = up.downstairs.lock().unwrap();
+            work.in_progress(id, cid);
+            work.complete(id, cid, None, Ok(())).unwrap();
+        }
+
+        /*
+         * It comes back, but won't take the extents at first.
+         */
+        up.ds_transition(1, DsState::WaitQuorum);
+        regions[1].lock().unwrap().refuse = true;
+        assert!(catch_up(&up, &dst, 1).await.is_err());
+        up.catch_up_failed(1);
+        assert_eq!(up.ds_state(1), DsState::FailedRepair);
+        assert!(up.is_out_of_date(1));
+
+        regions[1].lock().unwrap().refuse = false;
+        refresh_versions(&up, &dst, 1).await.unwrap();
+        catch_up(&up, &dst, 1).await.unwrap();
+        assert_eq!(up.ds_state(1), DsState::Active);
+        assert!(!up.is_out_of_date(1));
+        assert!(!up.catching_up(1));
+
+        /*
+         * Its newer looking extent 4 was never acked, so the active copy
+         * wins, along with what it missed.
+         */
+        let r0 = regions[0].lock().unwrap();
+        let r1 = regions[1].lock().unwrap();
+        for eid in [4, 6, 7] {
+            assert_eq!(r1.extents[eid], r0.extents[eid]);
+        }
+    }
 }